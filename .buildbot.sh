@@ -9,12 +9,35 @@ export RUSTUP_HOME="`pwd`/.rustup"
 curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs > rustup.sh
 sh rustup.sh --default-host x86_64-unknown-linux-gnu --default-toolchain none -y --no-modify-path
 export PATH=`pwd`/.cargo/bin/:$PATH
-rustup install nightly
+rustup install stable
+rustup default stable
 
 cargo fmt --all -- --check
 
 cargo test
 cargo test --release
+# Check that the benchmarks, and the synthetic traces that they use, work.
+cargo test --features bench synth
+cargo bench --features bench --no-run
+# Check that metrics are reported through the `metrics` facade.
+cargo test --features metrics instrument
+# Check that the pure-Rust, decode-only configuration builds.
+cargo build --no-default-features --features decode
+# Check that the collect-only configuration builds.
+cargo build --no-default-features --features perf-collector
+# Check that the decoder builds for WebAssembly, e.g. for decoding traces in a browser.
+rustup target add wasm32-unknown-unknown
+cargo build --no-default-features --features decode --target wasm32-unknown-unknown
+# Check that the packet parser builds without std, e.g. for bare-metal tools.
+rustup target add x86_64-unknown-none
+cargo build --no-default-features --target x86_64-unknown-none
+
+# Check that hwtracer still builds with its minimum supported Rust version (see `rust-version` in
+# `Cargo.toml`), resolving dependencies to versions which support it too.
+rustup install 1.70.0
+CARGO_RESOLVER_INCOMPATIBLE_RUST_VERSIONS=fallback cargo generate-lockfile
+cargo +1.70.0 build
+rm Cargo.lock
 
 which cargo-deny | cargo install cargo-deny
 cargo-deny check license
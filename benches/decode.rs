@@ -0,0 +1,93 @@
+//! Benchmarks of packet parsing and decoding throughput.
+//!
+//! Run with `cargo bench --features bench`. Synthetic traces (see `hwtracer::synth`) are always
+//! measured. To also measure a recorded trace, e.g. one saved by a JIT, set `HWTRACER_BENCH_TRACE`
+//! to the path of a file containing its raw Intel PT data.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hwtracer::{
+    decode::{TraceDecoderBuilder, TraceDecoderKind},
+    pt::PacketParser,
+    synth::SynthTraceBuilder,
+    Trace,
+};
+use std::{env, hint::black_box};
+
+/// The sizes of the synthetic traces to measure.
+const SIZES: [usize; 2] = [1 << 16, 1 << 22];
+
+/// Returns the traces to measure, with their names, and whether they refer to real code (and can
+/// thus be decoded into blocks by any decoder).
+fn traces() -> Vec<(String, Box<dyn Trace>, bool)> {
+    let mut traces = SIZES
+        .iter()
+        .map(|&n| {
+            let t = SynthTraceBuilder::new(n).build();
+            (format!("synth-{}k", n >> 10), t, false)
+        })
+        .collect::<Vec<_>>();
+    if let Ok(path) = env::var("HWTRACER_BENCH_TRACE") {
+        let t =
+            <dyn Trace>::from_file(&path).unwrap_or_else(|e| panic!("can't load {}: {}", path, e));
+        traces.push(("recorded".to_owned(), t, true));
+    }
+    traces
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, trace, _) in traces() {
+        group.throughput(Throughput::Bytes(trace.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                for pkt in PacketParser::new(trace.bytes()) {
+                    black_box(pkt.unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn parse_batched(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_batched");
+    let mut buf = Vec::new();
+    for (name, trace, _) in traces() {
+        group.throughput(Throughput::Bytes(trace.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let mut parser = PacketParser::new(trace.bytes());
+                while parser.parse_into(&mut buf, 1024).unwrap() > 0 {
+                    black_box(&buf);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn decode_blocks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_blocks");
+    let traces = traces();
+    for kind in TraceDecoderKind::available() {
+        let dec = TraceDecoderBuilder::new().kind(kind).build().unwrap();
+        for (name, trace, real) in &traces {
+            // Decoders which follow the traced code can only decode traces of real code.
+            if !real && kind != TraceDecoderKind::YkPT {
+                continue;
+            }
+            group.throughput(Throughput::Bytes(trace.len() as u64));
+            group.bench_function(BenchmarkId::new(kind.to_string(), name), |b| {
+                b.iter(|| {
+                    for blk in dec.iter_blocks(&**trace) {
+                        black_box(blk.unwrap());
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, parse_batched, decode_blocks);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+#[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::__cpuid_count;
 use rerun_except::rerun_except;
 use std::env;
@@ -11,6 +12,9 @@ const FEATURE_CHECKS_PATH: &str = "feature_checks";
 const C_DEPS_DIR: &str = "c_deps";
 const C_DEPS_MAKEFILE: &str = "c_deps.mk";
 
+/// Where the libipt git submodule lives, for use by the `vendored-libipt` feature.
+const VENDORED_LIBIPT_DIR: &str = "vendor/libipt";
+
 /// Simple feature check, returning `true` if we have the feature.
 ///
 /// The checks themselves are in files under `FEATURE_CHECKS_PATH`.
@@ -86,33 +90,124 @@ fn fetch_libipt(c_deps_dir: &Path) {
     env::set_current_dir(&prev_dir).unwrap();
 }
 
-// Checks if the CPU supports Intel Processor Trace.
+/// Build libipt's library (but not its tools) from the vendored source tree, without needing a
+/// network connection, cmake, or a system installation.
+///
+/// Returns the directory containing the generated `intel-pt.h` header.
+fn build_vendored_libipt(src_dir: &Path) -> PathBuf {
+    eprintln!("Building vendored libipt...");
+
+    if !src_dir.join("libipt").exists() {
+        panic!(
+            "vendored libipt sources not found in {}. Try `git submodule update --init`.",
+            src_dir.display()
+        );
+    }
+
+    // cmake would normally get the version from `CMakeLists.txt` and bake it into the public
+    // header, so we have to do the same.
+    let cmake_lists = fs::read_to_string(src_dir.join("CMakeLists.txt")).unwrap();
+    let version = |name: &str| {
+        let prefix = format!("set({} ", name);
+        cmake_lists
+            .lines()
+            .find_map(|l| l.trim().strip_prefix(&prefix)?.strip_suffix(')'))
+            .unwrap_or_else(|| panic!("can't find {} in libipt's CMakeLists.txt", name))
+            .trim()
+            .to_owned()
+    };
+    let major = version("PT_VERSION_MAJOR");
+    let minor = version("PT_VERSION_MINOR");
+    let patch = version("PT_VERSION_PATCH");
+
+    let mut inc_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    inc_dir.push("vendored_libipt_include");
+    fs::create_dir_all(&inc_dir).unwrap();
+    let header = fs::read_to_string(src_dir.join("libipt/include/intel-pt.h.in"))
+        .unwrap()
+        .replace("${PT_VERSION_MAJOR}", &major)
+        .replace("${PT_VERSION_MINOR}", &minor)
+        .replace("${PT_VERSION_PATCH}", &patch);
+    fs::write(inc_dir.join("intel-pt.h"), header).unwrap();
+
+    let mut ipt_build = cc::Build::new();
+    for dir in &["libipt/src", "libipt/src/posix"] {
+        for entry in fs::read_dir(src_dir.join(dir)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |e| e == "c") {
+                ipt_build.file(path);
+            }
+        }
+    }
+    ipt_build
+        .include(&inc_dir)
+        .include(src_dir.join("libipt/internal/include"))
+        .include(src_dir.join("libipt/internal/include/posix"))
+        .define("PT_VERSION_MAJOR", major.as_str())
+        .define("PT_VERSION_MINOR", minor.as_str())
+        .define("PT_VERSION_PATCH", patch.as_str())
+        .define("PT_VERSION_BUILD", "0")
+        .define("PT_VERSION_EXT", "\"\"")
+        .define("FEATURE_THREADS", None)
+        .warnings(false)
+        // Emits the directives needed to link the library.
+        .compile("ipt");
+
+    inc_dir
+}
+
+// Checks if the CPU supports Intel Processor Trace. This is the CPU that the build script runs on,
+// so only (non-cross) builds on x86_64 can test with real traces.
+#[cfg(target_arch = "x86_64")]
 fn cpu_supports_pt() -> bool {
     let res = unsafe { __cpuid_count(0x7, 0x0) };
     (res.ebx & (1 << 25)) != 0
 }
 
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_supports_pt() -> bool {
+    false
+}
+
+/// Returns `true` if we're building for x86_64 Linux. Note that `cfg!` can't be used for this, as
+/// it describes the host that the build script runs on, not the target.
+fn target_is_linux_x86_64() -> bool {
+    env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux")
+        && env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("x86_64")
+}
+
 fn main() {
     let mut c_build = cc::Build::new();
+    // Set if any C code needs to be compiled. With only the ykpt decoder, none does.
+    let mut need_c = false;
 
     let c_deps_dir = make_c_deps_dir();
     let c_deps_dir_s = c_deps_dir.display();
     c_build.file("src/util.c");
 
     // Check if we should build the perf collector.
-    if cfg!(all(target_os = "linux", target_arch = "x86_64"))
+    if env::var_os("CARGO_FEATURE_PERF_COLLECTOR").is_some()
+        && target_is_linux_x86_64()
         && feature_check("check_perf.c", "check_perf")
     {
+        need_c = true;
         c_build.file("src/collect/perf/collect.c");
         println!("cargo:rustc-cfg=collector_perf");
     }
 
-    // FIXME: libipt support is unconditionally built-in for now.
-    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+    if env::var_os("CARGO_FEATURE_LIBIPT_DECODER").is_some() && target_is_linux_x86_64() {
+        need_c = true;
         c_build.file("src/decode/libipt/decode.c");
 
         // Decide whether to build our own libipt.
-        if let Ok(val) = env::var("IPT_PATH") {
+        let vendored = env::var_os("CARGO_FEATURE_VENDORED_LIBIPT").is_some();
+        if vendored {
+            let inc_dir = build_vendored_libipt(Path::new(VENDORED_LIBIPT_DIR));
+            c_build.include(inc_dir);
+            // The vendored build doesn't include ptxed, which the tests need, so look for it on
+            // the `PATH`.
+            println!("cargo:rustc-env=PTXED=ptxed");
+        } else if let Ok(val) = env::var("IPT_PATH") {
             let mut inc_path = PathBuf::from(val.clone());
             inc_path.push("include");
             c_build.include(inc_path);
@@ -128,29 +223,40 @@ fn main() {
         }
 
         // We borrow the CPU detection functions from libipt (they are not exposed publicly).
-        // If we built our own libipt above, then the fetch is a no-op.
-        fetch_libipt(&c_deps_dir);
+        // If we built our own libipt above, then the fetch is a no-op. The vendored sources
+        // already contain what we need.
+        let libipt_src = if vendored {
+            PathBuf::from(VENDORED_LIBIPT_DIR)
+        } else {
+            fetch_libipt(&c_deps_dir);
+            c_deps_dir.join("libipt")
+        };
 
-        c_build.include(&format!("{}/libipt/libipt/internal/include", c_deps_dir_s));
-        c_build.file(&format!("{}/libipt/libipt/src/pt_cpu.c", c_deps_dir_s));
-        c_build.file(&format!(
-            "{}/libipt/libipt/src/posix/pt_cpuid.c",
-            c_deps_dir_s
-        ));
+        c_build.include(libipt_src.join("libipt/internal/include"));
+        c_build.file(libipt_src.join("libipt/src/pt_cpu.c"));
+        c_build.file(libipt_src.join("libipt/src/posix/pt_cpuid.c"));
 
         println!("cargo:rustc-cfg=decoder_libipt");
         if cpu_supports_pt() {
             println!("cargo:rustc-cfg=decoder_libipt_test");
         }
-        println!("cargo:rustc-link-lib=static=ipt");
+        if !vendored {
+            println!("cargo:rustc-link-lib=static=ipt");
+        }
     }
 
-    #[cfg(target_arch = "x86_64")]
-    println!("cargo:rustc-cfg=decoder_ykpt");
+    // The ykpt decoder is pure Rust and makes no assumptions about the host, so it can be built for
+    // any target: traces collected on x86_64 can be decoded e.g. on an aarch64 machine, or in a
+    // browser with WebAssembly.
+    if env::var_os("CARGO_FEATURE_DECODE").is_some() {
+        println!("cargo:rustc-cfg=decoder_ykpt");
+    }
 
-    c_build.include("src/util");
-    c_build.include("src"); // to find `hwtracer_private.h`.
-    c_build.compile("hwtracer_c");
+    if need_c {
+        c_build.include("src/util");
+        c_build.include("src"); // to find `hwtracer_private.h`.
+        c_build.compile("hwtracer_c");
+    }
 
     // Additional circumstances under which to re-run this build.rs.
     println!("cargo:rerun-if-env-changed=IPT_PATH");
@@ -5,7 +5,12 @@ use std::time::SystemTime;
 /// Prints the addresses of the first `qty` blocks in a trace along with it's name and
 /// computation result.
 fn print_trace(trace: Box<dyn Trace>, name: &str, result: u32, qty: usize) {
-    let dec = TraceDecoderBuilder::new().build().unwrap();
+    // The decoder can be chosen at runtime, e.g. `HWTRACER_DECODER=ykpt`.
+    let dec = TraceDecoderBuilder::new()
+        .kind_from_env()
+        .unwrap()
+        .build()
+        .unwrap();
     let count = dec.iter_blocks(&*trace).count();
     println!("{}: num_blocks={}, result={}", name, count, result);
 
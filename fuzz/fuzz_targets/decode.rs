@@ -0,0 +1,9 @@
+//! Fuzz the packet parser and the YkPT decoder's reconstruction of blocks and events.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = hwtracer::decode::fuzz_bytes(data);
+});
@@ -0,0 +1,17 @@
+//! Fuzz the packet parser on its own.
+
+#![no_main]
+
+use hwtracer::pt::PacketParser;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for pkt in PacketParser::new(data) {
+        match pkt {
+            Ok(pkt) => {
+                let _ = (pkt.to_string(), pkt.target_ip());
+            }
+            Err(_) => break,
+        }
+    }
+});
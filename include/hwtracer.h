@@ -0,0 +1,286 @@
+#ifndef HWTRACER_H
+#define HWTRACER_H
+
+/* Generated by cbindgen from src/capi.rs: do not edit by hand. */
+
+#include <stdbool.h>
+#include <stdint.h>
+#include <stddef.h>
+
+/*
+ The kind of an [HwtEvent]. See [crate::decode::DecodeEvent] for what each means.
+ */
+typedef enum HwtEventKind {
+  /*
+   `addr` is where execution resumed.
+   */
+  HwtEventKind_TracingEnabled,
+  /*
+   `addr` is where control went, if `has_addr` is set.
+   */
+  HwtEventKind_TracingDisabled,
+  HwtEventKind_Overflow,
+  /*
+   `value` is the new mode's address size in bits (16, 32 or 64).
+   */
+  HwtEventKind_ExecMode,
+  /*
+   `value` is the new core:bus ratio.
+   */
+  HwtEventKind_CoreBusRatio,
+  /*
+   `value` is the value written.
+   */
+  HwtEventKind_PTWrite,
+  /*
+   Control went from `addr` to `to` (if `has_to` is set).
+   */
+  HwtEventKind_AsyncTransfer,
+  HwtEventKind_ContextLost,
+  HwtEventKind_SessionBoundary,
+  /*
+   `value` is 1 if the traced context was switched out, and 0 if it was switched back in.
+   */
+  HwtEventKind_ContextSwitch,
+  HwtEventKind_TransactionBegin,
+  HwtEventKind_TransactionCommit,
+  /*
+   The transaction aborted at `addr` (if `has_addr` is set), and control went to `to` (if
+   `has_to` is set).
+   */
+  HwtEventKind_TransactionAbort,
+} HwtEventKind;
+
+/*
+ An iterator over the blocks of a trace.
+ */
+typedef struct HwtBlockIter HwtBlockIter;
+
+/*
+ A trace collector. See [crate::collect::TraceCollector].
+ */
+typedef struct HwtCollector HwtCollector;
+
+/*
+ An iterator over the events of a trace.
+ */
+typedef struct HwtEventIter HwtEventIter;
+
+/*
+ A collected (or loaded) trace.
+ */
+typedef struct HwtTrace HwtTrace;
+
+/*
+ A block of instructions, as yielded by [hwt_block_iter_next]. This has the same layout as
+ [crate::Block].
+ */
+typedef struct HwtBlock {
+  /*
+   The virtual address of the first instruction in the block.
+   */
+  uint64_t first_instr;
+  /*
+   The virtual address of the last instruction in the block.
+   */
+  uint64_t last_instr;
+  /*
+   Whether the block was executed speculatively, inside a TSX transaction.
+   */
+  bool speculative;
+} HwtBlock;
+
+/*
+ A high-level event, as yielded by [hwt_event_iter_next]. Fields which are not used by the
+ event's `kind` are zero.
+ */
+typedef struct HwtEvent {
+  HwtEventKind kind;
+  /*
+   Whether `addr` is valid.
+   */
+  bool has_addr;
+  /*
+   Whether `to` is valid.
+   */
+  bool has_to;
+  /*
+   A virtual address.
+   */
+  uint64_t addr;
+  /*
+   The virtual address of the destination of an asynchronous transfer.
+   */
+  uint64_t to;
+  /*
+   A value which isn't an address.
+   */
+  uint64_t value;
+} HwtEvent;
+
+/*
+ Returns a description of the last error on the calling thread, or `NULL` if there hasn't been
+ one. The string remains valid until the next call into hwtracer on the same thread.
+ */
+const char *hwt_last_error(void);
+
+/*
+ Create a trace collector with the default configuration (see
+ [crate::collect::TraceCollectorBuilder]). Returns `NULL` on error.
+ */
+HwtCollector *hwt_collector_new(void);
+
+/*
+ Free a trace collector. `tc` may be `NULL`.
+
+ # Safety
+
+ `tc` must have been returned by [hwt_collector_new] and not already freed.
+ */
+void hwt_collector_free(HwtCollector *tc);
+
+/*
+ Start collecting a trace of the calling thread. Returns 0 on success and -1 on error.
+
+ # Safety
+
+ `tc` must be a live trace collector.
+ */
+int hwt_collector_start(const HwtCollector *tc);
+
+/*
+ Stop collecting the trace of the calling thread, which must have been started with
+ [hwt_collector_start], and return it. Returns `NULL` on error.
+
+ # Safety
+
+ `tc` must be a live trace collector.
+ */
+HwtTrace *hwt_collector_stop(const HwtCollector *tc);
+
+/*
+ Make a trace from `len` bytes of raw Intel PT packet data at `bytes`, which are copied. See
+ [crate::Trace::from_bytes].
+
+ # Safety
+
+ `bytes` must point to at least `len` readable bytes (or may be `NULL` if `len` is 0).
+ */
+HwtTrace *hwt_trace_from_bytes(const uint8_t *bytes, size_t len);
+
+/*
+ Free a trace. `trace` may be `NULL`.
+
+ # Safety
+
+ `trace` must have been returned by hwtracer and not already freed. No block iterators over it
+ may be alive.
+ */
+void hwt_trace_free(HwtTrace *trace);
+
+/*
+ Returns the size of the trace's raw Intel PT packet data in bytes.
+
+ # Safety
+
+ `trace` must be a live trace.
+ */
+size_t hwt_trace_len(const HwtTrace *trace);
+
+/*
+ Returns a pointer to the trace's raw Intel PT packet data, [hwt_trace_len] bytes long, which
+ remains valid until the trace is freed.
+
+ # Safety
+
+ `trace` must be a live trace.
+ */
+const uint8_t *hwt_trace_bytes(const HwtTrace *trace);
+
+/*
+ Start iterating over the blocks of `trace`, with the default decoder (which the
+ [crate::decode::TraceDecoderKind::ENV_VAR] environment variable can override). Returns `NULL`
+ on error.
+
+ # Safety
+
+ `trace` must be a live trace, and must outlive the iterator.
+ */
+HwtBlockIter *hwt_trace_iter_blocks(const HwtTrace *trace);
+
+/*
+ Get the next block from `iter`, storing it in `*block`. Returns 1 if there was a block, 0 at
+ the end of the trace and -1 on error (after which the iterator should not be used again).
+
+ # Safety
+
+ `iter` must be a live block iterator, and `block` must point to writable memory for an
+ `HwtBlock`.
+ */
+int hwt_block_iter_next(HwtBlockIter *iter, HwtBlock *block);
+
+/*
+ Get up to `n` blocks from `iter`, storing them in the array `blocks`. Returns the number of
+ blocks stored, which is less than `n` only at the end of the trace or if an error occurs, and
+ -1 on error (after which the iterator should not be used again). An error that occurs after
+ some blocks have been stored is returned by the next call.
+
+ # Safety
+
+ `iter` must be a live block iterator, and `blocks` must point to writable memory for `n`
+ `HwtBlock`s.
+ */
+intptr_t hwt_block_iter_next_batch(HwtBlockIter *iter, HwtBlock *blocks, size_t n);
+
+/*
+ Free a block iterator. `iter` may be `NULL`.
+
+ # Safety
+
+ `iter` must have been returned by [hwt_trace_iter_blocks] and not already freed.
+ */
+void hwt_block_iter_free(HwtBlockIter *iter);
+
+/*
+ Start iterating over the events of `trace`, with the default decoder (which the
+ [crate::decode::TraceDecoderKind::ENV_VAR] environment variable can override). Returns `NULL`
+ on error.
+
+ # Safety
+
+ `trace` must be a live trace, and must outlive the iterator.
+ */
+HwtEventIter *hwt_trace_iter_events(const HwtTrace *trace);
+
+/*
+ Get the next event from `iter`, storing it in `*event`. Returns 1 if there was an event, 0 at
+ the end of the trace and -1 on error (after which the iterator should not be used again).
+
+ # Safety
+
+ `iter` must be a live event iterator, and `event` must point to writable memory for an
+ `HwtEvent`.
+ */
+int hwt_event_iter_next(HwtEventIter *iter, HwtEvent *event);
+
+/*
+ Get up to `n` events from `iter`, storing them in the array `events`. The return value is as
+ for [hwt_block_iter_next_batch].
+
+ # Safety
+
+ `iter` must be a live event iterator, and `events` must point to writable memory for `n`
+ `HwtEvent`s.
+ */
+intptr_t hwt_event_iter_next_batch(HwtEventIter *iter, HwtEvent *events, size_t n);
+
+/*
+ Free an event iterator. `iter` may be `NULL`.
+
+ # Safety
+
+ `iter` must have been returned by [hwt_trace_iter_events] and not already freed.
+ */
+void hwt_event_iter_free(HwtEventIter *iter);
+
+#endif /* HWTRACER_H */
@@ -0,0 +1,335 @@
+//! Anonymising traces so that they can be shared without revealing the traced program's layout.
+
+use crate::{
+    container::RawTrace,
+    errors::{DecodeError, HWTracerError},
+    SidebandEvent, SidebandRecord, Trace,
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// The first opaque identifier handed out. Small, but not so small as to look like a null pointer.
+const FIRST_ID: u64 = 0x1000;
+/// The gap between successive opaque identifiers.
+const ID_STRIDE: u64 = 0x10;
+
+// Packet opcodes (or, for extended packets, the byte following 0x02). See the Intel SDM, Vol. 3C,
+// Section 33.4.
+const OPC_PAD: u8 = 0x00;
+const OPC_EXT: u8 = 0x02;
+const OPC_TSC: u8 = 0x19;
+const OPC_MTC: u8 = 0x59;
+const OPC_MODE: u8 = 0x99;
+const OPC_TIP: u8 = 0x0d;
+const OPC_TIP_PGE: u8 = 0x11;
+const OPC_TIP_PGD: u8 = 0x01;
+const OPC_FUP: u8 = 0x1d;
+const EXT_CBR: u8 = 0x03;
+const EXT_PSBEND: u8 = 0x23;
+const EXT_PSB: u8 = 0x82;
+const EXT_LONG_TNT: u8 = 0xa3;
+const EXT_PIP: u8 = 0x43;
+const EXT_TMA: u8 = 0x73;
+const EXT_VMCS: u8 = 0xc8;
+const EXT_OVF: u8 = 0xf3;
+const EXT_MNT: u8 = 0xc3;
+const EXT_TRACESTOP: u8 = 0x83;
+const EXT_PWRE: u8 = 0x22;
+const EXT_PWRX: u8 = 0xa2;
+const EXT_MWAIT: u8 = 0xc2;
+
+/// The IPBytes field value meaning "a full 64-bit IP follows".
+const IPBYTES_FULL: u8 = 0b110;
+
+/// Rewrites a trace's IPs into opaque identifiers.
+struct Anonymizer {
+    /// The identifier given to each IP seen so far.
+    ids: HashMap<u64, u64>,
+    /// The last full IP, used to decompress the IPs of subsequent packets.
+    last_ip: u64,
+}
+
+impl Anonymizer {
+    /// Returns the opaque identifier for `ip`, handing out a new one if `ip` hasn't been seen.
+    fn id(&mut self, ip: u64) -> u64 {
+        let next = FIRST_ID + ID_STRIDE * u64::try_from(self.ids.len()).unwrap();
+        *self.ids.entry(ip).or_insert(next)
+    }
+
+    /// Rewrite the IP packet `pkt` (with opcode `opc`), appending the result to `out`.
+    fn ip_packet(&mut self, pkt: &[u8], opc: u8, out: &mut Vec<u8>) -> Result<(), HWTracerError> {
+        let ipbytes = pkt[0] >> 5;
+        let payload = &pkt[1..];
+        let mut buf = [0; 8];
+        buf[..payload.len()].copy_from_slice(payload);
+        let raw = u64::from_le_bytes(buf);
+        let ip = match ipbytes {
+            // The IP is suppressed, so there's nothing to hide.
+            0b000 => {
+                out.extend(pkt);
+                return Ok(());
+            }
+            0b001 => (self.last_ip & !0xffff) | raw,
+            0b010 => (self.last_ip & !0xffff_ffff) | raw,
+            // Sign extended from 48 bits.
+            0b011 => (((raw << 16) as i64) >> 16) as u64,
+            0b100 => (self.last_ip & !0xffff_ffff_ffff) | raw,
+            0b110 => raw,
+            _ => return Err(bad(&format!("reserved IPBytes value {:#b}", ipbytes))),
+        };
+        self.last_ip = ip;
+        // Compression relies on the relationship between successive IPs, which identifiers don't
+        // preserve, so every IP is written out in full.
+        out.push((IPBYTES_FULL << 5) | opc);
+        out.extend(self.id(ip).to_le_bytes());
+        Ok(())
+    }
+}
+
+fn bad(msg: &str) -> HWTracerError {
+    HWTracerError::Decode(DecodeError::parse(format!(
+        "can't anonymize trace: {}",
+        msg
+    )))
+}
+
+/// Returns the length of the packet at the start of `bytes`, or `None` if there isn't a complete
+/// packet there. Errors if the packet isn't one that we know how to anonymise.
+fn packet_len(bytes: &[u8]) -> Result<Option<usize>, HWTracerError> {
+    let b0 = bytes[0];
+    let len = match b0 {
+        OPC_PAD => 1,
+        OPC_EXT => match bytes.get(1) {
+            None => return Ok(None),
+            Some(&b1) => match b1 {
+                EXT_PSBEND | EXT_OVF | EXT_TRACESTOP => 2,
+                EXT_CBR | EXT_PWRE => 4,
+                EXT_TMA | EXT_VMCS | EXT_PWRX => 7,
+                EXT_PIP | EXT_LONG_TNT => 8,
+                EXT_MWAIT => 10,
+                EXT_MNT => 11,
+                EXT_PSB => 16,
+                // EXSTOP, with or without an IP.
+                _ if b1 & 0x7f == 0x62 => 2,
+                // PTW, whose payload size is in bits 6:5.
+                _ if b1 & 0x1f == 0x12 => match (b1 >> 5) & 0x3 {
+                    0b00 => 6,
+                    0b01 => 10,
+                    _ => return Err(bad("reserved PTW payload size")),
+                },
+                _ => return Err(bad(&format!("unknown packet 0x02 {:#04x}", b1))),
+            },
+        },
+        // Short TNT.
+        _ if b0 & 0x1 == 0 => 1,
+        OPC_TSC => 8,
+        OPC_MTC | OPC_MODE => 2,
+        // CYC: if the Exp bit is set, payload bytes follow until one has its low bit clear.
+        _ if b0 & 0x3 == 0x3 => {
+            if b0 & 0x4 == 0 {
+                1
+            } else {
+                match bytes[1..].iter().position(|b| b & 0x1 == 0) {
+                    Some(i) => i + 2,
+                    None => return Ok(None),
+                }
+            }
+        }
+        _ if matches!(b0 & 0x1f, OPC_TIP | OPC_TIP_PGE | OPC_TIP_PGD | OPC_FUP) => match b0 >> 5 {
+            0b000 => 1,
+            0b001 => 3,
+            0b010 => 5,
+            0b011 | 0b100 => 7,
+            0b110 => 9,
+            ipbytes => return Err(bad(&format!("reserved IPBytes value {:#b}", ipbytes))),
+        },
+        _ => return Err(bad(&format!("unknown packet {:#04x}", b0))),
+    };
+    Ok(if len <= bytes.len() { Some(len) } else { None })
+}
+
+/// Anonymise `trace`. See [Trace::anonymized].
+pub(crate) fn anonymize<T: Trace + ?Sized>(trace: &T) -> Result<RawTrace, HWTracerError> {
+    let bytes = trace.bytes();
+    let mut anon = Anonymizer {
+        ids: HashMap::new(),
+        last_ip: 0,
+    };
+    let mut out = Vec::with_capacity(bytes.len());
+    // Records which could reveal the program's layout or identity are dropped. The rest have
+    // their offsets moved along with the packets they follow.
+    let mut sideband = trace
+        .sideband()?
+        .into_iter()
+        .filter(|r| {
+            matches!(
+                r.event,
+                SidebandEvent::Switch { .. } | SidebandEvent::SessionBoundary
+            )
+        })
+        .peekable();
+    let mut sb_out = Vec::new();
+    // Data before the first PSB packet can't be decoded, so it isn't worth keeping.
+    let mut off = bytes
+        .windows(2)
+        .position(|w| w == [OPC_EXT, EXT_PSB])
+        .unwrap_or(bytes.len());
+    while off < bytes.len() {
+        while let Some(r) = sideband.next_if(|r| r.trace_offset <= off) {
+            sb_out.push(SidebandRecord {
+                trace_offset: out.len(),
+                event: r.event,
+            });
+        }
+        // A packet cut off by the end of the trace is dropped, as it may hold part of an IP.
+        let len = match packet_len(&bytes[off..])? {
+            Some(len) => len,
+            None => break,
+        };
+        let pkt = &bytes[off..off + len];
+        match pkt[0] {
+            OPC_EXT => match pkt[1] {
+                // PSB resets the last IP.
+                EXT_PSB => {
+                    anon.last_ip = 0;
+                    out.extend(pkt);
+                }
+                // The payloads of PTW (arbitrary program data), PIP (the CR3 register) and VMCS
+                // packets are zeroed.
+                b1 if b1 & 0x1f == 0x12 || b1 == EXT_PIP || b1 == EXT_VMCS => {
+                    out.extend(&pkt[..2]);
+                    out.extend(vec![0; len - 2]);
+                }
+                _ => out.extend(pkt),
+            },
+            b0 if matches!(b0 & 0x1f, OPC_TIP | OPC_TIP_PGE | OPC_TIP_PGD | OPC_FUP) => {
+                anon.ip_packet(pkt, b0 & 0x1f, &mut out)?;
+            }
+            _ => out.extend(pkt),
+        }
+        off += len;
+    }
+    sb_out.extend(sideband.map(|r| SidebandRecord {
+        trace_offset: out.len(),
+        event: r.event,
+    }));
+    // Provenance is whatever the application chose to attach, which may well be sensitive.
+    let mut meta = trace.meta();
+    meta.provenance.clear();
+    Ok(RawTrace {
+        bytes: out,
+        meta,
+        sideband: sb_out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{packet_len, FIRST_ID, ID_STRIDE, IPBYTES_FULL};
+    use crate::{container::RawTrace, SidebandEvent, SidebandRecord, Trace, TraceId};
+    use std::{convert::TryInto, path::PathBuf};
+
+    /// Returns the IP carried by the full IP packet `pkt`.
+    fn full_ip(pkt: &[u8]) -> u64 {
+        assert_eq!(pkt[0] >> 5, IPBYTES_FULL);
+        u64::from_le_bytes(pkt[1..9].try_into().unwrap())
+    }
+
+    #[test]
+    fn anonymize() {
+        #[rustfmt::skip]
+        let bytes = vec![
+            // Junk before the first PSB.
+            0xff, 0xff,
+            // PSB+, with a FUP giving the current IP.
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x7d, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55,
+            0x02, 0x23,
+            // TIP.PGE with a 48-bit sign-extended IP.
+            0x71, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55,
+            // Short TNT.
+            0x0a,
+            // TIP with a 16-bit update.
+            0x2d, 0x00, 0x10,
+            // PTW with a 32-bit payload.
+            0x02, 0x12, 0xef, 0xbe, 0xad, 0xde,
+            // A multi-byte CYC.
+            0x07, 0x03, 0x02,
+            // TIP, out of context.
+            0x0d,
+            // TIP.PGD with a 16-bit update back to the first IP.
+            0x21, 0x78, 0x56,
+            // A truncated TIP.
+            0x2d, 0x00,
+        ];
+        let mut trace = RawTrace::new(bytes);
+        let mmap = SidebandEvent::Mmap {
+            vaddr: 0x555512340000,
+            len: 0x10000,
+            pgoff: 0,
+            filename: PathBuf::from("/secret/prog"),
+        };
+        trace.sideband = vec![
+            SidebandRecord {
+                trace_offset: 0,
+                event: mmap,
+            },
+            SidebandRecord {
+                trace_offset: 35,
+                event: SidebandEvent::Switch { out: true },
+            },
+            SidebandRecord {
+                trace_offset: 100,
+                event: SidebandEvent::Switch { out: false },
+            },
+        ];
+        trace.meta.id = Some(TraceId(1));
+        trace
+            .meta
+            .provenance
+            .insert("user".to_owned(), "secret".to_owned());
+
+        let anon = trace.anonymized().unwrap();
+        let out = anon.bytes();
+        // Walk the packets, collecting the IPs.
+        let mut ips = Vec::new();
+        let mut ptw = None;
+        let mut off = 0;
+        while off < out.len() {
+            let len = packet_len(&out[off..]).unwrap().unwrap();
+            let pkt = &out[off..off + len];
+            match pkt[0] {
+                0x02 if pkt[1] == 0x12 => ptw = Some(pkt[2..].to_vec()),
+                0xdd | 0xd1 | 0xcd | 0xc1 => ips.push(full_ip(pkt)),
+                _ => (),
+            }
+            off += len;
+        }
+        assert_eq!(
+            ips,
+            vec![FIRST_ID, FIRST_ID, FIRST_ID + ID_STRIDE, FIRST_ID]
+        );
+        assert_eq!(ptw, Some(vec![0; 4]));
+        // The junk, the truncated TIP and the mmap are gone. The switches moved with their
+        // packets, as the IP packets before them grew to 9 bytes.
+        assert_eq!(out.len(), 16 + 9 + 2 + 9 + 1 + 9 + 6 + 3 + 1 + 9);
+        assert_eq!(&out[..2], &[0x02, 0x82]);
+        let offs = anon
+            .sideband()
+            .unwrap()
+            .iter()
+            .map(|r| r.trace_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offs, vec![16 + 9 + 2 + 9 + 1, out.len()]);
+        // The trace can still be identified, but not where it came from.
+        assert_eq!(anon.id(), Some(TraceId(1)));
+        assert!(anon.meta().provenance.is_empty());
+    }
+
+    #[test]
+    fn unknown_packet() {
+        let mut bytes = [0x02, 0x82].repeat(8);
+        bytes.extend([0x02, 0xff]);
+        assert!(RawTrace::new(bytes).anonymized().is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! Dump the packets of a trace in the style of libipt's `ptdump`.
+//!
+//! Usage: `hwt-dump [--raw] <trace>`
+//!
+//! The trace is expected to have been saved with `Trace::to_writer`, unless `--raw` is given, in
+//! which case the file is taken to hold nothing but raw Intel PT data (e.g. as extracted by
+//! `perf`).
+//!
+//! If a packet can't be parsed, a report on the failure (see `hwtracer::decode::diagnose`) is
+//! written to stderr.
+
+use hwtracer::{
+    decode::{diagnose, dump_packets},
+    Trace,
+};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    process,
+};
+
+fn usage() -> ! {
+    eprintln!("usage: hwt-dump [--raw] <trace>");
+    process::exit(1);
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (raw, path) = match args.as_slice() {
+        [path] if !path.starts_with('-') => (false, path),
+        [flag, path] if flag == "--raw" => (true, path),
+        _ => usage(),
+    };
+    let trace = if raw {
+        <dyn Trace>::from_file(path)
+    } else {
+        File::open(path)
+            .map_err(Into::into)
+            .and_then(|f| <dyn Trace>::from_reader(&mut BufReader::new(f)))
+    };
+    let trace = trace.unwrap_or_else(|e| {
+        eprintln!("hwt-dump: can't load {}: {}", path, e);
+        process::exit(1);
+    });
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let res = dump_packets(&*trace, &mut out);
+    // The dump is useful up to the point of failure, so flush it either way.
+    let _ = out.flush();
+    if let Err(e) = res {
+        eprintln!("hwt-dump: {}", e);
+        if let Ok(diag) = diagnose(&*trace, &e) {
+            eprintln!("{}", diag);
+        }
+        process::exit(1);
+    }
+}
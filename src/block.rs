@@ -1,13 +1,26 @@
-#[cfg(target_arch = "x86_64")]
+// Traces are always of x86_64 code, even when decoded elsewhere (e.g. in WebAssembly, or on an
+// aarch64 machine), so addresses are 64 bits wide whatever the width of the host's `usize`.
 type BlockAddr = u64;
 
 /// Information about a basic block.
+///
+/// The layout is fixed, and matches that of the C API's `HwtBlock`.
+#[repr(C)]
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
     /// Virtual address of the start of the first instruction in this block.
     first_instr: BlockAddr,
     /// Virtual address of the start of the last instruction in this block.
     last_instr: BlockAddr,
+    /// Was the block executed speculatively, inside a TSX transaction?
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    speculative: bool,
+}
+
+#[cfg(feature = "serde")]
+fn is_false(b: &bool) -> bool {
+    !b
 }
 
 impl Block {
@@ -18,9 +31,16 @@ impl Block {
         Self {
             first_instr,
             last_instr,
+            speculative: false,
         }
     }
 
+    /// Returns the block, marked as having been executed speculatively (or not).
+    pub fn with_speculative(mut self, speculative: bool) -> Self {
+        self.speculative = speculative;
+        self
+    }
+
     /// Returns the virtual address of the start of the first instruction in this block.
     pub fn first_instr(&self) -> BlockAddr {
         self.first_instr
@@ -30,4 +50,11 @@ impl Block {
     pub fn last_instr(&self) -> BlockAddr {
         self.last_instr
     }
+
+    /// Returns `true` if the block was executed speculatively, inside a TSX transaction which was
+    /// committed (or whose outcome is unknown, because the trace ended first). Blocks executed in
+    /// transactions which were aborted are never reported.
+    pub fn is_speculative(&self) -> bool {
+        self.speculative
+    }
 }
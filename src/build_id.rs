@@ -0,0 +1,238 @@
+//! Binding traces to the binaries that they were collected from, by their GNU build IDs.
+//!
+//! A trace only records where control went, so decoding it against a binary which has since been
+//! recompiled silently gives the wrong control flow. Traces collected by hwtracer therefore record
+//! the build IDs of the objects loaded into the traced process (see [crate::TraceMeta::build_ids]),
+//! and decoders which read code from those objects check them first (see
+//! [crate::decode::TraceDecoderConfig::ignore_build_ids]).
+
+#[cfg(feature = "decode")]
+use crate::errors::{DecodeError, HWTracerError};
+#[cfg(unix)]
+use crate::objects::{loaded_objects, ObjectFile};
+#[cfg(unix)]
+use libc::PT_NOTE;
+#[cfg(unix)]
+use std::slice;
+use std::{
+    convert::{TryFrom, TryInto},
+    path::PathBuf,
+};
+#[cfg(feature = "decode")]
+use std::{fs::File, io::Read};
+
+/// The type of the ELF note holding a GNU build ID.
+const NT_GNU_BUILD_ID: u32 = 3;
+/// The ELF program header type of a segment of notes, for platforms where libc doesn't define it.
+#[cfg(not(unix))]
+const PT_NOTE: u32 = 4;
+/// How much of the start of an ELF file is read looking for its build ID. The program headers and
+/// the notes that they point to come first in the binaries of all common linkers.
+#[cfg(feature = "decode")]
+const MAX_HEADER_LEN: u64 = 64 * 1024;
+
+/// The GNU build ID of an object (an executable or a shared object), identifying the exact build of
+/// it that was loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ObjectBuildId {
+    /// The path of the object's file.
+    pub path: PathBuf,
+    /// The object's build ID: usually a 20-byte SHA-1 hash, but any length is allowed.
+    pub build_id: Vec<u8>,
+}
+
+/// Returns the build IDs of the objects loaded into the current process. Objects without a build
+/// ID, or without a file (e.g. the VDSO), are left out.
+#[cfg(unix)]
+pub(crate) fn loaded() -> Vec<ObjectBuildId> {
+    let mut ids = Vec::new();
+    // If the objects can't be found, their build IDs are unknown.
+    for (file, obj) in loaded_objects().unwrap_or_default() {
+        let path = match file {
+            ObjectFile::Path(p) => p,
+            ObjectFile::Vdso => continue, // The VDSO has no file for a decoder to read.
+        };
+        let build_id = obj
+            .iter_phdrs()
+            .filter(|hdr| hdr.type_() == PT_NOTE)
+            .find_map(|hdr| {
+                let notes = unsafe {
+                    slice::from_raw_parts(
+                        (obj.addr() + hdr.vaddr()) as *const u8,
+                        usize::try_from(hdr.filesz()).unwrap(),
+                    )
+                };
+                note_build_id(notes).map(<[u8]>::to_vec)
+            });
+        if let Some(build_id) = build_id {
+            ids.push(ObjectBuildId { path, build_id });
+        }
+    }
+    ids
+}
+
+/// Returns the build IDs of the objects loaded into the current process. Only Unix objects are
+/// supported, so there are none.
+#[cfg(not(unix))]
+pub(crate) fn loaded() -> Vec<ObjectBuildId> {
+    Vec::new()
+}
+
+/// Check that each of the objects in `expected` still has the build ID that it had when the trace
+/// was collected, returning a [DecodeError::BuildIdMismatch] error for the first that doesn't.
+///
+/// Objects whose files no longer exist are skipped: the decoder can't read code from them, and
+/// reports that in its own way.
+#[cfg(feature = "decode")]
+pub(crate) fn verify(expected: &[ObjectBuildId]) -> Result<(), HWTracerError> {
+    for obj in expected {
+        let mut header = Vec::new();
+        match File::open(&obj.path) {
+            Ok(f) => f.take(MAX_HEADER_LEN).read_to_end(&mut header)?,
+            Err(_) => continue,
+        };
+        let found = file_build_id(&header);
+        if found != Some(&obj.build_id[..]) {
+            debug!(path = %obj.path.display(), "build ID mismatch");
+            return Err(HWTracerError::Decode(DecodeError::BuildIdMismatch {
+                path: obj.path.clone(),
+                expected: obj.build_id.clone(),
+                found: found.map(<[u8]>::to_vec),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Find the build ID of the 64-bit little-endian ELF file whose first bytes are `elf`, by looking
+/// through the notes segments listed in its program headers. Returns `None` if there isn't one (or
+/// `elf` isn't such an ELF file).
+#[cfg(feature = "decode")]
+fn file_build_id(elf: &[u8]) -> Option<&[u8]> {
+    // `e_ident`: the magic number, then `ELFCLASS64` and `ELFDATA2LSB`.
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let phoff = usize::try_from(u64_at(elf, 0x20)?).ok()?;
+    let phentsize = usize::from(u16_at(elf, 0x36)?);
+    let phnum = usize::from(u16_at(elf, 0x38)?);
+    (0..phnum).find_map(|i| {
+        let hdr_off = i
+            .checked_mul(phentsize)
+            .and_then(|o| phoff.checked_add(o))?;
+        let hdr = elf.get(hdr_off..)?;
+        if u32_at(hdr, 0)? != PT_NOTE {
+            return None;
+        }
+        let off = usize::try_from(u64_at(hdr, 0x08)?).ok()?;
+        let len = usize::try_from(u64_at(hdr, 0x20)?).ok()?;
+        note_build_id(elf.get(off..off.checked_add(len)?)?)
+    })
+}
+
+/// Find the build ID in `notes`, the contents of an ELF notes segment.
+fn note_build_id(mut notes: &[u8]) -> Option<&[u8]> {
+    // Each note is a header of three `u32`s (the lengths of the name and the descriptor, and the
+    // type), then the name and the descriptor, each padded to a multiple of 4 bytes.
+    let pad = |n: usize| Some(n.checked_add(3)? & !3);
+    while notes.len() >= 12 {
+        let namesz = usize::try_from(u32_at(notes, 0)?).ok()?;
+        let descsz = usize::try_from(u32_at(notes, 4)?).ok()?;
+        let name = notes.get(12..namesz.checked_add(12)?)?;
+        let desc_off = pad(namesz)?.checked_add(12)?;
+        let desc = notes.get(desc_off..desc_off.checked_add(descsz)?)?;
+        if u32_at(notes, 8)? == NT_GNU_BUILD_ID && name == b"GNU\0" {
+            return Some(desc);
+        }
+        notes = notes.get(desc_off.checked_add(pad(descsz)?)?..)?;
+    }
+    None
+}
+
+// ELF files are read as little-endian: Intel PT is only found on x86_64 machines.
+
+fn u16_at(b: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u32_at(b: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(b.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(b.get(off..off + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::note_build_id;
+
+    /// Make a note of type `typ` named `name` with descriptor `desc`.
+    fn note(name: &[u8], typ: u32, desc: &[u8]) -> Vec<u8> {
+        let mut n = Vec::new();
+        n.extend((name.len() as u32).to_le_bytes());
+        n.extend((desc.len() as u32).to_le_bytes());
+        n.extend(typ.to_le_bytes());
+        n.extend(name);
+        n.resize((n.len() + 3) & !3, 0);
+        n.extend(desc);
+        n.resize((n.len() + 3) & !3, 0);
+        n
+    }
+
+    #[test]
+    fn notes() {
+        let mut notes = note(b"GNU\0", 1, &[1, 2, 3, 4]);
+        notes.extend(note(b"Go\0", 3, &[5; 7]));
+        assert_eq!(note_build_id(&notes), None);
+        notes.extend(note(b"GNU\0", 3, &[6; 20]));
+        assert_eq!(note_build_id(&notes), Some(&[6; 20][..]));
+        assert_eq!(note_build_id(&notes[..notes.len() - 1]), None);
+    }
+
+    /// Check that ELF headers whose offsets overflow are rejected rather than panicking.
+    #[cfg(feature = "decode")]
+    #[test]
+    fn overflowing_offsets() {
+        use super::file_build_id;
+
+        let mut elf = b"\x7fELF\x02\x01".to_vec();
+        elf.resize(0x40, 0);
+        elf[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes()); // e_phoff
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes()); // e_phnum
+        assert_eq!(file_build_id(&elf), None);
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&u16::MAX.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(file_build_id(&elf), None);
+    }
+
+    /// Check that the build IDs of the objects loaded into this process are the ones in their
+    /// files, and that a changed build ID is caught.
+    #[cfg(all(unix, feature = "decode"))]
+    #[test]
+    fn loaded() {
+        use super::{loaded, verify, ObjectBuildId};
+        use crate::errors::{DecodeError, HWTracerError};
+
+        let ids = loaded();
+        verify(&ids).unwrap();
+        if let Some(obj) = ids.first() {
+            let mut bad = obj.clone();
+            bad.build_id[0] ^= 0xff;
+            assert!(matches!(
+                verify(&[bad]),
+                Err(HWTracerError::Decode(DecodeError::BuildIdMismatch { found: Some(f), .. }))
+                    if f == obj.build_id
+            ));
+        }
+        // Files which have gone are left for the decoder to complain about.
+        verify(&[ObjectBuildId {
+            path: "/no/such/object.so".into(),
+            build_id: vec![1],
+        }])
+        .unwrap();
+    }
+}
@@ -1,35 +1,58 @@
 //! C-level errors.
 
+#[cfg(decoder_libipt)]
 use crate::{
-    decode::libipt::{hwt_ipt_is_overflow_err, pt_errstr},
+    decode::libipt::{hwt_ipt_classify_err, hwt_ipt_is_overflow_err, pt_errstr},
+    errors::{LibIPTError, LibIPTErrorKind},
+};
+use crate::{
+    errors::{CollectError, DecodeError, MalformedTraceKind},
     HWTracerError,
 };
 use libc::c_int;
-use std::{
-    error::Error,
-    ffi::CStr,
-    fmt::{self, Display, Formatter},
-};
-
-/// An error indicated by a C-level libipt error code.
-#[derive(Debug)]
-struct LibIPTError(c_int);
+#[cfg(decoder_libipt)]
+use std::ffi::CStr;
+use std::io;
 
-impl Display for LibIPTError {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        // Ask libipt for a string representation of the error code.
-        let err_str = unsafe { CStr::from_ptr(pt_errstr(self.0)) };
-        write!(f, "libipt error: {}", err_str.to_str().unwrap())
-    }
-}
-
-impl Error for LibIPTError {
-    fn description(&self) -> &str {
-        "libipt error"
-    }
-
-    fn cause(&self) -> Option<&dyn Error> {
-        None
+/// Convert a C-level libipt error code into a `LibIPTError`.
+#[cfg(decoder_libipt)]
+fn libipt_error(code: c_int) -> LibIPTError {
+    // Must be kept in sync with `enum hwt_ipt_error_kind` in `decode.c`.
+    let kind = match unsafe { hwt_ipt_classify_err(code) } {
+        1 => LibIPTErrorKind::Internal,
+        2 => LibIPTErrorKind::Invalid,
+        3 => LibIPTErrorKind::NoSync,
+        4 => LibIPTErrorKind::BadOpcode,
+        5 => LibIPTErrorKind::BadPacket,
+        6 => LibIPTErrorKind::BadContext,
+        7 => LibIPTErrorKind::EndOfStream,
+        8 => LibIPTErrorKind::BadQuery,
+        9 => LibIPTErrorKind::NoMemory,
+        10 => LibIPTErrorKind::BadConfig,
+        11 => LibIPTErrorKind::NoIP,
+        12 => LibIPTErrorKind::IPSuppressed,
+        13 => LibIPTErrorKind::NoMap,
+        14 => LibIPTErrorKind::BadInstruction,
+        15 => LibIPTErrorKind::NoTime,
+        16 => LibIPTErrorKind::NoCBR,
+        17 => LibIPTErrorKind::BadImage,
+        18 => LibIPTErrorKind::BadLock,
+        19 => LibIPTErrorKind::NotSupported,
+        20 => LibIPTErrorKind::RetStackEmpty,
+        21 => LibIPTErrorKind::BadRetComp,
+        22 => LibIPTErrorKind::BadStatusUpdate,
+        23 => LibIPTErrorKind::NoEnable,
+        24 => LibIPTErrorKind::EventIgnored,
+        25 => LibIPTErrorKind::BadFile,
+        26 => LibIPTErrorKind::BadCPU,
+        _ => LibIPTErrorKind::Other(code),
+    };
+    // Ask libipt for a string representation of the error code.
+    let msg = unsafe { CStr::from_ptr(pt_errstr(code)) };
+    LibIPTError {
+        kind,
+        msg: msg.to_string_lossy().into_owned(),
+        offset: None,
     }
 }
 
@@ -41,6 +64,22 @@ enum PerfPTCErrorKind {
     Unknown,
     Errno,
     IPT,
+    Malformed,
+    Overflow,
+}
+
+/// Convert the `code` of a `hwt_cerror_malformed` error into a `MalformedTraceKind`.
+///
+/// Must be kept in sync with `enum hwt_malformed_kind` in `hwtracer_private.h`.
+fn malformed_kind(code: c_int) -> Option<MalformedTraceKind> {
+    match code {
+        0 => Some(MalformedTraceKind::UnexpectedDecoderStatus),
+        1 => Some(MalformedTraceKind::TruncatedBlock),
+        2 => Some(MalformedTraceKind::EmptyBlock),
+        3 => Some(MalformedTraceKind::UnknownInstrClass),
+        4 => Some(MalformedTraceKind::UnhandledEvent),
+        _ => None,
+    }
 }
 
 /// Represents an error occurring in C code.
@@ -68,14 +107,25 @@ impl From<PerfPTCError> for HWTracerError {
         match err.typ {
             PerfPTCErrorKind::Unused => HWTracerError::Unknown,
             PerfPTCErrorKind::Unknown => HWTracerError::Unknown,
-            PerfPTCErrorKind::Errno => HWTracerError::Errno(err.code),
+            PerfPTCErrorKind::Errno => {
+                HWTracerError::Collect(CollectError::Io(io::Error::from_raw_os_error(err.code)))
+            }
+            #[cfg(decoder_libipt)]
             PerfPTCErrorKind::IPT => {
                 // Overflow is a special case with its own error type.
                 match unsafe { hwt_ipt_is_overflow_err(err.code) } {
-                    true => HWTracerError::HWBufferOverflow,
-                    false => HWTracerError::Custom(Box::new(LibIPTError(err.code))),
+                    true => HWTracerError::Collect(CollectError::HWBufferOverflow),
+                    false => HWTracerError::Decode(DecodeError::LibIPT(libipt_error(err.code))),
                 }
             }
+            // Only the libipt decoder's C code raises libipt errors.
+            #[cfg(not(decoder_libipt))]
+            PerfPTCErrorKind::IPT => HWTracerError::Unknown,
+            PerfPTCErrorKind::Malformed => match malformed_kind(err.code) {
+                Some(k) => HWTracerError::Decode(DecodeError::malformed(k)),
+                None => HWTracerError::Unknown,
+            },
+            PerfPTCErrorKind::Overflow => HWTracerError::Collect(CollectError::HWBufferOverflow),
         }
     }
 }
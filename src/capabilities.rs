@@ -0,0 +1,92 @@
+//! What this build of hwtracer supports.
+//!
+//! Which collectors, decoders and codecs are available depends both on the cargo features that
+//! hwtracer was built with and on the machine it is running on. Applications which embed hwtracer
+//! can use [capabilities] to report what they can do, or to refuse work that they can't (see also
+//! [crate::Trace::probe_reader]).
+
+#[cfg(feature = "collect")]
+use crate::collect::TraceCollectorKind;
+#[cfg(feature = "decode")]
+use crate::decode::TraceDecoderKind;
+use crate::{container, Codec};
+use std::{fmt, ops::RangeInclusive};
+
+/// A format of hardware trace data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceFormat {
+    /// Intel Processor Trace.
+    IntelPT,
+}
+
+impl fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IntelPT => write!(f, "intel-pt"),
+        }
+    }
+}
+
+/// What this build of hwtracer supports on the current machine, as returned by [capabilities].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// The version of hwtracer (see [crate::version]).
+    pub version: &'static str,
+    /// The formats of trace data that can be collected and decoded.
+    pub trace_formats: Vec<TraceFormat>,
+    /// The versions of the container format that traces can be loaded from (see
+    /// [crate::Trace::from_reader]). Traces are always saved in the newest.
+    pub container_versions: RangeInclusive<u32>,
+    /// The codecs that saved traces can be compressed with, and loaded from.
+    pub codecs: Vec<Codec>,
+    /// The kinds of collector which were built in and can be used on this machine, in order of
+    /// preference.
+    #[cfg(feature = "collect")]
+    pub collectors: Vec<TraceCollectorKind>,
+    /// The kinds of decoder which are available, in order of preference. This includes any
+    /// custom decoders registered so far.
+    #[cfg(feature = "decode")]
+    pub decoders: Vec<TraceDecoderKind>,
+}
+
+/// Find out what this build of hwtracer supports on the current machine.
+pub fn capabilities() -> Capabilities {
+    #[allow(unused_mut)]
+    let mut codecs = vec![Codec::None];
+    #[cfg(feature = "zstd")]
+    codecs.push(Codec::Zstd);
+    Capabilities {
+        version: crate::version(),
+        trace_formats: vec![TraceFormat::IntelPT],
+        container_versions: container::MIN_VERSION..=container::VERSION,
+        codecs,
+        #[cfg(feature = "collect")]
+        collectors: TraceCollectorKind::available(),
+        #[cfg(feature = "decode")]
+        decoders: TraceDecoderKind::available(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capabilities, TraceFormat};
+    use crate::{container::RawTrace, Codec, Trace};
+
+    #[test]
+    fn current() {
+        let caps = capabilities();
+        assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(caps.trace_formats, vec![TraceFormat::IntelPT]);
+        assert!(caps.codecs.contains(&Codec::default()));
+        #[cfg(feature = "collect")]
+        assert!(caps.collectors.iter().all(|k| k.is_available()));
+        #[cfg(feature = "decode")]
+        assert!(caps.decoders.iter().all(|k| k.is_available()));
+
+        // Whatever we save, we can load.
+        let mut buf = Vec::new();
+        RawTrace::new(vec![1, 2, 3]).to_writer(&mut buf).unwrap();
+        let version = <dyn Trace>::probe_reader(&mut buf.as_slice()).unwrap();
+        assert!(caps.container_versions.contains(&version));
+    }
+}
@@ -0,0 +1,759 @@
+//! A C API, so that runtimes written in C or C++ can embed hwtracer.
+//!
+//! The API is only built with the `capi` feature. Build hwtracer as a library that C can link
+//! against with e.g.:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type staticlib
+//! ```
+//!
+//! and include `include/hwtracer.h`, which is generated from this module with:
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml -o include/hwtracer.h
+//! ```
+//!
+//! The header must be regenerated whenever this module changes.
+//!
+//! Every object returned by the API is owned by the caller and must be released with the
+//! matching `hwt_*_free` function. Functions that fail return `NULL` (or a negative number) and
+//! record a description of the error, which [hwt_last_error] returns.
+
+use crate::{
+    collect::{TraceCollector, TraceCollectorBuilder},
+    decode::{DecodeEvent, ExecMode, TraceDecoder, TraceDecoderBuilder},
+    errors::{ConfigError, HWTracerError},
+    Block, Trace,
+};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    ffi::CString,
+    mem,
+    os::raw::{c_char, c_int},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+thread_local! {
+    /// The description of the last error on this thread, if any.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    // Error descriptions never legitimately contain NULs.
+    let msg = CString::new(msg.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, turning an error or a panic (which mustn't unwind into C) into `fail`, after recording
+/// a description of it for [hwt_last_error].
+fn ffi<T, F>(fail: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, HWTracerError>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            fail
+        }
+        Err(_) => {
+            set_last_error("hwtracer panicked".to_owned());
+            fail
+        }
+    }
+}
+
+/// Get a reference to the object `p` points to, or an error if `p` is `NULL`.
+///
+/// # Safety
+///
+/// `p` must be `NULL` or point to a live object.
+unsafe fn deref<'a, T>(p: *const T, what: &str) -> Result<&'a T, HWTracerError> {
+    p.as_ref()
+        .ok_or_else(|| HWTracerError::Config(ConfigError::Invalid(format!("{} is NULL", what))))
+}
+
+/// A trace collector. See [crate::collect::TraceCollector].
+pub struct HwtCollector(TraceCollector);
+
+/// A collected (or loaded) trace.
+pub struct HwtTrace(Box<dyn Trace>);
+
+/// An iterator over the blocks of a trace.
+pub struct HwtBlockIter(DecodeIter<Block>);
+
+/// An iterator over the events of a trace.
+pub struct HwtEventIter(DecodeIter<DecodeEvent>);
+
+/// An iterator over some kind of decoder output.
+struct DecodeIter<T> {
+    /// The items being iterated over. This borrows from `_decoder` (and from the trace), so it
+    /// must be declared, and thus dropped, first.
+    items: Box<dyn Iterator<Item = Result<T, HWTracerError>>>,
+    /// An error which occurred part way through filling a batch, and which is thus reported by
+    /// the next call.
+    pending: Option<HWTracerError>,
+    _decoder: Box<dyn TraceDecoder>,
+}
+
+impl<T> DecodeIter<T> {
+    /// Start iterating over the output of `f` for `trace`, with the default decoder.
+    ///
+    /// # Safety
+    ///
+    /// `trace` must be a live trace, and must outlive the iterator.
+    unsafe fn new<F>(trace: *const HwtTrace, f: F) -> Result<Self, HWTracerError>
+    where
+        F: FnOnce(
+            &'static dyn TraceDecoder,
+            &'static dyn Trace,
+        ) -> Box<dyn Iterator<Item = Result<T, HWTracerError>>>,
+    {
+        let trace: &'static dyn Trace = &*deref(trace, "trace")?.0;
+        let decoder = TraceDecoderBuilder::new().kind_from_env()?.build()?;
+        // The decoder's heap allocation doesn't move when the box does, and `DecodeIter` drops
+        // the iterator before the decoder, so the iterator never outlives what it borrows.
+        let dec: &'static dyn TraceDecoder = &*(&*decoder as *const dyn TraceDecoder);
+        Ok(Self {
+            items: f(dec, trace),
+            pending: None,
+            _decoder: decoder,
+        })
+    }
+
+    /// Convert, with `conv`, as many items as will fit into `out`, returning how many were stored.
+    /// Fewer than `out.len()` are only stored at the end of the trace or if an error occurs. An
+    /// error is returned immediately if nothing has been stored yet, or is otherwise kept for the
+    /// next call.
+    fn next_batch<U>(
+        &mut self,
+        out: &mut [U],
+        conv: impl Fn(T) -> U,
+    ) -> Result<usize, HWTracerError> {
+        if let Some(e) = self.pending.take() {
+            return Err(e);
+        }
+        for (i, slot) in out.iter_mut().enumerate() {
+            match self.items.next() {
+                Some(Ok(x)) => *slot = conv(x),
+                Some(Err(e)) if i == 0 => return Err(e),
+                Some(Err(e)) => {
+                    self.pending = Some(e);
+                    return Ok(i);
+                }
+                None => return Ok(i),
+            }
+        }
+        Ok(out.len())
+    }
+}
+
+/// Get a mutable slice of the `n` elements at `p`, or an error if `p` is `NULL` (unless `n` is 0).
+///
+/// # Safety
+///
+/// If `n` is not 0, `p` must point to writable memory for `n` elements.
+unsafe fn deref_slice<'a, T>(
+    p: *mut T,
+    n: usize,
+    what: &str,
+) -> Result<&'a mut [T], HWTracerError> {
+    if n == 0 {
+        return Ok(&mut []);
+    }
+    deref(p, what)?;
+    Ok(slice::from_raw_parts_mut(p, n))
+}
+
+/// Get a mutable reference to an iterator, or an error if it is `NULL`.
+///
+/// # Safety
+///
+/// `iter` must be `NULL` or point to a live iterator.
+unsafe fn deref_iter<'a, T>(iter: *mut T) -> Result<&'a mut T, HWTracerError> {
+    iter.as_mut()
+        .ok_or_else(|| HWTracerError::Config(ConfigError::Invalid("iterator is NULL".into())))
+}
+
+/// A block of instructions, as yielded by [hwt_block_iter_next]. This has the same layout as
+/// [crate::Block].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HwtBlock {
+    /// The virtual address of the first instruction in the block.
+    pub first_instr: u64,
+    /// The virtual address of the last instruction in the block.
+    pub last_instr: u64,
+    /// Whether the block was executed speculatively, inside a TSX transaction.
+    pub speculative: bool,
+}
+
+impl From<&Block> for HwtBlock {
+    fn from(b: &Block) -> Self {
+        Self {
+            first_instr: b.first_instr(),
+            last_instr: b.last_instr(),
+            speculative: b.is_speculative(),
+        }
+    }
+}
+
+impl From<HwtBlock> for Block {
+    fn from(b: HwtBlock) -> Self {
+        Block::new(b.first_instr, b.last_instr).with_speculative(b.speculative)
+    }
+}
+
+/// The kind of an [HwtEvent]. See [crate::decode::DecodeEvent] for what each means.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HwtEventKind {
+    /// `addr` is where execution resumed.
+    TracingEnabled,
+    /// `addr` is where control went, if `has_addr` is set.
+    TracingDisabled,
+    Overflow,
+    /// `value` is the new mode's address size in bits (16, 32 or 64).
+    ExecMode,
+    /// `value` is the new core:bus ratio.
+    CoreBusRatio,
+    /// `value` is the value written.
+    PTWrite,
+    /// Control went from `addr` to `to` (if `has_to` is set).
+    AsyncTransfer,
+    ContextLost,
+    SessionBoundary,
+    /// `value` is 1 if the traced context was switched out, and 0 if it was switched back in.
+    ContextSwitch,
+    TransactionBegin,
+    TransactionCommit,
+    /// The transaction aborted at `addr` (if `has_addr` is set), and control went to `to` (if
+    /// `has_to` is set).
+    TransactionAbort,
+}
+
+/// A high-level event, as yielded by [hwt_event_iter_next]. Fields which are not used by the
+/// event's `kind` are zero.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HwtEvent {
+    pub kind: HwtEventKind,
+    /// Whether `addr` is valid.
+    pub has_addr: bool,
+    /// Whether `to` is valid.
+    pub has_to: bool,
+    /// A virtual address.
+    pub addr: u64,
+    /// The virtual address of the destination of an asynchronous transfer.
+    pub to: u64,
+    /// A value which isn't an address.
+    pub value: u64,
+}
+
+impl Default for HwtEvent {
+    fn default() -> Self {
+        Self {
+            kind: HwtEventKind::Overflow,
+            has_addr: false,
+            has_to: false,
+            addr: 0,
+            to: 0,
+            value: 0,
+        }
+    }
+}
+
+impl From<&DecodeEvent> for HwtEvent {
+    fn from(ev: &DecodeEvent) -> Self {
+        let (kind, addr, to, value) = match *ev {
+            DecodeEvent::TracingEnabled(ip) => (HwtEventKind::TracingEnabled, Some(ip), None, 0),
+            DecodeEvent::TracingDisabled(ip) => (HwtEventKind::TracingDisabled, ip, None, 0),
+            DecodeEvent::Overflow => (HwtEventKind::Overflow, None, None, 0),
+            DecodeEvent::ExecMode(m) => {
+                let bits = match m {
+                    ExecMode::Bits16 => 16,
+                    ExecMode::Bits32 => 32,
+                    ExecMode::Bits64 => 64,
+                };
+                (HwtEventKind::ExecMode, None, None, bits)
+            }
+            DecodeEvent::CoreBusRatio(r) => (HwtEventKind::CoreBusRatio, None, None, u64::from(r)),
+            DecodeEvent::PTWrite(v) => (HwtEventKind::PTWrite, None, None, v),
+            DecodeEvent::AsyncTransfer { from, to } => {
+                (HwtEventKind::AsyncTransfer, Some(from), to, 0)
+            }
+            DecodeEvent::ContextLost => (HwtEventKind::ContextLost, None, None, 0),
+            DecodeEvent::SessionBoundary => (HwtEventKind::SessionBoundary, None, None, 0),
+            DecodeEvent::ContextSwitch { out } => {
+                (HwtEventKind::ContextSwitch, None, None, u64::from(out))
+            }
+            DecodeEvent::TransactionBegin => (HwtEventKind::TransactionBegin, None, None, 0),
+            DecodeEvent::TransactionCommit => (HwtEventKind::TransactionCommit, None, None, 0),
+            DecodeEvent::TransactionAbort { from, to } => {
+                (HwtEventKind::TransactionAbort, from, to, 0)
+            }
+        };
+        Self {
+            kind,
+            has_addr: addr.is_some(),
+            has_to: to.is_some(),
+            addr: addr.unwrap_or(0),
+            to: to.unwrap_or(0),
+            value,
+        }
+    }
+}
+
+impl TryFrom<HwtEvent> for DecodeEvent {
+    type Error = HWTracerError;
+
+    /// Convert an event back, failing if it is inconsistent (e.g. a `TracingEnabled` event
+    /// without an address).
+    fn try_from(ev: HwtEvent) -> Result<Self, Self::Error> {
+        let invalid =
+            || HWTracerError::Config(ConfigError::Invalid(format!("invalid event: {:?}", ev)));
+        let addr = if ev.has_addr { Some(ev.addr) } else { None };
+        let to = if ev.has_to { Some(ev.to) } else { None };
+        Ok(match ev.kind {
+            HwtEventKind::TracingEnabled => DecodeEvent::TracingEnabled(addr.ok_or_else(invalid)?),
+            HwtEventKind::TracingDisabled => DecodeEvent::TracingDisabled(addr),
+            HwtEventKind::Overflow => DecodeEvent::Overflow,
+            HwtEventKind::ExecMode => DecodeEvent::ExecMode(match ev.value {
+                16 => ExecMode::Bits16,
+                32 => ExecMode::Bits32,
+                64 => ExecMode::Bits64,
+                _ => return Err(invalid()),
+            }),
+            HwtEventKind::CoreBusRatio => {
+                DecodeEvent::CoreBusRatio(u8::try_from(ev.value).map_err(|_| invalid())?)
+            }
+            HwtEventKind::PTWrite => DecodeEvent::PTWrite(ev.value),
+            HwtEventKind::AsyncTransfer => DecodeEvent::AsyncTransfer {
+                from: addr.ok_or_else(invalid)?,
+                to,
+            },
+            HwtEventKind::ContextLost => DecodeEvent::ContextLost,
+            HwtEventKind::SessionBoundary => DecodeEvent::SessionBoundary,
+            HwtEventKind::ContextSwitch => DecodeEvent::ContextSwitch {
+                out: match ev.value {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(invalid()),
+                },
+            },
+            HwtEventKind::TransactionBegin => DecodeEvent::TransactionBegin,
+            HwtEventKind::TransactionCommit => DecodeEvent::TransactionCommit,
+            HwtEventKind::TransactionAbort => DecodeEvent::TransactionAbort { from: addr, to },
+        })
+    }
+}
+
+/// Returns a description of the last error on the calling thread, or `NULL` if there hasn't been
+/// one. The string remains valid until the next call into hwtracer on the same thread.
+#[no_mangle]
+pub extern "C" fn hwt_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Create a trace collector with the default configuration (see
+/// [crate::collect::TraceCollectorBuilder]). Returns `NULL` on error.
+#[no_mangle]
+pub extern "C" fn hwt_collector_new() -> *mut HwtCollector {
+    ffi(ptr::null_mut(), || {
+        let tc = TraceCollectorBuilder::new().build()?;
+        Ok(Box::into_raw(Box::new(HwtCollector(tc))))
+    })
+}
+
+/// Free a trace collector. `tc` may be `NULL`.
+///
+/// # Safety
+///
+/// `tc` must have been returned by [hwt_collector_new] and not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_collector_free(tc: *mut HwtCollector) {
+    if !tc.is_null() {
+        drop(Box::from_raw(tc));
+    }
+}
+
+/// Start collecting a trace of the calling thread. Returns 0 on success and -1 on error.
+///
+/// # Safety
+///
+/// `tc` must be a live trace collector.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_collector_start(tc: *const HwtCollector) -> c_int {
+    ffi(-1, || {
+        deref(tc, "collector")?.0.start_thread_collector()?;
+        Ok(0)
+    })
+}
+
+/// Stop collecting the trace of the calling thread, which must have been started with
+/// [hwt_collector_start], and return it. Returns `NULL` on error.
+///
+/// # Safety
+///
+/// `tc` must be a live trace collector.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_collector_stop(tc: *const HwtCollector) -> *mut HwtTrace {
+    ffi(ptr::null_mut(), || {
+        let trace = deref(tc, "collector")?.0.stop_thread_collector()?;
+        Ok(Box::into_raw(Box::new(HwtTrace(trace))))
+    })
+}
+
+/// Make a trace from `len` bytes of raw Intel PT packet data at `bytes`, which are copied. See
+/// [crate::Trace::from_bytes].
+///
+/// # Safety
+///
+/// `bytes` must point to at least `len` readable bytes (or may be `NULL` if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_from_bytes(bytes: *const u8, len: usize) -> *mut HwtTrace {
+    ffi(ptr::null_mut(), || {
+        let bytes = if len == 0 {
+            Vec::new()
+        } else {
+            deref(bytes, "bytes")?;
+            slice::from_raw_parts(bytes, len).to_vec()
+        };
+        Ok(Box::into_raw(Box::new(HwtTrace(<dyn Trace>::from_bytes(
+            bytes,
+        )))))
+    })
+}
+
+/// Free a trace. `trace` may be `NULL`.
+///
+/// # Safety
+///
+/// `trace` must have been returned by hwtracer and not already freed. No block iterators over it
+/// may be alive.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_free(trace: *mut HwtTrace) {
+    if !trace.is_null() {
+        drop(Box::from_raw(trace));
+    }
+}
+
+/// Returns the size of the trace's raw Intel PT packet data in bytes.
+///
+/// # Safety
+///
+/// `trace` must be a live trace.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_len(trace: *const HwtTrace) -> usize {
+    ffi(0, || Ok(deref(trace, "trace")?.0.len()))
+}
+
+/// Returns a pointer to the trace's raw Intel PT packet data, [hwt_trace_len] bytes long, which
+/// remains valid until the trace is freed.
+///
+/// # Safety
+///
+/// `trace` must be a live trace.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_bytes(trace: *const HwtTrace) -> *const u8 {
+    ffi(ptr::null(), || {
+        Ok(deref(trace, "trace")?.0.bytes().as_ptr())
+    })
+}
+
+/// Start iterating over the blocks of `trace`, with the default decoder (which the
+/// [crate::decode::TraceDecoderKind::ENV_VAR] environment variable can override). Returns `NULL`
+/// on error.
+///
+/// # Safety
+///
+/// `trace` must be a live trace, and must outlive the iterator.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_iter_blocks(trace: *const HwtTrace) -> *mut HwtBlockIter {
+    ffi(ptr::null_mut(), || {
+        let iter = DecodeIter::new(trace, |dec, trace| dec.iter_blocks(trace))?;
+        Ok(Box::into_raw(Box::new(HwtBlockIter(iter))))
+    })
+}
+
+/// Get the next block from `iter`, storing it in `*block`. Returns 1 if there was a block, 0 at
+/// the end of the trace and -1 on error (after which the iterator should not be used again).
+///
+/// # Safety
+///
+/// `iter` must be a live block iterator, and `block` must point to writable memory for an
+/// `HwtBlock`.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_block_iter_next(
+    iter: *mut HwtBlockIter,
+    block: *mut HwtBlock,
+) -> c_int {
+    hwt_block_iter_next_batch(iter, block, 1) as c_int
+}
+
+/// Get up to `n` blocks from `iter`, storing them in the array `blocks`. Returns the number of
+/// blocks stored, which is less than `n` only at the end of the trace or if an error occurs, and
+/// -1 on error (after which the iterator should not be used again). An error that occurs after
+/// some blocks have been stored is returned by the next call.
+///
+/// # Safety
+///
+/// `iter` must be a live block iterator, and `blocks` must point to writable memory for `n`
+/// `HwtBlock`s.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_block_iter_next_batch(
+    iter: *mut HwtBlockIter,
+    blocks: *mut HwtBlock,
+    n: usize,
+) -> isize {
+    // `Block` and `HwtBlock` are both `repr(C)`, with fields of the same types in the same order,
+    // so blocks can be stored straight into the caller's array without being converted.
+    const _: () = assert!(mem::size_of::<Block>() == mem::size_of::<HwtBlock>());
+    const _: () = assert!(mem::align_of::<Block>() == mem::align_of::<HwtBlock>());
+    ffi(-1, || {
+        let iter = deref_iter(iter)?;
+        let blocks = deref_slice(blocks.cast::<Block>(), n, "block")?;
+        Ok(iter.0.next_batch(blocks, |b| b)? as isize)
+    })
+}
+
+/// Free a block iterator. `iter` may be `NULL`.
+///
+/// # Safety
+///
+/// `iter` must have been returned by [hwt_trace_iter_blocks] and not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_block_iter_free(iter: *mut HwtBlockIter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+/// Start iterating over the events of `trace`, with the default decoder (which the
+/// [crate::decode::TraceDecoderKind::ENV_VAR] environment variable can override). Returns `NULL`
+/// on error.
+///
+/// # Safety
+///
+/// `trace` must be a live trace, and must outlive the iterator.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_trace_iter_events(trace: *const HwtTrace) -> *mut HwtEventIter {
+    ffi(ptr::null_mut(), || {
+        let iter = DecodeIter::new(trace, |dec, trace| dec.iter_events(trace))?;
+        Ok(Box::into_raw(Box::new(HwtEventIter(iter))))
+    })
+}
+
+/// Get the next event from `iter`, storing it in `*event`. Returns 1 if there was an event, 0 at
+/// the end of the trace and -1 on error (after which the iterator should not be used again).
+///
+/// # Safety
+///
+/// `iter` must be a live event iterator, and `event` must point to writable memory for an
+/// `HwtEvent`.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_event_iter_next(
+    iter: *mut HwtEventIter,
+    event: *mut HwtEvent,
+) -> c_int {
+    hwt_event_iter_next_batch(iter, event, 1) as c_int
+}
+
+/// Get up to `n` events from `iter`, storing them in the array `events`. The return value is as
+/// for [hwt_block_iter_next_batch].
+///
+/// # Safety
+///
+/// `iter` must be a live event iterator, and `events` must point to writable memory for `n`
+/// `HwtEvent`s.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_event_iter_next_batch(
+    iter: *mut HwtEventIter,
+    events: *mut HwtEvent,
+    n: usize,
+) -> isize {
+    ffi(-1, || {
+        let iter = deref_iter(iter)?;
+        let events = deref_slice(events, n, "event")?;
+        Ok(iter.0.next_batch(events, |e| HwtEvent::from(&e))? as isize)
+    })
+}
+
+/// Free an event iterator. `iter` may be `NULL`.
+///
+/// # Safety
+///
+/// `iter` must have been returned by [hwt_trace_iter_events] and not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn hwt_event_iter_free(iter: *mut HwtEventIter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(hwt_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn trace_bytes() {
+        let data = [1u8, 2, 3];
+        unsafe {
+            let trace = hwt_trace_from_bytes(data.as_ptr(), data.len());
+            assert!(!trace.is_null());
+            assert_eq!(hwt_trace_len(trace), 3);
+            assert_eq!(slice::from_raw_parts(hwt_trace_bytes(trace), 3), &data);
+            hwt_trace_free(trace);
+
+            let trace = hwt_trace_from_bytes(ptr::null(), 0);
+            assert_eq!(hwt_trace_len(trace), 0);
+            hwt_trace_free(trace);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        unsafe {
+            assert!(hwt_trace_from_bytes(ptr::null(), 1).is_null());
+            assert!(last_error().contains("bytes is NULL"));
+            assert_eq!(hwt_collector_start(ptr::null()), -1);
+            assert!(last_error().contains("collector is NULL"));
+            assert!(hwt_trace_iter_blocks(ptr::null()).is_null());
+            let mut block = HwtBlock::default();
+            assert_eq!(hwt_block_iter_next(ptr::null_mut(), &mut block), -1);
+            assert!(last_error().contains("iterator is NULL"));
+            assert_eq!(
+                hwt_block_iter_next_batch(ptr::null_mut(), &mut block, 1),
+                -1
+            );
+            let mut event = HwtEvent::default();
+            assert_eq!(hwt_event_iter_next(ptr::null_mut(), &mut event), -1);
+            assert!(last_error().contains("iterator is NULL"));
+            // Freeing NULL is a no-op.
+            hwt_trace_free(ptr::null_mut());
+            hwt_block_iter_free(ptr::null_mut());
+            hwt_event_iter_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn batches() {
+        /// Store blocks straight into `blocks`, as [hwt_block_iter_next_batch] does.
+        fn next_batch(
+            iter: &mut DecodeIter<Block>,
+            blocks: &mut [HwtBlock],
+        ) -> Result<usize, HWTracerError> {
+            let out = unsafe {
+                slice::from_raw_parts_mut(blocks.as_mut_ptr().cast::<Block>(), blocks.len())
+            };
+            iter.next_batch(out, |b| b)
+        }
+
+        let mut iter = DecodeIter {
+            items: Box::new(
+                vec![
+                    Ok(Block::new(0x10, 0x18)),
+                    Ok(Block::new(0x20, 0x28).with_speculative(true)),
+                    Err(HWTracerError::Config(ConfigError::Invalid("oops".into()))),
+                    Ok(Block::new(0x30, 0x38)),
+                ]
+                .into_iter(),
+            ),
+            pending: None,
+            _decoder: TraceDecoderBuilder::new().build().unwrap(),
+        };
+        let mut blocks = [HwtBlock::default(); 3];
+        // The error is deferred until the blocks before it have been returned.
+        assert_eq!(next_batch(&mut iter, &mut blocks).unwrap(), 2);
+        assert_eq!(
+            blocks[1],
+            HwtBlock {
+                first_instr: 0x20,
+                last_instr: 0x28,
+                speculative: true
+            }
+        );
+        assert!(next_batch(&mut iter, &mut blocks).is_err());
+        assert_eq!(next_batch(&mut iter, &mut blocks).unwrap(), 1);
+        assert_eq!(Block::from(blocks[0]), Block::new(0x30, 0x38));
+        assert_eq!(next_batch(&mut iter, &mut blocks).unwrap(), 0);
+        assert_eq!(next_batch(&mut iter, &mut blocks[..0]).unwrap(), 0);
+
+        unsafe {
+            let trace = hwt_trace_from_bytes(ptr::null(), 0);
+            let iter = hwt_trace_iter_blocks(trace);
+            assert!(!iter.is_null());
+            assert_eq!(hwt_block_iter_next_batch(iter, blocks.as_mut_ptr(), 3), 0);
+            assert_eq!(hwt_block_iter_next_batch(iter, ptr::null_mut(), 1), -1);
+            assert!(last_error().contains("block is NULL"));
+            hwt_block_iter_free(iter);
+            hwt_trace_free(trace);
+        }
+    }
+
+    #[test]
+    fn event_conversions() {
+        let evs = [
+            DecodeEvent::TracingEnabled(0x1000),
+            DecodeEvent::TracingDisabled(None),
+            DecodeEvent::TracingDisabled(Some(0x2000)),
+            DecodeEvent::Overflow,
+            DecodeEvent::ExecMode(ExecMode::Bits32),
+            DecodeEvent::CoreBusRatio(42),
+            DecodeEvent::PTWrite(u64::MAX),
+            DecodeEvent::AsyncTransfer {
+                from: 0x3000,
+                to: Some(0x4000),
+            },
+            DecodeEvent::AsyncTransfer {
+                from: 0x3000,
+                to: None,
+            },
+            DecodeEvent::ContextLost,
+            DecodeEvent::SessionBoundary,
+            DecodeEvent::ContextSwitch { out: true },
+            DecodeEvent::ContextSwitch { out: false },
+            DecodeEvent::TransactionBegin,
+            DecodeEvent::TransactionCommit,
+            DecodeEvent::TransactionAbort {
+                from: Some(0x3000),
+                to: Some(0x4000),
+            },
+            DecodeEvent::TransactionAbort {
+                from: None,
+                to: None,
+            },
+        ];
+        for ev in &evs {
+            assert_eq!(&DecodeEvent::try_from(HwtEvent::from(ev)).unwrap(), ev);
+        }
+        let ev = HwtEvent::from(&DecodeEvent::ExecMode(ExecMode::Bits64));
+        assert_eq!(
+            (ev.kind, ev.value, ev.has_addr),
+            (HwtEventKind::ExecMode, 64, false)
+        );
+
+        // Inconsistent events are rejected.
+        let bad = HwtEvent {
+            kind: HwtEventKind::TracingEnabled,
+            ..HwtEvent::default()
+        };
+        assert!(DecodeEvent::try_from(bad).is_err());
+        let bad = HwtEvent {
+            kind: HwtEventKind::ExecMode,
+            value: 8,
+            ..HwtEvent::default()
+        };
+        assert!(DecodeEvent::try_from(bad).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Control-flow graphs built from decoded traces.
+//!
+//! ```no_run
+//! use hwtracer::{cfg::Cfg, decode::TraceDecoderBuilder, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let cfg = Cfg::from_blocks(dec.iter_blocks(&*trace)).unwrap();
+//! cfg.to_dot(&mut std::io::stdout()).unwrap(); // Render with e.g. `dot -Tsvg`.
+//! ```
+
+use crate::{errors::HWTracerError, Block};
+use std::{collections::BTreeMap, io::Write};
+
+/// A block, as a `(first_instr, last_instr)` pair, so that it can be used as a map key.
+type BlockKey = (u64, u64);
+
+fn key(b: &Block) -> BlockKey {
+    (b.first_instr(), b.last_instr())
+}
+
+/// The control-flow graph of what a trace actually executed: a node for each block that was
+/// executed, and an edge for each transition from one block to the next that was observed. Both
+/// carry the number of times that they were executed.
+///
+/// Edges are only ever inferred from the order in which blocks were decoded: if tracing was
+/// disabled between two blocks, the graph still has an edge between them.
+#[derive(Debug, Default)]
+pub struct Cfg {
+    /// The first block executed, if any.
+    entry: Option<BlockKey>,
+    /// The number of times each block was executed.
+    nodes: BTreeMap<BlockKey, usize>,
+    /// The number of times each transition from one block to another was observed.
+    edges: BTreeMap<(BlockKey, BlockKey), usize>,
+}
+
+impl Cfg {
+    /// Fold `blocks` (e.g. from [crate::decode::TraceDecoder::iter_blocks]) into a graph.
+    ///
+    /// Returns the first error that `blocks` yields, if any.
+    pub fn from_blocks<I>(blocks: I) -> Result<Self, HWTracerError>
+    where
+        I: IntoIterator<Item = Result<Block, HWTracerError>>,
+    {
+        let mut cfg = Self::default();
+        let mut prev = None;
+        for b in blocks {
+            let k = key(&b?);
+            *cfg.nodes.entry(k).or_insert(0) += 1;
+            match prev {
+                Some(p) => *cfg.edges.entry((p, k)).or_insert(0) += 1,
+                None => cfg.entry = Some(k),
+            }
+            prev = Some(k);
+        }
+        Ok(cfg)
+    }
+
+    /// Returns the first block executed, from which every other block in the graph can be
+    /// reached. `None` if the graph is empty.
+    pub fn entry(&self) -> Option<Block> {
+        self.entry.map(|(first, last)| Block::new(first, last))
+    }
+
+    /// Returns the blocks in the graph, in address order, with the number of times each was
+    /// executed.
+    pub fn nodes(&self) -> impl Iterator<Item = (Block, usize)> + '_ {
+        self.nodes
+            .iter()
+            .map(|(&(first, last), &n)| (Block::new(first, last), n))
+    }
+
+    /// Returns the edges in the graph, as `(from, to, count)` triples, where `count` is the number
+    /// of times that block `to` was executed straight after block `from`.
+    pub fn edges(&self) -> impl Iterator<Item = (Block, Block, usize)> + '_ {
+        self.edges
+            .iter()
+            .map(|(&((f1, l1), (f2, l2)), &n)| (Block::new(f1, l1), Block::new(f2, l2), n))
+    }
+
+    /// Write the graph to `w` in Graphviz's DOT language. Nodes are labelled with the address
+    /// range of their block and edges with the number of times they were taken.
+    pub fn to_dot(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        writeln!(w, "digraph cfg {{")?;
+        writeln!(w, "    node [shape=box, fontname=\"monospace\"];")?;
+        // Nodes are named after their position in `self.nodes`.
+        let ids = self
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(i, k)| (*k, i))
+            .collect::<BTreeMap<_, _>>();
+        for (i, ((first, last), n)) in self.nodes.iter().enumerate() {
+            writeln!(
+                w,
+                "    b{} [label=\"{:#x}..={:#x}\\n{}x\"];",
+                i, first, last, n
+            )?;
+        }
+        for ((from, to), n) in &self.edges {
+            writeln!(w, "    b{} -> b{} [label=\"{}\"];", ids[from], ids[to], n)?;
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cfg;
+    use crate::{errors::HWTracerError, Block};
+
+    /// A loop around the blocks at 0x20 and 0x30, entered from 0x10 and exited to 0x40.
+    fn sample() -> Vec<Result<Block, HWTracerError>> {
+        [0x10, 0x20, 0x30, 0x20, 0x30, 0x20, 0x30, 0x40]
+            .iter()
+            .map(|&a| Ok(Block::new(a, a + 4)))
+            .collect()
+    }
+
+    #[test]
+    fn from_blocks() {
+        let cfg = Cfg::from_blocks(sample()).unwrap();
+        let nodes = cfg
+            .nodes()
+            .map(|(b, n)| (b.first_instr(), n))
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![(0x10, 1), (0x20, 3), (0x30, 3), (0x40, 1)]);
+        assert_eq!(cfg.entry(), Some(Block::new(0x10, 0x14)));
+        let edges = cfg
+            .edges()
+            .map(|(f, t, n)| (f.first_instr(), t.first_instr(), n))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            vec![
+                (0x10, 0x20, 1),
+                (0x20, 0x30, 3),
+                (0x30, 0x20, 2),
+                (0x30, 0x40, 1)
+            ]
+        );
+
+        let mut blocks = sample();
+        blocks.insert(2, Err(HWTracerError::Unknown));
+        assert!(matches!(
+            Cfg::from_blocks(blocks),
+            Err(HWTracerError::Unknown)
+        ));
+    }
+
+    #[test]
+    fn to_dot() {
+        let cfg = Cfg::from_blocks(sample().into_iter().take(3)).unwrap();
+        let mut buf = Vec::new();
+        cfg.to_dot(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "digraph cfg {
+    node [shape=box, fontname=\"monospace\"];
+    b0 [label=\"0x10..=0x14\\n1x\"];
+    b1 [label=\"0x20..=0x24\\n1x\"];
+    b2 [label=\"0x30..=0x34\\n1x\"];
+    b0 -> b1 [label=\"1\"];
+    b1 -> b2 [label=\"1\"];
+}
+"
+        );
+    }
+}
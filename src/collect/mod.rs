@@ -1,9 +1,24 @@
 //! Trace collectors.
 
-use crate::{errors::HWTracerError, Trace};
+use crate::{
+    errors::{CollectError, ConfigError, HWTracerError},
+    instrument, Trace,
+};
+#[cfg(collector_perf)]
 use core::arch::x86_64::__cpuid_count;
-use libc::{size_t, sysconf, _SC_PAGESIZE};
-use std::{cell::RefCell, convert::TryFrom, sync::LazyLock};
+#[cfg(unix)]
+use libc::{sysconf, _SC_PAGESIZE};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
@@ -12,15 +27,25 @@ pub(crate) mod perf;
 #[cfg(collector_perf)]
 pub(crate) use perf::PerfTraceCollector;
 
-const PERF_DFLT_DATA_BUFSIZE: size_t = 64;
-static PERF_DFLT_AUX_BUFSIZE: LazyLock<size_t> = LazyLock::new(|| {
-    // Allocate enough pages for a 64MiB trace buffer.
+const PERF_DFLT_DATA_BUFSIZE: usize = 64;
+
+/// Returns the default size of the AUX buffer, in pages: enough pages for a 64MiB trace buffer.
+fn perf_dflt_aux_bufsize() -> usize {
     let mb64 = 1024 * 1024 * 64;
-    let page_sz = size_t::try_from(unsafe { sysconf(_SC_PAGESIZE) }).unwrap();
-    mb64 / page_sz + size_t::from(mb64 % page_sz != 0)
-});
+    let page_sz = page_size();
+    mb64 / page_sz + usize::from(mb64 % page_sz != 0)
+}
+
+const PERF_DFLT_INITIAL_TRACE_BUFSIZE: usize = 1024 * 1024; // 1MiB
 
-const PERF_DFLT_INITIAL_TRACE_BUFSIZE: size_t = 1024 * 1024; // 1MiB
+/// Returns the system's page size. This is only used to configure collectors, so platforms
+/// without any (e.g. WebAssembly) just assume 4KiB pages.
+fn page_size() -> usize {
+    #[cfg(unix)]
+    return usize::try_from(unsafe { sysconf(_SC_PAGESIZE) }).unwrap();
+    #[cfg(not(unix))]
+    return 4096;
+}
 
 thread_local! {
     /// When `Some` holds the `ThreadTraceCollector` that is collecting a trace of the current
@@ -31,16 +56,59 @@ thread_local! {
 /// The private innards of a `TraceCollector`.
 pub(crate) trait TraceCollectorImpl: Send + Sync {
     unsafe fn thread_collector(&self) -> Box<dyn ThreadTraceCollector>;
+    /// The configuration that the collector actually uses.
+    fn config(&self) -> TraceCollectorConfig;
 }
 
 /// The public interface offered by all trace collectors.
 pub struct TraceCollector {
     col_impl: Box<dyn TraceCollectorImpl>,
+    /// The directory that collected traces are recorded in, if any. See
+    /// [TraceCollectorBuilder::corpus_dir].
+    corpus_dir: Option<PathBuf>,
 }
 
+/// Numbers the traces recorded by this process, to give them unique file names.
+static CORPUS_SEQ: AtomicUsize = AtomicUsize::new(0);
+
 impl TraceCollector {
-    pub(crate) fn new(col_impl: Box<dyn TraceCollectorImpl>) -> Self {
-        Self { col_impl }
+    pub(crate) fn new(col_impl: Box<dyn TraceCollectorImpl>, corpus_dir: Option<PathBuf>) -> Self {
+        Self {
+            col_impl,
+            corpus_dir,
+        }
+    }
+
+    /// Returns the configuration that the collector actually uses, which may differ from what
+    /// was asked of the [TraceCollectorBuilder] (e.g. because defaults were filled in), so that it
+    /// can be logged.
+    pub fn config(&self) -> TraceCollectorConfig {
+        self.col_impl.config()
+    }
+
+    /// Returns the directory that collected traces are recorded in, if any. This may have been
+    /// chosen with [TraceCollectorBuilder::corpus_dir] or the
+    /// [TraceCollectorBuilder::CORPUS_ENV_VAR] environment variable.
+    pub fn corpus_dir(&self) -> Option<&Path> {
+        self.corpus_dir.as_deref()
+    }
+
+    /// Save `trace`, along with its metadata, to a new file in the corpus directory `dir`.
+    fn record(dir: &Path, trace: &dyn Trace) -> Result<(), HWTracerError> {
+        let file = loop {
+            let n = CORPUS_SEQ.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}.hwt", process::id(), n));
+            // A previous process with the same PID may have left traces behind.
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                f => break f?,
+            }
+        };
+        let mut w = BufWriter::new(file);
+        trace.to_writer(&mut w)?;
+        w.flush()?;
+        debug!(dir = %dir.display(), "recorded trace in corpus");
+        Ok(())
     }
 
     /// Start collecting a trace of the current thread.
@@ -48,28 +116,85 @@ impl TraceCollector {
         THREAD_TRACE_COLLECTOR.with(|inner| {
             let mut inner = inner.borrow_mut();
             if inner.is_some() {
-                Err(HWTracerError::AlreadyCollecting)
+                Err(HWTracerError::Collect(CollectError::AlreadyCollecting))
             } else {
                 let mut thr_col = unsafe { self.col_impl.thread_collector() };
-                thr_col.start_collector()?;
+                if let Err(e) = thr_col.start_collector() {
+                    debug!(error = %e, "failed to start collecting");
+                    instrument::collect_failed();
+                    return Err(e);
+                }
+                debug!("started collecting");
                 *inner = Some(thr_col);
                 Ok(())
             }
         })
     }
 
+    /// Attach the key/value pair `key` and `value` to the provenance of the trace being collected
+    /// on the current thread (see [crate::TraceMeta::provenance]), replacing any value that `key`
+    /// already had. Provenance is saved with the trace and reported when it fails to decode, so
+    /// that traces can be matched up with what the application was doing, e.g.:
+    ///
+    /// ```no_run
+    /// # use hwtracer::collect::TraceCollectorBuilder;
+    /// let tc = TraceCollectorBuilder::new().build().unwrap();
+    /// tc.start_thread_collector().unwrap();
+    /// tc.tag("request", "3f2a").unwrap();
+    /// // ...
+    /// let trace = tc.stop_thread_collector().unwrap();
+    /// ```
+    ///
+    /// An error is returned if the current thread isn't being traced.
+    pub fn tag<K: Into<String>, V: Into<String>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), HWTracerError> {
+        THREAD_TRACE_COLLECTOR.with(|inner| match &mut *inner.borrow_mut() {
+            Some(thr_col) => {
+                thr_col.tag(key.into(), value.into());
+                Ok(())
+            }
+            None => Err(HWTracerError::Collect(CollectError::AlreadyStopped)),
+        })
+    }
+
     /// Stop collecting a trace of the current thread.
+    ///
+    /// If the collector records a corpus (see [TraceCollectorBuilder::corpus_dir]), the trace is
+    /// saved before being returned. Failing to save it is logged, but the trace is still returned.
     pub fn stop_thread_collector(&self) -> Result<Box<dyn Trace>, HWTracerError> {
-        THREAD_TRACE_COLLECTOR.with(|inner| {
+        let trace = THREAD_TRACE_COLLECTOR.with(|inner| {
             let mut inner = inner.borrow_mut();
             if let Some(thr_col) = &mut *inner {
                 let ret = thr_col.stop_collector();
                 *inner = None;
+                #[cfg(feature = "tracing")]
+                match &ret {
+                    Ok(t) if t.is_empty() => warn!("stopped collecting: the trace is empty"),
+                    Ok(t) => debug!(
+                        len = t.len(),
+                        trace_id = t.id().map(tracing::field::display),
+                        "stopped collecting"
+                    ),
+                    Err(e) => debug!(error = %e, "failed to stop collecting"),
+                }
+                match &ret {
+                    Ok(t) => instrument::trace_collected(t.len()),
+                    Err(_) => instrument::collect_failed(),
+                }
                 ret
             } else {
-                Err(HWTracerError::AlreadyStopped)
+                Err(HWTracerError::Collect(CollectError::AlreadyStopped))
             }
-        })
+        })?;
+        if let Some(dir) = &self.corpus_dir {
+            if let Err(_e) = Self::record(dir, &*trace) {
+                warn!("can't save a trace in the corpus: {}", _e);
+            }
+        }
+        Ok(trace)
     }
 }
 
@@ -77,8 +202,12 @@ impl TraceCollector {
 pub(crate) trait ThreadTraceCollector {
     /// Start recording a trace.
     ///
-    /// Tracing continues until [stop_collector] is called.
+    /// Tracing continues until [stop_collector] is called. The trace is assigned a new
+    /// [crate::TraceId].
     fn start_collector(&mut self) -> Result<(), HWTracerError>;
+    /// Add `key` and `value` to the provenance of the trace being recorded. Only called between
+    /// [start_collector] and [stop_collector].
+    fn tag(&mut self, key: String, value: String);
     /// Turns off the tracer.
     ///
     /// Tracing continues until [stop_collector] is called.
@@ -86,13 +215,25 @@ pub(crate) trait ThreadTraceCollector {
 }
 
 /// Kinds of collector that hwtracer supports (in order of "auto-selection preference").
-#[derive(Debug, EnumIter)]
+#[derive(Clone, Copy, Debug, EnumIter, Eq, PartialEq)]
 pub enum TraceCollectorKind {
     /// The `perf` subsystem, as found on Linux.
     Perf,
 }
 
 impl TraceCollectorKind {
+    /// Returns the kinds of collector which are available (see [TraceCollectorKind::is_available]),
+    /// in order of preference.
+    pub fn available() -> Vec<Self> {
+        Self::iter().filter(|k| k.is_available()).collect()
+    }
+
+    /// Returns `true` if this kind of collector was compiled into hwtracer and can be used on the
+    /// current machine (e.g. the CPU supports Intel PT).
+    pub fn is_available(&self) -> bool {
+        self.match_platform().is_ok()
+    }
+
     /// Finds a suitable `TraceCollectorKind` for the current hardware/OS.
     fn default_for_platform() -> Option<Self> {
         TraceCollectorKind::iter().find(|kind| Self::match_platform(&kind).is_ok())
@@ -103,13 +244,15 @@ impl TraceCollectorKind {
         match self {
             Self::Perf => {
                 #[cfg(not(collector_perf))]
-                return Err(HWTracerError::CollectorUnavailable(Self::Perf));
+                return Err(HWTracerError::Config(ConfigError::CollectorUnavailable(
+                    Self::Perf,
+                )));
                 #[cfg(collector_perf)]
                 {
                     if !Self::pt_supported() {
-                        return Err(HWTracerError::NoHWSupport(
+                        return Err(HWTracerError::Collect(CollectError::NoHWSupport(
                             "Intel PT not supported by CPU".into(),
-                        ));
+                        )));
                     }
                     Ok(())
                 }
@@ -118,14 +261,39 @@ impl TraceCollectorKind {
     }
 
     /// Checks if the CPU supports Intel Processor Trace.
+    #[cfg(collector_perf)]
     fn pt_supported() -> bool {
         let res = unsafe { __cpuid_count(0x7, 0x0) };
         (res.ebx & (1 << 25)) != 0
     }
 }
 
+impl FromStr for TraceCollectorKind {
+    type Err = HWTracerError;
+
+    /// Parse the name of a kind of collector (currently only `perf`), ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::iter()
+            .find(|k| k.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                HWTracerError::Config(ConfigError::Invalid(format!(
+                    "unknown trace collector: {}",
+                    s
+                )))
+            })
+    }
+}
+
+impl fmt::Display for TraceCollectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Perf => write!(f, "perf"),
+        }
+    }
+}
+
 /// Configuration for trace collectors.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum TraceCollectorConfig {
     Perf(PerfCollectorConfig),
 }
@@ -137,19 +305,22 @@ pub enum TraceCollectorConfig {
 #[repr(C)]
 pub struct PerfCollectorConfig {
     /// Data buffer size, in pages. Must be a power of 2.
-    pub data_bufsize: size_t,
+    pub data_bufsize: usize,
     /// AUX buffer size, in pages. Must be a power of 2.
-    pub aux_bufsize: size_t,
+    pub aux_bufsize: usize,
     /// The initial trace storage buffer size (in bytes) of new traces.
-    pub initial_trace_bufsize: size_t,
+    pub initial_trace_bufsize: usize,
+    /// Collect sideband records (e.g. new executable mappings) alongside the trace.
+    pub sideband: bool,
 }
 
 impl Default for PerfCollectorConfig {
     fn default() -> Self {
         Self {
             data_bufsize: PERF_DFLT_DATA_BUFSIZE,
-            aux_bufsize: *PERF_DFLT_AUX_BUFSIZE,
+            aux_bufsize: perf_dflt_aux_bufsize(),
             initial_trace_bufsize: PERF_DFLT_INITIAL_TRACE_BUFSIZE,
+            sideband: true,
         }
     }
 }
@@ -194,15 +365,23 @@ impl TraceCollectorConfig {
 /// ```
 pub struct TraceCollectorBuilder {
     config: TraceCollectorConfig,
+    corpus_dir: Option<PathBuf>,
 }
 
 impl TraceCollectorBuilder {
+    /// The environment variable consulted by [TraceCollectorBuilder::build] if no corpus
+    /// directory was given with [TraceCollectorBuilder::corpus_dir].
+    pub const CORPUS_ENV_VAR: &'static str = "HWTRACER_CORPUS_DIR";
+
     /// Create a new `TraceCollectorBuilder` using sensible defaults.
     pub fn new() -> Self {
         let config = match TraceCollectorKind::default_for_platform().unwrap() {
             TraceCollectorKind::Perf => TraceCollectorConfig::Perf(PerfCollectorConfig::default()),
         };
-        Self { config }
+        Self {
+            config,
+            corpus_dir: None,
+        }
     }
 
     /// Select the kind of trace collector.
@@ -218,60 +397,96 @@ impl TraceCollectorBuilder {
         &mut self.config
     }
 
+    /// Record a corpus: save every trace that the collector collects, along with its metadata,
+    /// to a new file in the directory `dir` (which is created if need be). The traces can be
+    /// loaded again with [Trace::from_reader], e.g. to build regression tests for the decoders
+    /// from real workloads.
+    ///
+    /// If this isn't called, the directory named by the [TraceCollectorBuilder::CORPUS_ENV_VAR]
+    /// environment variable (if it is set and non-empty) is used.
+    pub fn corpus_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.corpus_dir = Some(dir.into());
+        self
+    }
+
     /// Build the trace collector
     ///
     /// An error is returned if the requested collector is inappropriate for the platform or not
-    /// compiled in to hwtracer.
+    /// compiled in to hwtracer, or if the corpus directory can't be created.
     pub fn build(self) -> Result<TraceCollector, HWTracerError> {
         let kind = self.config.kind();
         kind.match_platform()?;
+        let corpus_dir = self.corpus_dir.or_else(|| {
+            env::var_os(Self::CORPUS_ENV_VAR)
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from)
+        });
+        if let Some(dir) = &corpus_dir {
+            fs::create_dir_all(dir)?;
+        }
         match self.config {
             TraceCollectorConfig::Perf(_pt_conf) => {
                 #[cfg(collector_perf)]
-                return Ok(TraceCollector::new(Box::new(PerfTraceCollector::new(
-                    _pt_conf,
-                )?)));
+                return Ok(TraceCollector::new(
+                    Box::new(PerfTraceCollector::new(_pt_conf)?),
+                    corpus_dir,
+                ));
                 #[cfg(not(collector_perf))]
-                return Err(HWTracerError::CollectorUnavailable(self.kind));
+                return Err(HWTracerError::Config(ConfigError::CollectorUnavailable(
+                    kind,
+                )));
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::TraceCollectorKind;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn collector_kind_names() {
+        for kind in TraceCollectorKind::iter() {
+            assert_eq!(
+                kind.to_string().parse::<TraceCollectorKind>().unwrap(),
+                kind
+            );
+        }
+        assert_eq!(
+            "PERF".parse::<TraceCollectorKind>().unwrap(),
+            TraceCollectorKind::Perf
+        );
+        assert!("nope".parse::<TraceCollectorKind>().is_err());
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod test_helpers {
-    use crate::{collect::TraceCollector, errors::HWTracerError, test_helpers::work_loop, Trace};
+    use crate::{
+        collect::TraceCollector,
+        errors::{CollectError, HWTracerError},
+        testing::{trace_closure, work_loop},
+    };
     use std::thread;
 
-    /// Trace a closure that returns a u64.
-    pub fn trace_closure<F>(tc: &TraceCollector, f: F) -> Box<dyn Trace>
-    where
-        F: FnOnce() -> u64,
-    {
-        tc.start_thread_collector().unwrap();
-        let res = f();
-        let trace = tc.stop_thread_collector().unwrap();
-        println!("traced closure with result: {}", res); // To avoid over-optimisation.
-        trace
-    }
-
     /// Check that starting and stopping a trace collector works.
     pub fn basic_collection(tc: TraceCollector) {
-        let trace = trace_closure(&tc, || work_loop(500));
+        let trace = trace_closure(&tc, || work_loop(500)).unwrap();
         assert_ne!(trace.len(), 0);
     }
 
     /// Check that repeated usage of the same trace collector works.
     pub fn repeated_collection(tc: TraceCollector) {
         for _ in 0..10 {
-            trace_closure(&tc, || work_loop(500));
+            trace_closure(&tc, || work_loop(500)).unwrap();
         }
     }
 
     /// Check that repeated collection using different collectors works.
     pub fn repeated_collection_different_collectors(tcs: [TraceCollector; 10]) {
         for i in 0..10 {
-            trace_closure(&tcs[i], || work_loop(500));
+            trace_closure(&tcs[i], || work_loop(500)).unwrap();
         }
     }
 
@@ -280,7 +495,7 @@ pub(crate) mod test_helpers {
     pub fn already_started(tc: TraceCollector) {
         tc.start_thread_collector().unwrap();
         match tc.start_thread_collector() {
-            Err(HWTracerError::AlreadyCollecting) => (),
+            Err(HWTracerError::Collect(CollectError::AlreadyCollecting)) => (),
             _ => panic!(),
         };
         tc.stop_thread_collector().unwrap();
@@ -290,7 +505,7 @@ pub(crate) mod test_helpers {
     pub fn already_started_different_collectors(tc1: TraceCollector, tc2: TraceCollector) {
         tc1.start_thread_collector().unwrap();
         match tc2.start_thread_collector() {
-            Err(HWTracerError::AlreadyCollecting) => (),
+            Err(HWTracerError::Collect(CollectError::AlreadyCollecting)) => (),
             _ => panic!(),
         };
         tc1.stop_thread_collector().unwrap();
@@ -299,20 +514,45 @@ pub(crate) mod test_helpers {
     /// Check that stopping an unstarted trace collector makes an appropriate error.
     pub fn not_started(tc: TraceCollector) {
         match tc.stop_thread_collector() {
-            Err(HWTracerError::AlreadyStopped) => (),
+            Err(HWTracerError::Collect(CollectError::AlreadyStopped)) => (),
             _ => panic!(),
         };
     }
 
+    /// Check that traces are given unique IDs, and carry the provenance attached to them.
+    pub fn ids_and_provenance(tc: TraceCollector) {
+        assert!(matches!(
+            tc.tag("thread", "main"),
+            Err(HWTracerError::Collect(CollectError::AlreadyStopped))
+        ));
+        tc.start_thread_collector().unwrap();
+        tc.tag("thread", "main").unwrap();
+        tc.tag("request", "1").unwrap();
+        tc.tag("request", "2").unwrap();
+        work_loop(500);
+        let a = tc.stop_thread_collector().unwrap();
+        let b = trace_closure(&tc, || work_loop(500)).unwrap();
+        assert!(a.id().is_some());
+        assert_ne!(a.id(), b.id());
+        assert_eq!(
+            a.meta().provenance.into_iter().collect::<Vec<_>>(),
+            [
+                ("request".to_owned(), "2".to_owned()),
+                ("thread".to_owned(), "main".to_owned())
+            ]
+        );
+        assert!(b.meta().provenance.is_empty());
+    }
+
     /// Check that traces can be collected concurrently.
     pub fn concurrent_collection(tc: TraceCollector) {
         for _ in 0..10 {
             thread::scope(|s| {
                 let hndl = s.spawn(|| {
-                    trace_closure(&tc, || work_loop(500));
+                    trace_closure(&tc, || work_loop(500)).unwrap();
                 });
 
-                trace_closure(&tc, || work_loop(500));
+                trace_closure(&tc, || work_loop(500)).unwrap();
                 hndl.join().unwrap();
             });
         }
@@ -22,12 +22,14 @@
 #include <sys/stat.h>
 #include <time.h>
 #include <stdatomic.h>
-#include <intel-pt.h>
 
 #include "hwtracer_private.h"
 
 #define SYSFS_PT_TYPE   "/sys/bus/event_source/devices/intel_pt/type"
 #define MAX_PT_TYPE_STR 8
+// The Intel PT configuration (`perf_event_attr.config`) that we trace with:
+// the kernel's defaults. Must be kept in sync with `PT_CONFIG` in `mod.rs`.
+#define PT_CONFIG 0
 
 #define MAX_OPEN_PERF_TRIES  50000
 #define OPEN_PERF_WAIT_NSECS 10000000 // 1/100 of a second.
@@ -62,6 +64,7 @@ struct hwt_perf_collector_config {
     size_t      aux_bufsize;           // AUX buf size (in pages).
     size_t      initial_trace_bufsize; // Initial capacity (in bytes) of a
                                        // trace storage buffer.
+    bool        sideband;              // Collect sideband records?
 };
 
 /*
@@ -76,12 +79,18 @@ struct hwt_perf_trace_buf {
 /*
  * Storage for a trace.
  *
- * Shared with Rust code. Must stay in sync.
+ * Shared with Rust code. Must stay in sync. The Rust struct has further
+ * (Rust-only) fields after these, which C code must not touch.
  */
 struct hwt_perf_trace {
     struct hwt_perf_trace_buf buf;
     __u64 len;
     __u64 capacity;
+    // Sideband records. Each is a `__u64` trace offset followed by a raw perf
+    // record (as found in the data buffer).
+    struct hwt_perf_trace_buf sb_buf;
+    __u64 sb_len;
+    __u64 sb_capacity;
 };
 
 /*
@@ -123,10 +132,12 @@ static bool handle_sample(void *, struct perf_event_mmap_page *, struct
                           hwt_perf_trace *, void *, struct hwt_cerror *);
 static bool read_aux(void *, struct perf_event_mmap_page *,
                      struct hwt_perf_trace *, struct hwt_cerror *);
+static bool append_sideband(struct hwt_perf_trace *, __u64,
+                            struct perf_event_header *, struct hwt_cerror *);
 static bool poll_loop(int, int, struct perf_event_mmap_page *, void *,
                       struct hwt_perf_trace *, struct hwt_cerror *);
 static void *collector_thread(void *);
-static int open_perf(size_t, struct hwt_cerror *);
+static int open_perf(size_t, bool, struct hwt_cerror *);
 
 // Exposed Prototypes.
 struct hwt_perf_ctx *hwt_perf_init_collector(struct hwt_perf_collector_config *, struct hwt_cerror *);
@@ -134,6 +145,8 @@ bool hwt_perf_start_collector(struct hwt_perf_ctx *, struct hwt_perf_trace *, st
 bool hwt_perf_stop_collector(struct hwt_perf_ctx *tr_ctx, struct hwt_cerror *);
 bool hwt_perf_free_collector(struct hwt_perf_ctx *tr_ctx, struct hwt_cerror *);
 
+// Defined on the Rust side.
+void hwt_perf_aux_drained(__u64, __u64);
 
 /*
  * Called when the poll(2) loop is woken up with a POLL_IN. Samples are read
@@ -186,6 +199,11 @@ handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
     }
     atomic_store_explicit((_Atomic __u64 *) &hdr->data_tail, head, memory_order_relaxed);
 
+    // Any sideband record we are about to read was generated after the last
+    // time we drained the data buffer, and thus after all of the trace data we
+    // have stored so far.
+    __u64 sb_offset = trace->len;
+
     void *next_sample = data_tmp;
     while (next_sample != data_tmp_end) {
         struct perf_event_header *sample_hdr = next_sample;
@@ -198,7 +216,7 @@ handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
                 // truncated. If it was, then we didn't read out of the data buffer
                 // quickly/frequently enough.
                 if (rec_aux_sample->flags & PERF_AUX_FLAG_TRUNCATED) {
-                    hwt_set_cerr(err, hwt_cerror_ipt, pte_overflow);
+                    hwt_set_cerr(err, hwt_cerror_overflow, 0);
                     return false;
                 }
                 if (read_aux(aux_buf, hdr, trace, err) == false) {
@@ -206,13 +224,22 @@ handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
                 }
                 break;
             case PERF_RECORD_LOST:
-                hwt_set_cerr(err, hwt_cerror_ipt, pte_overflow);
+                hwt_set_cerr(err, hwt_cerror_overflow, 0);
                 return false;
                 break;
             case PERF_RECORD_LOST_SAMPLES:
                 // Shouldn't happen with PT.
                 errx(EXIT_FAILURE, "Unexpected PERF_RECORD_LOST_SAMPLES sample");
                 break;
+            case PERF_RECORD_MMAP2:
+            case PERF_RECORD_COMM:
+            case PERF_RECORD_SWITCH:
+                // Sideband records. These are only generated if we asked for
+                // them in open_perf().
+                if (!append_sideband(trace, sb_offset, sample_hdr, err)) {
+                    return false;
+                }
+                break;
         }
         next_sample += sample_hdr->size;
     }
@@ -279,6 +306,43 @@ read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
         trace->len += size + head;
     }
     atomic_store_explicit((_Atomic __u64 *) &hdr->aux_tail, head, memory_order_release);
+    hwt_perf_aux_drained(new_data_size, trace->len);
+    return true;
+}
+
+/*
+ * Append the sideband record `rec` to the sideband buffer of `trace`, tagging
+ * it with the trace offset `offset`.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+append_sideband(struct hwt_perf_trace *trace, __u64 offset,
+                struct perf_event_header *rec, struct hwt_cerror *err)
+{
+    __u64 entry_size = sizeof(offset) + rec->size;
+
+    // Reallocate the sideband buffer if more space is required.
+    __u64 required_capacity = trace->sb_len + entry_size;
+    if (required_capacity > trace->sb_capacity) {
+        if (required_capacity >= SIZE_MAX / 2) {
+            // We would overflow the size_t argument of realloc(3).
+            hwt_set_cerr(err, hwt_cerror_errno, ENOMEM);
+            return false;
+        }
+        size_t new_capacity = required_capacity * 2;
+        void *new_buf = realloc(trace->sb_buf.p, new_capacity);
+        if (new_buf == NULL) {
+            hwt_set_cerr(err, hwt_cerror_errno, errno);
+            return false;
+        }
+        trace->sb_capacity = new_capacity;
+        trace->sb_buf.p = new_buf;
+    }
+
+    memcpy(trace->sb_buf.p + trace->sb_len, &offset, sizeof(offset));
+    memcpy(trace->sb_buf.p + trace->sb_len + sizeof(offset), rec, rec->size);
+    trace->sb_len += entry_size;
     return true;
 }
 
@@ -356,10 +420,13 @@ done:
 /*
  * Opens the perf file descriptor and returns it.
  *
+ * If `sideband` is true, perf is asked to report changes to the traced
+ * program's environment (e.g. new executable mappings) in the data buffer.
+ *
  * Returns a file descriptor, or -1 on error.
  */
 static int
-open_perf(size_t aux_bufsize, struct hwt_cerror *err) {
+open_perf(size_t aux_bufsize, bool sideband, struct hwt_cerror *err) {
     struct perf_event_attr attr;
     memset(&attr, 0, sizeof(attr));
     attr.size = sizeof(attr);
@@ -381,6 +448,7 @@ open_perf(size_t aux_bufsize, struct hwt_cerror *err) {
         goto clean;
     }
     attr.type = atoi(pt_type_str);
+    attr.config = PT_CONFIG;
 
     // Exclude the kernel.
     attr.exclude_kernel = 1;
@@ -401,6 +469,18 @@ open_perf(size_t aux_bufsize, struct hwt_cerror *err) {
     // Generate a PERF_RECORD_AUX sample when the AUX buffer is almost full.
     attr.aux_watermark = (size_t) ((double) aux_bufsize * getpagesize()) * AUX_BUF_WAKE_RATIO;
 
+    if (sideband) {
+        // Report executable mappings (PERF_RECORD_MMAP2).
+        attr.mmap = 1;
+        attr.mmap2 = 1;
+        // Report thread name changes, including those due to execve(2)
+        // (PERF_RECORD_COMM).
+        attr.comm = 1;
+        attr.comm_exec = 1;
+        // Report context switches (PERF_RECORD_SWITCH).
+        attr.context_switch = 1;
+    }
+
     // Acquire file descriptor through which to talk to Intel PT. This syscall
     // could return EBUSY, meaning another process or thread has locked the
     // Perf device.
@@ -500,7 +580,7 @@ hwt_perf_init_collector(struct hwt_perf_collector_config *tr_conf, struct hwt_ce
     tr_ctx->perf_fd = -1;
 
     // Obtain a file descriptor through which to speak to perf.
-    tr_ctx->perf_fd = open_perf(tr_conf->aux_bufsize, err);
+    tr_ctx->perf_fd = open_perf(tr_conf->aux_bufsize, tr_conf->sideband, err);
     if (tr_ctx->perf_fd == -1) {
         hwt_set_cerr(err, hwt_cerror_errno, errno);
         failing = true;
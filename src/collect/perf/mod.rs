@@ -3,9 +3,10 @@
 use super::PerfCollectorConfig;
 use crate::{
     c_errors::PerfPTCError,
-    collect::{ThreadTraceCollector, TraceCollectorImpl},
-    errors::HWTracerError,
-    Trace,
+    collect::{ThreadTraceCollector, TraceCollectorConfig, TraceCollectorImpl},
+    errors::{CollectError, ConfigError, HWTracerError},
+    sideband::{parse_perf_record, perf_record_header, u64_at},
+    SidebandRecord, Trace, TraceId, TraceMeta,
 };
 use libc::{c_void, free, geteuid, malloc, size_t};
 use std::{convert::TryFrom, fs::File, io::Read, ptr, slice};
@@ -15,6 +16,8 @@ extern "C" {
         conf: *const PerfCollectorConfig,
         err: *mut PerfPTCError,
     ) -> *mut c_void;
+    // The C code only knows about the fields of `PerfTrace` up to and including `sb_capacity`.
+    #[allow(improper_ctypes)]
     fn hwt_perf_start_collector(
         tr_ctx: *mut c_void,
         trace: *mut PerfTrace,
@@ -24,7 +27,18 @@ extern "C" {
     fn hwt_perf_free_collector(tr_ctx: *mut c_void, err: *mut PerfPTCError) -> bool;
 }
 
+/// Called by the C code (on the collector thread) each time it drains `new_bytes` bytes of trace
+/// data from the AUX buffer into a trace, leaving the trace `len` bytes long.
+#[no_mangle]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+extern "C" fn hwt_perf_aux_drained(new_bytes: u64, len: u64) {
+    trace!(new_bytes, len, "drained AUX buffer");
+}
+
 const PERF_PERMS_PATH: &str = "/proc/sys/kernel/perf_event_paranoid";
+/// The Intel PT configuration that traces are collected with. Must be kept in sync with
+/// `PT_CONFIG` in `collect.c`.
+const PT_CONFIG: u64 = 0;
 
 /// The configuration for a Linux Perf collector.
 #[derive(Debug)]
@@ -42,14 +56,14 @@ impl PerfTraceCollector {
             (v & (v - 1)) == 0
         }
         if !power_of_2(config.data_bufsize) {
-            return Err(HWTracerError::BadConfig(String::from(
+            return Err(HWTracerError::Config(ConfigError::Invalid(String::from(
                 "data_bufsize must be a positive power of 2",
-            )));
+            ))));
         }
         if !power_of_2(config.aux_bufsize) {
-            return Err(HWTracerError::BadConfig(String::from(
+            return Err(HWTracerError::Config(ConfigError::Invalid(String::from(
                 "aux_bufsize must be a positive power of 2",
-            )));
+            ))));
         }
 
         // Check we have permissions to collect a PT trace using perf.
@@ -68,10 +82,11 @@ impl PerfTraceCollector {
                     "Tracing not permitted: you must be root or {} must contain -1",
                     PERF_PERMS_PATH
                 );
-                return Err(HWTracerError::Permissions(msg));
+                return Err(HWTracerError::Collect(CollectError::Permissions(msg)));
             }
         }
 
+        debug!(?config, "created perf collector");
         Ok(Self { config })
     }
 }
@@ -80,6 +95,10 @@ impl TraceCollectorImpl for PerfTraceCollector {
     unsafe fn thread_collector(&self) -> Box<dyn ThreadTraceCollector> {
         Box::new(PerfThreadTraceCollector::new(self.config.clone()))
     }
+
+    fn config(&self) -> TraceCollectorConfig {
+        TraceCollectorConfig::Perf(self.config.clone())
+    }
 }
 
 /// A collector that uses the Linux Perf interface to Intel Processor Trace.
@@ -127,6 +146,11 @@ impl ThreadTraceCollector for PerfThreadTraceCollector {
         //
         // Note that the C code will mutate the trace's members directly.
         let mut trace = Box::new(PerfTrace::new(self.config.initial_trace_bufsize)?);
+        // FIXME: This assumes that the thread runs on CPUs of the same model throughout.
+        trace.meta = TraceMeta {
+            id: Some(TraceId::next()),
+            ..TraceMeta::current(PT_CONFIG)
+        };
         let mut cerr = PerfPTCError::new();
         if !unsafe { hwt_perf_start_collector(self.ctx, &mut *trace, &mut cerr) } {
             return Err(cerr.into());
@@ -152,6 +176,15 @@ impl ThreadTraceCollector for PerfThreadTraceCollector {
         self.trace = None;
         Ok(ret as Box<dyn Trace>)
     }
+
+    fn tag(&mut self, key: String, value: String) {
+        self.trace
+            .as_mut()
+            .unwrap()
+            .meta
+            .provenance
+            .insert(key, value);
+    }
 }
 
 /// A wrapper around a manually malloc/free'd buffer for holding an Intel PT trace. We've split
@@ -167,6 +200,11 @@ struct PerfTraceBuf(*mut u8);
 /// unsafely) mark the struct as being Send.
 unsafe impl Send for PerfTrace {}
 
+/// The C code only writes to the buffers while the trace is being collected, and the trace isn't
+/// handed out until collection has stopped. From then on the buffers are only read, so the trace
+/// can safely be shared between threads.
+unsafe impl Sync for PerfTrace {}
+
 /// An Intel PT trace, obtained via Linux perf.
 #[repr(C)]
 #[derive(Debug)]
@@ -177,6 +215,14 @@ pub struct PerfTrace {
     len: u64,
     /// `buf`'s allocation size (in bytes), <= `len`.
     capacity: u64,
+    /// The sideband buffer. Each entry is a `u64` trace offset followed by a raw perf record.
+    sb_buf: PerfTraceBuf,
+    /// The length of the sideband buffer (in bytes).
+    sb_len: u64,
+    /// `sb_buf`'s allocation size (in bytes).
+    sb_capacity: u64,
+    /// Where and how the trace was collected. Not touched by the C code.
+    meta: TraceMeta,
 }
 
 impl PerfTrace {
@@ -193,8 +239,42 @@ impl PerfTrace {
             buf: PerfTraceBuf(buf),
             len: 0,
             capacity: capacity as u64,
+            // The C code allocates this on demand.
+            sb_buf: PerfTraceBuf(ptr::null_mut()),
+            sb_len: 0,
+            sb_capacity: 0,
+            meta: TraceMeta::default(),
         })
     }
+
+    /// Return the raw bytes of the sideband buffer.
+    fn sideband_bytes(&self) -> &[u8] {
+        if self.sb_buf.0.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.sb_buf.0, usize::try_from(self.sb_len).unwrap()) }
+    }
+}
+
+/// Parse the contents of a sideband buffer, as written by the C code, into sideband records.
+///
+/// Each entry in the buffer is a trace offset followed by a perf record. Records which hwtracer
+/// doesn't know about are skipped.
+fn parse_sideband(mut bytes: &[u8]) -> Result<Vec<SidebandRecord>, HWTracerError> {
+    let mut recs = Vec::new();
+    while !bytes.is_empty() {
+        let trace_offset = usize::try_from(u64_at(bytes, 0)?).unwrap();
+        let rec = &bytes[8..];
+        let (_, _, size) = perf_record_header(rec)?;
+        if let Some(event) = parse_perf_record(rec)? {
+            recs.push(SidebandRecord {
+                trace_offset,
+                event,
+            });
+        }
+        bytes = &rec[size..];
+    }
+    Ok(recs)
 }
 
 impl Trace for PerfTrace {
@@ -217,6 +297,14 @@ impl Trace for PerfTrace {
         usize::try_from(self.len).unwrap()
     }
 
+    fn meta(&self) -> TraceMeta {
+        self.meta.clone()
+    }
+
+    fn sideband(&self) -> Result<Vec<SidebandRecord>, HWTracerError> {
+        parse_sideband(self.sideband_bytes())
+    }
+
     #[cfg(test)]
     fn capacity(&self) -> usize {
         self.capacity as usize
@@ -228,20 +316,29 @@ impl Drop for PerfTrace {
         if !self.buf.0.is_null() {
             unsafe { free(self.buf.0 as *mut c_void) };
         }
+        if !self.sb_buf.0.is_null() {
+            unsafe { free(self.sb_buf.0 as *mut c_void) };
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{PerfCollectorConfig, PerfThreadTraceCollector};
+    use super::{parse_sideband, PerfCollectorConfig, PerfThreadTraceCollector, PT_CONFIG};
     use crate::{
         collect::{
             test_helpers, ThreadTraceCollector, TraceCollector, TraceCollectorBuilder,
             TraceCollectorConfig, TraceCollectorKind,
         },
-        errors::HWTracerError,
-        test_helpers::work_loop,
+        errors::{ConfigError, DecodeError, HWTracerError, MalformedTraceKind},
+        sideband::{
+            PERF_RECORD_COMM, PERF_RECORD_MISC_COMM_EXEC, PERF_RECORD_MISC_SWITCH_OUT,
+            PERF_RECORD_MMAP2, PERF_RECORD_SWITCH,
+        },
+        testing::{self, work_loop},
+        CpuId, SidebandEvent, SidebandRecord, Trace, TraceMeta,
     };
+    use std::{convert::TryFrom, env, fs, os::fd::AsRawFd, path::PathBuf, ptr};
 
     fn mk_collector() -> TraceCollector {
         TraceCollectorBuilder::new()
@@ -297,6 +394,11 @@ mod tests {
         test_helpers::concurrent_collection(mk_collector());
     }
 
+    #[test]
+    fn ids_and_provenance() {
+        test_helpers::ids_and_provenance(mk_collector());
+    }
+
     /// Check that a long trace causes the trace buffer to reallocate.
     #[test]
     fn relloc_trace_buf() {
@@ -313,6 +415,25 @@ mod tests {
         assert!(trace.capacity() > start_bufsize);
     }
 
+    /// Check that a built collector reports the configuration that it uses.
+    #[test]
+    fn effective_config() {
+        let mut bldr = TraceCollectorBuilder::new().kind(TraceCollectorKind::Perf);
+        match bldr.config() {
+            TraceCollectorConfig::Perf(ref mut ppt_conf) => ppt_conf.data_bufsize = 128,
+        }
+        let tc = bldr.build().unwrap();
+        match tc.config() {
+            TraceCollectorConfig::Perf(conf) => {
+                assert_eq!(conf.data_bufsize, 128);
+                assert_eq!(conf.aux_bufsize, PerfCollectorConfig::default().aux_bufsize);
+            }
+        }
+        if env::var_os(TraceCollectorBuilder::CORPUS_ENV_VAR).is_none() {
+            assert_eq!(tc.corpus_dir(), None);
+        }
+    }
+
     /// Check that an invalid data buffer size causes an error.
     #[test]
     fn test_config_bad_data_bufsize() {
@@ -321,7 +442,7 @@ mod tests {
             TraceCollectorConfig::Perf(ref mut ppt_conf) => ppt_conf.data_bufsize = 3,
         }
         match bldr.build() {
-            Err(HWTracerError::BadConfig(s)) => {
+            Err(HWTracerError::Config(ConfigError::Invalid(s))) => {
                 assert_eq!(s, "data_bufsize must be a positive power of 2");
             }
             _ => panic!(),
@@ -336,10 +457,221 @@ mod tests {
             TraceCollectorConfig::Perf(ref mut ppt_conf) => ppt_conf.aux_bufsize = 3,
         }
         match bldr.build() {
-            Err(HWTracerError::BadConfig(s)) => {
+            Err(HWTracerError::Config(ConfigError::Invalid(s))) => {
                 assert_eq!(s, "aux_bufsize must be a positive power of 2");
             }
             _ => panic!(),
         }
     }
+
+    /// Append a sideband buffer entry, in the format written by the C code, to `buf`.
+    fn push_sideband(buf: &mut Vec<u8>, offset: u64, typ: u32, misc: u16, body: &[u8]) {
+        buf.extend(offset.to_le_bytes());
+        buf.extend(typ.to_le_bytes());
+        buf.extend(misc.to_le_bytes());
+        buf.extend(u16::try_from(8 + body.len()).unwrap().to_le_bytes());
+        buf.extend(body);
+    }
+
+    #[test]
+    fn parse_sideband_records() {
+        let mut buf = Vec::new();
+
+        let mut mmap = Vec::new();
+        mmap.extend(1u32.to_le_bytes()); // pid
+        mmap.extend(2u32.to_le_bytes()); // tid
+        mmap.extend(0x1000u64.to_le_bytes()); // addr
+        mmap.extend(0x2000u64.to_le_bytes()); // len
+        mmap.extend(0x3000u64.to_le_bytes()); // pgoff
+        mmap.extend([0; 24]); // maj, min, ino, ino_generation
+        mmap.extend([0; 8]); // prot, flags
+        mmap.extend(b"/lib/libfoo.so\0\0");
+        push_sideband(&mut buf, 0, PERF_RECORD_MMAP2, 0, &mmap);
+
+        let mut comm = Vec::new();
+        comm.extend(1u32.to_le_bytes()); // pid
+        comm.extend(2u32.to_le_bytes()); // tid
+        comm.extend(b"prog\0\0\0\0");
+        push_sideband(
+            &mut buf,
+            100,
+            PERF_RECORD_COMM,
+            PERF_RECORD_MISC_COMM_EXEC,
+            &comm,
+        );
+
+        push_sideband(
+            &mut buf,
+            100,
+            PERF_RECORD_SWITCH,
+            PERF_RECORD_MISC_SWITCH_OUT,
+            &[],
+        );
+        // Unknown record types are skipped.
+        push_sideband(&mut buf, 200, 0xffff, 0, &[0; 8]);
+
+        assert_eq!(
+            parse_sideband(&buf).unwrap(),
+            vec![
+                SidebandRecord {
+                    trace_offset: 0,
+                    event: SidebandEvent::Mmap {
+                        vaddr: 0x1000,
+                        len: 0x2000,
+                        pgoff: 0x3000,
+                        filename: PathBuf::from("/lib/libfoo.so"),
+                    }
+                },
+                SidebandRecord {
+                    trace_offset: 100,
+                    event: SidebandEvent::Comm {
+                        pid: 1,
+                        tid: 2,
+                        comm: "prog".to_owned(),
+                        exec: true,
+                    }
+                },
+                SidebandRecord {
+                    trace_offset: 100,
+                    event: SidebandEvent::Switch { out: true }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_truncated_sideband() {
+        let mut buf = Vec::new();
+        push_sideband(&mut buf, 0, PERF_RECORD_COMM, 0, &[0; 16]);
+        buf.truncate(buf.len() - 1);
+        assert!(matches!(
+            parse_sideband(&buf),
+            Err(HWTracerError::Decode(DecodeError::Malformed {
+                kind: MalformedTraceKind::BadSidebandRecord,
+                ..
+            }))
+        ));
+    }
+
+    /// Check that mapping executable code while tracing gives rise to a sideband record.
+    #[test]
+    fn sideband_mmap() {
+        let exe = fs::canonicalize(env::current_exe().unwrap()).unwrap();
+        let f = fs::File::open(&exe).unwrap();
+        let mut tracer = PerfThreadTraceCollector::default();
+
+        tracer.start_collector().unwrap();
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                f.as_raw_fd(),
+                0,
+            )
+        };
+        let trace = tracer.stop_collector().unwrap();
+        assert_ne!(addr, libc::MAP_FAILED);
+        unsafe { libc::munmap(addr, 4096) };
+
+        let vaddr = addr as u64;
+        assert!(trace.sideband().unwrap().iter().any(|r| matches!(
+            &r.event,
+            SidebandEvent::Mmap { vaddr: v, filename, .. } if *v == vaddr && *filename == exe
+        )));
+    }
+
+    /// Check that no sideband records are collected when sideband collection is disabled.
+    #[test]
+    fn sideband_disabled() {
+        let config = PerfCollectorConfig {
+            sideband: false,
+            ..Default::default()
+        };
+        let mut tracer = PerfThreadTraceCollector::new(config);
+
+        tracer.start_collector().unwrap();
+        let res = work_loop(500);
+        let trace = tracer.stop_collector().unwrap();
+
+        println!("res: {}", res); // Stop over-optimisation.
+        assert!(trace.sideband().unwrap().is_empty());
+    }
+
+    /// Check that a trace records the CPU that collected it.
+    #[test]
+    fn trace_cpu() {
+        let mut tracer = PerfThreadTraceCollector::new(PerfCollectorConfig::default());
+        tracer.start_collector().unwrap();
+        let res = work_loop(10);
+        let trace = tracer.stop_collector().unwrap();
+
+        println!("res: {}", res); // Stop over-optimisation.
+        assert!(trace.cpu().is_some());
+        assert_eq!(trace.cpu(), CpuId::current());
+    }
+
+    /// Check that a trace records how it was collected.
+    #[test]
+    fn trace_meta() {
+        let mut tracer = PerfThreadTraceCollector::new(PerfCollectorConfig::default());
+        tracer.start_collector().unwrap();
+        let res = work_loop(10);
+        let trace = tracer.stop_collector().unwrap();
+
+        println!("res: {}", res); // Stop over-optimisation.
+        let meta = trace.meta();
+        assert!(meta.id.is_some());
+        assert_eq!(
+            TraceMeta { id: None, ..meta },
+            TraceMeta::current(PT_CONFIG)
+        );
+    }
+
+    /// Check that a collector recording a corpus saves every trace it collects.
+    #[test]
+    fn corpus() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = dir.path().join("corpus");
+        let tc = TraceCollectorBuilder::new()
+            .kind(TraceCollectorKind::Perf)
+            .corpus_dir(&corpus)
+            .build()
+            .unwrap();
+        assert_eq!(tc.corpus_dir(), Some(corpus.as_path()));
+        let traces = (0..2)
+            .map(|_| testing::trace_closure(&tc, || work_loop(10)).unwrap())
+            .collect::<Vec<_>>();
+        let mut saved = fs::read_dir(&corpus)
+            .unwrap()
+            .map(|e| {
+                let mut f = fs::File::open(e.unwrap().path()).unwrap();
+                <dyn Trace>::from_reader(&mut f).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(saved.len(), traces.len());
+        saved.sort_by_key(|t| t.bytes() != traces[0].bytes());
+        for (s, t) in saved.iter().zip(&traces) {
+            assert_eq!(s.bytes(), t.bytes());
+            assert_eq!(s.meta(), t.meta());
+        }
+    }
+
+    /// Check that a trace which can't be saved in the corpus is still returned.
+    #[test]
+    fn corpus_unwritable() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = dir.path().join("corpus");
+        let tc = TraceCollectorBuilder::new()
+            .kind(TraceCollectorKind::Perf)
+            .corpus_dir(&corpus)
+            .build()
+            .unwrap();
+        // Replace the corpus directory with a file, so nothing can be saved in it.
+        fs::remove_dir(&corpus).unwrap();
+        fs::write(&corpus, b"").unwrap();
+        let trace = testing::trace_closure(&tc, || work_loop(10)).unwrap();
+        assert_ne!(trace.len(), 0);
+    }
 }
@@ -0,0 +1,812 @@
+//! A container format for saving traces and loading them again later, possibly on another machine.
+//!
+//! # Compatibility
+//!
+//! Every container records the version of the format it was written in. hwtracer always writes
+//! the newest version ([VERSION]), and reads every version from [MIN_VERSION] onwards, so traces
+//! saved by one release of hwtracer can be loaded by any later release. The version is bumped
+//! whenever the layout changes, however slightly, and new fields are only ever appended to (or
+//! inserted into) the layout below, never repurposed. [MIN_VERSION] is only raised in a release
+//! which says so, and only once no archived traces are likely to need the versions it drops.
+//!
+//! Containers newer than [VERSION] are rejected with an error saying that a newer hwtracer is
+//! needed, rather than being misread.
+//!
+//! # Layout
+//!
+//! A container holds, in order (all integers little-endian):
+//!
+//!  - the magic bytes `HWTRACE\0`.
+//!  - the container format version (`u32`).
+//!  - the trace format (`u8`). Only Intel PT (0) is currently defined.
+//!  - the codec that the rest of the container is compressed with (`u8`): none (0) or zstd (1).
+//!    Version 1 containers lack this field and are never compressed.
+//!
+//! The rest of the container, after compression is undone, holds:
+//!
+//!  - the CPU that the trace was collected on: a `u8` flag saying whether it is known, followed
+//!    (if it is) by its family (`u16`), model (`u8`) and stepping (`u8`).
+//!  - the rest of the trace's [TraceMeta], each field being a `u8` flag saying whether it is known
+//!    followed (if it is) by its value: the TSC ratio (two `u32`s), the Intel PT configuration
+//!    (`u64`) and the hwtracer version (a `u64` length followed by UTF-8 bytes). Containers older
+//!    than version 3 lack these fields.
+//!  - the number of build IDs in the trace's [TraceMeta] (`u64`), followed by each one's path and
+//!    build ID (each a `u64` length followed by the bytes). Containers older than version 5 lack
+//!    this field.
+//!  - the trace's ID: a `u8` flag saying whether it has one, followed (if it does) by the ID
+//!    (`u128`). Then the number of provenance entries (`u64`), followed by each one's key and
+//!    value (each a `u64` length followed by UTF-8 bytes), in key order. Containers older than
+//!    version 6 lack these fields.
+//!  - the number of sideband records (`u64`), followed by the records themselves.
+//!  - the length of the raw trace data (`u64`), followed by the data itself.
+//!  - the XXH3 (64-bit) hash of the raw trace data (`u64`), checked when the container is read.
+//!    Containers older than version 4 lack this field.
+
+use crate::{
+    errors::{DecodeError, HWTracerError},
+    sideband::{path_bytes, path_from_bytes},
+    CpuId, ObjectBuildId, SidebandEvent, SidebandRecord, Trace, TraceId, TraceMeta,
+};
+#[cfg(test)]
+use std::fs::File;
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+const MAGIC: &[u8; 8] = b"HWTRACE\0";
+/// The container format version. Must be bumped whenever the layout changes.
+pub(crate) const VERSION: u32 = 6;
+/// The oldest container format version that can still be read.
+pub(crate) const MIN_VERSION: u32 = 1;
+/// The trace format of Intel PT traces.
+const FORMAT_INTEL_PT: u8 = 0;
+
+// Compression codecs.
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+/// How many bytes of trace data to write at a time.
+const WRITE_CHUNK_SIZE: usize = 1 << 20;
+
+/// How a trace is compressed when it is saved. Loading a trace works out how it was compressed by
+/// itself.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    /// No compression.
+    None,
+    /// [Zstandard](https://facebook.github.io/zstd/) compression. Intel PT traces typically
+    /// compress very well.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Default for Codec {
+    /// Compress traces whenever hwtracer has been built with a compression codec.
+    fn default() -> Self {
+        #[cfg(feature = "zstd")]
+        return Codec::Zstd;
+        #[cfg(not(feature = "zstd"))]
+        return Codec::None;
+    }
+}
+
+// Sideband record kinds.
+const SB_MMAP: u8 = 0;
+const SB_COMM: u8 = 1;
+const SB_SWITCH: u8 = 2;
+const SB_SESSION_BOUNDARY: u8 = 3;
+
+/// A trace held in memory, rather than one collected by hwtracer in this process.
+#[derive(Debug)]
+pub(crate) struct RawTrace {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) meta: TraceMeta,
+    pub(crate) sideband: Vec<SidebandRecord>,
+}
+
+impl RawTrace {
+    /// Make a trace from raw trace data, with no other information about it.
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            meta: TraceMeta::default(),
+            sideband: Vec::new(),
+        }
+    }
+}
+
+impl Trace for RawTrace {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn meta(&self) -> TraceMeta {
+        self.meta.clone()
+    }
+
+    fn sideband(&self) -> Result<Vec<SidebandRecord>, HWTracerError> {
+        Ok(self.sideband.clone())
+    }
+
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        file.write_all(&self.bytes).unwrap();
+    }
+}
+
+fn bad(msg: &str) -> HWTracerError {
+    HWTracerError::Decode(DecodeError::parse(format!("bad trace container: {}", msg)))
+}
+
+fn corrupt(msg: &str) -> HWTracerError {
+    HWTracerError::Decode(DecodeError::Corrupt(format!("trace container {}", msg)))
+}
+
+fn write_bytes(w: &mut dyn Write, bytes: &[u8]) -> Result<(), HWTracerError> {
+    w.write_all(&u64::try_from(bytes.len()).unwrap().to_le_bytes())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_array<const N: usize>(r: &mut dyn Read) -> Result<[u8; N], HWTracerError> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => corrupt("truncated"),
+        _ => e.into(),
+    })?;
+    Ok(buf)
+}
+
+fn read_u8(r: &mut dyn Read) -> Result<u8, HWTracerError> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_u16(r: &mut dyn Read) -> Result<u16, HWTracerError> {
+    Ok(u16::from_le_bytes(read_array(r)?))
+}
+
+fn read_u32(r: &mut dyn Read) -> Result<u32, HWTracerError> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_u64(r: &mut dyn Read) -> Result<u64, HWTracerError> {
+    Ok(u64::from_le_bytes(read_array(r)?))
+}
+
+fn read_u128(r: &mut dyn Read) -> Result<u128, HWTracerError> {
+    Ok(u128::from_le_bytes(read_array(r)?))
+}
+
+fn read_bool(r: &mut dyn Read) -> Result<bool, HWTracerError> {
+    match read_u8(r)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(bad("invalid boolean")),
+    }
+}
+
+fn read_bytes(r: &mut dyn Read) -> Result<Vec<u8>, HWTracerError> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    // Don't trust the length enough to preallocate it: a corrupt container could ask for anything.
+    r.take(len).read_to_end(&mut bytes)?;
+    if u64::try_from(bytes.len()).unwrap() != len {
+        return Err(corrupt("truncated"));
+    }
+    Ok(bytes)
+}
+
+fn write_sideband(w: &mut dyn Write, rec: &SidebandRecord) -> Result<(), HWTracerError> {
+    w.write_all(&u64::try_from(rec.trace_offset).unwrap().to_le_bytes())?;
+    match &rec.event {
+        SidebandEvent::Mmap {
+            vaddr,
+            len,
+            pgoff,
+            filename,
+        } => {
+            w.write_all(&[SB_MMAP])?;
+            w.write_all(&vaddr.to_le_bytes())?;
+            w.write_all(&len.to_le_bytes())?;
+            w.write_all(&pgoff.to_le_bytes())?;
+            write_bytes(w, &path_bytes(filename))?;
+        }
+        SidebandEvent::Comm {
+            pid,
+            tid,
+            comm,
+            exec,
+        } => {
+            w.write_all(&[SB_COMM])?;
+            w.write_all(&pid.to_le_bytes())?;
+            w.write_all(&tid.to_le_bytes())?;
+            write_bytes(w, comm.as_bytes())?;
+            w.write_all(&[u8::from(*exec)])?;
+        }
+        SidebandEvent::Switch { out } => {
+            w.write_all(&[SB_SWITCH, u8::from(*out)])?;
+        }
+        SidebandEvent::SessionBoundary => w.write_all(&[SB_SESSION_BOUNDARY])?,
+    }
+    Ok(())
+}
+
+fn read_sideband(r: &mut dyn Read) -> Result<SidebandRecord, HWTracerError> {
+    let trace_offset = usize::try_from(read_u64(r)?).map_err(|_| bad("offset too large"))?;
+    let event = match read_u8(r)? {
+        SB_MMAP => SidebandEvent::Mmap {
+            vaddr: read_u64(r)?,
+            len: read_u64(r)?,
+            pgoff: read_u64(r)?,
+            filename: path_from_bytes(&read_bytes(r)?),
+        },
+        SB_COMM => SidebandEvent::Comm {
+            pid: read_u32(r)?,
+            tid: read_u32(r)?,
+            comm: String::from_utf8(read_bytes(r)?).map_err(|_| bad("invalid thread name"))?,
+            exec: read_bool(r)?,
+        },
+        SB_SWITCH => SidebandEvent::Switch { out: read_bool(r)? },
+        SB_SESSION_BOUNDARY => SidebandEvent::SessionBoundary,
+        _ => return Err(bad("unknown sideband record kind")),
+    };
+    Ok(SidebandRecord {
+        trace_offset,
+        event,
+    })
+}
+
+/// Write `trace` to `w` in the container format, compressed with `codec`. See
+/// [Trace::to_writer_with].
+pub(crate) fn write<T: Trace + ?Sized>(
+    trace: &T,
+    w: &mut dyn Write,
+    codec: Codec,
+) -> Result<(), HWTracerError> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&[FORMAT_INTEL_PT])?;
+    match codec {
+        Codec::None => {
+            w.write_all(&[CODEC_NONE])?;
+            write_payload(trace, w)
+        }
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => {
+            w.write_all(&[CODEC_ZSTD])?;
+            let mut enc = zstd::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            write_payload(trace, &mut enc)?;
+            enc.finish()?;
+            Ok(())
+        }
+    }
+}
+
+/// Write the (possibly compressed) part of the container for `trace` to `w`.
+fn write_payload<T: Trace + ?Sized>(trace: &T, w: &mut dyn Write) -> Result<(), HWTracerError> {
+    let meta = trace.meta();
+    match meta.cpu {
+        Some(cpu) => {
+            w.write_all(&[1])?;
+            w.write_all(&cpu.family.to_le_bytes())?;
+            w.write_all(&[cpu.model, cpu.stepping])?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    match meta.tsc_ratio {
+        Some((num, den)) => {
+            w.write_all(&[1])?;
+            w.write_all(&num.to_le_bytes())?;
+            w.write_all(&den.to_le_bytes())?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    match meta.pt_config {
+        Some(config) => {
+            w.write_all(&[1])?;
+            w.write_all(&config.to_le_bytes())?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    match &meta.hwtracer_version {
+        Some(version) => {
+            w.write_all(&[1])?;
+            write_bytes(w, version.as_bytes())?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    w.write_all(&u64::try_from(meta.build_ids.len()).unwrap().to_le_bytes())?;
+    for id in &meta.build_ids {
+        write_bytes(w, &path_bytes(&id.path))?;
+        write_bytes(w, &id.build_id)?;
+    }
+    match meta.id {
+        Some(id) => {
+            w.write_all(&[1])?;
+            w.write_all(&id.0.to_le_bytes())?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    w.write_all(&u64::try_from(meta.provenance.len()).unwrap().to_le_bytes())?;
+    for (key, value) in &meta.provenance {
+        write_bytes(w, key.as_bytes())?;
+        write_bytes(w, value.as_bytes())?;
+    }
+    let sideband = trace.sideband()?;
+    w.write_all(&u64::try_from(sideband.len()).unwrap().to_le_bytes())?;
+    for rec in &sideband {
+        write_sideband(w, rec)?;
+    }
+    // The trace may not all be in memory, so is written (and hashed) a chunk at a time.
+    w.write_all(&u64::try_from(trace.len()).unwrap().to_le_bytes())?;
+    let mut hasher = Xxh3::new();
+    for chunk in trace.chunks(WRITE_CHUNK_SIZE) {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        w.write_all(&chunk)?;
+    }
+    w.write_all(&hasher.digest().to_le_bytes())?;
+    Ok(())
+}
+
+/// Read a trace in the container format from `r`. See [Trace::from_reader].
+pub(crate) fn read(r: &mut dyn Read) -> Result<RawTrace, HWTracerError> {
+    let (version, codec) = read_header(r)?;
+    match codec {
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => read_payload(&mut zstd::Decoder::new(r)?, version),
+        // `read_header` has checked that the codec is one that we support.
+        _ => read_payload(r, version),
+    }
+}
+
+/// Read just the header of a container from `r`, returning the container format version. See
+/// [Trace::probe_reader].
+pub(crate) fn probe(r: &mut dyn Read) -> Result<u32, HWTracerError> {
+    Ok(read_header(r)?.0)
+}
+
+/// Read the uncompressed header of a container from `r`, checking that the rest of the container
+/// can be read. Returns the container format version and the codec that the rest of the
+/// container is compressed with.
+fn read_header(r: &mut dyn Read) -> Result<(u32, u8), HWTracerError> {
+    if &read_array::<8>(r)? != MAGIC {
+        return Err(bad("not a trace container"));
+    }
+    let version = read_u32(r)?;
+    if version > VERSION {
+        return Err(bad(&format!(
+            "version {} is newer than this hwtracer supports (at most {}): upgrade hwtracer",
+            version, VERSION
+        )));
+    }
+    if version < MIN_VERSION {
+        return Err(bad(&format!(
+            "version {} is no longer supported (at least {} is required)",
+            version, MIN_VERSION
+        )));
+    }
+    if read_u8(r)? != FORMAT_INTEL_PT {
+        return Err(bad("unsupported trace format"));
+    }
+    let codec = match version {
+        1 => CODEC_NONE,
+        _ => read_u8(r)?,
+    };
+    match codec {
+        CODEC_NONE => Ok((version, codec)),
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => Ok((version, codec)),
+        #[cfg(not(feature = "zstd"))]
+        CODEC_ZSTD => Err(bad(
+            "compressed with zstd, but hwtracer was built without the `zstd` feature",
+        )),
+        _ => Err(bad("unknown compression codec")),
+    }
+}
+
+/// Read the (possibly compressed) part of a container in format version `version` from `r`.
+fn read_payload(r: &mut dyn Read, version: u32) -> Result<RawTrace, HWTracerError> {
+    let mut meta = TraceMeta {
+        cpu: match read_bool(r)? {
+            true => Some(CpuId {
+                family: read_u16(r)?,
+                model: read_u8(r)?,
+                stepping: read_u8(r)?,
+            }),
+            false => None,
+        },
+        ..Default::default()
+    };
+    if version >= 3 {
+        if read_bool(r)? {
+            meta.tsc_ratio = Some((read_u32(r)?, read_u32(r)?));
+        }
+        if read_bool(r)? {
+            meta.pt_config = Some(read_u64(r)?);
+        }
+        if read_bool(r)? {
+            meta.hwtracer_version = Some(
+                String::from_utf8(read_bytes(r)?).map_err(|_| bad("invalid hwtracer version"))?,
+            );
+        }
+    }
+    if version >= 5 {
+        for _ in 0..read_u64(r)? {
+            meta.build_ids.push(ObjectBuildId {
+                path: path_from_bytes(&read_bytes(r)?),
+                build_id: read_bytes(r)?,
+            });
+        }
+    }
+    if version >= 6 {
+        if read_bool(r)? {
+            meta.id = Some(TraceId(read_u128(r)?));
+        }
+        let string = |r: &mut dyn Read| {
+            String::from_utf8(read_bytes(r)?).map_err(|_| bad("invalid provenance"))
+        };
+        for _ in 0..read_u64(r)? {
+            let key = string(r)?;
+            meta.provenance.insert(key, string(r)?);
+        }
+    }
+    let mut sideband = Vec::new();
+    for _ in 0..read_u64(r)? {
+        sideband.push(read_sideband(r)?);
+    }
+    let bytes = read_bytes(r)?;
+    if version >= 4 && read_u64(r)? != xxh3_64(&bytes) {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(RawTrace {
+        bytes,
+        meta,
+        sideband,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{probe, read, Codec, RawTrace, CODEC_NONE, MIN_VERSION, VERSION};
+    use crate::{
+        errors::{DecodeError, HWTracerError},
+        CpuId, ObjectBuildId, SidebandEvent, SidebandRecord, Trace, TraceId, TraceMeta,
+    };
+    use std::{borrow::Cow, fs::File, path::PathBuf};
+
+    /// A trace which hands out its bytes one (owned) byte at a time, like a trace which isn't held
+    /// in memory would.
+    #[derive(Debug)]
+    struct ByteAtATime(RawTrace);
+
+    impl Trace for ByteAtATime {
+        fn bytes(&self) -> &[u8] {
+            unreachable!()
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.capacity()
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn meta(&self) -> TraceMeta {
+            self.0.meta()
+        }
+
+        fn sideband(&self) -> Result<Vec<SidebandRecord>, HWTracerError> {
+            self.0.sideband()
+        }
+
+        fn chunks(
+            &self,
+            _size: usize,
+        ) -> Box<dyn Iterator<Item = Result<Cow<'_, [u8]>, HWTracerError>> + '_> {
+            Box::new(self.0.bytes.iter().map(|b| Ok(Cow::Owned(vec![*b]))))
+        }
+
+        fn to_file(&self, file: &mut File) {
+            self.0.to_file(file)
+        }
+    }
+
+    fn sample() -> RawTrace {
+        RawTrace {
+            bytes: vec![0x02, 0x82, 0x02, 0x82, 0xff, 0x00],
+            meta: TraceMeta {
+                cpu: Some(CpuId {
+                    family: 6,
+                    model: 0x8c,
+                    stepping: 1,
+                }),
+                tsc_ratio: Some((188, 2)),
+                pt_config: Some(0x2001),
+                hwtracer_version: Some(String::from("0.1.0")),
+                build_ids: vec![ObjectBuildId {
+                    path: PathBuf::from("/lib/libfoo.so"),
+                    build_id: vec![0xab; 20],
+                }],
+                id: Some(TraceId(0x0123_4567_89ab_cdef_0011_2233_4455_6677)),
+                provenance: [("thread", "worker"), ("request", "42")]
+                    .iter()
+                    .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect(),
+            },
+            sideband: vec![
+                SidebandRecord {
+                    trace_offset: 0,
+                    event: SidebandEvent::Mmap {
+                        vaddr: 0x7f00_0000_0000,
+                        len: 0x1000,
+                        pgoff: 0x2000,
+                        filename: PathBuf::from("/lib/libfoo.so"),
+                    },
+                },
+                SidebandRecord {
+                    trace_offset: 4,
+                    event: SidebandEvent::Comm {
+                        pid: 10,
+                        tid: 11,
+                        comm: String::from("worker"),
+                        exec: true,
+                    },
+                },
+                SidebandRecord {
+                    trace_offset: 6,
+                    event: SidebandEvent::Switch { out: true },
+                },
+                SidebandRecord {
+                    trace_offset: 6,
+                    event: SidebandEvent::SessionBoundary,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let trace = sample();
+        let mut buf = Vec::new();
+        trace.to_writer(&mut buf).unwrap();
+        let loaded = <dyn Trace>::from_reader(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.bytes(), trace.bytes());
+        assert_eq!(loaded.meta(), trace.meta);
+        assert_eq!(loaded.cpu(), trace.meta.cpu);
+        assert_eq!(loaded.sideband().unwrap(), trace.sideband);
+    }
+
+    #[test]
+    fn round_trip_codecs() {
+        let codecs = [
+            Codec::None,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+        ];
+        let trace = RawTrace {
+            bytes: vec![0x02; 4096],
+            ..sample()
+        };
+        for codec in codecs {
+            let mut buf = Vec::new();
+            trace.to_writer_with(&mut buf, codec).unwrap();
+            let loaded = read(&mut buf.as_slice()).unwrap();
+            assert_eq!(loaded.bytes, trace.bytes);
+            assert_eq!(loaded.meta, trace.meta);
+            assert_eq!(loaded.sideband, trace.sideband);
+            if codec != Codec::None {
+                assert!(buf.len() < trace.bytes.len());
+            }
+        }
+    }
+
+    /// Containers written before compression was introduced can still be read.
+    #[test]
+    fn version_1() {
+        let mut trace = sample();
+        trace.meta = TraceMeta {
+            cpu: trace.meta.cpu,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        trace.to_writer_with(&mut buf, Codec::None).unwrap();
+        buf[8..12].copy_from_slice(&1u32.to_le_bytes());
+        buf.truncate(buf.len() - 8); // The checksum.
+        assert_eq!(buf.remove(13), CODEC_NONE);
+        // Remove the flags of the unknown metadata fields which follow the CPU, the number of
+        // build IDs, and the flag of the unknown ID and the number of provenance entries.
+        assert_eq!(buf.drain(18..21).collect::<Vec<_>>(), [0, 0, 0]);
+        assert_eq!(buf.drain(18..26).collect::<Vec<_>>(), [0; 8]);
+        assert_eq!(buf.drain(18..27).collect::<Vec<_>>(), [0; 9]);
+        let loaded = read(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.bytes, trace.bytes);
+        assert_eq!(loaded.meta, trace.meta);
+        assert_eq!(loaded.sideband, trace.sideband);
+    }
+
+    /// Every version from `MIN_VERSION` onwards can be read.
+    #[test]
+    fn old_versions() {
+        for version in MIN_VERSION..=VERSION {
+            let mut trace = sample();
+            if version < 3 {
+                // Only the CPU could be recorded.
+                trace.meta = TraceMeta {
+                    cpu: trace.meta.cpu,
+                    ..Default::default()
+                };
+            }
+            if version < 5 {
+                trace.meta.build_ids.clear();
+            }
+            if version < 6 {
+                trace.meta.id = None;
+                trace.meta.provenance.clear();
+            }
+            let mut buf = Vec::new();
+            trace.to_writer_with(&mut buf, Codec::None).unwrap();
+            buf[8..12].copy_from_slice(&version.to_le_bytes());
+            if version < 6 {
+                // The ID's flag and the number of provenance entries, which follow the build IDs.
+                let at = match version {
+                    1 | 2 => 30,
+                    3 | 4 => 59,
+                    _ => 109,
+                };
+                assert_eq!(buf.drain(at..at + 9).collect::<Vec<_>>(), [0; 9]);
+            }
+            if version < 5 {
+                // The number of build IDs, which follows the rest of the metadata.
+                let at = if version < 3 { 22 } else { 51 };
+                assert_eq!(buf.drain(at..at + 8).collect::<Vec<_>>(), [0; 8]);
+            }
+            if version < 4 {
+                buf.truncate(buf.len() - 8); // The checksum.
+            }
+            if version < 3 {
+                // The flags of the unknown metadata fields which follow the CPU.
+                assert_eq!(buf.drain(19..22).collect::<Vec<_>>(), [0, 0, 0]);
+            }
+            if version < 2 {
+                assert_eq!(buf.remove(13), CODEC_NONE);
+            }
+            let loaded = read(&mut buf.as_slice()).unwrap();
+            assert_eq!(loaded.bytes, trace.bytes, "version {}", version);
+            assert_eq!(loaded.meta, trace.meta, "version {}", version);
+            assert_eq!(loaded.sideband, trace.sideband, "version {}", version);
+        }
+
+        // Newer versions are refused, not misread, and can be spotted from the header alone.
+        let mut buf = Vec::new();
+        sample().to_writer(&mut buf).unwrap();
+        assert_eq!(probe(&mut buf.as_slice()).unwrap(), VERSION);
+        buf[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(probe(&mut &buf[..14]).is_err());
+        match read(&mut buf.as_slice()) {
+            Err(HWTracerError::Decode(DecodeError::Parse { msg, .. })) => {
+                assert!(msg.contains("upgrade"))
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn bad_codec() {
+        let mut buf = Vec::new();
+        sample().to_writer_with(&mut buf, Codec::None).unwrap();
+        buf[13] = 0xff;
+        assert!(probe(&mut buf.as_slice()).is_err());
+        assert!(matches!(
+            read(&mut buf.as_slice()),
+            Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+        ));
+        #[cfg(not(feature = "zstd"))]
+        {
+            buf[13] = super::CODEC_ZSTD;
+            assert!(matches!(
+                read(&mut buf.as_slice()),
+                Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+            ));
+        }
+    }
+
+    #[test]
+    fn from_bytes() {
+        let trace = <dyn Trace>::from_bytes(vec![1, 2, 3]);
+        assert_eq!(trace.bytes(), &[1, 2, 3]);
+        assert_eq!(trace.len(), 3);
+        assert!(!trace.is_empty());
+        assert_eq!(trace.cpu(), None);
+        assert!(trace.sideband().unwrap().is_empty());
+        let chunks = trace.chunks(2).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(chunks, vec![&[1, 2][..], &[3][..]]);
+        assert!(<dyn Trace>::from_bytes(Vec::new()).is_empty());
+    }
+
+    /// Check that traces are written a chunk at a time, rather than assuming that all of the trace
+    /// is in memory.
+    #[test]
+    fn chunked_write() {
+        let trace = sample();
+        let mut buf = Vec::new();
+        ByteAtATime(sample()).to_writer(&mut buf).unwrap();
+        let loaded = <dyn Trace>::from_reader(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.bytes(), trace.bytes());
+        assert_eq!(loaded.sideband().unwrap(), trace.sideband);
+    }
+
+    #[test]
+    fn unknown_cpu() {
+        let mut trace = sample();
+        trace.meta.cpu = None;
+        let mut buf = Vec::new();
+        trace.to_writer(&mut buf).unwrap();
+        assert_eq!(read(&mut buf.as_slice()).unwrap().meta, trace.meta);
+
+        trace.meta = TraceMeta::default();
+        let mut buf = Vec::new();
+        trace.to_writer(&mut buf).unwrap();
+        assert_eq!(read(&mut buf.as_slice()).unwrap().meta, trace.meta);
+    }
+
+    #[test]
+    fn bad_containers() {
+        let mut buf = Vec::new();
+        sample().to_writer(&mut buf).unwrap();
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            read(&mut bad_magic.as_slice()),
+            Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+        ));
+
+        let mut bad_version = buf.clone();
+        bad_version[8] = 0xff;
+        assert!(matches!(
+            read(&mut bad_version.as_slice()),
+            Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+        ));
+
+        // Chopping any number of bytes off the end must be detected.
+        for len in 0..buf.len() {
+            assert!(read(&mut &buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn corruption() {
+        let trace = sample();
+        let mut buf = Vec::new();
+        trace.to_writer_with(&mut buf, Codec::None).unwrap();
+        // Truncation anywhere in the payload is reported as such.
+        for len in 14..buf.len() {
+            assert!(matches!(
+                read(&mut &buf[..len]),
+                Err(HWTracerError::Decode(DecodeError::Corrupt(_)))
+            ));
+        }
+        // As is damage to the trace data, or to its checksum.
+        for off in [buf.len() - 9, buf.len() - 1] {
+            let mut damaged = buf.clone();
+            damaged[off] ^= 0x10;
+            assert!(matches!(
+                read(&mut damaged.as_slice()),
+                Err(HWTracerError::Decode(DecodeError::Corrupt(_)))
+            ));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Fast edge coverage of traces, for fuzzers.
+//!
+//! Rather than decoding a trace into blocks, which needs the traced code and disassembles every
+//! instruction executed, [EdgeMap::update] hashes the branch decisions and branch targets recorded
+//! in the trace's packets straight into an AFL-style map of hit counts. Two executions which took
+//! different paths almost always hit different entries in the map, which is all that a
+//! coverage-guided fuzzer needs to know, and which can be found in little more than the time taken
+//! to parse the trace.
+//!
+//! ```no_run
+//! use hwtracer::{coverage::EdgeMap, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let mut map = EdgeMap::new(1 << 16).unwrap();
+//! // For each execution of the fuzzed program:
+//! map.clear();
+//! map.update(&*trace).unwrap();
+//! let new_coverage = map.as_slice().iter().filter(|&&n| n > 0).count();
+//! ```
+
+use crate::{
+    errors::{ConfigError, HWTracerError},
+    pt::{Packet, PacketParser},
+    slice::first_psb_offset,
+    Trace,
+};
+
+/// A fixed-size map of hashed edges to the number of times that they were hit.
+///
+/// An "edge" here is a TNT packet (a run of conditional branch decisions) or a packet carrying a
+/// branch target, combined with the hash of everything since the last one. Hit counts saturate at
+/// 255.
+#[derive(Clone, Debug)]
+pub struct EdgeMap {
+    counts: Box<[u8]>,
+}
+
+impl EdgeMap {
+    /// Create a map with `size` entries, all zero. `size` must be a power of two.
+    ///
+    /// Larger maps make collisions between edges less likely, but take longer to clear and scan.
+    pub fn new(size: usize) -> Result<Self, HWTracerError> {
+        if !size.is_power_of_two() {
+            return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+                "edge map size {} isn't a power of two",
+                size
+            ))));
+        }
+        Ok(Self {
+            counts: vec![0; size].into_boxed_slice(),
+        })
+    }
+
+    /// Reset every entry to zero, e.g. before the next execution.
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+    }
+
+    /// Returns the hit count of each entry.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.counts
+    }
+
+    /// Add the edges of `trace` to the map. The map is reused rather than reallocated, so this can
+    /// be called once per execution at little cost.
+    ///
+    /// Any data before the first PSB packet is skipped. If a packet can't be parsed, an error is
+    /// returned, and the edges before it will already have been added.
+    pub fn update(&mut self, trace: &dyn Trace) -> Result<(), HWTracerError> {
+        let bytes = trace.bytes();
+        let mut parser = match first_psb_offset(bytes) {
+            Some(start) => PacketParser::new_at(bytes, start).skip_pads(true),
+            None => return Ok(()),
+        };
+        let mask = self.counts.len() - 1;
+        // The hash of the edges so far.
+        let mut prev = 0;
+        loop {
+            let off = parser.offset();
+            let pkt = match parser.next() {
+                Some(pkt) => pkt.map_err(|e| HWTracerError::from(e).at_offset(off))?,
+                None => return Ok(()),
+            };
+            let cur = match &pkt {
+                Packet::ShortTNT(p) => p.branches(),
+                Packet::LongTNT(p) => p.branches(),
+                Packet::TIP(..) | Packet::TIPPGE(..) | Packet::TIPPGD(..) | Packet::FUP(..) => {
+                    // Suppressed IPs all hash alike: the edge still happened.
+                    pkt.target_ip()
+                        .map_err(|e| HWTracerError::from(e).at_offset(off))?
+                        .unwrap_or(0)
+                }
+                Packet::OVF(_) => {
+                    // Packets were lost, so the next edge doesn't follow on from the last one.
+                    prev = 0;
+                    continue;
+                }
+                _ => continue,
+            };
+            // Distinguish TNT payloads from IPs of the same value.
+            let kind = pkt.kind() as u64;
+            prev = mix(prev, cur ^ (kind << 56));
+            let n = &mut self.counts[(prev ^ (prev >> 32)) as usize & mask];
+            *n = n.saturating_add(1);
+        }
+    }
+}
+
+/// Combine the hash `h` with `v`.
+fn mix(h: u64, v: u64) -> u64 {
+    (h.rotate_left(5) ^ v).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EdgeMap;
+    use crate::{
+        errors::{ConfigError, HWTracerError},
+        Trace,
+    };
+
+    #[test]
+    fn new() {
+        assert!(matches!(
+            EdgeMap::new(3),
+            Err(HWTracerError::Config(ConfigError::Invalid(_)))
+        ));
+        assert!(matches!(
+            EdgeMap::new(0),
+            Err(HWTracerError::Config(ConfigError::Invalid(_)))
+        ));
+        let map = EdgeMap::new(64).unwrap();
+        assert_eq!(map.as_slice(), &[0; 64][..]);
+    }
+
+    /// Make a trace which enables tracing, then takes a conditional branch each way as `taken`
+    /// says, then an indirect branch, then disables tracing.
+    fn trace(taken: [bool; 2]) -> Box<dyn Trace> {
+        #[rustfmt::skip]
+        let mut bytes = vec![
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+        ];
+        // Short TNTs, each with one branch.
+        bytes.extend(taken.iter().map(|&t| if t { 0x06 } else { 0x04 }));
+        #[rustfmt::skip]
+        bytes.extend_from_slice(&[
+            // TIP with a 16-bit compressed IP.
+            0x2d, 0x00, 0x20,
+            // TIP.PGD with no IP.
+            0x01,
+        ]);
+        <dyn Trace>::from_bytes(bytes)
+    }
+
+    #[test]
+    fn update() {
+        let mut map = EdgeMap::new(1 << 16).unwrap();
+        map.update(&*trace([true, false])).unwrap();
+        let first = map.as_slice().to_vec();
+        assert_eq!(first.iter().map(|&n| usize::from(n)).sum::<usize>(), 5);
+
+        // The same path hits the same edges.
+        map.update(&*trace([true, false])).unwrap();
+        assert!(map.as_slice().iter().zip(&first).all(|(&a, &b)| a == b * 2));
+
+        // A different path hits different edges.
+        map.clear();
+        map.update(&*trace([false, true])).unwrap();
+        assert_ne!(map.as_slice(), &first[..]);
+
+        // Traces with no PSB packet have no edges.
+        map.clear();
+        map.update(&*<dyn Trace>::from_bytes(vec![0; 16])).unwrap();
+        assert!(map.as_slice().iter().all(|&n| n == 0));
+    }
+}
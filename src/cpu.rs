@@ -0,0 +1,74 @@
+//! Identifying the CPU that a trace was collected on.
+
+use crate::pt::Errata;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid_count;
+use std::convert::TryFrom;
+
+/// The identity of an Intel CPU, as reported by the `cpuid` instruction.
+///
+/// Decoders use this to work around the errata of the CPU that a trace was collected on.
+// Must be kept in sync with `struct hwt_ipt_cpu` in `decode.c`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuId {
+    pub family: u16,
+    pub model: u8,
+    pub stepping: u8,
+}
+
+impl CpuId {
+    /// Identify the CPU that the calling thread is running on, or return `None` if it isn't an
+    /// Intel CPU.
+    #[cfg(target_arch = "x86_64")]
+    pub fn current() -> Option<Self> {
+        let vendor = unsafe { __cpuid_count(0, 0) };
+        // "GenuineIntel", split across three registers.
+        if (vendor.ebx, vendor.edx, vendor.ecx) != (0x756e_6547, 0x4965_6e69, 0x6c65_746e) {
+            return None;
+        }
+
+        // Decode the signature in the same way as libipt's `pt_cpu_read()`.
+        let sig = unsafe { __cpuid_count(1, 0) }.eax;
+        let mut family = u16::try_from((sig >> 8) & 0xf).unwrap();
+        if family == 0xf {
+            family += u16::try_from((sig >> 20) & 0xff).unwrap();
+        }
+        let mut model = u8::try_from((sig >> 4) & 0xf).unwrap();
+        if family == 0x6 || family >= 0xf {
+            model += u8::try_from(((sig >> 16) & 0xf) << 4).unwrap();
+        }
+        let stepping = u8::try_from(sig & 0xf).unwrap();
+        Some(Self {
+            family,
+            model,
+            stepping,
+        })
+    }
+
+    /// Identify the CPU that the calling thread is running on, or return `None` if it isn't an
+    /// Intel CPU.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn current() -> Option<Self> {
+        None
+    }
+
+    /// Returns the Intel PT errata of the CPU.
+    pub fn errata(&self) -> Errata {
+        Errata::for_model(self.family, self.model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuId;
+
+    #[test]
+    fn current() {
+        // Intel PT only exists on Intel CPUs, none of which have a zero family.
+        if let Some(cpu) = CpuId::current() {
+            assert_ne!(cpu.family, 0);
+        }
+    }
+}
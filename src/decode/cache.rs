@@ -0,0 +1,223 @@
+//! Caching of decoded blocks.
+
+use super::TraceDecoder;
+use crate::{errors::HWTracerError, Block, Trace};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// A least-recently-used cache of the blocks decoded from traces, keyed by a hash of each trace's
+/// bytes and the identity of the code image that it was decoded against.
+///
+/// JITs often trace the same path (e.g. the same loop iteration) many times over, producing
+/// byte-for-byte identical traces. [DecodeCache::blocks] decodes each distinct trace once, and
+/// returns the cached blocks for repeats of it.
+///
+/// The `image` passed to [DecodeCache::blocks] must identify everything besides the trace bytes
+/// that the blocks depend upon: the code that was traced (e.g. a hash of the JIT's code cache, or
+/// a counter bumped whenever code is loaded or patched) and the decoder's configuration (e.g. its
+/// address ranges). Traces are identified by a 128-bit hash of their bytes, so a cached result may
+/// in theory be returned for a different trace of the same length, but the chance is negligible.
+///
+/// Clones of a cache share its contents, so one cache can be used from many threads.
+#[derive(Clone, Debug)]
+pub struct DecodeCache(Arc<Mutex<CacheInner>>);
+
+#[derive(Debug)]
+struct CacheInner {
+    capacity: usize,
+    /// The cached blocks and the time that they were last used.
+    entries: HashMap<CacheKey, (Arc<[Block]>, u64)>,
+    /// Incremented on every lookup, so that the least recently used entry has the smallest time.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct CacheKey {
+    hash: u128,
+    len: usize,
+    image: u64,
+}
+
+impl DecodeCache {
+    /// Create a cache holding the blocks of up to `capacity` traces. A capacity of zero caches
+    /// nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(CacheInner {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        })))
+    }
+
+    /// Returns the blocks of `trace`, as decoded by `decoder` against the code image identified by
+    /// `image`, decoding them only if they aren't already cached.
+    ///
+    /// If the cache is full, the least recently used entry is evicted to make room. Errors aren't
+    /// cached, so a trace which fails to decode is decoded again if it is seen again.
+    pub fn blocks(
+        &self,
+        decoder: &dyn TraceDecoder,
+        trace: &dyn Trace,
+        image: u64,
+    ) -> Result<Arc<[Block]>, HWTracerError> {
+        let bytes = trace.bytes();
+        let key = CacheKey {
+            hash: xxh3_128(bytes),
+            len: bytes.len(),
+            image,
+        };
+        {
+            let mut inner = self.0.lock().unwrap();
+            inner.clock += 1;
+            let now = inner.clock;
+            if let Some((blocks, used)) = inner.entries.get_mut(&key) {
+                *used = now;
+                let blocks = Arc::clone(blocks);
+                inner.hits += 1;
+                return Ok(blocks);
+            }
+            inner.misses += 1;
+        }
+
+        // Don't hold the lock while decoding, so other threads can use the cache meanwhile. If two
+        // threads decode the same trace at once, the second to finish replaces the first's entry.
+        let blocks = decoder
+            .iter_blocks(trace)
+            .collect::<Result<Arc<[Block]>, _>>()?;
+        let mut inner = self.0.lock().unwrap();
+        if inner.capacity == 0 {
+            return Ok(blocks);
+        }
+        if inner.entries.len() >= inner.capacity && !inner.entries.contains_key(&key) {
+            // Evicting is linear in the capacity, but that is small next to the cost of decoding.
+            let lru = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| *k)
+                .unwrap();
+            inner.entries.remove(&lru);
+        }
+        let now = inner.clock;
+        inner.entries.insert(key, (Arc::clone(&blocks), now));
+        Ok(blocks)
+    }
+
+    /// Returns the number of traces whose blocks are cached.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().entries.len()
+    }
+
+    /// Returns `true` if nothing is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of lookups which were, and weren't, answered from the cache.
+    pub fn stats(&self) -> (u64, u64) {
+        let inner = self.0.lock().unwrap();
+        (inner.hits, inner.misses)
+    }
+
+    /// Remove everything from the cache, e.g. after the traced code has changed.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodeCache;
+    use crate::{
+        container::RawTrace,
+        decode::{TraceDecoder, TraceDecoderConfig},
+        errors::HWTracerError,
+        Block, Trace,
+    };
+    use std::{
+        iter,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A decoder which yields one block per byte of the trace, and counts the traces it decodes.
+    struct CountingDecoder(AtomicUsize);
+
+    impl TraceDecoder for CountingDecoder {
+        fn new(_config: TraceDecoderConfig) -> Self {
+            Self(AtomicUsize::new(0))
+        }
+
+        fn iter_blocks<'t>(
+            &'t self,
+            trace: &'t dyn Trace,
+        ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            if trace.bytes() == [0xff] {
+                return Box::new(iter::once(Err(HWTracerError::Unknown)));
+            }
+            Box::new(trace.bytes().iter().map(|&b| {
+                let addr = u64::from(b) * 0x10;
+                Ok(Block::new(addr, addr + 0xf))
+            }))
+        }
+    }
+
+    #[test]
+    fn lru() {
+        let dec = CountingDecoder::new(TraceDecoderConfig::default());
+        let cache = DecodeCache::new(2);
+        let (a, b, c) = (
+            RawTrace::new(vec![1, 2]),
+            RawTrace::new(vec![3]),
+            RawTrace::new(vec![4]),
+        );
+        let blocks = cache.blocks(&dec, &a, 0).unwrap();
+        assert_eq!(&*blocks, &[Block::new(0x10, 0x1f), Block::new(0x20, 0x2f)]);
+        assert_eq!(&*cache.blocks(&dec, &a, 0).unwrap(), &*blocks);
+        assert_eq!(dec.0.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats(), (1, 1));
+
+        // A different image is a different entry.
+        cache.blocks(&dec, &a, 1).unwrap();
+        assert_eq!(dec.0.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.len(), 2);
+
+        // `a` in image 0 was used least recently, so it's evicted.
+        cache.blocks(&dec, &a, 1).unwrap();
+        cache.blocks(&dec, &b, 0).unwrap();
+        assert_eq!(cache.len(), 2);
+        cache.blocks(&dec, &a, 1).unwrap();
+        assert_eq!(dec.0.load(Ordering::Relaxed), 3);
+        cache.blocks(&dec, &a, 0).unwrap();
+        assert_eq!(dec.0.load(Ordering::Relaxed), 4);
+
+        // Errors aren't cached.
+        let bad = RawTrace::new(vec![0xff]);
+        assert!(cache.blocks(&dec, &bad, 0).is_err());
+        assert!(cache.blocks(&dec, &bad, 0).is_err());
+        assert_eq!(dec.0.load(Ordering::Relaxed), 6);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.blocks(&dec, &c, 0).unwrap();
+        assert_eq!(dec.0.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let dec = CountingDecoder::new(TraceDecoderConfig::default());
+        let cache = DecodeCache::new(0);
+        let t = RawTrace::new(vec![1]);
+        cache.blocks(&dec, &t, 0).unwrap();
+        cache.blocks(&dec, &t, 0).unwrap();
+        assert_eq!(dec.0.load(Ordering::Relaxed), 2);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+//! Cancelling decodes from other threads.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A handle with which decodes can be abandoned from another thread (e.g. by a service enforcing a
+/// deadline on a pathological trace), without waiting for them to finish or killing the thread
+/// doing them. See [super::DecodeLimits::cancel].
+///
+/// Clones share the same state, so a clone can be kept to cancel the decodes of a decoder given the
+/// token. Decoders check the token as they go (after each packet, or each block for decoders which
+/// don't work at the packet level), and stop with [crate::errors::DecodeError::Cancelled] once it
+/// has been cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Make a token which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the decodes using the token: both those in progress and any started before
+    /// [CancellationToken::reset] is called.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the token has been cancelled (and not since reset).
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Un-cancel the token, so that decodes using it can run again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
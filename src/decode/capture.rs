@@ -0,0 +1,219 @@
+//! Watching for failures to decode: counting them (with the `metrics` feature), and saving the
+//! traces that fail, so that they can be attached to bug reports.
+
+use super::{
+    diagnose, BranchOutcome, DecodeEvent, DecodedPrefix, TraceDecoder, TraceDecoderConfig,
+    TraceDecoderKind,
+};
+use crate::{errors::HWTracerError, instrument, Block, Insn, Trace};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The number of failures captured by this process, which keeps the names of their files unique.
+static CAPTURED: AtomicUsize = AtomicUsize::new(0);
+
+/// A decoder which records each failure of the decoder that it wraps in the metrics, and saves
+/// the trace to a directory (see [TraceDecoderConfig::failure_dir]) if it is at fault, then
+/// reports the failure as the wrapped decoder would.
+pub(crate) struct WatchingDecoder {
+    decoder: Box<dyn TraceDecoder>,
+    /// The kind of the wrapped decoder.
+    kind: TraceDecoderKind,
+    dir: Option<PathBuf>,
+}
+
+impl WatchingDecoder {
+    /// Wrap `decoder`, of kind `kind`, saving the traces that it fails to decode to `dir`, if set.
+    pub(crate) fn new(
+        decoder: Box<dyn TraceDecoder>,
+        kind: TraceDecoderKind,
+        dir: Option<PathBuf>,
+    ) -> Self {
+        Self { decoder, kind, dir }
+    }
+
+    /// Record the failure if `res` is an error, saving `trace` if it was caused by its contents.
+    fn check<T>(&self, trace: &dyn Trace, res: &Result<T, HWTracerError>) {
+        if let Err(e) = res {
+            instrument::decode_failed(self.kind, e);
+            match &self.dir {
+                Some(dir) if e.is_bad_trace() => {
+                    if let Err(_e) = capture(dir, trace, e) {
+                        warn!("can't save a trace which failed to decode: {}", _e);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Wrap the iterator `itr` over the decoded items of `trace`, so that the trace is saved if
+    /// iteration fails.
+    fn iter<'t, T: 't>(
+        &'t self,
+        trace: &'t dyn Trace,
+        itr: Box<dyn Iterator<Item = Result<T, HWTracerError>> + 't>,
+    ) -> Box<dyn Iterator<Item = Result<T, HWTracerError>> + 't> {
+        Box::new(itr.inspect(move |res| self.check(trace, res)))
+    }
+}
+
+impl TraceDecoder for WatchingDecoder {
+    fn new(_config: TraceDecoderConfig) -> Self {
+        unreachable!("only made by wrapping a decoder, in TraceDecoderBuilder::build")
+    }
+
+    fn iter_blocks<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+        self.iter(trace, self.decoder.iter_blocks(trace))
+    }
+
+    fn decode_until(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&Block) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        let res = self.decoder.decode_until(trace, f);
+        self.check(trace, &res);
+        res
+    }
+
+    fn decode_blocks_into(
+        &self,
+        trace: &dyn Trace,
+        buf: &mut Vec<Block>,
+    ) -> Result<(), HWTracerError> {
+        let res = self.decoder.decode_blocks_into(trace, buf);
+        self.check(trace, &res);
+        res
+    }
+
+    /// Traces which may have been cut short are expected to fail, so aren't saved.
+    fn decode_prefix(&self, trace: &dyn Trace) -> Result<DecodedPrefix, HWTracerError> {
+        self.decoder.decode_prefix(trace)
+    }
+
+    fn decode_events_until(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&DecodeEvent) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        let res = self.decoder.decode_events_until(trace, f);
+        self.check(trace, &res);
+        res
+    }
+
+    fn decode_events_into(
+        &self,
+        trace: &dyn Trace,
+        buf: &mut Vec<DecodeEvent>,
+    ) -> Result<(), HWTracerError> {
+        let res = self.decoder.decode_events_into(trace, buf);
+        self.check(trace, &res);
+        res
+    }
+
+    fn iter_events<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<DecodeEvent, HWTracerError>> + '_> {
+        self.iter(trace, self.decoder.iter_events(trace))
+    }
+
+    fn iter_insns<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Insn, HWTracerError>> + '_> {
+        self.iter(trace, self.decoder.iter_insns(trace))
+    }
+
+    fn iter_branches<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<BranchOutcome, HWTracerError>> + '_> {
+        self.iter(trace, self.decoder.iter_branches(trace))
+    }
+}
+
+/// Save `trace`, which failed to decode with `err`, to a new pair of files in `dir` (which is
+/// created if need be): the trace itself, as written by [Trace::to_writer], in `<name>.hwt`, and a
+/// report on the failure (see [super::DecodeDiagnostics]) in `<name>.txt`. Returns the path of the
+/// trace's file.
+pub(crate) fn capture(
+    dir: &Path,
+    trace: &dyn Trace,
+    err: &HWTracerError,
+) -> Result<PathBuf, HWTracerError> {
+    fs::create_dir_all(dir)?;
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let name = format!(
+        "hwtracer-failure-{}-{}-{}",
+        secs,
+        process::id(),
+        CAPTURED.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = dir.join(&name).with_extension("hwt");
+    let mut w = BufWriter::new(File::create(&path)?);
+    trace.to_writer(&mut w)?;
+    w.flush()?;
+    let mut report = File::create(dir.join(name).with_extension("txt"))?;
+    match diagnose(trace, err) {
+        Ok(diag) => writeln!(report, "{}", diag)?,
+        // Diagnostics need the ykpt decoder, which may not have been built.
+        Err(_) => writeln!(report, "decoding failed: {}", err)?,
+    }
+    debug!(path = %path.display(), "saved a trace which failed to decode");
+    Ok(path)
+}
+
+// The test's trace fails to parse, which needs the ykpt decoder.
+#[cfg(all(test, decoder_ykpt))]
+mod tests {
+    use crate::{
+        decode::{TraceDecoderBuilder, TraceDecoderKind},
+        Trace,
+    };
+    use std::fs::{self, File};
+
+    /// Check that a trace which fails to decode is saved, with a report, and can be loaded again.
+    #[test]
+    fn capture() {
+        let dir = std::env::temp_dir().join(format!("hwtracer-capture-{}", std::process::id()));
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .capture_failures(&dir)
+            .build()
+            .unwrap();
+        let mut bytes = b"\x02\x82".repeat(8);
+        bytes.extend_from_slice(b"\x02\x23");
+        let good = <dyn Trace>::from_bytes(bytes.clone());
+        assert!(dec.iter_events(&*good).all(|ev| ev.is_ok()));
+        assert!(!dir.exists());
+
+        bytes.extend_from_slice(b"\x02\xff");
+        let bad = <dyn Trace>::from_bytes(bytes.clone());
+        assert!(dec.iter_events(&*bad).any(|ev| ev.is_err()));
+        let mut files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let saved = <dyn Trace>::from_reader(&mut File::open(&files[0]).unwrap()).unwrap();
+        assert_eq!(saved.bytes(), bytes);
+        let report = fs::read_to_string(&files[1]).unwrap();
+        assert!(report.starts_with("decoding failed at offset 0x12"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
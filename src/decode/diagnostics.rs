@@ -0,0 +1,87 @@
+//! Diagnosing failures to decode a trace.
+
+use crate::{pt::PacketParserState, TraceId};
+use std::{collections::BTreeMap, fmt};
+
+/// The number of packets before a failure kept by [DecodeDiagnostics::recent_packets].
+pub(crate) const RECENT_PACKETS: usize = 16;
+/// The number of bytes either side of a failure kept by [DecodeDiagnostics::window].
+pub(crate) const WINDOW_LEN: usize = 32;
+
+/// What was going on in a trace where decoding it failed, for bug reports and triage. Made by
+/// [super::diagnose].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecodeDiagnostics {
+    /// The ID of the trace, if it has one, so that the failure can be matched up with what the
+    /// traced program was doing.
+    pub trace_id: Option<TraceId>,
+    /// The trace's provenance (see [crate::TraceMeta::provenance]).
+    pub provenance: BTreeMap<String, String>,
+    /// The offset (in bytes) into the trace at which decoding failed.
+    pub offset: usize,
+    /// The error that decoding failed with.
+    pub error: String,
+    /// The error that the packet parser failed with at `offset`, if it did (the failure may have
+    /// been found by a later stage of decoding, or by a different decoder).
+    pub parse_error: Option<String>,
+    /// The state that the packet parser was in at `offset`, or `None` if it didn't get that far
+    /// (e.g. because there is no PSB packet before it).
+    pub parser_state: Option<PacketParserState>,
+    /// The last packets successfully parsed before `offset` (at most 16), oldest first, as
+    /// `(offset, text)` pairs formatted as by [super::dump_packets].
+    pub recent_packets: Vec<(usize, String)>,
+    /// The offset of the first byte in `window`.
+    pub window_start: usize,
+    /// The trace bytes around `offset` (up to 32 either side).
+    pub window: Vec<u8>,
+    /// The offset of the first PSB packet after `offset`, where decoding could resynchronise and
+    /// carry on, or `None` if there isn't one.
+    pub next_psb: Option<usize>,
+}
+
+impl fmt::Display for DecodeDiagnostics {
+    /// Formats the diagnostics as a multi-line report, with the failing byte bracketed in the hex
+    /// dump.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "decoding failed at offset {:#x}: {}",
+            self.offset, self.error
+        )?;
+        if let Some(id) = self.trace_id {
+            writeln!(f, "trace ID: {}", id)?;
+        }
+        for (key, value) in &self.provenance {
+            writeln!(f, "provenance: {} = {}", key, value)?;
+        }
+        if let Some(e) = &self.parse_error {
+            writeln!(f, "packet parser error: {}", e)?;
+        }
+        match self.parser_state {
+            Some(s) => writeln!(f, "parser state: {:?}", s)?,
+            None => writeln!(f, "parser state: not reached")?,
+        }
+        writeln!(f, "last {} packets:", self.recent_packets.len())?;
+        for (off, pkt) in &self.recent_packets {
+            writeln!(f, "  {:016x}  {}", off, pkt)?;
+        }
+        writeln!(f, "bytes around the failure:")?;
+        for (i, row) in self.window.chunks(16).enumerate() {
+            let row_start = self.window_start + i * 16;
+            write!(f, "  {:016x} ", row_start)?;
+            for (j, b) in row.iter().enumerate() {
+                if row_start + j == self.offset {
+                    write!(f, "[{:02x}]", b)?;
+                } else {
+                    write!(f, " {:02x} ", b)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        match self.next_psb {
+            Some(off) => write!(f, "next PSB (resync point): {:#x}", off),
+            None => write!(f, "next PSB (resync point): none"),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! An entry point for fuzzing the packet parser and decoders.
+
+use super::{dump_packets, DecodeLimits, TraceDecoderBuilder, TraceDecoderKind};
+use crate::{
+    errors::HWTracerError,
+    pt::{PacketParser, TNTBuffer},
+    Trace,
+};
+use std::io;
+
+/// The most packets decoded from one input, so that no input takes too long to fuzz.
+const MAX_PACKETS: usize = 1 << 16;
+
+/// Parse and decode `data` as raw Intel PT trace data in all of the ways which don't need the
+/// traced code: packet by packet (with and without PADs skipped, in one go and resumed part way
+/// through), as a packet dump, and into blocks and events with the YkPT decoder.
+///
+/// This is for fuzzers (see the `fuzz` directory), and never panics, whatever `data` holds:
+/// malformed data only makes decoding fail. Returns the first error that the YkPT decoder
+/// reports, if any.
+pub fn fuzz_bytes(data: &[u8]) -> Result<(), HWTracerError> {
+    for skip_pads in [false, true] {
+        let mut parser = PacketParser::new(data).skip_pads(skip_pads);
+        let mut tnt = TNTBuffer::new();
+        for _ in 0..MAX_PACKETS {
+            parser.tnt_run(&mut tnt);
+            tnt.clear();
+            match parser.next() {
+                Some(Ok(pkt)) => {
+                    let _ = (pkt.to_string(), pkt.target_ip());
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // Parse the first half of the data as a partial trace, then resume from where it stopped.
+    let mid = data.len() / 2;
+    let mut parser = PacketParser::new(&data[..mid]).partial(true);
+    while let Some(Ok(_)) = parser.next() {}
+    let off = parser.offset();
+    let mut parser = PacketParser::resume(&data[off..], off, parser.checkpoint());
+    while let Some(Ok(_)) = parser.next() {}
+
+    let trace = <dyn Trace>::from_bytes(data.to_vec());
+    let _ = dump_packets(&*trace, &mut io::sink());
+    let dec = TraceDecoderBuilder::new()
+        .kind(TraceDecoderKind::YkPT)
+        .limits(DecodeLimits {
+            max_packets: Some(MAX_PACKETS),
+            ..DecodeLimits::default()
+        })
+        .build()?;
+    for blk in dec.iter_blocks(&*trace) {
+        blk?;
+    }
+    for ev in dec.iter_events(&*trace) {
+        ev?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fuzz_bytes;
+    use crate::pt::encode::{Encoder, IPCompression};
+
+    /// Check that malformed inputs only make decoding fail.
+    #[test]
+    fn malformed() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x1000, IPCompression::Full)
+            .tnt(&[true; 100]);
+        let good = enc.into_bytes();
+        let mut inputs = vec![
+            Vec::new(),
+            vec![0x02],
+            vec![0xff; 64],
+            // A CYC packet with more extended bytes than fit in a 64-bit counter.
+            [&good[..18], &[0x07], &[0x01; 64][..]].concat(),
+        ];
+        // The good trace cut short at every point.
+        inputs.extend((0..good.len()).map(|i| good[..i].to_vec()));
+        for input in inputs {
+            let _ = fuzz_bytes(&input);
+        }
+        assert!(fuzz_bytes(&good).is_ok());
+    }
+}
@@ -6,7 +6,6 @@
 #include <stdbool.h>
 #include <inttypes.h>
 #include <stdint.h>
-#include <link.h>
 #include <errno.h>
 #include <stdlib.h>
 #include <unistd.h>
@@ -14,29 +13,134 @@
 
 #include "hwtracer_private.h"
 
-#define VDSO_NAME "linux-vdso.so.1"
+/*
+ * The kinds of result reported by `hwt_ipt_next_branch()`.
+ *
+ * Must be kept in sync with the Rust side.
+ */
+enum hwt_ipt_branch_kind {
+    hwt_ipt_branch_eos,            // End of stream.
+    hwt_ipt_branch_taken,          // Conditional branch taken.
+    hwt_ipt_branch_not_taken,      // Conditional branch not taken.
+    hwt_ipt_branch_indirect,       // Indirect branch to a known address.
+    hwt_ipt_branch_suppressed,     // Indirect branch to an unknown address.
+};
+
+/*
+ * The kinds of event reported by `hwt_ipt_next_event()`, and the meaning of
+ * the `a` and `b` values accompanying them.
+ *
+ * Must be kept in sync with the Rust side.
+ */
+enum hwt_ipt_event_kind {
+    hwt_ipt_event_eos,          // End of stream.
+    hwt_ipt_event_enabled,      // Tracing enabled. `a`: resume address.
+    hwt_ipt_event_disabled,     // Tracing disabled. `a`: destination.
+    hwt_ipt_event_overflow,     // Internal buffer overflow.
+    hwt_ipt_event_exec_mode,    // Execution mode change. `a`: bitness.
+    hwt_ipt_event_cbr,          // Core:bus ratio change. `a`: the ratio.
+    hwt_ipt_event_ptwrite,      // PTWRITE. `a`: the payload.
+    hwt_ipt_event_async_branch, // Async transfer. `a`: source, `b`: dest.
+};
+
+/*
+ * The kinds of libipt error that hwtracer distinguishes between.
+ *
+ * Must be kept in sync with `LibIPTErrorKind` on the Rust side.
+ */
+enum hwt_ipt_error_kind {
+    hwt_ipt_err_other,
+    hwt_ipt_err_internal,
+    hwt_ipt_err_invalid,
+    hwt_ipt_err_nosync,
+    hwt_ipt_err_bad_opc,
+    hwt_ipt_err_bad_packet,
+    hwt_ipt_err_bad_context,
+    hwt_ipt_err_eos,
+    hwt_ipt_err_bad_query,
+    hwt_ipt_err_nomem,
+    hwt_ipt_err_bad_config,
+    hwt_ipt_err_noip,
+    hwt_ipt_err_ip_suppressed,
+    hwt_ipt_err_nomap,
+    hwt_ipt_err_bad_insn,
+    hwt_ipt_err_no_time,
+    hwt_ipt_err_no_cbr,
+    hwt_ipt_err_bad_image,
+    hwt_ipt_err_bad_lock,
+    hwt_ipt_err_not_supported,
+    hwt_ipt_err_retstack_empty,
+    hwt_ipt_err_bad_retcomp,
+    hwt_ipt_err_bad_status_update,
+    hwt_ipt_err_no_enable,
+    hwt_ipt_err_event_ignored,
+    hwt_ipt_err_bad_file,
+    hwt_ipt_err_bad_cpu,
+};
 
-struct load_self_image_args {
-    struct pt_image *image;
-    int vdso_fd;
-    char *vdso_filename;
-    struct hwt_cerror *err;
-    const char *current_exe;
-    struct  pt_image_section_cache *iscache;
+/*
+ * The identity of the (Intel) CPU that a trace was collected on.
+ *
+ * Must be kept in sync with `CpuId` on the Rust side.
+ */
+struct hwt_ipt_cpu {
+    uint16_t family;
+    uint8_t model;
+    uint8_t stepping;
 };
 
 // Private prototypes.
-static bool handle_events(struct pt_block_decoder *, int *, struct hwt_cerror *);
-static bool load_self_image(struct load_self_image_args *);
-static int load_self_image_cb(struct dl_phdr_info *, size_t, void *);
-static bool block_is_terminated(struct pt_block *);
+static bool init_config(struct pt_config *, void *, uint64_t,
+                        const struct hwt_ipt_cpu *, struct hwt_cerror *);
+static bool handle_events(struct pt_block_decoder *, int *, bool *,
+                          struct hwt_cerror *);
+static bool handle_insn_events(struct pt_insn_decoder *, int *, struct hwt_cerror *);
+static bool check_event(struct pt_event *, struct hwt_cerror *);
+static bool translate_event(struct pt_event *, int *, uint64_t *, uint64_t *,
+                            bool *);
+static bool handle_query_events(struct pt_query_decoder *, int *, struct hwt_cerror *);
+static bool block_is_terminated(struct pt_block *, bool *, struct hwt_cerror *);
 
 // Public prototypes.
-void *hwt_ipt_init_block_decoder(void *, uint64_t, int, char *, int *,
-                                 struct hwt_cerror *, const char *);
+void *hwt_ipt_init_block_decoder(void *, uint64_t, const struct hwt_ipt_cpu *,
+                                 struct pt_image *, int *,
+                                 struct hwt_cerror *);
 bool hwt_ipt_next_block(struct pt_block_decoder *, int *, uint64_t *,
-                        uint64_t *, struct hwt_cerror *);
+                        uint64_t *, bool *, bool *, struct hwt_cerror *);
 void hwt_ipt_free_block_decoder(struct pt_block_decoder *);
+bool hwt_ipt_get_offset(struct pt_block_decoder *, uint64_t *,
+                        struct hwt_cerror *);
+bool hwt_ipt_next_event(struct pt_block_decoder *, int *, int *, uint64_t *,
+                        uint64_t *, bool *, struct hwt_cerror *);
+bool hwt_ipt_add_mmap(struct pt_image *, const char *, uint64_t, uint64_t,
+                      uint64_t, struct hwt_cerror *);
+bool hwt_ipt_clear_image(struct pt_image *, struct hwt_cerror *);
+bool hwt_ipt_set_read_callback(struct pt_image *, read_memory_callback_t *,
+                               void *, struct hwt_cerror *);
+void *hwt_ipt_init_insn_decoder(void *, uint64_t, const struct hwt_ipt_cpu *,
+                                struct pt_image *, int *,
+                                struct hwt_cerror *);
+bool hwt_ipt_next_insn(struct pt_insn_decoder *, int *, uint64_t *, uint8_t *,
+                       uint8_t *, struct hwt_cerror *);
+bool hwt_ipt_get_insn_offset(struct pt_insn_decoder *, uint64_t *,
+                             struct hwt_cerror *);
+void hwt_ipt_free_insn_decoder(struct pt_insn_decoder *);
+void *hwt_ipt_init_query_decoder(void *, uint64_t, const struct hwt_ipt_cpu *,
+                                 int *, struct hwt_cerror *);
+bool hwt_ipt_next_branch(struct pt_query_decoder *, int *, int *, uint64_t *,
+                         struct hwt_cerror *);
+bool hwt_ipt_get_query_offset(struct pt_query_decoder *, uint64_t *,
+                              struct hwt_cerror *);
+void hwt_ipt_free_query_decoder(struct pt_query_decoder *);
+void *hwt_ipt_alloc_iscache(uint64_t, struct hwt_cerror *);
+void hwt_ipt_free_iscache(struct pt_image_section_cache *);
+struct pt_image *hwt_ipt_alloc_image(struct hwt_cerror *);
+void hwt_ipt_free_image(struct pt_image *);
+bool hwt_ipt_add_cached(struct pt_image *, struct pt_image_section_cache *,
+                        const char *, uint64_t, uint64_t, uint64_t, int *,
+                        struct hwt_cerror *);
+int hwt_ipt_read_cached(struct pt_image_section_cache *, int, uint8_t *,
+                        uint64_t, uint64_t);
 
 /*
  * Dump the VDSO code into the open file descriptor `fd`, starting at `vaddr`
@@ -61,18 +165,16 @@ hwt_ipt_dump_vdso(int fd, uint64_t vaddr, size_t len, struct hwt_cerror *err)
 }
 
 /*
- * Get ready to retrieve the basic blocks from a PT trace using the code of the
- * current process for control flow recovery.
+ * Get ready to retrieve the basic blocks from a PT trace, recovering control
+ * flow from the code in `image`.
  *
  * Accepts a raw buffer `buf` of length `len`.
  *
- * `vdso_fd` is an open file descriptor for the filename `vdso_filename`. This
- * is where the VDSO code will be written. libipt will read this file lazily,
- * so it's up to the caller to make sure this file lives long enough for their
- * purposes.
+ * `cpu` identifies the CPU that the trace was collected on, so that libipt can
+ * work around its errata. If NULL, the current CPU is assumed.
  *
- * `current_exe` is an absolute path to an on-disk executable from which to
- * load the main executable's (i.e. not a shared library's) code.
+ * `image` is owned by the caller and must outlive the decoder. Code may be
+ * added to it (e.g. from a read callback) while the decoder is in use.
  *
  * `*decoder_status` will be updated to reflect the status of the decoder after
  * it has been synchronised.
@@ -80,38 +182,20 @@ hwt_ipt_dump_vdso(int fd, uint64_t vaddr, size_t len, struct hwt_cerror *err)
  * Returns a pointer to a configured libipt block decoder or NULL on error.
  */
 void *
-hwt_ipt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_filename,
-                           int *decoder_status, struct hwt_cerror *err,
-                           const char *current_exe) {
+hwt_ipt_init_block_decoder(void *buf, uint64_t len, const struct hwt_ipt_cpu *cpu,
+                           struct pt_image *image, int *decoder_status,
+                           struct hwt_cerror *err) {
     bool failing = false;
 
     // Make a block decoder configuration.
     struct pt_config config;
-    memset(&config, 0, sizeof(config));
-    config.size = sizeof(config);
-    config.begin = buf;
-    config.end = buf + len;
-    config.flags.variant.block.end_on_call = 1;
-    config.flags.variant.block.end_on_jump = 1;
-
-    // Decode for the current CPU.
     struct pt_block_decoder *decoder = NULL;
-    int rv = pt_cpu_read(&config.cpu);
-    if (rv != pte_ok) {
-        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+    if (!init_config(&config, buf, len, cpu, err)) {
         failing = true;
         goto clean;
     }
-
-    // Work around CPU bugs.
-    if (config.cpu.vendor) {
-        rv = pt_cpu_errata(&config.errata, &config.cpu);
-        if (rv < 0) {
-            hwt_set_cerr(err, hwt_cerror_ipt, -rv);
-            failing = true;
-            goto clean;
-        }
-    }
+    config.flags.variant.block.end_on_call = 1;
+    config.flags.variant.block.end_on_jump = 1;
 
     // Instantiate a decoder.
     decoder = pt_blk_alloc_decoder(&config);
@@ -126,6 +210,7 @@ hwt_ipt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_file
     if (*decoder_status == -pte_eos) {
         // There were no blocks in the stream. The user will find out on next
         // call to hwt_ipt_next_block().
+        *decoder_status = pts_eos;
         goto clean;
     } else if (*decoder_status < 0) {
         hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
@@ -133,30 +218,7 @@ hwt_ipt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_file
         goto clean;
     }
 
-    // Build and load a memory image from which to recover control flow.
-    struct pt_image *image = pt_image_alloc(NULL);
-    if (image == NULL) {
-        hwt_set_cerr(err, hwt_cerror_unknown, 0);
-        failing = true;
-        goto clean;
-    }
-
-    // Use image cache to speed up decoding.
-    struct pt_image_section_cache *iscache = pt_iscache_alloc(NULL);
-    if(iscache == NULL) {
-        hwt_set_cerr(err, hwt_cerror_unknown, 0);
-        failing = true;
-        goto clean;
-    }
-
-    struct load_self_image_args load_args = {image, vdso_fd, vdso_filename,
-                                             err, current_exe, iscache};
-    if (!load_self_image(&load_args)) {
-        failing = true;
-        goto clean;
-    }
-
-    rv = pt_blk_set_image(decoder, image);
+    int rv = pt_blk_set_image(decoder, image);
     if (rv < 0) {
         hwt_set_cerr(err, hwt_cerror_ipt, -rv);
         failing = true;
@@ -178,6 +240,11 @@ clean:
  * If first instruction address is 0, this indicates that the end of
  * the instruction stream has been reached.
  *
+ * `*speculative` is set to true if (any of) the block was executed
+ * speculatively, inside a TSX transaction. `*aborted` is set to true if a
+ * transaction was aborted before the block: every speculative block reported
+ * since the last non-speculative one was then never committed.
+ *
  * `*decoder_status` will be updated with the new decoder status after the operation.
  *
  * Returns true on success or false otherwise. Upon failure, `*first_instr` and
@@ -185,9 +252,12 @@ clean:
  */
 bool
 hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
-        uint64_t *first_instr, uint64_t *last_instr, struct hwt_cerror *err) {
+        uint64_t *first_instr, uint64_t *last_instr, bool *speculative,
+        bool *aborted, struct hwt_cerror *err) {
+    *speculative = false;
+    *aborted = false;
     // If there are events pending, look at those first.
-    if (handle_events(decoder, decoder_status, err) != true) {
+    if (handle_events(decoder, decoder_status, aborted, err) != true) {
         // handle_events will have already called hwt_set_cerr().
         return false;
     } else if (*decoder_status & pts_eos) {
@@ -196,7 +266,8 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
         return true;
     }
     if ((*decoder_status != 0) && (*decoder_status != pts_ip_suppressed)) {
-        panic("Unexpected decoder status: %d", *decoder_status);
+        hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_unexpected_status);
+        return false;
     }
 
     // The libipt block decoder may return a partial block (it could have been
@@ -204,14 +275,23 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
     // record (and eventually return) the address of the first block we see,
     // then keep decoding more blocks until we see a properly terminated block.
     struct pt_block block;
-    block.iclass = ptic_other;
     bool first_block = true;
     *last_instr = 0;
-    while (!block_is_terminated(&block)) {
-        if (handle_events(decoder, decoder_status, err) != true) {
+    bool terminated = false;
+    while (!terminated) {
+        bool aborted_here = false;
+        if (handle_events(decoder, decoder_status, &aborted_here, err) != true) {
             // handle_events will have already called hwt_set_cerr().
             return false;
-        } else if (*decoder_status & pts_eos) {
+        }
+        if (aborted_here) {
+            // The part of the block seen so far was executed in the aborted
+            // transaction, so is thrown away with the rest of it.
+            *aborted = true;
+            first_block = true;
+            *speculative = false;
+        }
+        if (*decoder_status & pts_eos) {
             // End of stream.
             *first_instr = 0;
             return true;
@@ -219,7 +299,8 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
         // It's possible at this point that we get notified of an event in the
         // stream. This will be handled in the next call to `hwt_ipt_next_block`.
         if ((*decoder_status != 0) && (*decoder_status != pts_event_pending)) {
-            panic("Unexpected decoder status: %d", *decoder_status);
+            hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_unexpected_status);
+            return false;
         }
 
         *decoder_status = pt_blk_next(decoder, &block, sizeof(block));
@@ -239,12 +320,14 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
         // XXX A truncated block occurs when a block straddles a section boundary.
         // In this case we may need some extra logic, but this should be rare.
         if (block.truncated != 0) {
-            panic("Truncated blocks are not implemented");
+            hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_truncated_block);
+            return false;
         }
 
         // A block should have at least one instruction.
         if (block.ninsn == 0) {
-            panic("Detected a block with 0 instructions");
+            hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_empty_block);
+            return false;
         }
 
         if (first_block) {
@@ -253,7 +336,12 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
             *first_instr = block.ip;
             first_block = false;
         }
+        *speculative |= block.speculative;
 
+        if (!block_is_terminated(&block, &terminated, err)) {
+            // block_is_terminated will have already called hwt_set_cerr().
+            return false;
+        }
     }
     // The address of the block's last instruction.
     *last_instr = block.end_ip;
@@ -261,17 +349,92 @@ hwt_ipt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
     return true;
 }
 
+/*
+ * Updates `*offset` with the decoder's current offset (in bytes) into the
+ * trace buffer.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_get_offset(struct pt_block_decoder *decoder, uint64_t *offset,
+                   struct hwt_cerror *err) {
+    int rv = pt_blk_get_offset(decoder, offset);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Maps `len` bytes of the file `filename`, starting at file offset `offset`,
+ * at the virtual address `vaddr` in `image`. Any existing sections that
+ * overlap are replaced.
+ *
+ * This is used to keep a decoder's image up to date with sideband
+ * information.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_add_mmap(struct pt_image *image, const char *filename,
+                 uint64_t offset, uint64_t len, uint64_t vaddr,
+                 struct hwt_cerror *err) {
+    int rv = pt_image_add_file(image, filename, offset, len, NULL, vaddr);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Removes all sections from `image`. This is used when the traced program's
+ * address space is replaced (e.g. by execve(2)).
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_clear_image(struct pt_image *image, struct hwt_cerror *err) {
+    // A NULL address space identifier matches all sections.
+    int rv = pt_image_remove_by_asid(image, NULL);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Installs `callback` as the means by which `image` reads code at addresses
+ * that aren't covered by any of its sections (e.g. JITted code living in
+ * anonymous mappings). `context` is passed to each invocation of `callback`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_set_read_callback(struct pt_image *image,
+                          read_memory_callback_t *callback, void *context,
+                          struct hwt_cerror *err) {
+    int rv = pt_image_set_callback(image, callback, context);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
 /*
  * Given a decoder and pointer to the decoder status, handle any pending events in
- * the PT packet stream and update the decoder status.
+ * the PT packet stream and update the decoder status. `*aborted` is set to true
+ * if a TSX transaction was aborted (and is otherwise left alone).
  *
  * Returns true on success, or false if an error occurred (e.g.) trace buffer
  * overflow.
  */
 static bool
-handle_events(struct pt_block_decoder *decoder, int *decoder_status, struct hwt_cerror *err) {
-    bool ret = true;
-
+handle_events(struct pt_block_decoder *decoder, int *decoder_status, bool *aborted,
+              struct hwt_cerror *err) {
     while(*decoder_status & pts_event_pending) {
         struct pt_event event;
         *decoder_status = pt_blk_event(decoder, &event, sizeof(event));
@@ -279,90 +442,227 @@ handle_events(struct pt_block_decoder *decoder, int *decoder_status, struct hwt_
             hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
             return false;
         }
+        if (!check_event(&event, err)) {
+            // check_event will have already called hwt_set_cerr().
+            return false;
+        }
+        if ((event.type == ptev_tsx) && event.variant.tsx.aborted) {
+            *aborted = true;
+        }
+    }
+    return true;
+}
+
+/*
+ * Advances the block decoder `decoder` to the next event of interest to
+ * hwtracer, which is described by `*kind`, `*a`, `*b` and `*suppressed` (see
+ * `enum hwt_ipt_event_kind`). `*suppressed` is true if the destination address
+ * of a disabled or asynchronous branch event is unknown.
+ *
+ * Unlike hwt_ipt_next_block(), overflows are reported as events rather than
+ * errors.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_next_event(struct pt_block_decoder *decoder, int *decoder_status,
+                   int *kind, uint64_t *a, uint64_t *b, bool *suppressed,
+                   struct hwt_cerror *err) {
+    while (true) {
+        if (*decoder_status == -pte_eos) {
+            // The stream ended while synchronising or fetching a block.
+            *kind = hwt_ipt_event_eos;
+            return true;
+        } else if (*decoder_status < 0) {
+            hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+            return false;
+        }
 
-        switch (event.type) {
-            // Tracing enabled/disabled packets (TIP.PGE/TIP.PGD).
-            // These tell us the chip has enabled or disabled tracing. We
-            // expect to see an enabled packet at the start of a trace as part
-            // of a PSB+ sequence, and a disabled packet at the end of our
-            // trace. Additional enable/disable packets may appear in the
-            // middle of the trace in the event of e.g. a context switch.
-            case ptev_enabled:
-            case ptev_disabled:
-            case ptev_async_disabled:
-                break;
-            // Trace overflow packet (OVF).
-            // This happens when the head of the ring buffer being used to
-            // store trace packets catches up with the tail. In such a
-            // scenario, packets were probably lost.
-            case ptev_overflow:
-                // We translate the overflow event to an overflow error for
-                // Rust to detect later.
-                hwt_set_cerr(err, hwt_cerror_ipt, pte_overflow);
-                ret = false;
-                break;
-            // Execution mode packet (MODE.Exec).
-            // We expect one of these at the start of our trace and every time
-            // the CPU changes between 16/32/64-bit execution modes.
-            case ptev_exec_mode:
-                break;
-            // Transaction mode packet (MODE.TSX).
-            // This is Intel TSX hardware transactional memory event notifying
-            // us of the start, commit or abort of a transaction. These can
-            // appear in the PSB+ sequence at the start of a trace.
-            case ptev_tsx:
-                break;
-            // Execution stop packet (EXSTOP).
-            // Indicates that the core has gone to sleep, e.g. if a deep
-            // C-state is entered. The core may wake up later.
-            case ptev_exstop:
-                break;
-            // MWAIT packet.
-            // Intel chips have hardware support for concurrency primitives in
-            // the form of `MONITOR`/`MWAIT`. This packet indicates that a
-            // `MWAIT` instruction woke up a hardware thread.
-            case ptev_mwait:
-                break;
-            // Power entry packet (PWRE).
-            // Indicates the entry of a C-state region.
-            case ptev_pwre:
-                break;
-            // Power exit packet (PWRX).
-            // Indicates the entry of a C-state region, thus returning the core
-            // back to C0.
-            case ptev_pwrx:
-                break;
-            // Core Bus Ratio (CBR) packet.
-            // We expect one of these at the start of the trace and every time
-            // the core clock speed changes.
-            case ptev_cbr:
-                break;
-            // Maintenance packet.
-            // This is a model-specific packet which we are explicitly told to
-            // ignore in the Intel manual.
-            case ptev_mnt:
-                break;
-            // We conservatively crash when receiving any other kind of packet.
-            // This includes packets which we don't expect to see because we
-            // didn't ask them to be emitted, e.g. TSC, STOP and CYC packets.
-            // We print what packet crashed us before dying to aid debugging.
-            default:
-                panic("Unhandled packet event type %d", event.type);
+        if (*decoder_status & pts_event_pending) {
+            struct pt_event event;
+            *decoder_status = pt_blk_event(decoder, &event, sizeof(event));
+            if (*decoder_status < 0) {
+                hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+                return false;
+            }
+            if (translate_event(&event, kind, a, b, suppressed)) {
+                return true;
+            }
+            continue;
         }
+
+        if (*decoder_status & pts_eos) {
+            *kind = hwt_ipt_event_eos;
+            return true;
+        }
+
+        // Events are reported between blocks, so skip over the next block.
+        struct pt_block block;
+        *decoder_status = pt_blk_next(decoder, &block, sizeof(block));
     }
-    return ret;
 }
 
 /*
- * Decides if a block is terminated by a control flow dispatch.
+ * Translates the libipt event `event` into hwtracer's terms. See
+ * hwt_ipt_next_event() for the meaning of the other arguments.
+ *
+ * Returns false if the event is of no interest to hwtracer.
+ */
+static bool
+translate_event(struct pt_event *event, int *kind, uint64_t *a, uint64_t *b,
+                bool *suppressed) {
+    *suppressed = event->ip_suppressed;
+    switch (event->type) {
+        case ptev_enabled:
+            if (event->ip_suppressed) {
+                // There's nowhere to say that tracing resumed.
+                return false;
+            }
+            *kind = hwt_ipt_event_enabled;
+            *a = event->variant.enabled.ip;
+            return true;
+        case ptev_disabled:
+            *kind = hwt_ipt_event_disabled;
+            *a = event->variant.disabled.ip;
+            return true;
+        case ptev_async_disabled:
+            *kind = hwt_ipt_event_disabled;
+            *a = event->variant.async_disabled.ip;
+            return true;
+        case ptev_overflow:
+            *kind = hwt_ipt_event_overflow;
+            return true;
+        case ptev_exec_mode:
+            switch (event->variant.exec_mode.mode) {
+                case ptem_16bit:
+                    *a = 16;
+                    break;
+                case ptem_32bit:
+                    *a = 32;
+                    break;
+                case ptem_64bit:
+                    *a = 64;
+                    break;
+                default:
+                    return false;
+            }
+            *kind = hwt_ipt_event_exec_mode;
+            return true;
+        case ptev_cbr:
+            *kind = hwt_ipt_event_cbr;
+            *a = event->variant.cbr.ratio;
+            return true;
+        case ptev_ptwrite:
+            *kind = hwt_ipt_event_ptwrite;
+            *a = event->variant.ptwrite.payload;
+            return true;
+        case ptev_async_branch:
+            *kind = hwt_ipt_event_async_branch;
+            *a = event->variant.async_branch.from;
+            *b = event->variant.async_branch.to;
+            return true;
+        default:
+            return false;
+    }
+}
+
+/*
+ * Given an event reported by a block or instruction flow decoder, decide if
+ * decoding can continue.
+ *
+ * Returns true if so, or false if an error occurred (e.g.) trace buffer
+ * overflow.
+ */
+static bool
+check_event(struct pt_event *event, struct hwt_cerror *err) {
+    switch (event->type) {
+        // Tracing enabled/disabled packets (TIP.PGE/TIP.PGD).
+        // These tell us the chip has enabled or disabled tracing. We
+        // expect to see an enabled packet at the start of a trace as part
+        // of a PSB+ sequence, and a disabled packet at the end of our
+        // trace. Additional enable/disable packets may appear in the
+        // middle of the trace in the event of e.g. a context switch.
+        case ptev_enabled:
+        case ptev_disabled:
+        case ptev_async_disabled:
+            break;
+        // Trace overflow packet (OVF).
+        // This happens when the head of the ring buffer being used to
+        // store trace packets catches up with the tail. In such a
+        // scenario, packets were probably lost.
+        case ptev_overflow:
+            // We translate the overflow event to an overflow error for
+            // Rust to detect later.
+            hwt_set_cerr(err, hwt_cerror_ipt, pte_overflow);
+            return false;
+        // Execution mode packet (MODE.Exec).
+        // We expect one of these at the start of our trace and every time
+        // the CPU changes between 16/32/64-bit execution modes.
+        case ptev_exec_mode:
+            break;
+        // Transaction mode packet (MODE.TSX).
+        // This is Intel TSX hardware transactional memory event notifying
+        // us of the start, commit or abort of a transaction. These can
+        // appear in the PSB+ sequence at the start of a trace.
+        case ptev_tsx:
+            break;
+        // Asynchronous branch (FUP followed by TIP).
+        // Control went somewhere other than where the code says, e.g. to
+        // the fallback handler of an aborted transaction. The decoder
+        // follows the branch itself.
+        case ptev_async_branch:
+            break;
+        // Execution stop packet (EXSTOP).
+        // Indicates that the core has gone to sleep, e.g. if a deep
+        // C-state is entered. The core may wake up later.
+        case ptev_exstop:
+            break;
+        // MWAIT packet.
+        // Intel chips have hardware support for concurrency primitives in
+        // the form of `MONITOR`/`MWAIT`. This packet indicates that a
+        // `MWAIT` instruction woke up a hardware thread.
+        case ptev_mwait:
+            break;
+        // Power entry packet (PWRE).
+        // Indicates the entry of a C-state region.
+        case ptev_pwre:
+            break;
+        // Power exit packet (PWRX).
+        // Indicates the entry of a C-state region, thus returning the core
+        // back to C0.
+        case ptev_pwrx:
+            break;
+        // Core Bus Ratio (CBR) packet.
+        // We expect one of these at the start of the trace and every time
+        // the core clock speed changes.
+        case ptev_cbr:
+            break;
+        // Maintenance packet.
+        // This is a model-specific packet which we are explicitly told to
+        // ignore in the Intel manual.
+        case ptev_mnt:
+            break;
+        // We conservatively fail when receiving any other kind of packet.
+        // This includes packets which we don't expect to see because we
+        // didn't ask them to be emitted, e.g. TSC, STOP and CYC packets.
+        default:
+            hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_unhandled_event);
+            return false;
+    }
+    return true;
+}
+
+/*
+ * Decides if a block is terminated by a control flow dispatch, storing the
+ * answer in `*terminated`.
  *
  * This is used to decide if libipt gave us a partial block or not.
+ *
+ * Returns true on success or false if the block's instruction class is unknown.
  */
 static bool
-block_is_terminated(struct pt_block *blk)
+block_is_terminated(struct pt_block *blk, bool *terminated, struct hwt_cerror *err)
 {
-    bool ret;
-
     switch (blk->iclass) {
         case ptic_call:
         case ptic_return:
@@ -371,111 +671,356 @@ block_is_terminated(struct pt_block *blk)
         case ptic_far_call:
         case ptic_far_return:
         case ptic_far_jump:
-            ret = true;
+            *terminated = true;
             break;
         case ptic_other:
         case ptic_ptwrite:
-            ret = false;
+            *terminated = false;
             break;
         case ptic_indirect:
-            ret = true;
+            *terminated = true;
             break;
         default:
-            panic("Unexpected instruction class: %d", blk->iclass);
+            hwt_set_cerr(err, hwt_cerror_malformed, hwt_malformed_unknown_iclass);
+            return false;
     }
-    return ret;
+    return true;
 }
 
 /*
- * Loads the libipt image `image` with the code of the current process.
+ * Initialises `*config` for decoding the raw trace buffer `buf` of length
+ * `len`, which was collected on the CPU `cpu`. If `cpu` is NULL, the trace is
+ * assumed to have been collected on the current CPU.
  *
  * Returns true on success or false otherwise.
  */
 static bool
-load_self_image(struct load_self_image_args *args)
+init_config(struct pt_config *config, void *buf, uint64_t len,
+            const struct hwt_ipt_cpu *cpu, struct hwt_cerror *err)
 {
-    if (dl_iterate_phdr(load_self_image_cb, args) != 0) {
+    memset(config, 0, sizeof(*config));
+    config->size = sizeof(*config);
+    config->begin = buf;
+    config->end = buf + len;
+
+    int rv;
+    if (cpu != NULL) {
+        // Decode for the CPU that collected the trace.
+        config->cpu.vendor = pcv_intel;
+        config->cpu.family = cpu->family;
+        config->cpu.model = cpu->model;
+        config->cpu.stepping = cpu->stepping;
+    } else {
+        rv = pt_cpu_read(&config->cpu);
+        if (rv != pte_ok) {
+            hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+            return false;
+        }
+    }
+
+    // Work around CPU bugs.
+    if (config->cpu.vendor) {
+        rv = pt_cpu_errata(&config->errata, &config->cpu);
+        if (rv < 0) {
+            hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+            return false;
+        }
+    }
+
+    return true;
+}
+
+/*
+ * Get ready to retrieve individual instructions from a PT trace, recovering
+ * control flow from the code in `image`. The arguments are as for
+ * hwt_ipt_init_block_decoder().
+ *
+ * Returns a pointer to a configured libipt instruction flow decoder or NULL on
+ * error.
+ */
+void *
+hwt_ipt_init_insn_decoder(void *buf, uint64_t len, const struct hwt_ipt_cpu *cpu,
+                          struct pt_image *image, int *decoder_status,
+                          struct hwt_cerror *err) {
+    struct pt_config config;
+    if (!init_config(&config, buf, len, cpu, err)) {
+        return NULL;
+    }
+
+    struct pt_insn_decoder *decoder = pt_insn_alloc_decoder(&config);
+    if (decoder == NULL) {
+        hwt_set_cerr(err, hwt_cerror_unknown, 0);
+        return NULL;
+    }
+
+    // Sync the decoder.
+    *decoder_status = pt_insn_sync_forward(decoder);
+    if (*decoder_status == -pte_eos) {
+        // There were no instructions in the stream. The user will find out on
+        // the next call to hwt_ipt_next_insn().
+        *decoder_status = pts_eos;
+        return decoder;
+    } else if (*decoder_status < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+        pt_insn_free_decoder(decoder);
+        return NULL;
+    }
+
+    int rv = pt_insn_set_image(decoder, image);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        pt_insn_free_decoder(decoder);
+        return NULL;
+    }
+    return decoder;
+}
+
+/*
+ * Fetches the next instruction from the instruction flow decoder `decoder`,
+ * storing its address in `*ip`, its size in `*size` and its encoding in
+ * `raw`, which must have space for at least 15 bytes.
+ *
+ * If the instruction address is 0, this indicates that the end of the
+ * instruction stream has been reached.
+ *
+ * `*decoder_status` will be updated with the new decoder status after the
+ * operation.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_next_insn(struct pt_insn_decoder *decoder, int *decoder_status,
+                  uint64_t *ip, uint8_t *raw, uint8_t *size,
+                  struct hwt_cerror *err) {
+    // If there are events pending, look at those first.
+    if (!handle_insn_events(decoder, decoder_status, err)) {
+        // handle_insn_events will have already called hwt_set_cerr().
         return false;
+    } else if (*decoder_status & pts_eos) {
+        // End of stream.
+        *ip = 0;
+        return true;
     }
 
-    if (fsync(args->vdso_fd) == -1) {
-        hwt_set_cerr(args->err, hwt_cerror_errno, errno);
+    struct pt_insn insn;
+    *decoder_status = pt_insn_next(decoder, &insn, sizeof(insn));
+    if (*decoder_status == -pte_eos) {
+        // End of stream is flagged as an error in the case of pt_insn_next().
+        *ip = 0;
+        return true;
+    } else if (*decoder_status < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
         return false;
     }
 
+    // Other +ve decoder status codes (e.g. pending events) are dealt with on
+    // the next call.
+    *ip = insn.ip;
+    *size = insn.size;
+    memcpy(raw, insn.raw, insn.size);
     return true;
 }
 
 /*
- * The callback for `load_self_image()`, called once for each program header.
+ * Updates `*offset` with the instruction flow decoder's current offset (in
+ * bytes) into the trace buffer.
  *
- * Returns 1 to stop iterating, and in our case to indicate an error. Returns 0
- * on success and to continue iterating. See dl_iterate_phdr(3) for information
- * on this interface.
+ * Returns true on success or false otherwise.
  */
-static int
-load_self_image_cb(struct dl_phdr_info *info, size_t size, void *data)
-{
-    ElfW(Phdr) phdr;
-    ElfW(Half) i;
-
-    (void) size; // Unused. Silence warning.
-    struct load_self_image_args *args = data;
-    struct hwt_cerror *err = args->err;
-
-    const char *filename = info->dlpi_name;
-    bool vdso = false;
-    if (!*filename) {
-        // On Linux, an empty name means that it is the executable itself.
-        filename = args->current_exe;
-    } else {
-        vdso = strcmp(filename, VDSO_NAME) == 0;
+bool
+hwt_ipt_get_insn_offset(struct pt_insn_decoder *decoder, uint64_t *offset,
+                        struct hwt_cerror *err) {
+    int rv = pt_insn_get_offset(decoder, offset);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
     }
+    return true;
+}
 
-    for (i = 0; i < info->dlpi_phnum; i++) {
-        phdr = info->dlpi_phdr[i];
-        // Ensure we only use loadable and executable sections.
-        if ((phdr.p_type != PT_LOAD) || (!(phdr.p_flags & PF_X))) {
+/*
+ * Given an instruction flow decoder and pointer to the decoder status, handle
+ * any pending events in the PT packet stream and update the decoder status.
+ *
+ * Returns true on success, or false if an error occurred (e.g.) trace buffer
+ * overflow.
+ */
+static bool
+handle_insn_events(struct pt_insn_decoder *decoder, int *decoder_status,
+                   struct hwt_cerror *err) {
+    while (*decoder_status & pts_event_pending) {
+        struct pt_event event;
+        *decoder_status = pt_insn_event(decoder, &event, sizeof(event));
+        if (*decoder_status < 0) {
+            hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+            return false;
+        }
+        if (!check_event(&event, err)) {
+            // check_event will have already called hwt_set_cerr().
+            return false;
+        }
+    }
+    return true;
+}
+
+/*
+ * Free an instruction flow decoder.
+ */
+void
+hwt_ipt_free_insn_decoder(struct pt_insn_decoder *decoder) {
+    if (decoder != NULL) {
+        pt_insn_free_decoder(decoder);
+    }
+}
+
+/*
+ * Get ready to retrieve raw branch outcomes from a PT trace using libipt's
+ * query decoder. No code image is required, as no disassembly takes place.
+ *
+ * Accepts a raw buffer `buf` of length `len`, collected on the CPU `cpu` (see
+ * hwt_ipt_init_block_decoder()).
+ *
+ * `*decoder_status` will be updated to reflect the status of the decoder after
+ * it has been synchronised.
+ *
+ * Returns a pointer to a configured libipt query decoder or NULL on error.
+ */
+void *
+hwt_ipt_init_query_decoder(void *buf, uint64_t len, const struct hwt_ipt_cpu *cpu,
+                           int *decoder_status, struct hwt_cerror *err) {
+    struct pt_config config;
+    if (!init_config(&config, buf, len, cpu, err)) {
+        return NULL;
+    }
+
+    struct pt_query_decoder *decoder = pt_qry_alloc_decoder(&config);
+    if (decoder == NULL) {
+        hwt_set_cerr(err, hwt_cerror_unknown, 0);
+        return NULL;
+    }
+
+    // Sync the decoder. We don't need the IP that it synchronised at.
+    uint64_t ip;
+    *decoder_status = pt_qry_sync_forward(decoder, &ip);
+    if (*decoder_status == -pte_eos) {
+        // There's nothing in the stream. The user will find out on the next
+        // call to hwt_ipt_next_branch().
+        *decoder_status = pts_eos;
+    } else if (*decoder_status < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+        pt_qry_free_decoder(decoder);
+        return NULL;
+    }
+    return decoder;
+}
+
+/*
+ * Fetches the next branch outcome from the query decoder `decoder`.
+ *
+ * `*kind` is set to a `enum hwt_ipt_branch_kind`. For indirect branches, the
+ * target address is stored in `*ip`.
+ *
+ * `*decoder_status` will be updated with the new decoder status after the
+ * operation.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_next_branch(struct pt_query_decoder *decoder, int *decoder_status,
+                    int *kind, uint64_t *ip, struct hwt_cerror *err) {
+    for (;;) {
+        if (*decoder_status & pts_eos) {
+            *kind = hwt_ipt_branch_eos;
+            return true;
+        }
+        if (*decoder_status & pts_event_pending) {
+            if (!handle_query_events(decoder, decoder_status, err)) {
+                // handle_query_events will have already called hwt_set_cerr().
+                return false;
+            }
             continue;
         }
 
-        uint64_t vaddr = info->dlpi_addr + phdr.p_vaddr;
-        uint64_t offset;
-
-        // Load the code into the libipt image.
-        //
-        // The VDSO is special. It doesn't exist on-disk as a regular library,
-        // but rather it is a set of pages shared with the kernel.
-        //
-        // XXX Since libipt currently requires us to load from a file, we have
-        // to dump the VDSO to disk and have libipt load it back in.
-        //
-        // Discussion on adding libipt support for loading from memory here:
-        // https://github.com/01org/processor-trace/issues/37
-        if (vdso) {
-            int rv = hwt_ipt_dump_vdso(args->vdso_fd, vaddr, phdr.p_filesz, err);
-            if (!rv) {
-                return 1;
+        // We don't know the layout of the code, so we don't know if the next
+        // branch is conditional or indirect. Ask for a conditional branch
+        // first, and if that's not what's next, ask for an indirect branch.
+        int taken;
+        int rv = pt_qry_cond_branch(decoder, &taken);
+        if (rv >= 0) {
+            *decoder_status = rv;
+            *kind = taken ? hwt_ipt_branch_taken : hwt_ipt_branch_not_taken;
+            return true;
+        } else if (rv == -pte_bad_query) {
+            rv = pt_qry_indirect_branch(decoder, ip);
+            if (rv >= 0) {
+                *decoder_status = rv;
+                *kind = (rv & pts_ip_suppressed) ?
+                    hwt_ipt_branch_suppressed : hwt_ipt_branch_indirect;
+                return true;
             }
-            filename = args->vdso_filename;
-            offset = 0;
-        } else {
-            offset = phdr.p_offset;
         }
 
-        int isid = pt_iscache_add_file(args->iscache, filename, offset, phdr.p_filesz, vaddr);
-        if (isid < 0) {
-            return 1;
+        if (rv == -pte_eos) {
+            *kind = hwt_ipt_branch_eos;
+            return true;
         }
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+}
 
-        int rv = pt_image_add_cached(args->image, args->iscache, isid, NULL);
-        if (rv < 0) {
-            hwt_set_cerr(err, hwt_cerror_ipt, -rv);
-            return 1;
+/*
+ * Updates `*offset` with the query decoder's current offset (in bytes) into
+ * the trace buffer.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_get_query_offset(struct pt_query_decoder *decoder, uint64_t *offset,
+                         struct hwt_cerror *err) {
+    int rv = pt_qry_get_offset(decoder, offset);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Given a query decoder and pointer to the decoder status, consume any pending
+ * events in the PT packet stream and update the decoder status.
+ *
+ * Branch-only decoding has no use for events, so they are discarded, with
+ * the exception of overflows, which mean that branch outcomes were lost.
+ *
+ * Returns true on success, or false if an error occurred.
+ */
+static bool
+handle_query_events(struct pt_query_decoder *decoder, int *decoder_status,
+                    struct hwt_cerror *err) {
+    while (*decoder_status & pts_event_pending) {
+        struct pt_event event;
+        *decoder_status = pt_qry_event(decoder, &event, sizeof(event));
+        if (*decoder_status < 0) {
+            hwt_set_cerr(err, hwt_cerror_ipt, -*decoder_status);
+            return false;
+        }
+        if (event.type == ptev_overflow) {
+            hwt_set_cerr(err, hwt_cerror_ipt, pte_overflow);
+            return false;
         }
     }
+    return true;
+}
 
-    return 0;
+/*
+ * Free a query decoder.
+ */
+void
+hwt_ipt_free_query_decoder(struct pt_query_decoder *decoder) {
+    if (decoder != NULL) {
+        pt_qry_free_decoder(decoder);
+    }
 }
 
 /*
@@ -488,6 +1033,38 @@ hwt_ipt_free_block_decoder(struct pt_block_decoder *decoder) {
     }
 }
 
+/*
+ * Allocates an image section cache which keeps up to `limit` bytes of
+ * sections mapped. A limit of zero means that sections are unmapped as soon
+ * as they are no longer in use.
+ *
+ * Returns the cache on success or NULL otherwise.
+ */
+void *
+hwt_ipt_alloc_iscache(uint64_t limit, struct hwt_cerror *err) {
+    struct pt_image_section_cache *iscache = pt_iscache_alloc(NULL);
+    if (iscache == NULL) {
+        hwt_set_cerr(err, hwt_cerror_unknown, 0);
+        return NULL;
+    }
+
+    int rv = pt_iscache_set_limit(iscache, limit);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        pt_iscache_free(iscache);
+        return NULL;
+    }
+    return iscache;
+}
+
+/*
+ * Frees an image section cache allocated by hwt_ipt_alloc_iscache().
+ */
+void
+hwt_ipt_free_iscache(struct pt_image_section_cache *iscache) {
+    pt_iscache_free(iscache);
+}
+
 /*
  * Indicates if the specified error code is the overflow code.
  * This exists to avoid copying (and keeping in sync) the ipt error code on the
@@ -497,3 +1074,131 @@ bool
 hwt_ipt_is_overflow_err(int err) {
     return err == pte_overflow;
 }
+
+/*
+ * Classifies the (positive) libipt error code `err`.
+ *
+ * Like `hwt_ipt_is_overflow_err()`, this saves the Rust side from having to
+ * know libipt's numeric error codes.
+ */
+int
+hwt_ipt_classify_err(int err) {
+    switch (err) {
+        case pte_internal:
+            return hwt_ipt_err_internal;
+        case pte_invalid:
+            return hwt_ipt_err_invalid;
+        case pte_nosync:
+            return hwt_ipt_err_nosync;
+        case pte_bad_opc:
+            return hwt_ipt_err_bad_opc;
+        case pte_bad_packet:
+            return hwt_ipt_err_bad_packet;
+        case pte_bad_context:
+            return hwt_ipt_err_bad_context;
+        case pte_eos:
+            return hwt_ipt_err_eos;
+        case pte_bad_query:
+            return hwt_ipt_err_bad_query;
+        case pte_nomem:
+            return hwt_ipt_err_nomem;
+        case pte_bad_config:
+            return hwt_ipt_err_bad_config;
+        case pte_noip:
+            return hwt_ipt_err_noip;
+        case pte_ip_suppressed:
+            return hwt_ipt_err_ip_suppressed;
+        case pte_nomap:
+            return hwt_ipt_err_nomap;
+        case pte_bad_insn:
+            return hwt_ipt_err_bad_insn;
+        case pte_no_time:
+            return hwt_ipt_err_no_time;
+        case pte_no_cbr:
+            return hwt_ipt_err_no_cbr;
+        case pte_bad_image:
+            return hwt_ipt_err_bad_image;
+        case pte_bad_lock:
+            return hwt_ipt_err_bad_lock;
+        case pte_not_supported:
+            return hwt_ipt_err_not_supported;
+        case pte_retstack_empty:
+            return hwt_ipt_err_retstack_empty;
+        case pte_bad_retcomp:
+            return hwt_ipt_err_bad_retcomp;
+        case pte_bad_status_update:
+            return hwt_ipt_err_bad_status_update;
+        case pte_no_enable:
+            return hwt_ipt_err_no_enable;
+        case pte_event_ignored:
+            return hwt_ipt_err_event_ignored;
+        case pte_bad_file:
+            return hwt_ipt_err_bad_file;
+        case pte_bad_cpu:
+            return hwt_ipt_err_bad_cpu;
+        default:
+            return hwt_ipt_err_other;
+    }
+}
+
+/*
+ * Allocates an empty image. Code is added to it with hwt_ipt_add_cached() and
+ * hwt_ipt_add_mmap().
+ *
+ * Returns the image on success or NULL otherwise.
+ */
+struct pt_image *
+hwt_ipt_alloc_image(struct hwt_cerror *err) {
+    struct pt_image *image = pt_image_alloc(NULL);
+    if (image == NULL) {
+        hwt_set_cerr(err, hwt_cerror_unknown, 0);
+    }
+    return image;
+}
+
+/*
+ * Frees an image allocated with hwt_ipt_alloc_image(). Any decoders using the
+ * image must already have been freed.
+ */
+void
+hwt_ipt_free_image(struct pt_image *image) {
+    pt_image_free(image);
+}
+
+/*
+ * Loads `len` bytes of the file `filename`, starting at file offset `offset`,
+ * into `iscache` and maps them at the virtual address `vaddr` in `image`.
+ * `*isid` is set to the identifier of the section in `iscache`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+hwt_ipt_add_cached(struct pt_image *image,
+                   struct pt_image_section_cache *iscache,
+                   const char *filename, uint64_t offset, uint64_t len,
+                   uint64_t vaddr, int *isid, struct hwt_cerror *err) {
+    *isid = pt_iscache_add_file(iscache, filename, offset, len, vaddr);
+    if (*isid < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -*isid);
+        return false;
+    }
+
+    int rv = pt_image_add_cached(image, iscache, *isid, NULL);
+    if (rv < 0) {
+        hwt_set_cerr(err, hwt_cerror_ipt, -rv);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Reads up to `size` bytes of code at `vaddr` from the section `isid` of
+ * `iscache` into `buf`.
+ *
+ * Returns the number of bytes read, or a negative libipt error code.
+ */
+int
+hwt_ipt_read_cached(struct pt_image_section_cache *iscache, int isid,
+                    uint8_t *buf, uint64_t size, uint64_t vaddr) {
+    return pt_iscache_read(iscache, buf, size, isid, vaddr);
+}
@@ -0,0 +1,343 @@
+//! The code images that the libipt decoders recover control flow from.
+
+use super::{
+    hwt_ipt_add_cached, hwt_ipt_add_mmap, hwt_ipt_alloc_image, hwt_ipt_clear_image,
+    hwt_ipt_dump_vdso, hwt_ipt_free_image, hwt_ipt_read_cached, hwt_ipt_set_read_callback,
+    SectionCache,
+};
+use crate::{
+    c_errors::PerfPTCError,
+    decode::{report_warning, DecodeWarning, MemReader, WarningHandler},
+    errors::HWTracerError,
+    objects::{loaded_objects, ObjectFile},
+    SidebandEvent, SidebandRecord,
+};
+use libc::{c_int, c_void, size_t, PF_X, PT_LOAD};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    convert::TryFrom,
+    ffi::CString,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    slice,
+};
+use tempfile::NamedTempFile;
+
+/// libipt's `pte_nomap` error code. Must be kept in sync with `enum pt_error_code` in libipt.
+const PTE_NOMAP: c_int = 13;
+
+/// An executable segment of an object loaded into the current process.
+struct Segment {
+    /// The virtual address at which the segment is loaded.
+    vaddr: u64,
+    /// The size of the segment in bytes.
+    len: u64,
+    /// The file from which the segment's code is read.
+    filename: CString,
+    /// The offset of the segment in `filename`.
+    offset: u64,
+    /// The segment's identifier in the section cache, once it has been loaded.
+    isid: Cell<Option<c_int>>,
+}
+
+impl Segment {
+    /// Returns `true` if the segment overlaps `len` bytes starting at `vaddr`.
+    fn overlaps(&self, vaddr: u64, len: u64) -> bool {
+        self.vaddr < vaddr.saturating_add(len) && vaddr < self.vaddr + self.len
+    }
+}
+
+/// A libipt image holding the code of the current process.
+///
+/// Segments of code aren't loaded up front. Instead, libipt asks the image for code that it
+/// hasn't got yet (through [read_memory]), at which point the segment containing that code is
+/// loaded into the image. Objects that the trace never touches thus cost little more than a
+/// [Segment].
+pub(super) struct CodeImage<'t> {
+    /// C-level libipt image.
+    image: *mut c_void,
+    /// The section cache that segments are loaded through.
+    iscache: SectionCache,
+    /// The executable segments of the current process, sorted by address.
+    segments: Vec<Segment>,
+    /// VDSO code (stored temporarily).
+    vdso_tempfile: NamedTempFile,
+    /// Reads code which isn't backed by a file, if set.
+    mem_reader: Option<&'t MemReader>,
+    /// Receives any warnings, if set.
+    warnings: Option<&'t WarningHandler>,
+}
+
+impl<'t> CodeImage<'t> {
+    /// Create an image of the code of the current process which loads code through `iscache` and
+    /// falls back on `mem_reader` (if set) for code not backed by a file. Code which can't be found
+    /// is reported to `warnings` (if set).
+    ///
+    /// libipt holds on to the address of the image, so it is boxed to keep it in place.
+    pub(super) fn new(
+        iscache: SectionCache,
+        mem_reader: Option<&'t MemReader>,
+        warnings: Option<&'t WarningHandler>,
+    ) -> Result<Box<Self>, HWTracerError> {
+        // Make a temp file to write the VDSO code into.
+        let vdso_tempfile = NamedTempFile::new()?;
+        let mut cerr = PerfPTCError::new();
+        let image = unsafe { hwt_ipt_alloc_image(&mut cerr) };
+        if image.is_null() {
+            return Err(cerr.into());
+        }
+        let mut this = Box::new(Self {
+            image,
+            iscache,
+            segments: Vec::new(),
+            vdso_tempfile,
+            mem_reader,
+            warnings,
+        });
+        this.find_segments()?;
+        let context = &*this as *const Self as *mut c_void;
+        if !unsafe { hwt_ipt_set_read_callback(image, read_memory, context, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(this)
+    }
+
+    /// Returns the C-level libipt image.
+    pub(super) fn as_ptr(&self) -> *mut c_void {
+        self.image
+    }
+
+    /// Record (but don't load) the executable segments of the objects loaded into the current
+    /// process.
+    fn find_segments(&mut self) -> Result<(), HWTracerError> {
+        let vdso_filename = CString::new(self.vdso_tempfile.path().as_os_str().as_bytes())?;
+        for (file, obj) in loaded_objects()? {
+            let filename = match file {
+                ObjectFile::Path(p) => Some(CString::new(p.as_os_str().as_bytes())?),
+                ObjectFile::Vdso => None,
+            };
+            for hdr in obj.iter_phdrs() {
+                if hdr.type_() != PT_LOAD || hdr.flags() & PF_X == 0 {
+                    continue; // Only look at loadable and executable segments.
+                }
+                let vaddr = obj.addr() + hdr.vaddr();
+                let (filename, offset) = match &filename {
+                    Some(f) => (f.clone(), hdr.offset()),
+                    None => {
+                        // The VDSO doesn't exist on-disk, but libipt can only load code from
+                        // files, so we dump it into a temp file. It's tiny, so there's nothing to
+                        // gain from doing this lazily.
+                        let mut cerr = PerfPTCError::new();
+                        let len = size_t::try_from(hdr.filesz()).unwrap();
+                        let fd = self.vdso_tempfile.as_raw_fd();
+                        if !unsafe { hwt_ipt_dump_vdso(fd, vaddr, len, &mut cerr) } {
+                            return Err(cerr.into());
+                        }
+                        (vdso_filename.clone(), 0)
+                    }
+                };
+                self.segments.push(Segment {
+                    vaddr,
+                    len: hdr.filesz(),
+                    filename,
+                    offset,
+                    isid: Cell::new(None),
+                });
+            }
+        }
+        self.vdso_tempfile.as_file().sync_all()?;
+        self.segments.sort_by_key(|s| s.vaddr);
+        Ok(())
+    }
+
+    /// Load `seg` into the image, if it isn't already, returning its section cache identifier.
+    fn load(&self, seg: &Segment) -> Result<c_int, HWTracerError> {
+        if let Some(isid) = seg.isid.get() {
+            return Ok(isid);
+        }
+        let mut isid = 0;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe {
+            hwt_ipt_add_cached(
+                self.image,
+                self.iscache.as_ptr(),
+                seg.filename.as_ptr(),
+                seg.offset,
+                seg.len,
+                seg.vaddr,
+                &mut isid,
+                &mut cerr,
+            )
+        } {
+            return Err(cerr.into());
+        }
+        seg.isid.set(Some(isid));
+        Ok(isid)
+    }
+
+    /// Read the code at `ip` into `buf` on libipt's behalf, returning the number of bytes read or
+    /// a negative libipt error code. libipt only asks for code which isn't in the image yet.
+    fn read(&self, ip: u64, buf: &mut [u8]) -> c_int {
+        let rv = self.read_code(ip, buf);
+        if rv == -PTE_NOMAP {
+            report_warning(self.warnings, DecodeWarning::MissingCode(ip));
+        }
+        rv
+    }
+
+    /// Does the work of [CodeImage::read].
+    fn read_code(&self, ip: u64, buf: &mut [u8]) -> c_int {
+        let i = self.segments.partition_point(|s| s.vaddr <= ip);
+        let seg = i.checked_sub(1).map(|i| &self.segments[i]);
+        match seg.filter(|s| s.overlaps(ip, 1)) {
+            Some(seg) => match self.load(seg) {
+                // Subsequent reads from this segment are served by the image directly, but libipt
+                // still expects this read to be satisfied by us.
+                Ok(isid) => unsafe {
+                    hwt_ipt_read_cached(
+                        self.iscache.as_ptr(),
+                        isid,
+                        buf.as_mut_ptr(),
+                        u64::try_from(buf.len()).unwrap(),
+                        ip,
+                    )
+                },
+                // If the segment can't be loaded then, as far as libipt is concerned, there's no
+                // code there.
+                Err(_) => -PTE_NOMAP,
+            },
+            None => match self
+                .mem_reader
+                .map_or(0, |r| r.read(ip, buf).min(buf.len()))
+            {
+                0 => -PTE_NOMAP,
+                n => c_int::try_from(n).unwrap_or(c_int::MAX),
+            },
+        }
+    }
+
+    /// Apply to the image any pending sideband records in `sideband` which occurred before the
+    /// decoder reached `offset` bytes into the trace. Returns the number of session boundaries
+    /// passed.
+    pub(super) fn apply_sideband(
+        &mut self,
+        sideband: &mut VecDeque<SidebandRecord>,
+        offset: usize,
+    ) -> Result<usize, HWTracerError> {
+        let mut boundaries = 0;
+        while let Some(rec) = sideband.front() {
+            if rec.trace_offset > offset {
+                break;
+            }
+            let rec = sideband.pop_front().unwrap();
+            let mut cerr = PerfPTCError::new();
+            let ok = match rec.event {
+                SidebandEvent::Mmap {
+                    vaddr,
+                    len,
+                    pgoff,
+                    filename,
+                } => {
+                    // Pseudo-files (e.g. `[vdso]`) and anonymous mappings (e.g. JITted code) can't
+                    // be loaded from disk.
+                    let bytes = filename.as_os_str().as_bytes();
+                    if bytes.starts_with(b"[") || bytes.starts_with(b"//anon") {
+                        continue;
+                    }
+                    // libipt lets the most recently added code win where sections overlap, so any
+                    // segments that this mapping replaces must be loaded before it.
+                    for seg in self.segments.iter().filter(|s| s.overlaps(vaddr, len)) {
+                        self.load(seg)?;
+                    }
+                    let filename = CString::new(bytes)?;
+                    unsafe {
+                        hwt_ipt_add_mmap(
+                            self.image,
+                            filename.as_ptr(),
+                            pgoff,
+                            len,
+                            vaddr,
+                            &mut cerr,
+                        )
+                    }
+                }
+                SidebandEvent::Comm { exec: true, .. } => {
+                    // The old address space is gone. Subsequent mmaps describe the new one.
+                    self.segments.clear();
+                    unsafe { hwt_ipt_clear_image(self.image, &mut cerr) }
+                }
+                // Neither renaming a thread nor switching it on or off a CPU affects its address
+                // space.
+                SidebandEvent::Comm { exec: false, .. } | SidebandEvent::Switch { .. } => true,
+                // Each session's sideband describes its own changes to the address space, so
+                // there's nothing to undo here.
+                SidebandEvent::SessionBoundary => {
+                    boundaries += 1;
+                    true
+                }
+            };
+            if !ok {
+                return Err(cerr.into());
+            }
+        }
+        Ok(boundaries)
+    }
+}
+
+impl<'t> Drop for CodeImage<'t> {
+    fn drop(&mut self) {
+        unsafe { hwt_ipt_free_image(self.image) };
+    }
+}
+
+/// The read callback installed into a libipt image. `context` points to a [CodeImage].
+unsafe extern "C" fn read_memory(
+    buffer: *mut u8,
+    size: size_t,
+    _asid: *const c_void,
+    ip: u64,
+    context: *mut c_void,
+) -> c_int {
+    let image = &*(context as *const CodeImage);
+    image.read(ip, slice::from_raw_parts_mut(buffer, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeImage, PTE_NOMAP};
+    use crate::{decode::libipt::SectionCache, testing::work_loop};
+    use std::slice;
+
+    /// Returns the number of segments which have been loaded into `image`.
+    fn num_loaded(image: &CodeImage) -> usize {
+        image
+            .segments
+            .iter()
+            .filter(|s| s.isid.get().is_some())
+            .count()
+    }
+
+    /// Check that code is only loaded into an image when it is read, and that the code read is
+    /// the code in memory.
+    #[test]
+    fn lazy_load() {
+        let image = CodeImage::new(SectionCache::new(0).unwrap(), None, None).unwrap();
+        assert!(!image.segments.is_empty());
+        assert_eq!(num_loaded(&image), 0);
+
+        let ip = work_loop as fn(u64) -> u64 as usize;
+        let mut buf = [0; 8];
+        assert_eq!(image.read(ip as u64, &mut buf), 8);
+        assert_eq!(num_loaded(&image), 1);
+        let expect = unsafe { slice::from_raw_parts(ip as *const u8, buf.len()) };
+        assert_eq!(&buf[..], expect);
+
+        // Reading more code from the same segment doesn't load anything else.
+        assert_eq!(image.read(ip as u64 + 1, &mut buf), 8);
+        assert_eq!(num_loaded(&image), 1);
+
+        // There's no code at address zero.
+        assert_eq!(image.read(0, &mut buf), -PTE_NOMAP);
+        assert_eq!(num_loaded(&image), 1);
+    }
+}
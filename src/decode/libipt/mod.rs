@@ -1,107 +1,494 @@
 //! The libipt trace decoder.
 
-use crate::{c_errors::PerfPTCError, decode::TraceDecoder, errors::HWTracerError, Block, Trace};
-use libc::{c_char, c_int, c_void};
-use std::{convert::TryFrom, env, ffi::CString, os::fd::AsRawFd, ptr};
-use tempfile::NamedTempFile;
+mod image;
+
+use crate::{
+    build_id,
+    c_errors::PerfPTCError,
+    decode::{
+        AddrFilter, BranchOutcome, DecodeEvent, ExecMode, LimitTracker, MemReader, TraceDecoder,
+        TraceDecoderConfig, WarningHandler,
+    },
+    errors::{DecodeError, HWTracerError, LibIPTErrorKind},
+    insn::MAX_INSN_LEN,
+    instrument::Span,
+    Block, CpuId, Insn, SidebandRecord, Trace,
+};
+use image::CodeImage;
+use libc::{c_char, c_int, c_void, size_t};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    ptr,
+    sync::{Arc, OnceLock},
+};
 
 extern "C" {
     // decode.c
     fn hwt_ipt_init_block_decoder(
         buf: *const c_void,
         len: u64,
-        vdso_fd: c_int,
-        vdso_filename: *const c_char,
+        cpu: *const CpuId,
+        image: *mut c_void,
         decoder_status: *mut c_int,
         err: *mut PerfPTCError,
-        current_exe: *const c_char,
     ) -> *mut c_void;
     fn hwt_ipt_next_block(
         decoder: *mut c_void,
         decoder_status: *mut c_int,
         addr: *mut u64,
         len: *mut u64,
+        speculative: *mut bool,
+        aborted: *mut bool,
         err: *mut PerfPTCError,
     ) -> bool;
     fn hwt_ipt_free_block_decoder(decoder: *mut c_void);
+    fn hwt_ipt_get_offset(decoder: *mut c_void, offset: *mut u64, err: *mut PerfPTCError) -> bool;
+    fn hwt_ipt_next_event(
+        decoder: *mut c_void,
+        decoder_status: *mut c_int,
+        kind: *mut c_int,
+        a: *mut u64,
+        b: *mut u64,
+        suppressed: *mut bool,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_add_mmap(
+        image: *mut c_void,
+        filename: *const c_char,
+        offset: u64,
+        len: u64,
+        vaddr: u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_clear_image(image: *mut c_void, err: *mut PerfPTCError) -> bool;
+    fn hwt_ipt_set_read_callback(
+        image: *mut c_void,
+        callback: ReadMemoryCallback,
+        context: *mut c_void,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_init_insn_decoder(
+        buf: *const c_void,
+        len: u64,
+        cpu: *const CpuId,
+        image: *mut c_void,
+        decoder_status: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn hwt_ipt_next_insn(
+        decoder: *mut c_void,
+        decoder_status: *mut c_int,
+        ip: *mut u64,
+        raw: *mut u8,
+        size: *mut u8,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_get_insn_offset(
+        decoder: *mut c_void,
+        offset: *mut u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_free_insn_decoder(decoder: *mut c_void);
+    fn hwt_ipt_init_query_decoder(
+        buf: *const c_void,
+        len: u64,
+        cpu: *const CpuId,
+        decoder_status: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn hwt_ipt_next_branch(
+        decoder: *mut c_void,
+        decoder_status: *mut c_int,
+        kind: *mut c_int,
+        ip: *mut u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_get_query_offset(
+        decoder: *mut c_void,
+        offset: *mut u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_free_query_decoder(decoder: *mut c_void);
+    fn hwt_ipt_alloc_iscache(limit: u64, err: *mut PerfPTCError) -> *mut c_void;
+    fn hwt_ipt_free_iscache(iscache: *mut c_void);
+    fn hwt_ipt_alloc_image(err: *mut PerfPTCError) -> *mut c_void;
+    fn hwt_ipt_free_image(image: *mut c_void);
+    fn hwt_ipt_add_cached(
+        image: *mut c_void,
+        iscache: *mut c_void,
+        filename: *const c_char,
+        offset: u64,
+        len: u64,
+        vaddr: u64,
+        isid: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn hwt_ipt_read_cached(
+        iscache: *mut c_void,
+        isid: c_int,
+        buf: *mut u8,
+        size: u64,
+        vaddr: u64,
+    ) -> c_int;
+    fn hwt_ipt_dump_vdso(fd: c_int, vaddr: u64, len: size_t, err: *mut PerfPTCError) -> bool;
     // util.c
     pub(crate) fn hwt_ipt_is_overflow_err(err: c_int) -> bool;
+    pub(crate) fn hwt_ipt_classify_err(err: c_int) -> c_int;
     // libipt
     pub(crate) fn pt_errstr(error_code: c_int) -> *const c_char;
 }
 
-pub(crate) struct LibIPTTraceDecoder {}
+/// A cache of the sections of code loaded into libipt's images.
+///
+/// Sharing a cache between decoders (by passing clones of it to
+/// [TraceDecoderBuilder::section_cache](crate::decode::TraceDecoderBuilder::section_cache))
+/// saves each decoder from re-opening and re-mapping the same ELF sections. If no cache is
+/// configured, each decoder makes a private cache when it first decodes a trace.
+#[derive(Clone, Debug)]
+pub struct SectionCache(Arc<SectionCacheHandle>);
+
+impl SectionCache {
+    /// Create a section cache which keeps up to `limit` bytes of sections mapped, even when they
+    /// are not in use by any decoder. A limit of zero means sections are unmapped as soon as they
+    /// are no longer in use.
+    pub fn new(limit: u64) -> Result<Self, HWTracerError> {
+        let mut cerr = PerfPTCError::new();
+        let iscache = unsafe { hwt_ipt_alloc_iscache(limit, &mut cerr) };
+        if iscache.is_null() {
+            return Err(cerr.into());
+        }
+        Ok(Self(Arc::new(SectionCacheHandle(iscache))))
+    }
+
+    fn as_ptr(&self) -> *mut c_void {
+        self.0 .0
+    }
+}
+
+/// Owns a C-level libipt image section cache.
+#[derive(Debug)]
+struct SectionCacheHandle(*mut c_void);
+
+// libipt protects its image section caches with a lock, so they may be used from any thread.
+unsafe impl Send for SectionCacheHandle {}
+unsafe impl Sync for SectionCacheHandle {}
+
+impl Drop for SectionCacheHandle {
+    fn drop(&mut self) {
+        unsafe { hwt_ipt_free_iscache(self.0) };
+    }
+}
+
+pub(crate) struct LibIPTTraceDecoder {
+    config: TraceDecoderConfig,
+    /// Built from `config.addr_ranges` once, rather than for every trace decoded.
+    addr_filter: AddrFilter,
+    /// The section cache used if none is configured, made when the decoder first decodes a trace
+    /// and then shared by all of its iterators. `None` if it couldn't be made.
+    private_cache: OnceLock<Option<SectionCache>>,
+}
+
+impl LibIPTTraceDecoder {
+    /// Returns the section cache for an iterator to load code through.
+    fn section_cache(&self) -> Option<SectionCache> {
+        // If the private cache couldn't be made, each iterator tries to make its own, and reports
+        // the error if it can't either.
+        self.config.section_cache.clone().or_else(|| {
+            self.private_cache
+                .get_or_init(|| SectionCache::new(0).ok())
+                .clone()
+        })
+    }
+}
 
 impl TraceDecoder for LibIPTTraceDecoder {
-    fn new() -> Self {
-        Self {}
+    fn new(config: TraceDecoderConfig) -> Self {
+        let addr_filter = AddrFilter::new(&config.addr_ranges);
+        Self {
+            config,
+            addr_filter,
+            private_cache: OnceLock::new(),
+        }
     }
 
     fn iter_blocks<'t>(
         &'t self,
         trace: &'t dyn Trace,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
-        let itr = LibIPTBlockIterator {
+        let span = debug_span!(
+            "decode_blocks",
+            decoder = "libipt",
+            len = trace.len(),
+            trace_id = trace.id().map(tracing::field::display)
+        );
+        Box::new(LibIPTBlockIterator::new(self, trace, span))
+    }
+
+    fn iter_events<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<DecodeEvent, HWTracerError>> + '_> {
+        let span = debug_span!(
+            "decode_events",
+            decoder = "libipt",
+            len = trace.len(),
+            trace_id = trace.id().map(tracing::field::display)
+        );
+        Box::new(LibIPTEventIterator {
+            blocks: LibIPTBlockIterator::new(self, trace, span),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn iter_insns<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Insn, HWTracerError>> + '_> {
+        let itr = LibIPTInsnIterator {
             decoder: ptr::null_mut(),
             decoder_status: 0,
-            vdso_tempfile: None,
+            image: None,
             trace,
             errored: false,
+            limits: LimitTracker::for_config(&self.config),
+            addr_filter: &self.addr_filter,
+            sideband: VecDeque::new(),
+            mem_reader: self.config.mem_reader.as_ref(),
+            warnings: self.config.warning_handler.as_ref(),
+            section_cache: self.section_cache(),
+            verify_build_ids: !self.config.ignore_build_ids,
         };
         Box::new(itr)
     }
+
+    fn iter_branches<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<BranchOutcome, HWTracerError>> + '_> {
+        let itr = LibIPTBranchIterator {
+            decoder: ptr::null_mut(),
+            decoder_status: 0,
+            trace,
+            errored: false,
+            limits: LimitTracker::for_config(&self.config),
+        };
+        Box::new(itr)
+    }
+}
+
+/// Get a pointer to pass to C for the identity of a trace's CPU. If the CPU is unknown, the C code
+/// assumes the current CPU.
+fn cpu_ptr(cpu: &Option<CpuId>) -> *const CpuId {
+    cpu.as_ref().map_or(ptr::null(), |c| c as *const CpuId)
+}
+
+/// The signature shared by the C functions which create a libipt decoder over a code image.
+type InitDecoderFn = unsafe extern "C" fn(
+    *const c_void,
+    u64,
+    *const CpuId,
+    *mut c_void,
+    *mut c_int,
+    *mut PerfPTCError,
+) -> *mut c_void;
+
+/// Create a libipt decoder for `trace` using `init`, recovering control flow from `image`.
+///
+/// The caller must keep `image` alive for as long as the decoder.
+fn init_self_decoder(
+    init: InitDecoderFn,
+    trace: &dyn Trace,
+    decoder_status: &mut c_int,
+    image: &CodeImage,
+) -> Result<*mut c_void, HWTracerError> {
+    let cpu = trace.cpu();
+    let mut cerr = PerfPTCError::new();
+    let decoder = unsafe {
+        init(
+            trace.bytes().as_ptr() as *const c_void,
+            u64::try_from(trace.len()).unwrap(),
+            cpu_ptr(&cpu),
+            image.as_ptr(),
+            decoder_status,
+            &mut cerr,
+        )
+    };
+    if decoder.is_null() {
+        return Err(cerr.into());
+    }
+    Ok(decoder)
 }
 
+/// The signature of libipt's `read_memory_callback_t`.
+type ReadMemoryCallback =
+    unsafe extern "C" fn(*mut u8, size_t, *const c_void, u64, *mut c_void) -> c_int;
+
 /// Iterate over the blocks of an Intel PT trace using libipt.
 struct LibIPTBlockIterator<'t> {
     /// C-level libipt block decoder.
     decoder: *mut c_void,
     /// Stores the current libipt-level status of the above decoder.
     decoder_status: c_int,
-    /// VDSO code (stored temporarily).
-    #[allow(dead_code)]
-    // Rust doesn't know that this exists only to keep the file long enough.
-    vdso_tempfile: Option<NamedTempFile>,
+    /// The code that the decoder recovers control flow from. Must outlive the decoder.
+    image: Option<Box<CodeImage<'t>>>,
     /// The trace we are iterating over.
     trace: &'t dyn Trace,
     /// Set to true when an error has occured.
     errored: bool,
+    /// Set to true when the end of the trace has been reached.
+    ended: bool,
+    /// The blocks executed so far in the TSX transaction in progress, held back until it is
+    /// committed (or thrown away if it is aborted).
+    tx: Vec<Block>,
+    /// Blocks ready to be reported.
+    ready: VecDeque<Block>,
+    /// Enforces the decoder's resource limits.
+    limits: LimitTracker,
+    /// Decides which blocks are reported.
+    addr_filter: &'t AddrFilter,
+    /// Sideband records not yet applied to the decoder's image.
+    sideband: VecDeque<SidebandRecord>,
+    /// Reads code which isn't backed by a file, if set.
+    mem_reader: Option<&'t MemReader>,
+    /// Receives any warnings, if set.
+    warnings: Option<&'t WarningHandler>,
+    /// The section cache to load code through. If `None`, a private cache is created when the
+    /// decoder is initialised.
+    section_cache: Option<SectionCache>,
+    /// If `true`, the build IDs of the objects that the trace was collected from are checked
+    /// before decoding starts.
+    verify_build_ids: bool,
+    /// The span that decoding is done in.
+    span: Span,
 }
 
 impl<'t> LibIPTBlockIterator<'t> {
+    /// Make an iterator over the blocks of `trace`, decoded as configured for `decoder`, doing the
+    /// decoding in `span`. The C-level decoder is only initialised when the first block is asked
+    /// for.
+    fn new(decoder: &'t LibIPTTraceDecoder, trace: &'t dyn Trace, span: Span) -> Self {
+        Self {
+            decoder: ptr::null_mut(),
+            decoder_status: 0,
+            image: None,
+            trace,
+            errored: false,
+            ended: false,
+            tx: Vec::new(),
+            ready: VecDeque::new(),
+            limits: LimitTracker::for_config(&decoder.config),
+            addr_filter: &decoder.addr_filter,
+            sideband: VecDeque::new(),
+            mem_reader: decoder.config.mem_reader.as_ref(),
+            warnings: decoder.config.warning_handler.as_ref(),
+            section_cache: decoder.section_cache(),
+            verify_build_ids: !decoder.config.ignore_build_ids,
+            span,
+        }
+    }
+
     /// Initialise the block decoder.
     fn init_decoder(&mut self) -> Result<(), HWTracerError> {
-        // Make a temp file for the C code to write the VDSO code into.
-        //
-        // We have to do this because libipt lazily reads the code from the files you load into the
-        // image. We store it into `self` to ensure the file lives as long as the iterator.
-        let vdso_tempfile = NamedTempFile::new()?;
-        // File name of a NamedTempFile should always be valid UTF-8, unwrap() below can't fail.
-        let vdso_filename = CString::new(vdso_tempfile.path().to_str().unwrap())?;
+        if self.verify_build_ids {
+            build_id::verify(&self.trace.meta().build_ids)?;
+        }
+        let iscache = match &self.section_cache {
+            Some(c) => c.clone(),
+            None => SectionCache::new(0)?,
+        };
+        // The image must outlive the decoder, so we keep hold of it in `self`.
+        let image = CodeImage::new(iscache, self.mem_reader, self.warnings)?;
+        self.decoder = init_self_decoder(
+            hwt_ipt_init_block_decoder,
+            self.trace,
+            &mut self.decoder_status,
+            &image,
+        )?;
+        self.image = Some(image);
+
+        // Records tagged with offset 0 predate all of the trace data, so apply them straight away.
+        self.sideband = VecDeque::from(self.trace.sideband()?);
+        debug!(
+            sideband_records = self.sideband.len(),
+            shared_cache = self.section_cache.is_some(),
+            "initialised libipt block decoder"
+        );
+        self.apply_sideband(0)?;
+        Ok(())
+    }
+
+    /// Apply pending sideband records to the decoder's image, returning the number of session
+    /// boundaries passed. See [CodeImage::apply_sideband].
+    fn apply_sideband(&mut self, offset: usize) -> Result<usize, HWTracerError> {
+        match &mut self.image {
+            Some(image) => image.apply_sideband(&mut self.sideband, offset),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the number of bytes of the trace consumed by the decoder so far.
+    fn offset(&self) -> Result<usize, HWTracerError> {
+        let mut offset = 0;
         let mut cerr = PerfPTCError::new();
-        let decoder = unsafe {
-            hwt_ipt_init_block_decoder(
-                self.trace.bytes().as_ptr() as *const c_void,
-                u64::try_from(self.trace.len()).unwrap(),
-                vdso_tempfile.as_raw_fd(),
-                vdso_filename.as_ptr(),
+        if !unsafe { hwt_ipt_get_offset(self.decoder, &mut offset, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(usize::try_from(offset).unwrap())
+    }
+
+    /// Fetch the next block from the decoder, whether or not it passes the address filter, or
+    /// `None` at the end of the trace. If a transaction was aborted before the block, the blocks
+    /// held back from it are thrown away.
+    fn next_block(&mut self) -> Result<Option<Block>, HWTracerError> {
+        let mut first_instr = 0;
+        let mut last_instr = 0;
+        let mut speculative = false;
+        let mut aborted = false;
+        let mut cerr = PerfPTCError::new();
+        let rv = unsafe {
+            hwt_ipt_next_block(
+                self.decoder,
                 &mut self.decoder_status,
+                &mut first_instr,
+                &mut last_instr,
+                &mut speculative,
+                &mut aborted,
                 &mut cerr,
-                // FIXME: current_exe() isn't reliable. We should find another way to do this.
-                CString::new(env::current_exe().unwrap().to_str().unwrap())
-                    .unwrap()
-                    .as_c_str()
-                    .as_ptr() as *const c_char,
             )
         };
-        if decoder.is_null() {
-            return Err(cerr.into());
+        if !rv {
+            let mut err = HWTracerError::from(cerr);
+            if let HWTracerError::Decode(DecodeError::LibIPT(ref mut e)) = err {
+                e.offset = self.offset().ok();
+            }
+            debug!(error = %err, "libipt failed to decode block");
+            return Err(err);
         }
-
-        vdso_tempfile.as_file().sync_all()?;
-        self.decoder = decoder;
-        self.vdso_tempfile = Some(vdso_tempfile);
-        Ok(())
+        if aborted {
+            trace!(blocks = self.tx.len(), "transaction aborted");
+            self.tx.clear();
+        }
+        if first_instr == 0 {
+            // End of packet stream. A decoder which never synchronised (there's no PSB in the
+            // trace) has skipped over all of it.
+            let off = match self.offset() {
+                Err(HWTracerError::Decode(DecodeError::LibIPT(e)))
+                    if e.kind() == LibIPTErrorKind::NoSync =>
+                {
+                    self.trace.len()
+                }
+                r => r?,
+            };
+            self.limits.finish(off, self.trace.len())?;
+            return Ok(None);
+        }
+        let off = self.offset()?;
+        self.limits.block(off)?;
+        self.apply_sideband(off)?;
+        Ok(Some(
+            Block::new(first_instr, last_instr).with_speculative(speculative),
+        ))
     }
 }
 
@@ -115,10 +502,15 @@ impl<'t> Iterator for LibIPTBlockIterator<'t> {
     type Item = Result<Block, HWTracerError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // There was an error in a previous iteration.
-        if self.errored {
+        if let Some(block) = self.ready.pop_front() {
+            return Some(Ok(block));
+        }
+        // There was an error in a previous iteration, or there is nothing left.
+        if self.errored || self.ended {
             return None;
         }
+        let span = self.span.clone();
+        let _entered = span.enter();
 
         // Lazily initialise the block decoder.
         if self.decoder.is_null() {
@@ -128,50 +520,428 @@ impl<'t> Iterator for LibIPTBlockIterator<'t> {
             }
         }
 
-        let mut first_instr = 0;
-        let mut last_instr = 0;
+        loop {
+            match self.next_block() {
+                Ok(Some(block)) if block.is_speculative() => {
+                    if self.addr_filter.matches(block.first_instr()) {
+                        self.tx.push(block);
+                    }
+                }
+                Ok(Some(block)) => {
+                    // Any transaction in progress was committed.
+                    self.ready.extend(self.tx.drain(..));
+                    if self.addr_filter.matches(block.first_instr()) {
+                        self.ready.push_back(block);
+                    }
+                    if let Some(block) = self.ready.pop_front() {
+                        return Some(Ok(block));
+                    }
+                }
+                Ok(None) => {
+                    // We can't tell whether a transaction still in progress at the end of the
+                    // trace was committed, so its blocks are reported (as speculative).
+                    self.ended = true;
+                    self.ready.extend(self.tx.drain(..));
+                    return self.ready.pop_front().map(Ok);
+                }
+                Err(e) => {
+                    self.errored = true; // This iterator is unusable now.
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Iterate over the individual instructions of an Intel PT trace using libipt's instruction flow
+/// decoder.
+struct LibIPTInsnIterator<'t> {
+    /// C-level libipt instruction flow decoder.
+    decoder: *mut c_void,
+    /// Stores the current libipt-level status of the above decoder.
+    decoder_status: c_int,
+    /// The code that the decoder recovers control flow from. Must outlive the decoder.
+    image: Option<Box<CodeImage<'t>>>,
+    /// The trace we are iterating over.
+    trace: &'t dyn Trace,
+    /// Set to true when an error has occured.
+    errored: bool,
+    /// Enforces the decoder's resource limits.
+    limits: LimitTracker,
+    /// Decides which instructions are reported.
+    addr_filter: &'t AddrFilter,
+    /// Sideband records not yet applied to the decoder's image.
+    sideband: VecDeque<SidebandRecord>,
+    /// Reads code which isn't backed by a file, if set.
+    mem_reader: Option<&'t MemReader>,
+    /// Receives any warnings, if set.
+    warnings: Option<&'t WarningHandler>,
+    /// The section cache to load code through. If `None`, a private cache is created when the
+    /// decoder is initialised.
+    section_cache: Option<SectionCache>,
+    /// If `true`, the build IDs of the objects that the trace was collected from are checked
+    /// before decoding starts.
+    verify_build_ids: bool,
+}
+
+impl<'t> LibIPTInsnIterator<'t> {
+    /// Initialise the instruction flow decoder.
+    fn init_decoder(&mut self) -> Result<(), HWTracerError> {
+        if self.verify_build_ids {
+            build_id::verify(&self.trace.meta().build_ids)?;
+        }
+        let iscache = match &self.section_cache {
+            Some(c) => c.clone(),
+            None => SectionCache::new(0)?,
+        };
+        // The image must outlive the decoder, so we keep hold of it in `self`.
+        let image = CodeImage::new(iscache, self.mem_reader, self.warnings)?;
+        self.decoder = init_self_decoder(
+            hwt_ipt_init_insn_decoder,
+            self.trace,
+            &mut self.decoder_status,
+            &image,
+        )?;
+        self.image = Some(image);
+        self.sideband = VecDeque::from(self.trace.sideband()?);
+        self.apply_sideband(0)
+    }
+
+    /// Apply pending sideband records to the decoder's image. See [CodeImage::apply_sideband].
+    fn apply_sideband(&mut self, offset: usize) -> Result<(), HWTracerError> {
+        match &mut self.image {
+            Some(image) => image.apply_sideband(&mut self.sideband, offset).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the number of bytes of the trace consumed by the decoder so far.
+    fn offset(&self) -> Result<usize, HWTracerError> {
+        let mut offset = 0;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { hwt_ipt_get_insn_offset(self.decoder, &mut offset, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(usize::try_from(offset).unwrap())
+    }
+
+    /// Fetch the next instruction which passes the address filter, or `None` at the end of the
+    /// trace.
+    fn next_insn(&mut self) -> Result<Option<Insn>, HWTracerError> {
+        if self.decoder.is_null() {
+            self.init_decoder()?;
+        }
+        loop {
+            let mut ip = 0;
+            let mut raw = [0; MAX_INSN_LEN];
+            let mut size = 0;
+            let mut cerr = PerfPTCError::new();
+            let rv = unsafe {
+                hwt_ipt_next_insn(
+                    self.decoder,
+                    &mut self.decoder_status,
+                    &mut ip,
+                    raw.as_mut_ptr(),
+                    &mut size,
+                    &mut cerr,
+                )
+            };
+            if !rv {
+                let mut err = HWTracerError::from(cerr);
+                if let HWTracerError::Decode(DecodeError::LibIPT(ref mut e)) = err {
+                    e.offset = self.offset().ok();
+                }
+                return Err(err);
+            }
+            if ip == 0 {
+                return Ok(None); // End of packet stream.
+            }
+            let off = self.offset()?;
+            self.limits.bytes(off)?;
+            self.apply_sideband(off)?;
+            if self.addr_filter.matches(ip) {
+                return Ok(Some(Insn::new(ip, &raw[..usize::from(size)])));
+            }
+        }
+    }
+}
+
+impl<'t> Drop for LibIPTInsnIterator<'t> {
+    fn drop(&mut self) {
+        unsafe { hwt_ipt_free_insn_decoder(self.decoder) };
+    }
+}
+
+impl<'t> Iterator for LibIPTInsnIterator<'t> {
+    type Item = Result<Insn, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // There was an error in a previous iteration.
+        if self.errored {
+            return None;
+        }
+        match self.next_insn() {
+            Ok(Some(insn)) => Some(Ok(insn)),
+            Ok(None) => None,
+            Err(e) => {
+                self.errored = true; // This iterator is unusable now.
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// The kinds of event reported by `hwt_ipt_next_event()`. Must be kept in sync with `enum
+// hwt_ipt_event_kind` in `decode.c`.
+const EVENT_EOS: c_int = 0;
+const EVENT_ENABLED: c_int = 1;
+const EVENT_DISABLED: c_int = 2;
+const EVENT_OVERFLOW: c_int = 3;
+const EVENT_EXEC_MODE: c_int = 4;
+const EVENT_CBR: c_int = 5;
+const EVENT_PTWRITE: c_int = 6;
+const EVENT_ASYNC_BRANCH: c_int = 7;
+
+/// Iterate over the high-level events of an Intel PT trace using libipt's block decoder.
+///
+/// The decoder state (and its initialisation) is shared with block iteration: events are simply
+/// reported instead of blocks.
+struct LibIPTEventIterator<'t> {
+    blocks: LibIPTBlockIterator<'t>,
+    /// Events which have been decoded, but not yet returned.
+    pending: VecDeque<DecodeEvent>,
+}
+
+impl<'t> LibIPTEventIterator<'t> {
+    /// Fetch the next event which passes the address filter, or `None` at the end of the trace.
+    fn next_event(&mut self) -> Result<Option<DecodeEvent>, HWTracerError> {
+        let itr = &mut self.blocks;
+        if itr.decoder.is_null() {
+            itr.init_decoder()?;
+        }
+        loop {
+            if let Some(ev) = self.pending.pop_front() {
+                return Ok(Some(ev));
+            }
+            let mut kind = 0;
+            let mut a = 0;
+            let mut b = 0;
+            let mut suppressed = false;
+            let mut cerr = PerfPTCError::new();
+            let rv = unsafe {
+                hwt_ipt_next_event(
+                    itr.decoder,
+                    &mut itr.decoder_status,
+                    &mut kind,
+                    &mut a,
+                    &mut b,
+                    &mut suppressed,
+                    &mut cerr,
+                )
+            };
+            if !rv {
+                let mut err = HWTracerError::from(cerr);
+                if let HWTracerError::Decode(DecodeError::LibIPT(ref mut e)) = err {
+                    e.offset = itr.offset().ok();
+                }
+                return Err(err);
+            }
+            let dest = |ip| if suppressed { None } else { Some(ip) };
+            let ev = match kind {
+                EVENT_EOS => return Ok(None),
+                EVENT_ENABLED => DecodeEvent::TracingEnabled(a),
+                EVENT_DISABLED => DecodeEvent::TracingDisabled(dest(a)),
+                EVENT_OVERFLOW => DecodeEvent::Overflow,
+                EVENT_EXEC_MODE => DecodeEvent::ExecMode(match a {
+                    16 => ExecMode::Bits16,
+                    32 => ExecMode::Bits32,
+                    _ => ExecMode::Bits64,
+                }),
+                EVENT_CBR => DecodeEvent::CoreBusRatio(u8::try_from(a).unwrap_or(u8::MAX)),
+                EVENT_PTWRITE => DecodeEvent::PTWrite(a),
+                EVENT_ASYNC_BRANCH => DecodeEvent::AsyncTransfer {
+                    from: a,
+                    to: dest(b),
+                },
+                _ => return Err(HWTracerError::Unknown),
+            };
+            let off = itr.offset()?;
+            itr.limits.bytes(off)?;
+            // Sessions end with tracing disabled and begin with it enabled, so the boundary goes
+            // before the first event we see past it.
+            for _ in 0..itr.apply_sideband(off)? {
+                self.pending.push_back(DecodeEvent::SessionBoundary);
+            }
+            if itr.addr_filter.matches_event(&ev) {
+                self.pending.push_back(ev);
+            }
+        }
+    }
+}
+
+impl<'t> Iterator for LibIPTEventIterator<'t> {
+    type Item = Result<DecodeEvent, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // There was an error in a previous iteration.
+        if self.blocks.errored {
+            return None;
+        }
+        match self.next_event() {
+            Ok(Some(ev)) => Some(Ok(ev)),
+            Ok(None) => None,
+            Err(e) => {
+                self.blocks.errored = true; // This iterator is unusable now.
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// The kinds of result reported by `hwt_ipt_next_branch()`. Must be kept in sync with `enum
+// hwt_ipt_branch_kind` in `decode.c`.
+const BRANCH_EOS: c_int = 0;
+const BRANCH_TAKEN: c_int = 1;
+const BRANCH_NOT_TAKEN: c_int = 2;
+const BRANCH_INDIRECT: c_int = 3;
+const BRANCH_SUPPRESSED: c_int = 4;
+
+/// Iterate over the branch outcomes of an Intel PT trace using libipt's query decoder.
+struct LibIPTBranchIterator<'t> {
+    /// C-level libipt query decoder.
+    decoder: *mut c_void,
+    /// Stores the current libipt-level status of the above decoder.
+    decoder_status: c_int,
+    /// The trace we are iterating over.
+    trace: &'t dyn Trace,
+    /// Set to true when an error has occured.
+    errored: bool,
+    /// Enforces the decoder's resource limits.
+    limits: LimitTracker,
+}
+
+impl<'t> LibIPTBranchIterator<'t> {
+    /// Initialise the query decoder.
+    fn init_decoder(&mut self) -> Result<(), HWTracerError> {
+        let cpu = self.trace.cpu();
+        let mut cerr = PerfPTCError::new();
+        let decoder = unsafe {
+            hwt_ipt_init_query_decoder(
+                self.trace.bytes().as_ptr() as *const c_void,
+                u64::try_from(self.trace.len()).unwrap(),
+                cpu_ptr(&cpu),
+                &mut self.decoder_status,
+                &mut cerr,
+            )
+        };
+        if decoder.is_null() {
+            return Err(cerr.into());
+        }
+        self.decoder = decoder;
+        Ok(())
+    }
+
+    /// Returns the number of bytes of the trace consumed by the decoder so far.
+    fn offset(&self) -> Result<usize, HWTracerError> {
+        let mut offset = 0;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { hwt_ipt_get_query_offset(self.decoder, &mut offset, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(usize::try_from(offset).unwrap())
+    }
+
+    /// Fetch the next branch outcome, or `None` at the end of the trace.
+    fn next_branch(&mut self) -> Result<Option<BranchOutcome>, HWTracerError> {
+        if self.decoder.is_null() {
+            self.init_decoder()?;
+        }
+        let mut kind = 0;
+        let mut ip = 0;
         let mut cerr = PerfPTCError::new();
         let rv = unsafe {
-            hwt_ipt_next_block(
+            hwt_ipt_next_branch(
                 self.decoder,
                 &mut self.decoder_status,
-                &mut first_instr,
-                &mut last_instr,
+                &mut kind,
+                &mut ip,
                 &mut cerr,
             )
         };
         if !rv {
-            self.errored = true; // This iterator is unusable now.
-            return Some(Err(HWTracerError::from(cerr)));
+            let mut err = HWTracerError::from(cerr);
+            if let HWTracerError::Decode(DecodeError::LibIPT(ref mut e)) = err {
+                e.offset = self.offset().ok();
+            }
+            return Err(err);
         }
-        if first_instr == 0 {
-            None // End of packet stream.
-        } else {
-            Some(Ok(Block::new(first_instr, last_instr)))
+        let outcome = match kind {
+            BRANCH_EOS => return Ok(None),
+            BRANCH_TAKEN => BranchOutcome::Conditional(true),
+            BRANCH_NOT_TAKEN => BranchOutcome::Conditional(false),
+            BRANCH_INDIRECT => BranchOutcome::Indirect(Some(ip)),
+            BRANCH_SUPPRESSED => BranchOutcome::Indirect(None),
+            _ => return Err(HWTracerError::Unknown),
+        };
+        self.limits.bytes(self.offset()?)?;
+        Ok(Some(outcome))
+    }
+}
+
+impl<'t> Drop for LibIPTBranchIterator<'t> {
+    fn drop(&mut self) {
+        unsafe { hwt_ipt_free_query_decoder(self.decoder) };
+    }
+}
+
+impl<'t> Iterator for LibIPTBranchIterator<'t> {
+    type Item = Result<BranchOutcome, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // There was an error in a previous iteration.
+        if self.errored {
+            return None;
+        }
+        match self.next_branch() {
+            Ok(Some(outcome)) => Some(Ok(outcome)),
+            Ok(None) => None,
+            Err(e) => {
+                self.errored = true; // This iterator is unusable now.
+                Some(Err(e))
+            }
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "collect"))]
 mod tests {
-    use super::{LibIPTBlockIterator, PerfPTCError};
+    use super::{
+        hwt_ipt_dump_vdso, LibIPTBlockIterator, LibIPTTraceDecoder, PerfPTCError, SectionCache,
+        Span,
+    };
     use crate::{
-        collect::{
-            perf::PerfTrace, test_helpers::trace_closure, TraceCollector, TraceCollectorBuilder,
+        collect::TraceCollector,
+        decode::{
+            test_helpers, BranchOutcome, DecodeEvent, DecodeLimit, DecodeLimits, DecodeWarning,
+            ExecMode, MemReader, TraceDecoder, TraceDecoderBuilder, TraceDecoderConfig,
+            TraceDecoderKind, WarningHandler,
         },
-        decode::{test_helpers, TraceDecoderKind},
-        errors::HWTracerError,
-        test_helpers::work_loop,
+        errors::{DecodeError, HWTracerError, LibIPTErrorKind},
+        pt::encode::{Encoder, IPCompression},
+        testing::{collector, trace_closure, work_loop},
         Block, Trace,
     };
-    use libc::{c_int, size_t, PF_X, PT_LOAD};
-    use std::{convert::TryFrom, env, os::fd::AsRawFd, process::Command, ptr};
+    use libc::{size_t, PF_X, PT_LOAD};
+    use std::{
+        convert::TryFrom,
+        env,
+        os::fd::AsRawFd,
+        process::Command,
+        ptr,
+        sync::{Arc, Mutex},
+    };
     use tempfile::NamedTempFile;
 
-    extern "C" {
-        fn hwt_ipt_dump_vdso(fd: c_int, vaddr: u64, len: size_t, err: &PerfPTCError) -> bool;
-    }
-
     const VDSO_FILENAME: &str = "linux-vdso.so.1";
 
     /// Gets the ptxed arguments required to decode a trace for the current process.
@@ -214,13 +984,13 @@ mod tests {
                 let offset;
 
                 if filename == VDSO_FILENAME {
-                    let cerr = PerfPTCError::new();
+                    let mut cerr = PerfPTCError::new();
                     if !unsafe {
                         hwt_ipt_dump_vdso(
                             vdso_tempfile.as_raw_fd(),
                             vaddr,
                             size_t::try_from(hdr.memsz()).unwrap(),
-                            &cerr,
+                            &mut cerr,
                         )
                     } {
                         panic!("failed to dump vdso");
@@ -314,7 +1084,7 @@ mod tests {
     where
         F: FnOnce() -> u64,
     {
-        let trace = trace_closure(&tc, f);
+        let trace = trace_closure(&tc, f).unwrap();
         let expects = get_expected_blocks(&trace);
         test_helpers::test_expected_blocks(trace, TraceDecoderKind::LibIPT, expects.iter());
     }
@@ -322,14 +1092,18 @@ mod tests {
     /// Check that the block decoder agrees with the reference implementation in ptxed.
     #[test]
     fn versus_ptxed_short_trace() {
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         trace_and_check_blocks(&tc, || work_loop(10));
     }
 
     /// Check that the block decoder agrees ptxed on a (likely) empty trace;
     #[test]
     fn versus_ptxed_empty_trace() {
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         trace_and_check_blocks(&tc, || work_loop(0));
     }
 
@@ -338,7 +1112,9 @@ mod tests {
     fn versus_ptxed_vdso() {
         use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
 
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         trace_and_check_blocks(&tc, || {
             let mut res = 0;
             let mut tv = timespec {
@@ -358,26 +1134,27 @@ mod tests {
     /// Check that the block decoder agrees with ptxed on long trace.
     #[test]
     fn versus_ptxed_long_trace() {
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         trace_and_check_blocks(&tc, || work_loop(3000));
     }
 
     /// Check that a block iterator returns none after an error.
     #[test]
     fn error_stops_block_iter() {
-        // A zero-sized trace will lead to an error.
-        let trace = PerfTrace::new(0).unwrap();
-        let mut itr = LibIPTBlockIterator {
-            decoder: ptr::null_mut(),
-            decoder_status: 0,
-            vdso_tempfile: None,
-            trace: &trace,
-            errored: false,
-        };
+        // A PSB followed by an undefined extended opcode will lead to an error.
+        let mut enc = Encoder::new();
+        enc.psb();
+        let mut bytes = enc.into_bytes();
+        bytes.extend([0x02, 0xff].repeat(4));
+        let trace = <dyn Trace>::from_bytes(bytes);
+        let dec = LibIPTTraceDecoder::new(TraceDecoderConfig::default());
+        let mut itr = LibIPTBlockIterator::new(&dec, &*trace, Span::default());
 
         // First we expect a libipt error.
         match itr.next() {
-            Some(Err(HWTracerError::Custom(e))) => {
+            Some(Err(HWTracerError::Decode(DecodeError::LibIPT(e)))) => {
                 assert!(e.to_string().starts_with("libipt error: "))
             }
             _ => panic!(),
@@ -390,7 +1167,365 @@ mod tests {
 
     #[test]
     fn ten_times_as_many_blocks() {
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         test_helpers::ten_times_as_many_blocks(tc, TraceDecoderKind::LibIPT);
     }
+
+    #[test]
+    fn max_blocks_limit() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let limits = DecodeLimits {
+            max_blocks: Some(10),
+            ..Default::default()
+        };
+        test_helpers::limit_exceeded(tc, TraceDecoderKind::LibIPT, limits, DecodeLimit::Blocks);
+    }
+
+    #[test]
+    fn deadline_limit() {
+        use std::time::Instant;
+
+        let Some(tc) = collector() else {
+            return;
+        };
+        let limits = DecodeLimits {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        test_helpers::limit_exceeded(tc, TraceDecoderKind::LibIPT, limits, DecodeLimit::Deadline);
+    }
+
+    #[test]
+    fn addr_ranges() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        test_helpers::addr_ranges(tc, TraceDecoderKind::LibIPT);
+    }
+
+    #[test]
+    fn decode_until() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        test_helpers::decode_until(tc, TraceDecoderKind::LibIPT);
+    }
+
+    #[test]
+    fn decode_on_other_threads() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        test_helpers::decode_on_other_threads(tc, TraceDecoderKind::LibIPT);
+    }
+
+    /// Check that branch-only decoding reports the outcomes of a loop's branches.
+    #[test]
+    fn branch_outcomes() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .build()
+            .unwrap();
+
+        let count_conds = |iters| {
+            let trace = trace_closure(&tc, || work_loop(iters)).unwrap();
+            dec.iter_branches(&*trace)
+                .map(|b| b.unwrap())
+                .filter(|b| matches!(b, BranchOutcome::Conditional(_)))
+                .count()
+        };
+        let small = count_conds(10);
+        let big = count_conds(100);
+        assert!(small > 0);
+        assert!(big > small * 5);
+    }
+
+    /// Check that instruction-level decoding agrees with block decoding.
+    #[test]
+    fn insns_versus_blocks() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(10)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .build()
+            .unwrap();
+        let blocks = dec
+            .iter_blocks(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let insns = dec
+            .iter_insns(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(insns.len() > blocks.len());
+        assert_eq!(insns[0].ip(), blocks[0].first_instr());
+        assert!(insns.iter().all(|i| !i.bytes().is_empty()));
+    }
+
+    /// Check that a real trace starts by enabling tracing and ends by disabling it.
+    #[test]
+    fn events_enable_disable() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(10)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .build()
+            .unwrap();
+        let evs = dec
+            .iter_events(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let first_enable = evs
+            .iter()
+            .position(|e| matches!(e, DecodeEvent::TracingEnabled(_)))
+            .unwrap();
+        let last_disable = evs
+            .iter()
+            .rposition(|e| matches!(e, DecodeEvent::TracingDisabled(_)))
+            .unwrap();
+        assert!(first_enable < last_disable);
+        assert!(evs.contains(&DecodeEvent::ExecMode(ExecMode::Bits64)));
+    }
+
+    /// Check that an empty trace decodes to no blocks, rather than failing to sync.
+    #[test]
+    fn empty_trace() {
+        let trace = <dyn Trace>::from_bytes(Vec::new());
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .build()
+            .unwrap();
+        assert_eq!(dec.iter_blocks(&*trace).count(), 0);
+    }
+
+    /// Check that blocks executed in TSX transactions are marked as speculative, and that those of
+    /// aborted transactions are thrown away.
+    #[test]
+    fn tsx_blocks() {
+        // Blocks of a `nop` and a `jz` to the next block, whichever way it goes.
+        const BASE: u64 = 0x10_0000;
+        let code = [0x90, 0x74, 0x00].repeat(16);
+        let len = u64::try_from(code.len()).unwrap();
+        let reader = MemReader::new(move |vaddr, buf| {
+            if !(BASE..BASE + len).contains(&vaddr) {
+                return 0;
+            }
+            let code = &code[usize::try_from(vaddr - BASE).unwrap()..];
+            let n = buf.len().min(code.len());
+            buf[..n].copy_from_slice(&code[..n]);
+            n
+        });
+        let blk = |i: u64| BASE + i * 3;
+        let mut enc = Encoder::new();
+        enc.psb()
+            .mode_exec(ExecMode::Bits64)
+            .fup(blk(0), IPCompression::Full)
+            .psbend()
+            .tnt(&[true])
+            // A transaction which is committed.
+            .mode_tsx(true, false)
+            .fup(blk(1), IPCompression::Update16)
+            .tnt(&[true, true])
+            .mode_tsx(false, false)
+            .fup(blk(3), IPCompression::Update16)
+            .tnt(&[true])
+            // A transaction which is aborted, jumping to a handler.
+            .mode_tsx(true, false)
+            .fup(blk(4), IPCompression::Update16)
+            .tnt(&[true, true])
+            .mode_tsx(false, true)
+            .fup(blk(6), IPCompression::Update16)
+            .tip(blk(10), IPCompression::Update16)
+            .tnt(&[true]);
+        let trace = <dyn Trace>::from_bytes(enc.into_bytes());
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .mem_reader(reader)
+            .build()
+            .unwrap();
+        let blocks = dec
+            .iter_blocks(&*trace)
+            .map(|b| b.map(|b| (b.first_instr(), b.is_speculative())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            [
+                (blk(0), false),
+                (blk(1), true),
+                (blk(2), true),
+                (blk(3), false),
+                (blk(10), false)
+            ]
+        );
+    }
+
+    /// Check that a memory reader lets the decoder follow control flow through code which isn't
+    /// backed by a file.
+    #[test]
+    fn mem_reader_jit_code() {
+        use libc::{
+            mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE,
+        };
+        use std::mem;
+
+        const PAGE_SIZE: usize = 4096;
+
+        // "JIT" a function consisting of a lone `ret` instruction into an anonymous mapping.
+        let page = unsafe {
+            mmap(
+                ptr::null_mut(),
+                PAGE_SIZE,
+                PROT_READ | PROT_WRITE | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, MAP_FAILED);
+        unsafe { *(page as *mut u8) = 0xc3 };
+        let jit_fn: extern "C" fn() = unsafe { mem::transmute(page) };
+
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || {
+            jit_fn();
+            0
+        })
+        .unwrap();
+
+        // Without a memory reader, libipt has no way to get at the JITted code, and says where it
+        // was missing.
+        let start = page as u64;
+        let end = start + u64::try_from(PAGE_SIZE).unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let w = Arc::clone(&warnings);
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .warning_handler(WarningHandler::new(move |warning| {
+                w.lock().unwrap().push(warning.clone())
+            }))
+            .build()
+            .unwrap();
+        match dec.iter_blocks(&*trace).collect::<Result<Vec<_>, _>>() {
+            Err(HWTracerError::Decode(DecodeError::LibIPT(e))) => {
+                assert_eq!(e.kind(), LibIPTErrorKind::NoMap)
+            }
+            _ => panic!(),
+        }
+        assert!(warnings
+            .lock()
+            .unwrap()
+            .contains(&DecodeWarning::MissingCode(start)));
+
+        let reader = MemReader::new(move |vaddr, buf| {
+            if !(start..end).contains(&vaddr) {
+                return 0;
+            }
+            let n = buf.len().min(usize::try_from(end - vaddr).unwrap());
+            unsafe { ptr::copy_nonoverlapping(vaddr as *const u8, buf.as_mut_ptr(), n) };
+            n
+        });
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .mem_reader(reader)
+            .build()
+            .unwrap();
+        let blocks = dec
+            .iter_blocks(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(blocks.iter().any(|b| b.first_instr() == start));
+
+        unsafe { munmap(page, PAGE_SIZE) };
+    }
+
+    /// Check that decoders sharing a section cache decode the same as those using private caches.
+    #[test]
+    fn shared_section_cache() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(10)).unwrap();
+        let decode = |dec: Box<dyn TraceDecoder>| {
+            dec.iter_blocks(&*trace)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let expect = decode(
+            TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::LibIPT)
+                .build()
+                .unwrap(),
+        );
+        let cache = SectionCache::new(1024 * 1024).unwrap();
+        for _ in 0..2 {
+            let dec = TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::LibIPT)
+                .section_cache(cache.clone())
+                .build()
+                .unwrap();
+            assert_eq!(decode(dec), expect);
+        }
+    }
+
+    /// Check that a trace made from raw packet data in a file decodes the same as the trace the
+    /// data came from.
+    #[test]
+    fn decode_from_file() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(10)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .build()
+            .unwrap();
+        let expect = dec
+            .iter_blocks(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut tmpf = NamedTempFile::new().unwrap();
+        trace.to_file(tmpf.as_file_mut());
+        let loaded = <dyn Trace>::from_file(tmpf.path()).unwrap();
+        test_helpers::test_expected_blocks(loaded, TraceDecoderKind::LibIPT, expect.iter());
+    }
+
+    /// Check that the byte limit applies to branch-only decoding.
+    #[test]
+    fn branch_outcomes_max_bytes() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(100)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::LibIPT)
+            .limits(DecodeLimits {
+                max_bytes: Some(1),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let res = dec.iter_branches(&*trace).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(
+            res,
+            Err(HWTracerError::Decode(DecodeError::LimitExceeded(
+                DecodeLimit::Bytes
+            )))
+        ));
+    }
 }
@@ -0,0 +1,214 @@
+//! Measurement of decoding throughput.
+
+use std::{
+    cell::Cell,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Measurements of one or more decodes, as recorded by [DecodeMetrics].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DecodeStats {
+    /// The number of trace bytes consumed.
+    pub bytes: usize,
+    /// The number of packets decoded, not counting PADs. Only counted by decoders which operate
+    /// at the packet level (currently only the YkPT decoder).
+    pub packets: usize,
+    /// The number of blocks decoded.
+    pub blocks: usize,
+    /// The time from the start of decoding until the first packet or block was decoded: the time
+    /// spent finding the start of the trace and, for decoders which read the traced code (e.g.
+    /// libipt), loading it.
+    pub setup_time: Duration,
+    /// The time spent decoding after the setup phase.
+    pub decode_time: Duration,
+}
+
+impl DecodeStats {
+    /// Returns the total time spent decoding.
+    pub fn elapsed(&self) -> Duration {
+        self.setup_time + self.decode_time
+    }
+
+    /// Returns the number of trace bytes consumed per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.per_sec(self.bytes)
+    }
+
+    /// Returns the number of packets decoded per second.
+    pub fn packets_per_sec(&self) -> f64 {
+        self.per_sec(self.packets)
+    }
+
+    /// Returns the number of blocks decoded per second.
+    pub fn blocks_per_sec(&self) -> f64 {
+        self.per_sec(self.blocks)
+    }
+
+    /// Returns `n` divided by the elapsed time in seconds, or zero if no time elapsed.
+    fn per_sec(&self, n: usize) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            n as f64 / secs
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+        self.blocks += other.blocks;
+        self.setup_time += other.setup_time;
+        self.decode_time += other.decode_time;
+    }
+}
+
+/// Records the [DecodeStats] of the decodes done by a decoder, so that the performance of decoding
+/// can be monitored (e.g. to spot pathological traces in production).
+///
+/// Metrics are only recorded if set with [super::TraceDecoderBuilder::metrics], as measuring has a
+/// (small) cost. A decode is recorded when it finishes: when the iterator doing it is dropped, or
+/// when methods such as [super::TraceDecoder::decode_until] return. Decodes stopped by an error
+/// or a limit are recorded too.
+///
+/// Clones of a `DecodeMetrics` share their measurements, so one may be shared by many decoders,
+/// and read from any thread.
+///
+/// ```no_run
+/// use hwtracer::{decode::{DecodeMetrics, TraceDecoderBuilder}, Trace};
+/// # let trace: Box<dyn Trace> = todo!();
+/// let metrics = DecodeMetrics::new();
+/// let dec = TraceDecoderBuilder::new().metrics(metrics.clone()).build().unwrap();
+/// let blocks = dec.iter_blocks(&*trace).collect::<Result<Vec<_>, _>>().unwrap();
+/// let stats = metrics.last().unwrap();
+/// println!("{} bytes/sec, {:?} setting up", stats.bytes_per_sec(), stats.setup_time);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DecodeMetrics(Arc<Mutex<MetricsInner>>);
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    last: Option<DecodeStats>,
+    total: DecodeStats,
+    decodes: u64,
+}
+
+impl DecodeMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the stats of the most recently finished decode, if there has been one.
+    pub fn last(&self) -> Option<DecodeStats> {
+        self.0.lock().unwrap().last.clone()
+    }
+
+    /// Returns the stats of all of the decodes recorded, summed.
+    pub fn total(&self) -> DecodeStats {
+        self.0.lock().unwrap().total.clone()
+    }
+
+    /// Returns the number of decodes recorded.
+    pub fn decodes(&self) -> u64 {
+        self.0.lock().unwrap().decodes
+    }
+
+    /// Forget everything recorded so far.
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = MetricsInner::default();
+    }
+
+    fn record(&self, stats: DecodeStats) {
+        let mut inner = self.0.lock().unwrap();
+        inner.total.add(&stats);
+        inner.decodes += 1;
+        inner.last = Some(stats);
+    }
+}
+
+/// Measures a single decode, on behalf of a [super::LimitTracker].
+#[derive(Debug)]
+pub(crate) struct MetricsRecorder {
+    metrics: DecodeMetrics,
+    start: Instant,
+    /// When the first packet or block was decoded, ending the setup phase.
+    first: Cell<Option<Instant>>,
+    /// The furthest offset into the trace reached.
+    bytes: Cell<usize>,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn new(metrics: DecodeMetrics) -> Self {
+        Self {
+            metrics,
+            start: Instant::now(),
+            first: Cell::new(None),
+            bytes: Cell::new(0),
+        }
+    }
+
+    /// Record that the decoder has consumed `offset` bytes of the trace.
+    pub(crate) fn progress(&self, offset: usize) {
+        if self.first.get().is_none() {
+            self.first.set(Some(Instant::now()));
+        }
+        self.bytes.set(self.bytes.get().max(offset));
+    }
+
+    /// Record the finished decode, which decoded `packets` packets and `blocks` blocks.
+    pub(crate) fn finish(self, packets: usize, blocks: usize) {
+        let end = Instant::now();
+        let first = self.first.get().unwrap_or(end);
+        self.metrics.record(DecodeStats {
+            bytes: self.bytes.get(),
+            packets,
+            blocks,
+            setup_time: first - self.start,
+            decode_time: end - first,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeMetrics, DecodeStats, MetricsRecorder};
+    use std::time::Duration;
+
+    #[test]
+    fn record() {
+        let metrics = DecodeMetrics::new();
+        assert_eq!(metrics.last(), None);
+        for n in 1..=2 {
+            let rec = MetricsRecorder::new(metrics.clone());
+            rec.progress(100 * n);
+            rec.progress(10);
+            rec.finish(n, 2 * n);
+        }
+        let last = metrics.last().unwrap();
+        assert_eq!((last.bytes, last.packets, last.blocks), (200, 2, 4));
+        assert_eq!(metrics.decodes(), 2);
+        let total = metrics.total();
+        assert_eq!((total.bytes, total.packets, total.blocks), (300, 3, 6));
+
+        metrics.reset();
+        assert_eq!(metrics.decodes(), 0);
+        assert_eq!(metrics.last(), None);
+    }
+
+    #[test]
+    fn rates() {
+        let stats = DecodeStats {
+            bytes: 3000,
+            packets: 300,
+            blocks: 30,
+            setup_time: Duration::from_millis(500),
+            decode_time: Duration::from_millis(2500),
+        };
+        assert_eq!(stats.elapsed(), Duration::from_secs(3));
+        assert_eq!(stats.bytes_per_sec(), 1000.0);
+        assert_eq!(stats.packets_per_sec(), 100.0);
+        assert_eq!(stats.blocks_per_sec(), 10.0);
+        assert_eq!(DecodeStats::default().bytes_per_sec(), 0.0);
+    }
+}
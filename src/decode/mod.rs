@@ -1,27 +1,99 @@
 //! Trace decoders.
 
-use crate::{errors::HWTracerError, Block, Trace};
+#[cfg(feature = "metrics")]
+use crate::instrument;
+pub use crate::pt::ExecMode;
+use crate::{
+    errors::{ConfigError, DecodeError, HWTracerError},
+    Block, Insn, Trace,
+};
+#[cfg(feature = "metrics")]
+use std::cell::Cell;
+use std::{
+    env, fmt,
+    io::Write,
+    iter,
+    ops::{ControlFlow, Range},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+mod cache;
+pub use cache::DecodeCache;
+#[cfg(feature = "fuzz")]
+mod fuzz;
+#[cfg(feature = "fuzz")]
+pub use fuzz::fuzz_bytes;
+mod cancel;
+pub use cancel::CancellationToken;
+mod capture;
+use capture::WatchingDecoder;
+mod diagnostics;
+pub use diagnostics::DecodeDiagnostics;
+mod metrics;
+// `self::` tells the module apart from the `metrics` crate.
+use self::metrics::MetricsRecorder;
+pub use self::metrics::{DecodeMetrics, DecodeStats};
+mod prefix;
+pub use prefix::{DecodedPrefix, PrefixEnd};
+mod reuse;
+pub use reuse::ReusableDecoder;
+
 #[cfg(decoder_libipt)]
 pub(crate) mod libipt;
 #[cfg(decoder_libipt)]
 use libipt::LibIPTTraceDecoder;
+#[cfg(decoder_libipt)]
+pub use libipt::SectionCache;
 
 #[cfg(decoder_ykpt)]
 mod ykpt;
 #[cfg(decoder_ykpt)]
 use ykpt::YkPTTraceDecoder;
 
-#[derive(Clone, Copy, Debug, EnumIter)]
+#[derive(Clone, Copy, Debug, EnumIter, Eq, PartialEq)]
 pub enum TraceDecoderKind {
     LibIPT,
     YkPT,
+    /// A decoder from outside of hwtracer, registered with [register_decoder] under the given
+    /// name.
+    #[strum(disabled)]
+    Custom(&'static str),
 }
 
 impl TraceDecoderKind {
-    /// Returns the default kind of decoder for the current platform.
+    /// The environment variable consulted by [TraceDecoderBuilder::kind_from_env]. Its value is
+    /// parsed with [TraceDecoderKind::from_str].
+    pub const ENV_VAR: &'static str = "HWTRACER_DECODER";
+
+    /// Returns `true` if this kind of decoder was compiled into hwtracer and is appropriate for
+    /// the current platform.
+    pub fn is_available(&self) -> bool {
+        self.match_platform().is_ok()
+    }
+
+    /// Returns the kinds of decoder which are available (see [TraceDecoderKind::is_available]):
+    /// hwtracer's own in order of preference, followed by any registered with [register_decoder].
+    pub fn available() -> Vec<Self> {
+        let mut kinds = Self::iter()
+            .filter(|k| k.is_available())
+            .collect::<Vec<_>>();
+        kinds.extend(
+            CUSTOM_DECODERS
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(name, _)| Self::Custom(name)),
+        );
+        kinds
+    }
+
+    /// Returns the default kind of decoder for the current platform. Custom decoders are never
+    /// chosen by default.
     fn default_for_platform() -> Option<Self> {
         for kind in Self::iter() {
             if Self::match_platform(&kind).is_ok() {
@@ -38,21 +110,674 @@ impl TraceDecoderKind {
                 #[cfg(decoder_libipt)]
                 return Ok(());
                 #[cfg(not(decoder_libipt))]
-                return Err(HWTracerError::DecoderUnavailable(Self::LibIPT));
+                return Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+                    Self::LibIPT,
+                )));
             }
             Self::YkPT => {
                 #[cfg(decoder_ykpt)]
                 return Ok(());
                 #[cfg(not(decoder_ykpt))]
-                return Err(HWTracerError::DecoderUnavailable(Self::YkPT));
+                return Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+                    Self::YkPT,
+                )));
+            }
+            Self::Custom(name) => match custom_decoder(name) {
+                Some(_) => Ok(()),
+                None => Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+                    *self,
+                ))),
+            },
+        }
+    }
+}
+
+/// Creates a custom decoder, as registered with [register_decoder].
+type DecoderFactory = fn(TraceDecoderConfig) -> Box<dyn TraceDecoder>;
+
+/// Decoders registered with [register_decoder], keyed by name.
+static CUSTOM_DECODERS: RwLock<Vec<(&'static str, DecoderFactory)>> = RwLock::new(Vec::new());
+
+/// Register the decoder `D` under `name`, so that it can be selected in the same way as
+/// hwtracer's own decoders: with [TraceDecoderBuilder::kind], by parsing `name` (e.g. from the
+/// [TraceDecoderKind::ENV_VAR] environment variable), and so on. Decoders are built by calling
+/// [TraceDecoder::new] with the builder's [TraceDecoderConfig].
+///
+/// Returns the kind to select the decoder with. An error is returned if `name` is empty, or if it
+/// is already the name (ignoring case) of a decoder.
+pub fn register_decoder<D>(name: &'static str) -> Result<TraceDecoderKind, HWTracerError>
+where
+    D: TraceDecoder + 'static,
+{
+    fn factory<D: TraceDecoder + 'static>(config: TraceDecoderConfig) -> Box<dyn TraceDecoder> {
+        Box::new(D::new(config))
+    }
+
+    if name.is_empty() {
+        return Err(HWTracerError::Config(ConfigError::Invalid(
+            "trace decoder names can't be empty".into(),
+        )));
+    }
+    let mut decoders = CUSTOM_DECODERS.write().unwrap();
+    let clashes = TraceDecoderKind::iter()
+        .map(|k| k.to_string())
+        .chain(decoders.iter().map(|(n, _)| n.to_string()))
+        .any(|n| n.eq_ignore_ascii_case(name));
+    if clashes {
+        return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+            "trace decoder already registered: {}",
+            name
+        ))));
+    }
+    decoders.push((name, factory::<D>));
+    debug!(name, "registered custom trace decoder");
+    Ok(TraceDecoderKind::Custom(name))
+}
+
+/// Returns the name and factory of the registered decoder called `name` (ignoring case).
+fn custom_decoder(name: &str) -> Option<(&'static str, DecoderFactory)> {
+    CUSTOM_DECODERS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+impl FromStr for TraceDecoderKind {
+    type Err = HWTracerError;
+
+    /// Parse the name of a kind of decoder (`libipt`, `ykpt` or the name of a decoder registered
+    /// with [register_decoder]), ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::iter()
+            .find(|k| k.to_string().eq_ignore_ascii_case(s))
+            .or_else(|| custom_decoder(s).map(|(name, _)| Self::Custom(name)))
+            .ok_or_else(|| {
+                HWTracerError::Config(ConfigError::Invalid(format!(
+                    "unknown trace decoder: {}",
+                    s
+                )))
+            })
+    }
+}
+
+impl fmt::Display for TraceDecoderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LibIPT => write!(f, "libipt"),
+            Self::YkPT => write!(f, "ykpt"),
+            Self::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Limits on the resources that a decoder may consume while decoding a trace.
+///
+/// A limit of `None` means "unlimited". When a limit is exceeded, the decoder stops and reports
+/// [DecodeError::LimitExceeded].
+#[derive(Clone, Debug, Default)]
+pub struct DecodeLimits {
+    /// The maximum number of packets to decode, not counting PADs. This is only enforced by
+    /// decoders which operate at the packet level (currently only the YkPT decoder).
+    pub max_packets: Option<usize>,
+    /// The maximum number of blocks to decode.
+    pub max_blocks: Option<usize>,
+    /// The maximum number of trace bytes to consume.
+    pub max_bytes: Option<usize>,
+    /// A point in time after which decoding should be abandoned.
+    pub deadline: Option<Instant>,
+    /// A token with which decoding can be abandoned from another thread. Once it is cancelled,
+    /// the decoder stops and reports [DecodeError::Cancelled].
+    pub cancel: Option<CancellationToken>,
+}
+
+/// Identifies which of the [DecodeLimits] was exceeded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeLimit {
+    Packets,
+    Blocks,
+    Bytes,
+    Deadline,
+}
+
+/// Keeps track of the resources consumed by a decoder, checking them against the [DecodeLimits].
+/// If metrics are being recorded (see [DecodeMetrics], and the `metrics` feature), the decode is
+/// recorded when the tracker is dropped.
+///
+/// Custom decoders (see [register_decoder]) can use this to enforce the limits, and record the
+/// metrics, in their [TraceDecoderConfig] in the same way as hwtracer's own decoders.
+#[derive(Debug)]
+pub struct LimitTracker {
+    limits: DecodeLimits,
+    /// The number of packets decoded so far.
+    packets: usize,
+    /// The number of blocks decoded so far.
+    blocks: usize,
+    /// The number of branch decisions parsed from TNT packets but not yet consumed.
+    tnt_pending: usize,
+    /// If `true`, [LimitTracker::finish] checks that the whole trace was consumed.
+    verify_complete: bool,
+    /// Measures the decode, if metrics are being recorded.
+    metrics: Option<MetricsRecorder>,
+    /// When the decode started.
+    #[cfg(feature = "metrics")]
+    started: Instant,
+    /// The furthest offset into the trace reached.
+    #[cfg(feature = "metrics")]
+    consumed: Cell<usize>,
+}
+
+impl LimitTracker {
+    pub fn new(limits: DecodeLimits) -> Self {
+        Self {
+            limits,
+            packets: 0,
+            blocks: 0,
+            tnt_pending: 0,
+            verify_complete: false,
+            metrics: None,
+            #[cfg(feature = "metrics")]
+            started: Instant::now(),
+            #[cfg(feature = "metrics")]
+            consumed: Cell::new(0),
+        }
+    }
+
+    /// Make a tracker for a decode using `config`, which enforces its limits and records its
+    /// metrics (if any).
+    pub fn for_config(config: &TraceDecoderConfig) -> Self {
+        let mut tracker = Self::new(config.limits.clone());
+        tracker.metrics = config.metrics.clone().map(MetricsRecorder::new);
+        tracker.verify_complete = config.verify_complete;
+        tracker
+    }
+
+    /// Record that a packet was decoded, leaving the decoder having consumed `offset` bytes of
+    /// the trace.
+    pub fn packet(&mut self, offset: usize) -> Result<(), HWTracerError> {
+        self.packets += 1;
+        if matches!(self.limits.max_packets, Some(max) if self.packets > max) {
+            return Err(self.exceeded(DecodeLimit::Packets));
+        }
+        self.bytes(offset)
+    }
+
+    /// Record that a block was decoded, leaving the decoder having consumed `offset` bytes of
+    /// the trace.
+    pub fn block(&mut self, offset: usize) -> Result<(), HWTracerError> {
+        self.blocks += 1;
+        if matches!(self.limits.max_blocks, Some(max) if self.blocks > max) {
+            return Err(self.exceeded(DecodeLimit::Blocks));
+        }
+        self.bytes(offset)
+    }
+
+    /// Check that the decoder having consumed `offset` bytes of the trace is within limits.
+    ///
+    /// The deadline and the cancellation token are also checked here, since this is called
+    /// regularly by all decoders.
+    pub fn bytes(&self, offset: usize) -> Result<(), HWTracerError> {
+        if let Some(m) = &self.metrics {
+            m.progress(offset);
+        }
+        #[cfg(feature = "metrics")]
+        self.consumed.set(self.consumed.get().max(offset));
+        if matches!(self.limits.max_bytes, Some(max) if offset > max) {
+            return Err(self.exceeded(DecodeLimit::Bytes));
+        }
+        if matches!(self.limits.deadline, Some(deadline) if Instant::now() > deadline) {
+            return Err(self.exceeded(DecodeLimit::Deadline));
+        }
+        if matches!(&self.limits.cancel, Some(token) if token.is_cancelled()) {
+            debug!(
+                packets = self.packets,
+                blocks = self.blocks,
+                "decode cancelled"
+            );
+            return Err(HWTracerError::Decode(DecodeError::Cancelled));
+        }
+        Ok(())
+    }
+
+    /// Record that `n` branch decisions were parsed from a TNT packet.
+    pub fn tnt_parsed(&mut self, n: usize) {
+        self.tnt_pending += n;
+    }
+
+    /// Record that `n` of the branch decisions parsed were consumed (e.g. to walk the traced code).
+    pub fn tnt_consumed(&mut self, n: usize) {
+        self.tnt_pending = self.tnt_pending.saturating_sub(n);
+    }
+
+    /// Record that the decoder reached what it took to be the end of the trace, having consumed
+    /// `offset` of its `len` bytes.
+    ///
+    /// If [TraceDecoderConfig::verify_complete] is set and any bytes of the trace, or any branch
+    /// decisions recorded with [LimitTracker::tnt_parsed], weren't consumed, a
+    /// [DecodeError::Incomplete] error is returned. Otherwise this does nothing.
+    pub fn finish(&self, offset: usize, len: usize) -> Result<(), HWTracerError> {
+        let bytes = len.saturating_sub(offset);
+        if !self.verify_complete || (bytes == 0 && self.tnt_pending == 0) {
+            return Ok(());
+        }
+        debug!(bytes, tnt = self.tnt_pending, "decode incomplete");
+        Err(HWTracerError::Decode(DecodeError::Incomplete {
+            bytes,
+            tnt: self.tnt_pending,
+        }))
+    }
+
+    /// Make the error reporting that `limit` was exceeded.
+    fn exceeded(&self, limit: DecodeLimit) -> HWTracerError {
+        debug!(
+            ?limit,
+            packets = self.packets,
+            blocks = self.blocks,
+            "decode limit exceeded"
+        );
+        HWTracerError::Decode(DecodeError::LimitExceeded(limit))
+    }
+}
+
+impl Drop for LimitTracker {
+    fn drop(&mut self) {
+        if let Some(m) = self.metrics.take() {
+            m.finish(self.packets, self.blocks);
+        }
+        #[cfg(feature = "metrics")]
+        instrument::decode_finished(self.consumed.get(), self.started.elapsed());
+    }
+}
+
+/// Write a textual dump of the packets in `trace` to `w`, one packet per line, in the same style
+/// as libipt's `ptdump` tool.
+///
+/// Packets are parsed by the YkPT decoder's packet parser, so comparing the dump with `ptdump`'s
+/// output for the same trace is a good way to track down problems in that parser. Data before the
+/// first PSB packet is skipped. If a packet can't be parsed, the error is written to `w` (as
+/// `ptdump` would) and returned.
+pub fn dump_packets(trace: &dyn Trace, w: &mut dyn Write) -> Result<(), HWTracerError> {
+    #[cfg(decoder_ykpt)]
+    return ykpt::dump_packets(trace, w);
+    #[cfg(not(decoder_ykpt))]
+    {
+        let _ = (trace, w);
+        Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+            TraceDecoderKind::YkPT,
+        )))
+    }
+}
+
+/// Iterate over the packets in `trace`, as `(offset, text)` pairs, where `text` is formatted as
+/// by [dump_packets]. Iteration stops after the first packet that can't be parsed.
+pub fn iter_packets(
+    trace: &dyn Trace,
+) -> Box<dyn Iterator<Item = Result<(usize, String), HWTracerError>> + '_> {
+    #[cfg(decoder_ykpt)]
+    return Box::new(ykpt::iter_packets(trace));
+    #[cfg(not(decoder_ykpt))]
+    {
+        let _ = trace;
+        Box::new(iter::once(Err(HWTracerError::Config(
+            ConfigError::DecoderUnavailable(TraceDecoderKind::YkPT),
+        ))))
+    }
+}
+
+/// Gather diagnostics about the failure of decoding `trace` with the error `err` (which may have
+/// come from any decoder): where it happened, the packets leading up to it, the bytes around it
+/// and whether decoding could resynchronise after it. See [DecodeDiagnostics].
+///
+/// The packets are parsed by the YkPT decoder's packet parser. If `err` doesn't say where in the
+/// trace it happened (see [HWTracerError::trace_offset]), the failure is taken to be where that
+/// parser fails, or the end of the trace if it doesn't.
+pub fn diagnose(
+    trace: &dyn Trace,
+    err: &HWTracerError,
+) -> Result<DecodeDiagnostics, HWTracerError> {
+    #[cfg(decoder_ykpt)]
+    return ykpt::diagnose(trace, err);
+    #[cfg(not(decoder_ykpt))]
+    {
+        let _ = (trace, err);
+        Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+            TraceDecoderKind::YkPT,
+        )))
+    }
+}
+
+/// Returns `true` if tracing was still enabled at the end of `trace`: i.e. if the last TIP.PGE
+/// packet in the trace isn't followed by a TIP.PGD packet.
+fn ends_enabled(trace: &dyn Trace) -> Result<bool, HWTracerError> {
+    #[cfg(decoder_ykpt)]
+    return ykpt::ends_enabled(trace);
+    #[cfg(not(decoder_ykpt))]
+    {
+        let _ = trace;
+        Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+            TraceDecoderKind::YkPT,
+        )))
+    }
+}
+
+/// A high-level event that occurred during the execution of a traced program.
+///
+/// Events describe things other than straight-line control flow. All addresses are virtual
+/// addresses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecodeEvent {
+    /// Tracing was enabled (TIP.PGE), with execution resuming at the specified address.
+    TracingEnabled(u64),
+    /// Tracing was disabled (TIP.PGD), marking the end of a traced region.
+    ///
+    /// If the hardware reported where control went when tracing stopped, the address is
+    /// included. Often it isn't (e.g. because control went into the kernel), in which case this
+    /// is `None`: the traced region ended and the destination is unknown.
+    TracingDisabled(Option<u64>),
+    /// The hardware's internal buffers overflowed (OVF) and trace packets were lost.
+    Overflow,
+    /// The execution mode of the CPU changed (MODE.Exec).
+    ExecMode(ExecMode),
+    /// The core:bus ratio (and thus the core clock frequency) changed (CBR).
+    CoreBusRatio(u8),
+    /// A `PTWRITE` instruction wrote the specified value into the trace (PTW).
+    PTWrite(u64),
+    /// Control was asynchronously transferred (e.g. by an interrupt or exception) from the
+    /// instruction at `from` to `to`. `to` is `None` if the destination wasn't reported.
+    AsyncTransfer { from: u64, to: Option<u64> },
+    /// Control was transferred to an address that the hardware didn't report because it was out
+    /// of the traced context (e.g. a TIP packet with a suppressed IP). Control flow can't be
+    /// followed again until the next event or packet carrying a full IP.
+    ContextLost,
+    /// The trace moved from one collection session to the next (see [crate::Trace::concat]), or
+    /// across a gap where trace data was left out (see [crate::Trace::sample_psb_regions]).
+    /// Control flow doesn't continue across the boundary.
+    SessionBoundary,
+    /// The traced thread was switched out (`out == true`), so that what follows comes from
+    /// another context, or switched back in (`out == false`). Found from PIP packets (a change of
+    /// address space, see [crate::pt::PIPPacket]) and from [crate::SidebandEvent::Switch] records.
+    /// See [TraceDecoderConfig::traced_context_only].
+    ContextSwitch { out: bool },
+    /// A TSX transaction began (MODE.TSX). The events up to the matching
+    /// [DecodeEvent::TransactionCommit] happened speculatively inside the transaction.
+    ///
+    /// A transaction's events are only reported once it commits, so this is always followed by a
+    /// commit: the events of a transaction that aborts (or whose outcome is lost, e.g. to an
+    /// overflow) are discarded, since their effects were rolled back. Only reported by the ykpt
+    /// decoder.
+    TransactionBegin,
+    /// The transaction started by the last [DecodeEvent::TransactionBegin] committed.
+    TransactionCommit,
+    /// A TSX transaction aborted at the instruction at `from`, and control went to the abort
+    /// handler at `to`. Either address is `None` if it wasn't reported. The transaction's events
+    /// are discarded. Only reported by the ykpt decoder.
+    TransactionAbort { from: Option<u64>, to: Option<u64> },
+}
+
+/// A branch outcome recorded in a trace, as reported by [TraceDecoder::iter_branches].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BranchOutcome {
+    /// A conditional branch was taken (`true`) or not taken (`false`).
+    Conditional(bool),
+    /// An indirect branch, return or far transfer went to the specified address. `None` if the
+    /// address wasn't reported (e.g. because it was out of the traced context).
+    Indirect(Option<u64>),
+}
+
+/// Restricts decoder output to blocks and events in a set of virtual address ranges.
+///
+/// Custom decoders can use this to honour [TraceDecoderConfig::addr_ranges].
+#[derive(Clone, Debug)]
+pub struct AddrFilter {
+    /// Sorted, non-overlapping, non-empty address ranges. If empty, everything matches.
+    ranges: Vec<Range<u64>>,
+}
+
+impl AddrFilter {
+    pub fn new(ranges: &[Range<u64>]) -> Self {
+        let mut sorted = ranges
+            .iter()
+            .filter(|r| !r.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        sorted.sort_by_key(|r| r.start);
+        // Merge overlapping and adjacent ranges so that `matches` can binary search.
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+        for r in sorted {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    /// Returns `true` if `addr` should be included in the decoder's output.
+    pub fn matches(&self, addr: u64) -> bool {
+        if self.ranges.is_empty() {
+            return true;
+        }
+        // Find the last range starting at or before `addr`.
+        let idx = self.ranges.partition_point(|r| r.start <= addr);
+        idx > 0 && addr < self.ranges[idx - 1].end
+    }
+
+    /// Returns `true` if `ev` should be included in the decoder's output. Events which don't
+    /// carry an address always match.
+    pub fn matches_event(&self, ev: &DecodeEvent) -> bool {
+        match *ev {
+            DecodeEvent::TracingEnabled(ip) | DecodeEvent::TracingDisabled(Some(ip)) => {
+                self.matches(ip)
+            }
+            DecodeEvent::AsyncTransfer { from, to } => {
+                self.matches(from) || matches!(to, Some(to) if self.matches(to))
+            }
+            DecodeEvent::TransactionAbort { from, to } => {
+                matches!(from, Some(from) if self.matches(from))
+                    || matches!(to, Some(to) if self.matches(to))
+                    || (from.is_none() && to.is_none())
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Supplies the decoder with code that it can't find in any file, such as JITted code living in
+/// an anonymous mapping.
+///
+/// The wrapped function is passed a virtual address and a buffer. It should copy as many bytes of
+/// the code starting at that address as it can (up to the length of the buffer) into the buffer,
+/// and return the number of bytes copied. Returning 0 means that the address isn't mapped. The
+/// function must not panic.
+#[derive(Clone)]
+pub struct MemReader(Arc<MemReadFn>);
+
+/// The type of function wrapped by a [MemReader].
+type MemReadFn = dyn Fn(u64, &mut [u8]) -> usize + Send + Sync;
+
+impl MemReader {
+    /// Wrap the function `f` for use by a decoder.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(u64, &mut [u8]) -> usize + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Read code starting at `vaddr` into `buf`, returning the number of bytes read.
+    pub fn read(&self, vaddr: u64, buf: &mut [u8]) -> usize {
+        (self.0)(vaddr, buf)
+    }
+}
+
+impl fmt::Debug for MemReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MemReader")
+    }
+}
+
+/// Something odd, but recoverable, that a decoder came across while decoding a trace.
+///
+/// Rather than stopping with an error, the decoder reports a warning (see [WarningHandler]) and
+/// carries on, but its output may be less complete than it would otherwise be.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecodeWarning {
+    /// `len` bytes of trace data starting `offset` bytes into the trace couldn't be decoded, and
+    /// were skipped (e.g. data before the first PSB packet).
+    SkippedBytes { offset: usize, len: usize },
+    /// The MODE packet `offset` bytes into the trace has a leaf (i.e. kind of mode) that the
+    /// decoder doesn't understand, and was ignored.
+    UnknownMode { offset: usize, leaf: u8 },
+    /// The decoder needed the code at the specified virtual address, but couldn't find it in any
+    /// file or with the [MemReader].
+    MissingCode(u64),
+}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SkippedBytes { offset, len } => {
+                write!(f, "skipped {} undecodable bytes at offset {}", len, offset)
+            }
+            Self::UnknownMode { offset, leaf } => {
+                write!(
+                    f,
+                    "ignored MODE packet with unknown leaf {:03b} at offset {}",
+                    leaf, offset
+                )
             }
+            Self::MissingCode(vaddr) => write!(f, "no code found at address {:#x}", vaddr),
         }
     }
 }
 
-pub trait TraceDecoder {
+/// Receives the [DecodeWarning]s reported by a decoder.
+///
+/// The wrapped function is called, on the thread doing the decoding, as soon as the decoder comes
+/// across something to warn about. The function must not panic.
+///
+/// ```
+/// use hwtracer::decode::{TraceDecoderBuilder, WarningHandler};
+/// use std::sync::{Arc, Mutex};
+///
+/// let warnings = Arc::new(Mutex::new(Vec::new()));
+/// let w = Arc::clone(&warnings);
+/// let dec = TraceDecoderBuilder::new()
+///     .warning_handler(WarningHandler::new(move |warning| {
+///         w.lock().unwrap().push(warning.clone())
+///     }))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct WarningHandler(Arc<WarnFn>);
+
+/// The type of function wrapped by a [WarningHandler].
+type WarnFn = dyn Fn(&DecodeWarning) + Send + Sync;
+
+impl WarningHandler {
+    /// Wrap the function `f` for use by a decoder.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&DecodeWarning) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Report `warning` to the wrapped function.
+    pub fn warn(&self, warning: &DecodeWarning) {
+        (self.0)(warning)
+    }
+}
+
+impl fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningHandler")
+    }
+}
+
+/// Report `warning` to `handler`, if there is one, and log it.
+pub(crate) fn report_warning(handler: Option<&WarningHandler>, warning: DecodeWarning) {
+    warn!(warning = %warning, "decode warning");
+    if let Some(h) = handler {
+        h.warn(&warning);
+    }
+}
+
+/// Configuration common to all trace decoders.
+#[derive(Clone, Debug, Default)]
+pub struct TraceDecoderConfig {
+    /// Resource limits to apply when decoding.
+    pub limits: DecodeLimits,
+    /// If non-empty, only blocks and events whose addresses fall within these virtual address
+    /// ranges are reported.
+    ///
+    /// Note that packets outside of the ranges must still be parsed, as compressed IP packets
+    /// depend on the IPs that came before them.
+    pub addr_ranges: Vec<Range<u64>>,
+    /// Reads code which isn't backed by a file. Only used by decoders which read code from an
+    /// image of the traced program (currently only libipt).
+    pub mem_reader: Option<MemReader>,
+    /// Receives the recoverable oddities that the decoder comes across. If `None`, they are only
+    /// logged (see the `tracing` feature).
+    pub warning_handler: Option<WarningHandler>,
+    /// If `true`, [TraceDecoder::decode_events_until] parses packets on a separate thread from
+    /// the one reconstructing events from them. Only used by the ykpt decoder.
+    pub pipelined: bool,
+    /// Records the throughput of each decode, if set.
+    pub metrics: Option<DecodeMetrics>,
+    /// If `true`, decoding blocks which reaches the end of the trace without having consumed all
+    /// of it fails with [DecodeError::Incomplete], reporting how much was left over. This is an
+    /// invariant check, which catches decoder bugs that would otherwise silently lose control
+    /// flow.
+    ///
+    /// Leftover bytes are checked by both of hwtracer's decoders. Leftover branch decisions are
+    /// only checked by decoders which track them (currently only the ykpt decoder): libipt keeps
+    /// them to itself.
+    pub verify_complete: bool,
+    /// If `true`, don't check that the objects that code is read from are the builds of them that
+    /// were loaded when the trace was collected (see [crate::TraceMeta::build_ids]). Otherwise, a
+    /// changed object fails decoding with [DecodeError::BuildIdMismatch], rather than silently
+    /// giving the wrong control flow. Only decoders which read code (currently only libipt) check.
+    pub ignore_build_ids: bool,
+    /// If `true`, only events from the traced context are reported: events between a
+    /// [DecodeEvent::ContextSwitch] out of it and the switch back in are dropped (the switches
+    /// themselves are still reported). A trace may contain other contexts if it was collected
+    /// per-CPU, or if the traced thread was preempted by one that shares its trace configuration.
+    ///
+    /// The traced context is the address space of the first PIP packet in the trace (or in each
+    /// session, see [DecodeEvent::SessionBoundary]). Only used by the ykpt decoder.
+    pub traced_context_only: bool,
+    /// If set, each trace that fails to decode because of its contents (see
+    /// [HWTracerError::is_bad_trace]) is saved to this directory, along with a report on the
+    /// failure (see [diagnose]), so that it can be attached to a bug report. The trace is written
+    /// as by [Trace::to_writer] to `hwtracer-failure-<time>-<pid>-<n>.hwt`, and the report to the
+    /// `.txt` file of the same name. The failure is still reported to the caller.
+    ///
+    /// Traces decoded with [TraceDecoder::decode_prefix], which are expected to be cut short,
+    /// aren't saved.
+    pub failure_dir: Option<PathBuf>,
+    /// The cache through which the libipt decoder loads sections of code. Sharing a cache between
+    /// decoders avoids repeatedly re-opening and re-mapping the same ELF sections.
+    #[cfg(decoder_libipt)]
+    pub section_cache: Option<SectionCache>,
+}
+
+/// A trace decoder.
+///
+/// As well as hwtracer's own decoders, this may be implemented by other crates, and the resulting
+/// decoders made available through [TraceDecoderBuilder] with [register_decoder].
+///
+/// Decoders are `Send` and `Sync`, so one decoder can be shared between worker threads (e.g. in an
+/// `Arc`), each decoding a different trace (or the same one) at the same time. The iterators that
+/// a decoder returns aren't `Send`: a trace must be decoded on one thread from start to finish.
+pub trait TraceDecoder: Send + Sync {
     /// Create the trace decoder.
-    fn new() -> Self
+    fn new(config: TraceDecoderConfig) -> Self
     where
         Self: Sized;
 
@@ -61,10 +786,152 @@ pub trait TraceDecoder {
         &'t self,
         trace: &'t dyn Trace,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_>;
+
+    /// Decode the blocks of the trace, passing each to `f`, until either the trace is exhausted or
+    /// `f` returns `ControlFlow::Break`. Blocks after the break aren't decoded.
+    ///
+    /// Returns `true` if decoding was stopped early by `f`.
+    fn decode_until(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&Block) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        for blk in self.iter_blocks(trace) {
+            if f(&blk?).is_break() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Decode the blocks of the trace into `buf`, replacing its contents.
+    ///
+    /// `buf`'s allocation is reused, so decoding many traces into the same buffer (e.g. one per
+    /// worker thread) performs no allocations once the buffer is as large as the largest trace,
+    /// rather than growing a fresh `Vec` from empty for each trace.
+    ///
+    /// If an error occurs, it is returned and `buf` holds the blocks decoded before it.
+    fn decode_blocks_into(
+        &self,
+        trace: &dyn Trace,
+        buf: &mut Vec<Block>,
+    ) -> Result<(), HWTracerError> {
+        buf.clear();
+        for blk in self.iter_blocks(trace) {
+            buf.push(blk?);
+        }
+        Ok(())
+    }
+
+    /// Decode the blocks of a trace which may have been cut short, returning those decoded along
+    /// with the last point up to which they can be fully trusted (see [DecodedPrefix]).
+    ///
+    /// Rather than fail, decoding stops at the first decode error, which is recorded in the
+    /// prefix. A trace which decodes without error, but ends while tracing is still enabled, is
+    /// also reported as incomplete. Other errors (e.g. [ConfigError::Unsupported]) are returned.
+    fn decode_prefix(&self, trace: &dyn Trace) -> Result<DecodedPrefix, HWTracerError> {
+        let mut blocks = Vec::new();
+        for blk in self.iter_blocks(trace) {
+            match blk {
+                Ok(blk) => blocks.push(blk),
+                Err(e @ HWTracerError::Decode(_)) => {
+                    return Ok(DecodedPrefix::new(blocks, PrefixEnd::Error(e)))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let end = match ends_enabled(trace) {
+            Ok(false) => PrefixEnd::Complete,
+            Ok(true) => PrefixEnd::Unterminated,
+            Err(e @ HWTracerError::Decode(_)) => PrefixEnd::Error(e),
+            Err(e) => return Err(e),
+        };
+        Ok(DecodedPrefix::new(blocks, end))
+    }
+
+    /// Decode the events of the trace, passing each to `f`, until either the trace is exhausted or
+    /// `f` returns `ControlFlow::Break`, as [TraceDecoder::decode_until] does for blocks.
+    ///
+    /// If the decoder is configured to be pipelined (see [TraceDecoderConfig::pipelined]) and
+    /// supports it, packets are parsed on another thread, concurrently with the events being
+    /// reconstructed from them and passed to `f`. This speeds up decoding large traces on
+    /// machines with spare cores.
+    fn decode_events_until(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&DecodeEvent) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        for ev in self.iter_events(trace) {
+            if f(&ev?).is_break() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Decode the events of the trace into `buf`, replacing its contents and reusing its
+    /// allocation, as [TraceDecoder::decode_blocks_into] does for blocks.
+    fn decode_events_into(
+        &self,
+        trace: &dyn Trace,
+        buf: &mut Vec<DecodeEvent>,
+    ) -> Result<(), HWTracerError> {
+        buf.clear();
+        for ev in self.iter_events(trace) {
+            buf.push(ev?);
+        }
+        Ok(())
+    }
+
+    /// Iterate over the high-level events of the trace.
+    ///
+    /// Decoders which can't report events yield a single [ConfigError::Unsupported] error.
+    fn iter_events<'t>(
+        &'t self,
+        _trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<DecodeEvent, HWTracerError>> + '_> {
+        Box::new(iter::once(Err(HWTracerError::Config(
+            ConfigError::Unsupported("this decoder can't report events".into()),
+        ))))
+    }
+
+    /// Iterate over the individual instructions executed by the trace.
+    ///
+    /// This is considerably slower than iterating over blocks, as every instruction is decoded.
+    ///
+    /// Decoders which can't report individual instructions yield a single
+    /// [ConfigError::Unsupported] error.
+    fn iter_insns<'t>(
+        &'t self,
+        _trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Insn, HWTracerError>> + '_> {
+        Box::new(iter::once(Err(HWTracerError::Config(
+            ConfigError::Unsupported("this decoder can't report individual instructions".into()),
+        ))))
+    }
+
+    /// Iterate over the outcomes of the branches recorded in the trace, without disassembling
+    /// any code. This is much faster than block decoding, but the caller must know the layout
+    /// of the traced code to make sense of the outcomes.
+    ///
+    /// Address range restrictions don't apply to branch outcomes, since conditional outcomes
+    /// carry no address.
+    ///
+    /// Decoders which can't report branch outcomes yield a single [ConfigError::Unsupported]
+    /// error.
+    fn iter_branches<'t>(
+        &'t self,
+        _trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<BranchOutcome, HWTracerError>> + '_> {
+        Box::new(iter::once(Err(HWTracerError::Config(
+            ConfigError::Unsupported("this decoder can't report branch outcomes".into()),
+        ))))
+    }
 }
 
 pub struct TraceDecoderBuilder {
     kind: TraceDecoderKind,
+    config: TraceDecoderConfig,
 }
 
 impl TraceDecoderBuilder {
@@ -72,6 +939,7 @@ impl TraceDecoderBuilder {
     pub fn new() -> Self {
         Self {
             kind: TraceDecoderKind::default_for_platform().unwrap(),
+            config: TraceDecoderConfig::default(),
         }
     }
 
@@ -81,39 +949,407 @@ impl TraceDecoderBuilder {
         self
     }
 
+    /// Select the first available kind of decoder in `kinds`, which are given in order of
+    /// preference. If none of them are available, the selection is unchanged.
+    pub fn kind_preferences(mut self, kinds: &[TraceDecoderKind]) -> Self {
+        if let Some(kind) = kinds.iter().find(|k| k.is_available()) {
+            self.kind = *kind;
+        }
+        self
+    }
+
+    /// Select the kind of decoder named by the [TraceDecoderKind::ENV_VAR] environment variable,
+    /// falling back on the current selection if the variable isn't set or the decoder it names
+    /// isn't available.
+    ///
+    /// An error is returned if the variable doesn't name a kind of decoder.
+    pub fn kind_from_env(self) -> Result<Self, HWTracerError> {
+        match env::var(TraceDecoderKind::ENV_VAR) {
+            Ok(v) => {
+                let kind = v.parse::<TraceDecoderKind>()?;
+                Ok(self.kind_preferences(&[kind]))
+            }
+            Err(env::VarError::NotPresent) => Ok(self),
+            Err(env::VarError::NotUnicode(_)) => Err(HWTracerError::Config(ConfigError::Invalid(
+                format!("{} is not valid unicode", TraceDecoderKind::ENV_VAR),
+            ))),
+        }
+    }
+
+    /// Set the resource limits for the decoder.
+    pub fn limits(mut self, limits: DecodeLimits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// Only report blocks and events whose addresses fall within `ranges`.
+    pub fn addr_ranges(mut self, ranges: Vec<Range<u64>>) -> Self {
+        self.config.addr_ranges = ranges;
+        self
+    }
+
+    /// Use `reader` to read code which the decoder can't find in any file.
+    pub fn mem_reader(mut self, reader: MemReader) -> Self {
+        self.config.mem_reader = Some(reader);
+        self
+    }
+
+    /// Report the recoverable oddities that the decoder comes across to `handler`.
+    pub fn warning_handler(mut self, handler: WarningHandler) -> Self {
+        self.config.warning_handler = Some(handler);
+        self
+    }
+
+    /// Parse packets and reconstruct events from them on separate threads. See
+    /// [TraceDecoderConfig::pipelined].
+    pub fn pipelined(mut self, pipelined: bool) -> Self {
+        self.config.pipelined = pipelined;
+        self
+    }
+
+    /// Record the throughput of each decode in `metrics`, which may be shared with other
+    /// decoders.
+    pub fn metrics(mut self, metrics: DecodeMetrics) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// Check that each decode of blocks consumes the whole of the trace. See
+    /// [TraceDecoderConfig::verify_complete].
+    pub fn verify_complete(mut self, verify: bool) -> Self {
+        self.config.verify_complete = verify;
+        self
+    }
+
+    /// Don't check the build IDs of the objects that code is read from. See
+    /// [TraceDecoderConfig::ignore_build_ids].
+    pub fn ignore_build_ids(mut self, ignore: bool) -> Self {
+        self.config.ignore_build_ids = ignore;
+        self
+    }
+
+    /// Only report events from the traced context. See [TraceDecoderConfig::traced_context_only].
+    pub fn traced_context_only(mut self, only: bool) -> Self {
+        self.config.traced_context_only = only;
+        self
+    }
+
+    /// Save the traces that fail to decode to `dir`. See [TraceDecoderConfig::failure_dir].
+    pub fn capture_failures(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.failure_dir = Some(dir.into());
+        self
+    }
+
+    /// Load code through the section cache `cache`, which may be shared with other decoders.
+    /// Only used by the libipt decoder.
+    #[cfg(decoder_libipt)]
+    pub fn section_cache(mut self, cache: SectionCache) -> Self {
+        self.config.section_cache = Some(cache);
+        self
+    }
+
     /// Build the trace decoder.
     ///
     /// An error is returned if the requested decoder is inappropriate for the platform or the
     /// requested decoder was not compiled in to hwtracer.
-    pub fn build(self) -> Result<Box<dyn TraceDecoder>, HWTracerError> {
+    pub fn build(mut self) -> Result<Box<dyn TraceDecoder>, HWTracerError> {
+        let failure_dir = self.config.failure_dir.take();
+        let kind = self.kind;
+        let dec = self.build_kind()?;
+        // Failures are only watched for if there's something to do about them.
+        Ok(if failure_dir.is_some() || cfg!(feature = "metrics") {
+            Box::new(WatchingDecoder::new(dec, kind, failure_dir))
+        } else {
+            dec
+        })
+    }
+
+    /// Build the selected kind of decoder.
+    fn build_kind(self) -> Result<Box<dyn TraceDecoder>, HWTracerError> {
         self.kind.match_platform()?;
         match self.kind {
             TraceDecoderKind::LibIPT => {
                 #[cfg(decoder_libipt)]
-                return Ok(Box::new(LibIPTTraceDecoder::new()));
+                return Ok(Box::new(LibIPTTraceDecoder::new(self.config)));
                 #[cfg(not(decoder_libipt))]
-                return Err(HWTracerError::DecoderUnavailable(self.kind));
+                return Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+                    self.kind,
+                )));
             }
             TraceDecoderKind::YkPT => {
                 #[cfg(decoder_ykpt)]
-                return Ok(Box::new(YkPTTraceDecoder::new()));
+                return Ok(Box::new(YkPTTraceDecoder::new(self.config)));
                 #[cfg(not(decoder_ykpt))]
-                return Err(HWTracerError::DecoderUnavailable(self.kind));
+                return Err(HWTracerError::Config(ConfigError::DecoderUnavailable(
+                    self.kind,
+                )));
+            }
+            TraceDecoderKind::Custom(name) => {
+                // `match_platform` has checked that the decoder is registered.
+                let (_, factory) = custom_decoder(name).unwrap();
+                Ok(factory(self.config))
             }
         }
     }
 }
 
-/// Decoder agnostic tests  and helper routines live here.
 #[cfg(test)]
+mod tests {
+    use super::{
+        register_decoder, AddrFilter, DecodeEvent, TraceDecoder, TraceDecoderBuilder,
+        TraceDecoderConfig, TraceDecoderKind,
+    };
+    use crate::{container::RawTrace, errors::HWTracerError, Block, Trace};
+    use std::iter;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn decoder_kind_names() {
+        for kind in TraceDecoderKind::iter() {
+            assert_eq!(kind.to_string().parse::<TraceDecoderKind>().unwrap(), kind);
+        }
+        assert_eq!(
+            "YkPT".parse::<TraceDecoderKind>().unwrap(),
+            TraceDecoderKind::YkPT
+        );
+        assert!("nope".parse::<TraceDecoderKind>().is_err());
+    }
+
+    #[test]
+    fn decoder_kind_preferences() {
+        let prefs = TraceDecoderKind::iter().rev().collect::<Vec<_>>();
+        let bldr = TraceDecoderBuilder::new().kind_preferences(&prefs);
+        assert_eq!(bldr.kind, *prefs.iter().find(|k| k.is_available()).unwrap());
+    }
+
+    /// A custom decoder which reports a single block, spanning the first configured address range.
+    struct OneBlockDecoder(TraceDecoderConfig);
+
+    impl TraceDecoder for OneBlockDecoder {
+        fn new(config: TraceDecoderConfig) -> Self {
+            Self(config)
+        }
+
+        fn iter_blocks<'t>(
+            &'t self,
+            _trace: &'t dyn Trace,
+        ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+            let r = &self.0.addr_ranges[0];
+            Box::new(iter::once(Ok(Block::new(r.start, r.end - 1))))
+        }
+    }
+
+    #[test]
+    fn custom_decoder() {
+        let unregistered = TraceDecoderKind::Custom("onEblock");
+        assert!(!unregistered.is_available());
+        assert!(TraceDecoderBuilder::new()
+            .kind(unregistered)
+            .build()
+            .is_err());
+
+        let kind = register_decoder::<OneBlockDecoder>("oneblock").unwrap();
+        assert_eq!(kind, TraceDecoderKind::Custom("oneblock"));
+        assert!(kind.is_available());
+        assert_eq!("OneBlock".parse::<TraceDecoderKind>().unwrap(), kind);
+        assert_eq!(kind.to_string(), "oneblock");
+        // Custom decoders aren't enumerated with the built-in kinds.
+        assert!(TraceDecoderKind::iter().all(|k| k != kind));
+
+        // Names must be non-empty and unique.
+        assert!(register_decoder::<OneBlockDecoder>("ONEBLOCK").is_err());
+        assert!(register_decoder::<OneBlockDecoder>("ykpt").is_err());
+        assert!(register_decoder::<OneBlockDecoder>("").is_err());
+
+        let dec = TraceDecoderBuilder::new()
+            .kind_preferences(&[kind])
+            .addr_ranges(vec![0x1000..0x1010, 0x2000..0x2010])
+            .build()
+            .unwrap();
+        let blocks = dec
+            .iter_blocks(&RawTrace::new(Vec::new()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(blocks, vec![Block::new(0x1000, 0x100f)]);
+    }
+
+    #[test]
+    fn decode_into() {
+        let dec = OneBlockDecoder(TraceDecoderConfig {
+            addr_ranges: vec![0x1000..0x1010, 0x2000..0x2010],
+            ..Default::default()
+        });
+        let trace = RawTrace::new(Vec::new());
+        let mut buf = Vec::with_capacity(16);
+        buf.push(Block::new(0, 1));
+        let ptr = buf.as_ptr();
+        for _ in 0..2 {
+            dec.decode_blocks_into(&trace, &mut buf).unwrap();
+            assert_eq!(buf, vec![Block::new(0x1000, 0x100f)]);
+            assert_eq!(buf.as_ptr(), ptr);
+        }
+
+        // Errors are returned, and the events before them kept.
+        let mut evs = vec![DecodeEvent::Overflow];
+        assert!(matches!(
+            dec.decode_events_into(&trace, &mut evs),
+            Err(HWTracerError::Config(_))
+        ));
+        assert!(evs.is_empty());
+    }
+
+    /// Check that traces and decoders can be moved to, and shared between, threads.
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+        assert_send_sync::<dyn Trace>();
+        assert_send_sync::<dyn TraceDecoder>();
+        assert_send_sync::<TraceDecoderConfig>();
+        assert_send_sync::<TraceDecoderBuilder>();
+    }
+
+    #[test]
+    fn addr_filter_empty() {
+        let f = AddrFilter::new(&[]);
+        assert!(f.matches(0));
+        assert!(f.matches(u64::MAX));
+    }
+
+    #[test]
+    fn addr_filter_ranges() {
+        let f = AddrFilter::new(&[0x300..0x400, 0x100..0x200, 0x180..0x280, 0x500..0x500]);
+        assert!(!f.matches(0xff));
+        assert!(f.matches(0x100));
+        assert!(f.matches(0x27f));
+        assert!(!f.matches(0x280));
+        assert!(f.matches(0x300));
+        assert!(!f.matches(0x400));
+        // Empty ranges match nothing.
+        assert!(!f.matches(0x500));
+    }
+
+    #[test]
+    fn addr_filter_events() {
+        let f = AddrFilter::new(&[0x100..0x200, 0x300..0x400]);
+        assert!(f.matches_event(&DecodeEvent::Overflow));
+        assert!(f.matches_event(&DecodeEvent::TracingDisabled(None)));
+        assert!(f.matches_event(&DecodeEvent::TracingEnabled(0x150)));
+        assert!(!f.matches_event(&DecodeEvent::TracingEnabled(0x250)));
+        assert!(f.matches_event(&DecodeEvent::AsyncTransfer {
+            from: 0x50,
+            to: Some(0x150)
+        }));
+        assert!(!f.matches_event(&DecodeEvent::AsyncTransfer {
+            from: 0x50,
+            to: None
+        }));
+        assert!(f.matches_event(&DecodeEvent::TransactionAbort {
+            from: None,
+            to: Some(0x350)
+        }));
+        assert!(!f.matches_event(&DecodeEvent::TransactionAbort {
+            from: Some(0x50),
+            to: None
+        }));
+        assert!(f.matches_event(&DecodeEvent::TransactionAbort {
+            from: None,
+            to: None
+        }));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        use super::{BranchOutcome, ExecMode};
+        use crate::{
+            Block, CpuId, ObjectBuildId, SidebandEvent, SidebandRecord, TraceId, TraceMeta,
+        };
+
+        let evs = vec![
+            DecodeEvent::TracingEnabled(0x1000),
+            DecodeEvent::TracingDisabled(None),
+            DecodeEvent::ExecMode(ExecMode::Bits64),
+            DecodeEvent::AsyncTransfer {
+                from: 0x1000,
+                to: Some(0x2000),
+            },
+            DecodeEvent::SessionBoundary,
+            DecodeEvent::ContextSwitch { out: true },
+            DecodeEvent::TransactionBegin,
+            DecodeEvent::TransactionCommit,
+            DecodeEvent::TransactionAbort {
+                from: Some(0x1000),
+                to: None,
+            },
+        ];
+        let json = serde_json::to_string(&evs).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<DecodeEvent>>(&json).unwrap(),
+            evs
+        );
+
+        let brs = vec![
+            BranchOutcome::Conditional(true),
+            BranchOutcome::Indirect(None),
+        ];
+        let json = serde_json::to_string(&brs).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<BranchOutcome>>(&json).unwrap(),
+            brs
+        );
+
+        let blk = Block::new(0x1000, 0x1010);
+        let json = serde_json::to_string(&blk).unwrap();
+        assert_eq!(json, r#"{"first_instr":4096,"last_instr":4112}"#);
+        assert_eq!(serde_json::from_str::<Block>(&json).unwrap(), blk);
+
+        let meta = TraceMeta {
+            cpu: Some(CpuId {
+                family: 6,
+                model: 0x8c,
+                stepping: 1,
+            }),
+            tsc_ratio: Some((168, 2)),
+            pt_config: Some(0x2001),
+            hwtracer_version: Some("0.1.0".into()),
+            build_ids: vec![ObjectBuildId {
+                path: "/lib/libfoo.so".into(),
+                build_id: vec![0xab; 20],
+            }],
+            id: Some(TraceId(u128::MAX)),
+            provenance: [("request".to_owned(), "42".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+        };
+        let json = serde_json::to_string(&meta).unwrap();
+        assert_eq!(serde_json::from_str::<TraceMeta>(&json).unwrap(), meta);
+
+        let rec = SidebandRecord {
+            trace_offset: 64,
+            event: SidebandEvent::Mmap {
+                vaddr: 0x40_0000,
+                len: 0x1000,
+                pgoff: 0,
+                filename: "/bin/true".into(),
+            },
+        };
+        let json = serde_json::to_string(&rec).unwrap();
+        assert_eq!(serde_json::from_str::<SidebandRecord>(&json).unwrap(), rec);
+    }
+}
+
+/// Decoder agnostic tests  and helper routines live here.
+#[cfg(all(test, feature = "collect"))]
 mod test_helpers {
-    use super::{TraceDecoder, TraceDecoderBuilder, TraceDecoderKind};
+    use super::{DecodeLimit, DecodeLimits, TraceDecoder, TraceDecoderBuilder, TraceDecoderKind};
     use crate::{
-        collect::{test_helpers::trace_closure, TraceCollector},
-        test_helpers::work_loop,
+        collect::TraceCollector,
+        errors::{DecodeError, HWTracerError},
+        testing::{trace_closure, work_loop},
         Block, Trace,
     };
-    use std::slice::Iter;
+    use std::{ops::ControlFlow, slice::Iter, sync::Arc, thread};
 
     /// Helper to check an expected list of blocks matches what we actually got.
     pub fn test_expected_blocks(
@@ -145,8 +1381,8 @@ mod test_helpers {
     /// Trace two loops, one 10x larger than the other, then check the proportions match the number
     /// of block the trace passes through.
     pub fn ten_times_as_many_blocks(mut tc: TraceCollector, decoder_kind: TraceDecoderKind) {
-        let trace1 = trace_closure(&mut tc, || work_loop(10));
-        let trace2 = trace_closure(&mut tc, || work_loop(100));
+        let trace1 = trace_closure(&mut tc, || work_loop(10)).unwrap();
+        let trace2 = trace_closure(&mut tc, || work_loop(100)).unwrap();
 
         let dec: Box<dyn TraceDecoder> = TraceDecoderBuilder::new()
             .kind(decoder_kind)
@@ -160,4 +1396,124 @@ mod test_helpers {
         // we trace either side of the loop itself. On a smallish trace, that will be significant.
         assert!(ct2 > ct1 * 8);
     }
+
+    /// Check that a trace collected on this thread can be decoded on others, by a decoder shared
+    /// between them, with the same results as here.
+    pub fn decode_on_other_threads(tc: TraceCollector, decoder_kind: TraceDecoderKind) {
+        let trace: Arc<dyn Trace> = Arc::from(trace_closure(&tc, || work_loop(10)).unwrap());
+        let dec: Arc<dyn TraceDecoder> = Arc::from(
+            TraceDecoderBuilder::new()
+                .kind(decoder_kind)
+                .build()
+                .unwrap(),
+        );
+        let decode = |dec: &dyn TraceDecoder, trace: &dyn Trace| {
+            dec.iter_blocks(trace)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let expect = decode(&*dec, &*trace);
+        let workers = (0..2)
+            .map(|_| {
+                let (dec, trace) = (Arc::clone(&dec), Arc::clone(&trace));
+                thread::spawn(move || decode(&*dec, &*trace))
+            })
+            .collect::<Vec<_>>();
+        for w in workers {
+            assert_eq!(w.join().unwrap(), expect);
+        }
+    }
+
+    /// Check that decoding with the given limits stops with a `LimitExceeded` error for `limit`.
+    pub fn limit_exceeded(
+        tc: TraceCollector,
+        decoder_kind: TraceDecoderKind,
+        limits: DecodeLimits,
+        limit: DecodeLimit,
+    ) {
+        let trace = trace_closure(&tc, || work_loop(100)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(decoder_kind)
+            .limits(limits)
+            .build()
+            .unwrap();
+        let mut itr = dec.iter_blocks(&*trace);
+        loop {
+            match itr.next() {
+                Some(Ok(_)) => (),
+                Some(Err(HWTracerError::Decode(DecodeError::LimitExceeded(l)))) => {
+                    assert_eq!(l, limit);
+                    break;
+                }
+                _ => panic!(),
+            }
+        }
+        // Once a limit has been exceeded, decoding stops.
+        assert!(itr.next().is_none());
+    }
+
+    /// Check that only blocks within the requested address range are reported.
+    pub fn addr_ranges(tc: TraceCollector, decoder_kind: TraceDecoderKind) {
+        let trace = trace_closure(&tc, || work_loop(100)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(decoder_kind)
+            .build()
+            .unwrap();
+        let all = dec
+            .iter_blocks(&*trace)
+            .map(|b| b.unwrap().first_instr())
+            .collect::<Vec<_>>();
+        // Filter on the range spanned by the first block.
+        let range = all[0]..all[0] + 1;
+
+        let dec = TraceDecoderBuilder::new()
+            .kind(decoder_kind)
+            .addr_ranges(vec![range.clone()])
+            .build()
+            .unwrap();
+        let filtered = dec
+            .iter_blocks(&*trace)
+            .map(|b| b.unwrap().first_instr())
+            .collect::<Vec<_>>();
+        let expect = all
+            .into_iter()
+            .filter(|a| range.contains(a))
+            .collect::<Vec<_>>();
+        assert!(!filtered.is_empty());
+        assert_eq!(filtered, expect);
+    }
+
+    /// Check that `decode_until` stops as soon as the predicate asks it to.
+    pub fn decode_until(tc: TraceCollector, decoder_kind: TraceDecoderKind) {
+        let trace = trace_closure(&tc, || work_loop(100)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(decoder_kind)
+            .build()
+            .unwrap();
+
+        let mut seen = 0;
+        let stopped = dec
+            .decode_until(&*trace, &mut |_| {
+                seen += 1;
+                if seen == 5 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert!(stopped);
+        assert_eq!(seen, 5);
+
+        // A predicate that never breaks sees every block.
+        let mut seen = 0;
+        let stopped = dec
+            .decode_until(&*trace, &mut |_| {
+                seen += 1;
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert!(!stopped);
+        assert_eq!(seen, dec.iter_blocks(&*trace).count());
+    }
 }
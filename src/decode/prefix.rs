@@ -0,0 +1,169 @@
+//! Decoding traces which may have been cut short.
+
+use crate::{errors::HWTracerError, Block};
+
+/// How decoding of a [DecodedPrefix] ended.
+#[derive(Debug)]
+pub enum PrefixEnd {
+    /// The whole trace was decoded, and tracing was disabled at its end: nothing is missing.
+    Complete,
+    /// The whole trace was decoded, but tracing was still enabled at its end, so the trace was
+    /// cut short (e.g. because collection stopped abruptly, or the trace was truncated after it
+    /// was saved).
+    Unterminated,
+    /// Decoding stopped at a decode error (e.g. a packet cut short by the end of the trace, or an
+    /// exceeded resource limit).
+    Error(HWTracerError),
+}
+
+/// The blocks decoded from a trace which may be truncated, split at the last point up to which
+/// they can be fully trusted. Made by [super::TraceDecoder::decode_prefix].
+///
+/// If decoding stopped early, or the trace ended while tracing was enabled, the last block decoded
+/// may have been cut short by the end of the decoded data. Blocks before it are trusted: each was
+/// followed by another block, so its end is known. If the trace is complete, all of the blocks are
+/// trusted.
+#[derive(Debug)]
+pub struct DecodedPrefix {
+    blocks: Vec<Block>,
+    trusted: usize,
+    end: PrefixEnd,
+}
+
+impl DecodedPrefix {
+    /// Make a prefix from the blocks decoded from a trace and how decoding ended.
+    pub(crate) fn new(blocks: Vec<Block>, end: PrefixEnd) -> Self {
+        let trusted = match end {
+            PrefixEnd::Complete => blocks.len(),
+            PrefixEnd::Unterminated | PrefixEnd::Error(_) => blocks.len().saturating_sub(1),
+        };
+        Self {
+            blocks,
+            trusted,
+            end,
+        }
+    }
+
+    /// Returns `true` if the whole trace was decoded and nothing is missing from its end.
+    pub fn is_complete(&self) -> bool {
+        matches!(self.end, PrefixEnd::Complete)
+    }
+
+    /// Returns how decoding ended.
+    pub fn end(&self) -> &PrefixEnd {
+        &self.end
+    }
+
+    /// Returns all of the blocks decoded, trusted or not.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Returns the blocks which can be fully trusted.
+    pub fn trusted(&self) -> &[Block] {
+        &self.blocks[..self.trusted]
+    }
+
+    /// Returns the blocks after the last trusted one (at most one block), which may be
+    /// incomplete.
+    pub fn untrusted(&self) -> &[Block] {
+        &self.blocks[self.trusted..]
+    }
+
+    /// Returns the blocks which can be fully trusted, discarding the rest.
+    pub fn into_trusted(mut self) -> Vec<Block> {
+        self.blocks.truncate(self.trusted);
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrefixEnd;
+    use crate::{
+        container::RawTrace,
+        decode::{TraceDecoder, TraceDecoderConfig},
+        errors::{ConfigError, DecodeError, HWTracerError},
+        pt::{
+            encode::{Encoder, IPCompression},
+            PacketParser,
+        },
+        Block, Trace,
+    };
+
+    /// A decoder which yields a one-instruction block at the target of each IP packet, and fails
+    /// where the trace can't be parsed. If `unsupported` is set, it fails straight away instead.
+    struct IPDecoder {
+        unsupported: bool,
+    }
+
+    impl TraceDecoder for IPDecoder {
+        fn new(_config: TraceDecoderConfig) -> Self {
+            Self { unsupported: false }
+        }
+
+        fn iter_blocks<'t>(
+            &'t self,
+            trace: &'t dyn Trace,
+        ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+            if self.unsupported {
+                return Box::new(std::iter::once(Err(HWTracerError::Config(
+                    ConfigError::Unsupported("nope".into()),
+                ))));
+            }
+            Box::new(
+                PacketParser::new(trace.bytes()).filter_map(|pkt| match pkt {
+                    Ok(pkt) => pkt.target_ip().unwrap().map(|ip| Ok(Block::new(ip, ip))),
+                    Err(e) => Some(Err(HWTracerError::Decode(DecodeError::parse(
+                        e.to_string(),
+                    )))),
+                }),
+            )
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> super::DecodedPrefix {
+        IPDecoder::new(TraceDecoderConfig::default())
+            .decode_prefix(&RawTrace::new(bytes.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn prefix() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x1000, IPCompression::Full)
+            .tip(0x2000, IPCompression::Update16);
+        let unterminated = enc.bytes().to_vec();
+        enc.tip_pgd(0, IPCompression::Suppressed);
+
+        let pfx = decode(enc.bytes());
+        assert!(pfx.is_complete());
+        assert_eq!(
+            pfx.trusted(),
+            [Block::new(0x1000, 0x1000), Block::new(0x2000, 0x2000)]
+        );
+        assert!(pfx.untrusted().is_empty());
+
+        // Tracing was still enabled at the end, so the last block may be cut short.
+        let pfx = decode(&unterminated);
+        assert!(matches!(pfx.end(), PrefixEnd::Unterminated));
+        assert_eq!(pfx.blocks().len(), 2);
+        assert_eq!(pfx.untrusted(), [Block::new(0x2000, 0x2000)]);
+        assert_eq!(pfx.into_trusted(), [Block::new(0x1000, 0x1000)]);
+
+        // The last packet is cut short.
+        let pfx = decode(&unterminated[..unterminated.len() - 1]);
+        assert!(matches!(
+            pfx.end(),
+            PrefixEnd::Error(HWTracerError::Decode(_))
+        ));
+        assert_eq!(pfx.blocks(), [Block::new(0x1000, 0x1000)]);
+        assert!(pfx.trusted().is_empty());
+
+        // Errors which aren't about the trace aren't hidden in a prefix.
+        let dec = IPDecoder { unsupported: true };
+        assert!(dec.decode_prefix(&RawTrace::new(unterminated)).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Decoding many traces with one decoder.
+
+use super::{DecodeEvent, TraceDecoder};
+use crate::{errors::HWTracerError, Block, Trace};
+
+/// A decoder for decoding many traces, one after another, with as little per-trace overhead as
+/// possible (e.g. for a JIT which decodes every trace it collects before compiling it).
+///
+/// The wrapped decoder is built once, so its configuration (e.g. its address filter) is only
+/// processed once, and decoded blocks and events are written into buffers which are reused from
+/// one trace to the next. Once the buffers have grown to fit the largest trace, decoding allocates
+/// nothing for its output.
+///
+/// ```no_run
+/// use hwtracer::{decode::{ReusableDecoder, TraceDecoderBuilder}, Trace};
+/// # let traces: Vec<Box<dyn Trace>> = Vec::new();
+/// let mut dec = ReusableDecoder::new(TraceDecoderBuilder::new().build().unwrap());
+/// for trace in &traces {
+///     let blocks = dec.decode(&**trace).unwrap();
+///     println!("{} blocks", blocks.len());
+/// }
+/// ```
+pub struct ReusableDecoder {
+    decoder: Box<dyn TraceDecoder>,
+    blocks: Vec<Block>,
+    events: Vec<DecodeEvent>,
+}
+
+impl ReusableDecoder {
+    /// Wrap `decoder` (e.g. from [super::TraceDecoderBuilder::build]).
+    pub fn new(decoder: Box<dyn TraceDecoder>) -> Self {
+        Self {
+            decoder,
+            blocks: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Decode the blocks of `trace`. The blocks are valid until the next call.
+    pub fn decode(&mut self, trace: &dyn Trace) -> Result<&[Block], HWTracerError> {
+        self.decoder.decode_blocks_into(trace, &mut self.blocks)?;
+        Ok(&self.blocks)
+    }
+
+    /// Decode the events of `trace`. The events are valid until the next call.
+    pub fn decode_events(&mut self, trace: &dyn Trace) -> Result<&[DecodeEvent], HWTracerError> {
+        self.decoder.decode_events_into(trace, &mut self.events)?;
+        Ok(&self.events)
+    }
+
+    /// Returns the wrapped decoder, e.g. to iterate over a trace's instructions.
+    pub fn decoder(&self) -> &dyn TraceDecoder {
+        &*self.decoder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReusableDecoder;
+    use crate::{
+        container::RawTrace,
+        decode::{TraceDecoder, TraceDecoderConfig},
+        errors::HWTracerError,
+        Block, Trace,
+    };
+
+    /// A decoder which yields one block per byte of the trace.
+    struct ByteDecoder;
+
+    impl TraceDecoder for ByteDecoder {
+        fn new(_config: TraceDecoderConfig) -> Self {
+            Self
+        }
+
+        fn iter_blocks<'t>(
+            &'t self,
+            trace: &'t dyn Trace,
+        ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+            Box::new(trace.bytes().iter().map(|&b| {
+                let addr = u64::from(b) * 0x10;
+                Ok(Block::new(addr, addr + 0xf))
+            }))
+        }
+    }
+
+    #[test]
+    fn reuse() {
+        let mut dec = ReusableDecoder::new(Box::new(ByteDecoder));
+        let blocks = dec.decode(&RawTrace::new(vec![1, 2, 3])).unwrap();
+        assert_eq!(blocks.len(), 3);
+        let ptr = blocks.as_ptr();
+
+        // A trace no bigger than the largest so far reuses the buffer.
+        let blocks = dec.decode(&RawTrace::new(vec![4, 5])).unwrap();
+        assert_eq!(blocks, [Block::new(0x40, 0x4f), Block::new(0x50, 0x5f)]);
+        assert_eq!(blocks.as_ptr(), ptr);
+    }
+}
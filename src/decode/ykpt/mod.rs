@@ -1,61 +1,1545 @@
 //! The Yk PT trace decoder.
 
-use crate::{decode::TraceDecoder, errors::HWTracerError, Block, Trace};
+use crate::{
+    decode::{
+        diagnostics::{RECENT_PACKETS, WINDOW_LEN},
+        report_warning, AddrFilter, DecodeDiagnostics, DecodeEvent, DecodeWarning, LimitTracker,
+        TraceDecoder, TraceDecoderConfig, WarningHandler,
+    },
+    errors::{DecodeError, HWTracerError},
+    instrument::Span,
+    pt::{Errata, Packet, PacketError, PacketParser, ParserCheckpoint, TsxState, MAX_PACKET_LEN},
+    slice::first_psb_offset,
+    Block, CpuId, SidebandEvent, Trace,
+};
+use std::{
+    borrow::Cow, collections::VecDeque, io::Write, iter, mem, ops::ControlFlow, sync::mpsc, thread,
+};
 
-mod packet_parser;
-use packet_parser::PacketParser;
+/// The number of packets that the packet parsing stage of a pipelined decode sends to the event
+/// reconstruction stage at once. Sending packets in batches keeps the cost of synchronisation
+/// between the stages small next to the cost of parsing.
+const PIPELINE_BATCH: usize = 4096;
+/// The number of batches by which the packet parsing stage may get ahead of the event
+/// reconstruction stage. This bounds the memory used by a pipelined decode.
+const PIPELINE_DEPTH: usize = 4;
+/// The size of the chunks in which traces are read (see [Trace::chunks]). Only about this much of
+/// a trace is held in memory at once, however large the trace is.
+const CHUNK_SIZE: usize = 64 * 1024;
 
-pub(crate) struct YkPTTraceDecoder {}
+pub(crate) struct YkPTTraceDecoder {
+    config: TraceDecoderConfig,
+    /// Built from `config.addr_ranges` once, rather than for every trace decoded.
+    addr_filter: AddrFilter,
+}
 
 impl TraceDecoder for YkPTTraceDecoder {
-    fn new() -> Self {
-        Self {}
+    fn new(config: TraceDecoderConfig) -> Self {
+        let addr_filter = AddrFilter::new(&config.addr_ranges);
+        Self {
+            config,
+            addr_filter,
+        }
     }
 
     fn iter_blocks<'t>(
         &'t self,
         trace: &'t dyn Trace,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
+        let warnings = self.config.warning_handler.as_ref();
         let itr = YkPTBlockIterator {
             errored: false,
-            parser: PacketParser::new(trace.bytes()),
+            packets: Packets::new(trace, warnings),
+            limits: LimitTracker::for_config(&self.config),
+            span: debug_span!(
+                "decode_blocks",
+                decoder = "ykpt",
+                len = trace.len(),
+                trace_id = trace.id().map(tracing::field::display)
+            ),
         };
         Box::new(itr)
     }
+
+    fn iter_events<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<DecodeEvent, HWTracerError>> + '_> {
+        let mut itr = YkPTEventIterator::new(
+            trace,
+            LimitTracker::for_config(&self.config),
+            &self.addr_filter,
+            self.config.warning_handler.as_ref(),
+        );
+        match sideband_marks(trace) {
+            Ok((boundaries, switches)) => {
+                itr.boundaries = boundaries;
+                itr.switches = switches;
+            }
+            Err(e) => return Box::new(iter::once(Err(e))),
+        }
+        itr.traced_context_only = self.config.traced_context_only;
+        Box::new(itr)
+    }
+
+    fn decode_events_until(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&DecodeEvent) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        if self.config.pipelined {
+            return self.decode_events_pipelined(trace, f);
+        }
+        for ev in self.iter_events(trace) {
+            if f(&ev?).is_break() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl YkPTTraceDecoder {
+    /// Decode the events of `trace` as [TraceDecoder::decode_events_until] does, but with the
+    /// packets parsed on another thread and sent to this one in batches.
+    fn decode_events_pipelined(
+        &self,
+        trace: &dyn Trace,
+        f: &mut dyn FnMut(&DecodeEvent) -> ControlFlow<()>,
+    ) -> Result<bool, HWTracerError> {
+        let (boundaries, switches) = sideband_marks(trace)?;
+        let warnings = self.config.warning_handler.as_ref();
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+            s.spawn(move || {
+                let mut packets = Packets::new(trace, warnings);
+                loop {
+                    let mut batch = Vec::with_capacity(PIPELINE_BATCH);
+                    // Nothing can be parsed after an error, so it ends the last batch.
+                    let mut done = false;
+                    while !done && batch.len() < PIPELINE_BATCH {
+                        match packets.next() {
+                            Some(Ok(p)) => batch.push(Ok(p)),
+                            Some(Err(e)) => {
+                                batch.push(Err(StageError::from(e)));
+                                done = true;
+                            }
+                            None => done = true,
+                        }
+                    }
+                    // If the reconstruction stage has stopped, there's no point carrying on.
+                    if tx.send(batch).is_err() || done {
+                        return;
+                    }
+                }
+            });
+            let mut itr = YkPTEventIterator::with_packets(
+                rx.into_iter()
+                    .flatten()
+                    .map(|p| p.map_err(HWTracerError::from)),
+                debug_span!(
+                    "decode_events",
+                    decoder = "ykpt",
+                    len = trace.len(),
+                    trace_id = trace.id().map(tracing::field::display)
+                ),
+                LimitTracker::for_config(&self.config),
+                &self.addr_filter,
+                warnings,
+            );
+            itr.boundaries = boundaries;
+            itr.switches = switches;
+            itr.traced_context_only = self.config.traced_context_only;
+            // Returning drops the receiver, which stops the parsing stage if it hasn't finished.
+            for ev in itr {
+                if f(&ev?).is_break() {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+    }
+}
+
+/// The offsets in a trace at which the traced thread was switched out (`true`) or in (`false`).
+type Switches = VecDeque<(usize, bool)>;
+
+/// Returns the offsets in `trace` at which new collection sessions start, and its context
+/// switches, each in ascending order.
+fn sideband_marks(trace: &dyn Trace) -> Result<(VecDeque<usize>, Switches), HWTracerError> {
+    let mut boundaries = VecDeque::new();
+    let mut switches = VecDeque::new();
+    for r in trace.sideband()? {
+        match r.event {
+            SidebandEvent::SessionBoundary => boundaries.push_back(r.trace_offset),
+            SidebandEvent::Switch { out } => switches.push_back((r.trace_offset, out)),
+            _ => (),
+        }
+    }
+    Ok((boundaries, switches))
+}
+
+/// An error from the packet parsing stage of a pipelined decode. Unlike [HWTracerError], this can
+/// be sent between threads: errors other than decoding errors (e.g. I/O errors reading the trace)
+/// are passed on as their messages.
+enum StageError {
+    Decode(DecodeError),
+    Other(String),
+}
+
+impl From<HWTracerError> for StageError {
+    fn from(e: HWTracerError) -> Self {
+        match e {
+            HWTracerError::Decode(e) => Self::Decode(e),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<StageError> for HWTracerError {
+    fn from(e: StageError) -> Self {
+        match e {
+            StageError::Decode(e) => HWTracerError::Decode(e),
+            StageError::Other(msg) => HWTracerError::Custom(msg.into()),
+        }
+    }
+}
+
+/// A parsed packet, with the offsets into the trace at which it starts and ends.
+struct ParsedPacket {
+    off: usize,
+    end: usize,
+    pkt: Packet,
+}
+
+/// Iterates over the packets of a trace, with their offsets, reading the trace in chunks (see
+/// [Trace::chunks]) so that only a chunk or so of it need be in memory at once.
+///
+/// Parsing starts at the first PSB packet. Any data before it can't be parsed, so is skipped, with
+/// a warning. If there's no PSB packet, parsing fails at the end of the trace. Decoding ignores
+/// PAD packets, so they are skipped. The errata of the trace's CPU are worked around (see
+/// [errata]).
+struct Packets<'t> {
+    chunks: Box<dyn Iterator<Item = Result<Cow<'t, [u8]>, HWTracerError>> + 't>,
+    /// The bytes of the trace read but not yet parsed, preceded by some already parsed.
+    buf: Vec<u8>,
+    /// The offset into the trace of `buf[0]`.
+    base: usize,
+    /// The offset into `buf` of the next packet.
+    pos: usize,
+    /// The length of the trace, in bytes.
+    len: usize,
+    /// True once all of the trace has been read into `buf`.
+    eof: bool,
+    /// True once the first PSB packet has been found.
+    synced: bool,
+    /// The state of the parser after the last packet parsed.
+    checkpoint: ParserCheckpoint,
+    /// Receives any warnings, if set.
+    warnings: Option<&'t WarningHandler>,
+}
+
+impl<'t> Packets<'t> {
+    fn new(trace: &'t dyn Trace, warnings: Option<&'t WarningHandler>) -> Self {
+        Self {
+            chunks: trace.chunks(CHUNK_SIZE),
+            buf: Vec::new(),
+            base: 0,
+            pos: 0,
+            len: trace.len(),
+            eof: false,
+            synced: false,
+            checkpoint: PacketParser::new(&[])
+                .skip_pads(true)
+                .errata(errata(trace))
+                .checkpoint(),
+            warnings,
+        }
+    }
+
+    /// Returns the offset into the trace of the next packet: once all of the packets have been
+    /// iterated over, the number of bytes consumed.
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    /// Read the next chunk of the trace into `buf`, first dropping the bytes already parsed.
+    fn fill(&mut self) -> Result<(), HWTracerError> {
+        self.buf.drain(..self.pos);
+        self.base += self.pos;
+        self.pos = 0;
+        match self.chunks.next() {
+            Some(chunk) => self.buf.extend_from_slice(&chunk?),
+            None => self.eof = true,
+        }
+        Ok(())
+    }
+
+    /// Skip to the first PSB packet, reading as much of the trace as it takes to find it.
+    fn sync(&mut self) -> Result<(), HWTracerError> {
+        loop {
+            if let Some(start) = first_psb_offset(&self.buf) {
+                self.pos = start;
+                break;
+            }
+            if self.eof {
+                // Let parsing fail on whatever is left.
+                self.synced = true;
+                return Ok(());
+            }
+            // Keep the bytes that might be the start of a PSB packet cut short by the chunk.
+            self.pos = self.buf.len().saturating_sub(MAX_PACKET_LEN - 1);
+            self.fill()?;
+        }
+        self.synced = true;
+        let start = self.base + self.pos;
+        if start > 0 {
+            report_warning(
+                self.warnings,
+                DecodeWarning::SkippedBytes {
+                    offset: 0,
+                    len: start,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<'t> Iterator for Packets<'t> {
+    type Item = Result<ParsedPacket, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.synced {
+            if let Err(e) = self.sync() {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let off = self.base + self.pos;
+            let mut parser = PacketParser::resume(&self.buf[self.pos..], off, self.checkpoint)
+                .partial(!self.eof);
+            let pkt = parse_packet(&mut parser);
+            // Skipped PADs are consumed even if no packet was parsed.
+            self.pos = parser.offset() - self.base;
+            match pkt {
+                Some(Ok(pkt)) => {
+                    self.checkpoint = parser.checkpoint();
+                    return Some(Ok(ParsedPacket {
+                        off,
+                        end: self.base + self.pos,
+                        pkt,
+                    }));
+                }
+                Some(Err(e)) => return Some(Err(packet_error(off, e))),
+                None if self.eof => return None,
+                None => {
+                    if let Err(e) = self.fill() {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the errata of the CPU that `trace` was collected on. As with libipt, if the CPU isn't
+/// known, the trace is assumed to have been collected on the current CPU.
+fn errata(trace: &dyn Trace) -> Errata {
+    trace
+        .cpu()
+        .or_else(CpuId::current)
+        .map_or_else(Errata::default, |cpu| cpu.errata())
+}
+
+/// Returns `true` if tracing was still enabled at the end of `trace` (see
+/// [super::TraceDecoder::decode_prefix]).
+pub(super) fn ends_enabled(trace: &dyn Trace) -> Result<bool, HWTracerError> {
+    let mut enabled = false;
+    for pkt in Packets::new(trace, None) {
+        match pkt?.pkt {
+            Packet::TIPPGE(..) => enabled = true,
+            Packet::TIPPGD(..) => enabled = false,
+            _ => (),
+        }
+    }
+    Ok(enabled)
+}
+
+/// Parse the next packet with `parser`, if there is one. Errors report the offset into the trace
+/// at which the packet couldn't be parsed.
+fn next_packet(parser: &mut PacketParser) -> Option<Result<Packet, HWTracerError>> {
+    let off = parser.offset();
+    Some(parse_packet(parser)?.map_err(|e| packet_error(off, e)))
+}
+
+/// Parse the next packet with `parser`, if there is one, logging PSBs and errors.
+fn parse_packet(parser: &mut PacketParser) -> Option<Result<Packet, PacketError>> {
+    #[cfg(feature = "tracing")]
+    let off = parser.offset();
+    let pkt = parser.next()?;
+    #[cfg(feature = "tracing")]
+    match &pkt {
+        Ok(Packet::PSB(_)) => debug!(offset = off, "PSB"),
+        Ok(_) => (),
+        Err(e) => debug!(offset = off, error = %e, "failed to parse packet"),
+    }
+    Some(pkt)
+}
+
+/// Make the error reporting that the packet at `offset` couldn't be parsed.
+fn packet_error(offset: usize, e: PacketError) -> HWTracerError {
+    HWTracerError::from(e).at_offset(offset)
+}
+
+/// Write the packets of `trace` to `w` in the style of `ptdump`. See [crate::decode::dump_packets].
+pub(crate) fn dump_packets(trace: &dyn Trace, w: &mut dyn Write) -> Result<(), HWTracerError> {
+    // Like `ptdump`, skip anything before the first PSB packet, as it can't be parsed.
+    let start = match trace.psb_offsets().first() {
+        Some(&off) => off,
+        None => return Ok(()),
+    };
+    let mut parser = PacketParser::new_at(trace.bytes(), start).errata(errata(trace));
+    loop {
+        let off = parser.offset();
+        match next_packet(&mut parser) {
+            Some(Ok(pkt)) => writeln!(w, "{:016x}  {}", off, pkt)?,
+            Some(Err(e)) => {
+                writeln!(w, "[{:016x}: error: {}]", off, e)?;
+                return Err(e);
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Iterate over the packets of `trace`, with their offsets, formatted in the style of `ptdump`.
+/// See [crate::decode::iter_packets].
+pub(crate) fn iter_packets(
+    trace: &dyn Trace,
+) -> impl Iterator<Item = Result<(usize, String), HWTracerError>> + '_ {
+    let mut parser = trace
+        .psb_offsets()
+        .first()
+        .map(|&start| PacketParser::new_at(trace.bytes(), start).errata(errata(trace)));
+    iter::from_fn(move || {
+        let p = parser.as_mut()?;
+        let off = p.offset();
+        match next_packet(p)? {
+            Ok(pkt) => Some(Ok((off, pkt.to_string()))),
+            Err(e) => {
+                parser = None;
+                Some(Err(e))
+            }
+        }
+    })
+}
+
+/// Gather diagnostics about the failure of decoding `trace` with `err`. See
+/// [crate::decode::diagnose].
+pub(crate) fn diagnose(
+    trace: &dyn Trace,
+    err: &HWTracerError,
+) -> Result<DecodeDiagnostics, HWTracerError> {
+    let bytes = trace.bytes();
+    let psbs = trace.psb_offsets();
+    let stop = err.trace_offset();
+    let mut recent = VecDeque::with_capacity(RECENT_PACKETS);
+    let mut parser_state = None;
+    let mut parse_error = None;
+    let mut offset = stop.unwrap_or(bytes.len());
+    if let Some(&start) = psbs
+        .first()
+        .filter(|&&s| stop.map_or(true, |stop| s <= stop))
+    {
+        let mut parser = PacketParser::new_at(bytes, start).errata(errata(trace));
+        loop {
+            let off = parser.offset();
+            if stop.is_some_and(|stop| off >= stop) {
+                parser_state = Some(parser.state());
+                break;
+            }
+            let state = parser.state();
+            match next_packet(&mut parser) {
+                Some(Ok(pkt)) => {
+                    if recent.len() == RECENT_PACKETS {
+                        recent.pop_front();
+                    }
+                    recent.push_back((off, pkt.to_string()));
+                }
+                Some(Err(e)) => {
+                    // Without an offset from the error, the parser's failure is the failure.
+                    // Otherwise, the parser only tells us about the failure if it failed there.
+                    if stop.is_none() || stop == Some(off) {
+                        offset = off;
+                        parse_error = Some(e.to_string());
+                        parser_state = Some(state);
+                    }
+                    break;
+                }
+                None => {
+                    if stop.is_none() {
+                        parser_state = Some(state);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    let offset = offset.min(bytes.len());
+    let window_start = offset.saturating_sub(WINDOW_LEN);
+    let window = bytes[window_start..(offset + WINDOW_LEN).min(bytes.len())].to_vec();
+    let meta = trace.meta();
+    Ok(DecodeDiagnostics {
+        trace_id: meta.id,
+        provenance: meta.provenance,
+        offset,
+        error: err.to_string(),
+        parse_error,
+        parser_state,
+        recent_packets: recent.into(),
+        window_start,
+        window,
+        next_psb: psbs.into_iter().find(|&p| p > offset),
+    })
 }
 
 /// Iterate over the blocks of an Intel PT trace using the fast Yk PT decoder.
 struct YkPTBlockIterator<'t> {
     /// Set to true when an error has occured.
     errored: bool,
-    /// PT packet iterator.
-    parser: PacketParser<'t>,
+    /// The packets of the trace.
+    packets: Packets<'t>,
+    /// Enforces the decoder's resource limits.
+    limits: LimitTracker,
+    /// The span that decoding is done in.
+    span: Span,
 }
 
 impl<'t> Iterator for YkPTBlockIterator<'t> {
     type Item = Result<Block, HWTracerError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if !self.errored {
-            // FIXME: For now this is dummy code to prevent dead-code warnings. Later block binding
-            // logic will go here.
-            self.parser.next().unwrap().unwrap();
+        if self.errored {
+            return None;
+        }
+        let span = self.span.clone();
+        let _entered = span.enter();
+        // FIXME: Block binding logic is not yet implemented. For now we walk the packets (so
+        // that parse errors and resource limits are reported) but yield no blocks. Branch
+        // decisions from TNT packets should be queued in a `pt::TNTBuffer` as they are consumed.
+        while let Some(pkt) = self.packets.next() {
+            if let Err(e) = pkt.and_then(|p| {
+                self.limits.tnt_parsed(p.pkt.num_branches());
+                self.limits.packet(p.end)
+            }) {
+                self.errored = true;
+                return Some(Err(e));
+            }
+        }
+        if let Err(e) = self.limits.finish(self.packets.offset(), self.packets.len) {
+            self.errored = true;
+            return Some(Err(e));
         }
         None
     }
 }
 
-#[cfg(test)]
+/// Iterate over the high-level events of an Intel PT trace using the Yk PT decoder.
+///
+/// The packets come from `P`, which is usually a parser running on the same thread, but may be
+/// fed by a parser on another thread (see [YkPTTraceDecoder::decode_events_pipelined]).
+struct YkPTEventIterator<'t, P = Packets<'t>> {
+    /// Set to true when an error has occured.
+    errored: bool,
+    /// The packets of the trace.
+    packets: P,
+    /// Enforces the decoder's resource limits.
+    limits: LimitTracker,
+    /// Decides which events are reported.
+    addr_filter: &'t AddrFilter,
+    /// Events which have been decoded, but not yet returned.
+    pending: VecDeque<DecodeEvent>,
+    /// True when we are inside a PSB+ sequence. A FUP packet inside PSB+ reports the current IP
+    /// and doesn't indicate an asynchronous transfer.
+    in_psbplus: bool,
+    /// True when the next FUP packet is bound to the previous packet (e.g. a PTW or OVF packet),
+    /// and thus doesn't indicate an asynchronous transfer.
+    bound_fup: bool,
+    /// The source address of an asynchronous transfer, waiting for the TIP or TIP.PGD packet that
+    /// tells us where control went.
+    async_from: Option<u64>,
+    /// The events of the TSX transaction being executed, held back until it commits, and
+    /// discarded if it aborts. `None` outside of a transaction.
+    speculative: Option<Vec<DecodeEvent>>,
+    /// Set when a transaction aborts, until the packet that tells us where control went: holds
+    /// where the transaction aborted, if the FUP packet bound to the abort has been seen.
+    tx_abort: Option<Option<u64>>,
+    /// The offsets of the session boundaries (see [crate::Trace::concat]) not yet reached.
+    boundaries: VecDeque<usize>,
+    /// The offsets of the context switches recorded in the sideband not yet reached, with whether
+    /// the traced thread was switched out.
+    switches: Switches,
+    /// The CR3 value of the traced context: that of the first PIP packet in the session.
+    traced_cr3: Option<u64>,
+    /// True while the traced context is switched out.
+    switched_out: bool,
+    /// If true, packets from other contexts are skipped (see
+    /// [TraceDecoderConfig::traced_context_only]).
+    traced_context_only: bool,
+    /// Receives any warnings, if set.
+    warnings: Option<&'t WarningHandler>,
+    /// The span that decoding is done in.
+    span: Span,
+}
+
+impl<'t> YkPTEventIterator<'t> {
+    fn new(
+        trace: &'t dyn Trace,
+        limits: LimitTracker,
+        addr_filter: &'t AddrFilter,
+        warnings: Option<&'t WarningHandler>,
+    ) -> Self {
+        let packets = Packets::new(trace, warnings);
+        let span = debug_span!(
+            "decode_events",
+            decoder = "ykpt",
+            len = trace.len(),
+            trace_id = trace.id().map(tracing::field::display)
+        );
+        Self::with_packets(packets, span, limits, addr_filter, warnings)
+    }
+}
+
+impl<'t, P> YkPTEventIterator<'t, P>
+where
+    P: Iterator<Item = Result<ParsedPacket, HWTracerError>>,
+{
+    /// Make an iterator over the events of a trace whose packets are `packets`, decoding in `span`.
+    fn with_packets(
+        packets: P,
+        span: Span,
+        limits: LimitTracker,
+        addr_filter: &'t AddrFilter,
+        warnings: Option<&'t WarningHandler>,
+    ) -> Self {
+        Self {
+            errored: false,
+            packets,
+            limits,
+            addr_filter,
+            pending: VecDeque::new(),
+            in_psbplus: false,
+            bound_fup: false,
+            async_from: None,
+            speculative: None,
+            tx_abort: None,
+            boundaries: VecDeque::new(),
+            switches: VecDeque::new(),
+            traced_cr3: None,
+            switched_out: false,
+            traced_context_only: false,
+            warnings,
+            span,
+        }
+    }
+
+    /// Process the packet found `offset` bytes into the trace, queueing any events that it gives
+    /// rise to.
+    fn process_packet(&mut self, pkt: Packet, offset: usize) -> Result<(), HWTracerError> {
+        if self.traced_context_only
+            && self.switched_out
+            && !matches!(pkt, Packet::PSB(_) | Packet::PSBEND(_) | Packet::PIP(_))
+        {
+            // The packet belongs to another context. Only those telling us when the traced context
+            // returns (and delimiting PSB+, which may report it) matter.
+            return Ok(());
+        }
+        let ip = pkt.target_ip()?;
+        let pkt_ip_suppressed = pkt.ip_suppressed();
+        let bound_fup = mem::replace(&mut self.bound_fup, false);
+        match pkt {
+            Packet::PSB(_) => self.in_psbplus = true,
+            Packet::PSBEND(_) => self.in_psbplus = false,
+            // Padding doesn't separate a FUP from the packet it is bound to.
+            Packet::PAD(_) => self.bound_fup = bound_fup,
+            Packet::CBR(p) => self.pending.push_back(DecodeEvent::CoreBusRatio(p.ratio)),
+            Packet::MODE(p) => {
+                if let Some(mode) = p.exec_mode() {
+                    self.emit(DecodeEvent::ExecMode(mode));
+                } else if let Some(state) = p.tsx_state() {
+                    self.transaction(state);
+                    self.bound_fup = true;
+                } else {
+                    report_warning(
+                        self.warnings,
+                        DecodeWarning::UnknownMode {
+                            offset,
+                            leaf: p.leaf(),
+                        },
+                    );
+                }
+            }
+            Packet::OVF(_) => {
+                // Whether the transaction being executed (if any) committed is lost.
+                self.speculative = None;
+                self.tx_abort = None;
+                self.pending.push_back(DecodeEvent::Overflow);
+                self.async_from = None;
+                self.bound_fup = true;
+            }
+            Packet::PTW(p) => {
+                self.emit(DecodeEvent::PTWrite(p.payload()));
+                self.bound_fup = p.has_ip();
+            }
+            Packet::FUP(..) => {
+                if bound_fup && self.tx_abort == Some(None) {
+                    self.tx_abort = Some(ip);
+                } else if !self.in_psbplus && !bound_fup {
+                    self.async_from = ip;
+                }
+            }
+            Packet::TIP(..) => {
+                self.abort_to(ip);
+                if let Some(from) = self.async_from.take() {
+                    self.emit(DecodeEvent::AsyncTransfer { from, to: ip });
+                }
+                // An out of context TIP leaves the last IP untouched, but we can no longer know
+                // where execution is.
+                if pkt_ip_suppressed {
+                    self.emit(DecodeEvent::ContextLost);
+                }
+            }
+            Packet::TIPPGE(..) => match ip {
+                Some(ip) => {
+                    self.abort_to(Some(ip));
+                    self.emit(DecodeEvent::TracingEnabled(ip));
+                }
+                None => {
+                    return Err(HWTracerError::Decode(DecodeError::parse(
+                        "TIP.PGE packet has no target IP".into(),
+                    )))
+                }
+            },
+            Packet::TIPPGD(..) => {
+                // If the IP was suppressed, `ip` is `None`: we know the traced region ended, but
+                // not where control went.
+                self.abort_to(ip);
+                if let Some(from) = self.async_from.take() {
+                    self.emit(DecodeEvent::AsyncTransfer { from, to: ip });
+                }
+                self.emit(DecodeEvent::TracingDisabled(ip));
+            }
+            Packet::PIP(p) => match self.traced_cr3 {
+                Some(cr3) => self.switch_context(p.cr3() != cr3),
+                None => self.traced_cr3 = Some(p.cr3()),
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Queue the event `ev`, holding it back if it happened inside a transaction (see
+    /// [DecodeEvent::TransactionBegin]).
+    fn emit(&mut self, ev: DecodeEvent) {
+        match &mut self.speculative {
+            Some(evs) => evs.push(ev),
+            None => self.pending.push_back(ev),
+        }
+    }
+
+    /// Act on a MODE.TSX packet reporting the transactional state `state`.
+    fn transaction(&mut self, state: TsxState) {
+        match state {
+            // PSB+ repeats the state, so a transaction may already be under way.
+            TsxState::Inside => {
+                if self.speculative.is_none() {
+                    self.speculative = Some(Vec::new());
+                }
+            }
+            TsxState::Outside => {
+                if let Some(evs) = self.speculative.take() {
+                    self.pending.push_back(DecodeEvent::TransactionBegin);
+                    self.pending.extend(evs);
+                    self.pending.push_back(DecodeEvent::TransactionCommit);
+                }
+            }
+            // The abort is reported once we know where control went.
+            TsxState::Aborted => {
+                self.speculative = None;
+                self.tx_abort = Some(None);
+            }
+        }
+    }
+
+    /// If a transaction has aborted, report that control went from it to `to`.
+    fn abort_to(&mut self, to: Option<u64>) {
+        if let Some(from) = self.tx_abort.take() {
+            self.pending
+                .push_back(DecodeEvent::TransactionAbort { from, to });
+        }
+    }
+
+    /// Record that the traced context was switched out (if `out` is `true`) or in, reporting it if
+    /// that changes anything.
+    fn switch_context(&mut self, out: bool) {
+        if out != self.switched_out {
+            self.switched_out = out;
+            // Control flow doesn't continue from one context into another.
+            self.async_from = None;
+            self.bound_fup = false;
+            self.pending.push_back(DecodeEvent::ContextSwitch { out });
+        }
+    }
+}
+
+impl<'t, P> Iterator for YkPTEventIterator<'t, P>
+where
+    P: Iterator<Item = Result<ParsedPacket, HWTracerError>>,
+{
+    type Item = Result<DecodeEvent, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        loop {
+            if let Some(ev) = self.pending.pop_front() {
+                if self.addr_filter.matches_event(&ev) {
+                    return Some(Ok(ev));
+                }
+                continue;
+            }
+            if self.errored {
+                return None;
+            }
+            let res = self.packets.next()?.and_then(|p| {
+                while matches!(self.boundaries.front(), Some(b) if p.off >= *b) {
+                    // Nothing from the previous session carries over into the next.
+                    self.boundaries.pop_front();
+                    self.in_psbplus = false;
+                    self.bound_fup = false;
+                    self.async_from = None;
+                    self.speculative = None;
+                    self.tx_abort = None;
+                    self.traced_cr3 = None;
+                    self.switched_out = false;
+                    self.pending.push_back(DecodeEvent::SessionBoundary);
+                }
+                while matches!(self.switches.front(), Some((s, _)) if p.off >= *s) {
+                    let (_, out) = self.switches.pop_front().unwrap();
+                    self.switch_context(out);
+                }
+                self.limits.packet(p.end)?;
+                self.process_packet(p.pkt, p.off)
+            });
+            if let Err(e) = res {
+                self.errored = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+// Many of the tests trace this process, so need a collector.
+#[cfg(all(test, feature = "collect"))]
 mod tests {
+    use super::{diagnose, dump_packets, YkPTEventIterator};
     use crate::{
-        collect::TraceCollectorBuilder,
-        decode::{test_helpers, TraceDecoderKind},
+        container::RawTrace,
+        decode::{
+            test_helpers, AddrFilter, CancellationToken, DecodeEvent, DecodeLimit, DecodeLimits,
+            DecodeMetrics, DecodeWarning, ExecMode, LimitTracker, TraceDecoderBuilder,
+            TraceDecoderKind, WarningHandler,
+        },
+        errors::{DecodeError, HWTracerError},
+        pt::{
+            encode::{Encoder, IPCompression},
+            PacketParserState,
+        },
+        testing::{collector, trace_closure, work_loop},
+        SidebandEvent, SidebandRecord, Trace, TraceId,
+    };
+    use std::{
+        ops::ControlFlow,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
     };
 
+    #[test]
+    fn dump() {
+        #[rustfmt::skip]
+        let mut bytes = vec![
+            // Junk, which is skipped.
+            0xff,
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x03, 0x2c, 0x00,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // MODE.Exec, 64-bit.
+            0x99, 0x01,
+            // Short TNT: not taken, taken.
+            0x0a,
+            // TIP with a 16-bit compressed IP.
+            0x2d, 0x00, 0x20,
+            // CYC.
+            0x13,
+            // PTW with a 32-bit payload.
+            0x02, 0x12, 0x78, 0x56, 0x34, 0x12,
+            // TIP.PGD with no IP.
+            0x01,
+        ];
+        let mut out = Vec::new();
+        dump_packets(&*<dyn Trace>::from_bytes(bytes.clone()), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+0000000000000001  psb
+0000000000000011  cbr        2c
+0000000000000015  psbend
+0000000000000017  tip.pge    6: 0000555512345678
+0000000000000020  mode.exec  cs.l
+0000000000000022  tnt.8      .!
+0000000000000023  tip        1: ????????????2000
+0000000000000026  cyc        2
+0000000000000027  ptw        0: 12345678
+000000000000002d  tip.pgd    0: ????????????????
+"
+        );
+
+        // Parsing stops at the first error, which is included in the dump.
+        bytes.extend([0x02, 0xff]);
+        let mut out = Vec::new();
+        assert!(dump_packets(&*<dyn Trace>::from_bytes(bytes), &mut out).is_err());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("[000000000000002e: error: "));
+    }
+
     #[ignore] // FIXME
     #[test]
     fn ten_times_as_many_blocks() {
-        let tc = TraceCollectorBuilder::new().build().unwrap();
+        let Some(tc) = collector() else {
+            return;
+        };
         test_helpers::ten_times_as_many_blocks(tc, TraceDecoderKind::YkPT);
     }
+
+    #[test]
+    fn decode_on_other_threads() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        test_helpers::decode_on_other_threads(tc, TraceDecoderKind::YkPT);
+    }
+
+    #[test]
+    fn max_packets_limit() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let limits = DecodeLimits {
+            max_packets: Some(1),
+            ..Default::default()
+        };
+        test_helpers::limit_exceeded(tc, TraceDecoderKind::YkPT, limits, DecodeLimit::Packets);
+    }
+
+    #[test]
+    fn max_bytes_limit() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let limits = DecodeLimits {
+            max_bytes: Some(16),
+            ..Default::default()
+        };
+        test_helpers::limit_exceeded(tc, TraceDecoderKind::YkPT, limits, DecodeLimit::Bytes);
+    }
+
+    /// Check that the expected events are decoded from a hand-crafted packet stream.
+    #[test]
+    fn crafted_events() {
+        #[rustfmt::skip]
+        let bytes = [
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // MODE.Exec (64-bit).
+            0x99, 0x01,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // CBR.
+            0x02, 0x03, 0x2a, 0x00,
+            // PTW with a 32-bit payload.
+            0x02, 0x12, 0xef, 0xbe, 0xad, 0xde,
+            // FUP followed by TIP: an asynchronous transfer.
+            0x3d, 0x00, 0x10,
+            0x2d, 0x00, 0x20,
+            // OVF.
+            0x02, 0xf3,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // TIP, out of context.
+            0x0d,
+            // TIP.PGD with no IP.
+            0x01,
+        ];
+        let trace = <dyn Trace>::from_bytes(bytes.to_vec());
+        let evs = YkPTEventIterator::new(
+            &*trace,
+            LimitTracker::new(DecodeLimits::default()),
+            &AddrFilter::new(&[]),
+            None,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert_eq!(
+            evs,
+            vec![
+                DecodeEvent::ExecMode(ExecMode::Bits64),
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::CoreBusRatio(42),
+                DecodeEvent::PTWrite(0xdeadbeef),
+                DecodeEvent::AsyncTransfer {
+                    from: 0x555512341000,
+                    to: Some(0x555512342000)
+                },
+                DecodeEvent::Overflow,
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::ContextLost,
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+    }
+
+    /// Check that verifying completeness reports the branch decisions left over by block decoding,
+    /// and that trailing PADs count as consumed.
+    #[test]
+    fn verify_complete() {
+        let mut enc = Encoder::new();
+        enc.psb().psbend().tip_pge(0x1000, IPCompression::Full);
+        let mut padded = enc.clone();
+        padded.pad().pad();
+        let untaken = <dyn Trace>::from_bytes(padded.into_bytes());
+        enc.tnt(&[true; 10]).tip_pgd(0, IPCompression::Suppressed);
+        let taken = <dyn Trace>::from_bytes(enc.into_bytes());
+
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .verify_complete(true)
+            .build()
+            .unwrap();
+        assert!(dec.iter_blocks(&*untaken).all(|b| b.is_ok()));
+        let res = dec.iter_blocks(&*taken).collect::<Result<Vec<_>, _>>();
+        assert!(matches!(
+            res,
+            Err(HWTracerError::Decode(DecodeError::Incomplete {
+                bytes: 0,
+                tnt: 10
+            }))
+        ));
+
+        // Without verification, the leftovers go unremarked.
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .build()
+            .unwrap();
+        assert!(dec.iter_blocks(&*taken).all(|b| b.is_ok()));
+    }
+
+    /// Check that a pipelined decode gives the same events as an ordinary one, across many
+    /// batches of packets, and stops when asked to or when a packet can't be parsed.
+    #[test]
+    fn pipelined() {
+        #[rustfmt::skip]
+        let mut bytes = vec![
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+        ];
+        for i in 0..10_000u32 {
+            // PTW with a 32-bit payload, then a short TNT and a PAD.
+            bytes.extend_from_slice(&[0x02, 0x12]);
+            bytes.extend_from_slice(&i.to_le_bytes());
+            bytes.extend_from_slice(&[0x06, 0x00]);
+        }
+        // TIP.PGD with no IP.
+        bytes.push(0x01);
+        let trace = <dyn Trace>::from_bytes(bytes.clone());
+        let bldr = || TraceDecoderBuilder::new().kind(TraceDecoderKind::YkPT);
+        let expected = bldr()
+            .build()
+            .unwrap()
+            .iter_events(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(expected.len(), 10_002);
+
+        let dec = bldr().pipelined(true).build().unwrap();
+        let mut evs = Vec::new();
+        let stopped = dec
+            .decode_events_until(&*trace, &mut |ev| {
+                evs.push(ev.clone());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert!(!stopped);
+        assert_eq!(evs, expected);
+
+        let mut n = 0;
+        let stopped = dec
+            .decode_events_until(&*trace, &mut |_| {
+                n += 1;
+                if n == 5 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert!(stopped);
+        assert_eq!(n, 5);
+
+        // Junk in place of the TIP.PGD.
+        bytes.pop();
+        bytes.extend_from_slice(&[0x02, 0xff]);
+        let trace = <dyn Trace>::from_bytes(bytes);
+        let mut n = 0;
+        assert!(dec
+            .decode_events_until(&*trace, &mut |_| {
+                n += 1;
+                ControlFlow::Continue(())
+            })
+            .is_err());
+        assert_eq!(n, 10_001);
+    }
+
+    /// Check that a decode, pipelined or not, stops soon after it is cancelled from another
+    /// thread, and that decodes with the token fail until it is reset.
+    #[test]
+    fn cancel() {
+        let mut enc = Encoder::new();
+        enc.psb().psbend().tip_pge(0x1000, IPCompression::Full);
+        for i in 0..10_000 {
+            enc.ptw(i, false);
+        }
+        enc.tip_pgd(0, IPCompression::Suppressed);
+        let trace = <dyn Trace>::from_bytes(enc.into_bytes());
+        let token = CancellationToken::new();
+        for pipelined in [false, true] {
+            let dec = TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::YkPT)
+                .limits(DecodeLimits {
+                    cancel: Some(token.clone()),
+                    ..Default::default()
+                })
+                .pipelined(pipelined)
+                .build()
+                .unwrap();
+            let mut n = 0;
+            let res = dec.decode_events_until(&*trace, &mut |_| {
+                n += 1;
+                if n == 10 {
+                    let token = token.clone();
+                    thread::spawn(move || token.cancel()).join().unwrap();
+                }
+                ControlFlow::Continue(())
+            });
+            assert!(matches!(
+                res,
+                Err(HWTracerError::Decode(DecodeError::Cancelled))
+            ));
+            assert_eq!(n, 10);
+            assert!(dec.iter_events(&*trace).next().unwrap().is_err());
+            token.reset();
+            assert!(dec.iter_events(&*trace).all(|ev| ev.is_ok()));
+        }
+    }
+
+    /// Check that the throughput of decodes, pipelined or not, is recorded if asked for.
+    #[test]
+    fn metrics() {
+        #[rustfmt::skip]
+        let mut bytes = vec![
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+        ];
+        for _ in 0..100 {
+            // A short TNT and a PAD.
+            bytes.extend_from_slice(&[0x06, 0x00]);
+        }
+        // TIP.PGD with no IP.
+        bytes.push(0x01);
+        let trace = <dyn Trace>::from_bytes(bytes);
+        let metrics = DecodeMetrics::new();
+        for pipelined in [false, true] {
+            let dec = TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::YkPT)
+                .metrics(metrics.clone())
+                .pipelined(pipelined)
+                .build()
+                .unwrap();
+            dec.decode_events_until(&*trace, &mut |_| ControlFlow::Continue(()))
+                .unwrap();
+            let stats = metrics.last().unwrap();
+            assert_eq!(stats.bytes, trace.len());
+            // PSB, PSBEND, TIP.PGE, the TNTs and TIP.PGD.
+            assert_eq!(stats.packets, 104);
+            assert!(stats.elapsed() > Duration::ZERO);
+        }
+        assert_eq!(metrics.decodes(), 2);
+        assert_eq!(metrics.total().packets, 208);
+    }
+
+    /// Check that session boundaries in concatenated traces are reported, and that an
+    /// asynchronous transfer left pending at the end of a session doesn't leak into the next.
+    #[test]
+    fn session_boundaries() {
+        #[rustfmt::skip]
+        let session = [
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // A FUP whose TIP never arrives.
+            0x3d, 0x00, 0x10,
+        ];
+        let a = <dyn Trace>::from_bytes(session.to_vec());
+        // The second session ends with a TIP.PGD (with no IP), which would complete the first
+        // session's asynchronous transfer if it leaked.
+        let mut b = session[..session.len() - 3].to_vec();
+        b.push(0x01);
+        let b = <dyn Trace>::from_bytes(b);
+        let trace = <dyn Trace>::concat(&[&*a, &*b]).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .build()
+            .unwrap();
+        let evs = dec
+            .iter_events(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            evs,
+            vec![
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::SessionBoundary,
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+    }
+
+    /// Check that diagnostics locate a failure, whether or not the error says where it is, and
+    /// find the packets before it and where decoding could resynchronise.
+    #[test]
+    fn diagnostics() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x5555_1000, IPCompression::Full)
+            .tnt(&[true]);
+        let bad = enc.bytes().len();
+        let mut bytes = enc.into_bytes();
+        bytes.extend_from_slice(&[0x02, 0xff]);
+        let psb = bytes.len();
+        let mut enc = Encoder::new();
+        enc.psb().psbend();
+        bytes.extend_from_slice(enc.bytes());
+        let mut trace = RawTrace::new(bytes);
+        trace.meta.id = Some(TraceId(0x1234));
+        trace
+            .meta
+            .provenance
+            .insert("thread".to_owned(), "main".to_owned());
+
+        let err = HWTracerError::Decode(DecodeError::parse("bad".into()));
+        let diag = diagnose(&trace, &err).unwrap();
+        assert_eq!(diag.offset, bad);
+        assert_eq!(diag.trace_id, Some(TraceId(0x1234)));
+        assert_eq!(diag.provenance, trace.meta.provenance);
+        assert!(diag.parse_error.is_some());
+        assert_eq!(diag.parser_state, Some(PacketParserState::Normal));
+        assert_eq!(
+            diag.recent_packets
+                .iter()
+                .map(|(off, _)| *off)
+                .collect::<Vec<_>>(),
+            [0, 16, 18, 27]
+        );
+        assert_eq!(diag.window_start, 0);
+        assert_eq!(diag.window.len(), trace.len());
+        assert_eq!(diag.next_psb, Some(psb));
+        let report = diag.to_string();
+        assert!(report.contains("[02] ff"));
+        assert!(report.contains("trace ID: 00000000000000000000000000001234"));
+        assert!(report.contains("provenance: thread = main"));
+
+        // The error says where it happened, before the parser fails.
+        let diag = diagnose(&trace, &err.at_offset(18)).unwrap();
+        assert_eq!(diag.offset, 18);
+        assert_eq!(diag.parse_error, None);
+        assert_eq!(diag.recent_packets.len(), 2);
+        assert_eq!(diag.next_psb, Some(psb));
+    }
+
+    /// Check that context switches are found from PIP packets and sideband records, and that
+    /// packets from other contexts can be skipped.
+    #[test]
+    fn context_switches() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .pip(0x1000, false)
+            .psbend()
+            .tip_pge(0x5555_1000, IPCompression::Full)
+            // Another process runs, then this one returns.
+            .pip(0x2000, false)
+            .ptw(1, false)
+            .pip(0x1000, false)
+            .ptw(2, false);
+        let sb_off = enc.bytes().len();
+        // The thread is switched out, then in again.
+        enc.ptw(3, false).ptw(4, false);
+        let sb_in = enc.bytes().len();
+        enc.tip_pgd(0, IPCompression::Suppressed);
+        let trace = RawTrace {
+            bytes: enc.into_bytes(),
+            meta: Default::default(),
+            sideband: [(sb_off, true), (sb_in, false)]
+                .iter()
+                .map(|&(trace_offset, out)| SidebandRecord {
+                    trace_offset,
+                    event: SidebandEvent::Switch { out },
+                })
+                .collect(),
+        };
+        let decode = |only| {
+            TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::YkPT)
+                .traced_context_only(only)
+                .build()
+                .unwrap()
+                .iter_events(&trace)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let out = DecodeEvent::ContextSwitch { out: true };
+        let back = DecodeEvent::ContextSwitch { out: false };
+        assert_eq!(
+            decode(false),
+            vec![
+                DecodeEvent::TracingEnabled(0x5555_1000),
+                out.clone(),
+                DecodeEvent::PTWrite(1),
+                back.clone(),
+                DecodeEvent::PTWrite(2),
+                out.clone(),
+                DecodeEvent::PTWrite(3),
+                DecodeEvent::PTWrite(4),
+                back.clone(),
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+        assert_eq!(
+            decode(true),
+            vec![
+                DecodeEvent::TracingEnabled(0x5555_1000),
+                out.clone(),
+                back.clone(),
+                DecodeEvent::PTWrite(2),
+                out,
+                back,
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+    }
+
+    /// Check that the events of committed transactions are reported between their begin and
+    /// commit, and that those of aborted transactions (or of one cut short by an overflow) are
+    /// discarded.
+    #[test]
+    fn transactions() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x5555_1000, IPCompression::Full)
+            // A transaction which commits.
+            .mode_tsx(true, false)
+            .fup(0x5555_1010, IPCompression::Update16)
+            .ptw(1, false)
+            .mode_tsx(false, false)
+            .fup(0x5555_1020, IPCompression::Update16)
+            // A transaction which aborts, going to its abort handler.
+            .mode_tsx(true, false)
+            .fup(0x5555_1030, IPCompression::Update16)
+            .ptw(2, false)
+            .mode_tsx(false, true)
+            .fup(0x5555_1040, IPCompression::Update16)
+            .tip(0x5555_1100, IPCompression::Update16)
+            .ptw(3, false)
+            // A transaction whose outcome is lost.
+            .mode_tsx(true, false)
+            .fup(0x5555_1200, IPCompression::Update16)
+            .ptw(4, false)
+            .ovf()
+            .fup(0x5555_1300, IPCompression::Full)
+            .tip_pgd(0, IPCompression::Suppressed);
+        let trace = <dyn Trace>::from_bytes(enc.into_bytes());
+        let evs = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .build()
+            .unwrap()
+            .iter_events(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            evs,
+            vec![
+                DecodeEvent::TracingEnabled(0x5555_1000),
+                DecodeEvent::TransactionBegin,
+                DecodeEvent::PTWrite(1),
+                DecodeEvent::TransactionCommit,
+                DecodeEvent::TransactionAbort {
+                    from: Some(0x5555_1040),
+                    to: Some(0x5555_1100),
+                },
+                DecodeEvent::PTWrite(3),
+                DecodeEvent::Overflow,
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+    }
+
+    /// Check that junk before the first PSB packet, and MODE packets of an unknown kind, are
+    /// skipped with warnings rather than stopping decoding.
+    #[test]
+    fn warnings() {
+        #[rustfmt::skip]
+        let bytes = [
+            // Junk.
+            0xff, 0xff,
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // MODE with a reserved leaf.
+            0x99, 0x40,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // TIP.PGD with no IP.
+            0x01,
+        ];
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let w = Arc::clone(&warnings);
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .warning_handler(WarningHandler::new(move |warning| {
+                w.lock().unwrap().push(warning.clone())
+            }))
+            .build()
+            .unwrap();
+        let evs = dec
+            .iter_events(&*<dyn Trace>::from_bytes(bytes.to_vec()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            evs,
+            vec![
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            vec![
+                DecodeWarning::SkippedBytes { offset: 0, len: 2 },
+                DecodeWarning::UnknownMode {
+                    offset: 20,
+                    leaf: 0b010
+                },
+            ]
+        );
+    }
+
+    /// Check that a real trace starts by enabling tracing and ends by disabling it.
+    #[test]
+    fn events_enable_disable() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(10)).unwrap();
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .build()
+            .unwrap();
+        let evs = dec
+            .iter_events(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let first_enable = evs
+            .iter()
+            .position(|e| matches!(e, DecodeEvent::TracingEnabled(_)))
+            .unwrap();
+        let last_disable = evs
+            .iter()
+            .rposition(|e| matches!(e, DecodeEvent::TracingDisabled(_)))
+            .unwrap();
+        assert!(first_enable < last_disable);
+    }
+
+    /// Check that an asynchronous transfer which disables tracing, with the destination
+    /// suppressed, is reported as the end of a traced region with no address.
+    #[test]
+    fn async_disable_suppressed() {
+        #[rustfmt::skip]
+        let bytes = [
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // FUP with a 16-bit compressed IP.
+            0x3d, 0x00, 0x10,
+            // TIP.PGD with no IP.
+            0x01,
+        ];
+        let trace = <dyn Trace>::from_bytes(bytes.to_vec());
+        let evs = YkPTEventIterator::new(
+            &*trace,
+            LimitTracker::new(DecodeLimits::default()),
+            &AddrFilter::new(&[]),
+            None,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert_eq!(
+            evs,
+            vec![
+                DecodeEvent::TracingEnabled(0x555512345678),
+                DecodeEvent::AsyncTransfer {
+                    from: 0x555512341000,
+                    to: None
+                },
+                DecodeEvent::TracingDisabled(None),
+            ]
+        );
+    }
 }
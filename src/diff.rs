@@ -0,0 +1,277 @@
+//! Comparing the paths taken by two traces.
+//!
+//! ```no_run
+//! use hwtracer::{decode::TraceDecoderBuilder, diff::diff_blocks, Trace};
+//! # let before = <dyn Trace>::from_bytes(Vec::new());
+//! # let after = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let divs = diff_blocks(dec.iter_blocks(&*before), dec.iter_blocks(&*after), 1000).unwrap();
+//! for d in &divs {
+//!     println!("diverged at blocks {} and {}", d.a_index, d.b_index);
+//! }
+//! ```
+
+use crate::{errors::HWTracerError, Block};
+use std::{collections::VecDeque, iter::Fuse};
+
+/// A point at which two block sequences diverge.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Divergence {
+    /// The index of the first differing block in the first sequence.
+    pub a_index: usize,
+    /// The index of the first differing block in the second sequence.
+    pub b_index: usize,
+    /// The blocks that only the first sequence executed, from `a_index` up to the point at which
+    /// the sequences converge again (or to the end, if they don't).
+    pub a_only: Vec<Block>,
+    /// Likewise for the second sequence, from `b_index`.
+    pub b_only: Vec<Block>,
+}
+
+/// A block, as a `(first_instr, last_instr)` pair, which is cheaper to compare and copy.
+type BlockKey = (u64, u64);
+
+/// One of the block sequences being compared, of which only the blocks from the current position
+/// onwards that have been looked at so far are kept.
+struct Side<I> {
+    blocks: Fuse<I>,
+    /// The blocks read from `blocks` and not yet passed over.
+    buf: VecDeque<BlockKey>,
+    /// The index in the sequence of `buf[0]`.
+    start: usize,
+}
+
+impl<I> Side<I>
+where
+    I: Iterator<Item = Result<Block, HWTracerError>>,
+{
+    fn new(blocks: I) -> Self {
+        Self {
+            blocks: blocks.fuse(),
+            buf: VecDeque::new(),
+            start: 0,
+        }
+    }
+
+    /// Returns the block at index `idx` (which must not be before the current position), reading
+    /// up to it if necessary, or `None` if the sequence is shorter than that.
+    fn get(&mut self, idx: usize) -> Result<Option<BlockKey>, HWTracerError> {
+        while self.start + self.buf.len() <= idx {
+            match self.blocks.next() {
+                Some(b) => {
+                    let b = b?;
+                    self.buf.push_back((b.first_instr(), b.last_instr()));
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(self.buf[idx - self.start]))
+    }
+
+    /// Move the current position on to `idx`, returning the blocks passed over.
+    fn advance(&mut self, idx: usize) -> Vec<Block> {
+        let n = idx - self.start;
+        self.start = idx;
+        self.buf.drain(..n).map(|(f, l)| Block::new(f, l)).collect()
+    }
+
+    /// Move the current position on to the end of the sequence, returning the blocks passed over.
+    fn advance_to_end(&mut self) -> Result<Vec<Block>, HWTracerError> {
+        while self.get(self.start + self.buf.len())?.is_some() {}
+        Ok(self.advance(self.start + self.buf.len()))
+    }
+}
+
+/// An iterator over the points at which two block sequences diverge, as returned by
+/// [iter_divergences].
+pub struct Divergences<A, B> {
+    a: Side<A>,
+    b: Side<B>,
+    window: usize,
+    /// Set once there can be no more divergences, or an error has been returned.
+    done: bool,
+}
+
+impl<A, B> Divergences<A, B>
+where
+    A: Iterator<Item = Result<Block, HWTracerError>>,
+    B: Iterator<Item = Result<Block, HWTracerError>>,
+{
+    fn next_divergence(&mut self) -> Result<Option<Divergence>, HWTracerError> {
+        let (a, b) = (&mut self.a, &mut self.b);
+        loop {
+            match (a.get(a.start)?, b.get(b.start)?) {
+                (None, None) => return Ok(None),
+                (Some(x), Some(y)) if x == y => {
+                    a.advance(a.start + 1);
+                    b.advance(b.start + 1);
+                }
+                _ => break,
+            }
+        }
+        let (i, j) = (a.start, b.start);
+        // Find the nearest convergence point, trying those with the fewest differing blocks first.
+        for dist in 1..=2 * self.window {
+            for di in dist.saturating_sub(self.window)..=dist.min(self.window) {
+                let (ai, bj) = (i + di, j + dist - di);
+                if let (Some(x), Some(y)) = (a.get(ai)?, b.get(bj)?) {
+                    if x == y {
+                        return Ok(Some(Divergence {
+                            a_index: i,
+                            b_index: j,
+                            a_only: a.advance(ai),
+                            b_only: b.advance(bj),
+                        }));
+                    }
+                }
+            }
+        }
+        self.done = true;
+        Ok(Some(Divergence {
+            a_index: i,
+            b_index: j,
+            a_only: a.advance_to_end()?,
+            b_only: b.advance_to_end()?,
+        }))
+    }
+}
+
+impl<A, B> Iterator for Divergences<A, B>
+where
+    A: Iterator<Item = Result<Block, HWTracerError>>,
+    B: Iterator<Item = Result<Block, HWTracerError>>,
+{
+    type Item = Result<Divergence, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_divergence().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+/// Lazily compare the block sequences `a` and `b`, as [diff_blocks] does, yielding each
+/// divergence as soon as it has been found.
+///
+/// Blocks are only read from `a` and `b` as far as is needed to find the next divergence (up to
+/// `window` blocks beyond it), and only the blocks since the last divergence are kept in memory.
+/// A caller that is only interested in the first divergence (e.g. to find where a run went
+/// wrong) therefore doesn't pay for decoding the rest of either trace.
+///
+/// Yields the first error that either sequence yields, if any, and then stops.
+pub fn iter_divergences<A, B>(a: A, b: B, window: usize) -> Divergences<A::IntoIter, B::IntoIter>
+where
+    A: IntoIterator<Item = Result<Block, HWTracerError>>,
+    B: IntoIterator<Item = Result<Block, HWTracerError>>,
+{
+    Divergences {
+        a: Side::new(a.into_iter()),
+        b: Side::new(b.into_iter()),
+        window,
+        done: false,
+    }
+}
+
+/// Compare the block sequences `a` and `b` (e.g. from [crate::decode::TraceDecoder::iter_blocks]
+/// for two runs of the same program), returning the points at which they diverge, in order. An
+/// empty vector means that the sequences are identical.
+///
+/// After a divergence the sequences are considered to converge again at the nearest pair of
+/// matching blocks no more than `window` blocks further on in each. If there is no such pair, the
+/// divergence's differing blocks run to the end of both sequences and no further divergences are
+/// reported. Larger windows find more convergence points, at a cost of up to `window` squared
+/// comparisons per divergence.
+///
+/// Returns the first error that either sequence yields, if any.
+pub fn diff_blocks<A, B>(a: A, b: B, window: usize) -> Result<Vec<Divergence>, HWTracerError>
+where
+    A: IntoIterator<Item = Result<Block, HWTracerError>>,
+    B: IntoIterator<Item = Result<Block, HWTracerError>>,
+{
+    iter_divergences(a, b, window).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_blocks, iter_divergences, Divergence};
+    use crate::{errors::HWTracerError, Block};
+
+    fn blocks(addrs: &[u64]) -> Vec<Result<Block, HWTracerError>> {
+        addrs.iter().map(|&a| Ok(Block::new(a, a + 4))).collect()
+    }
+
+    fn div(a_index: usize, b_index: usize, a_only: &[u64], b_only: &[u64]) -> Divergence {
+        let only = |addrs: &[u64]| addrs.iter().map(|&a| Block::new(a, a + 4)).collect();
+        Divergence {
+            a_index,
+            b_index,
+            a_only: only(a_only),
+            b_only: only(b_only),
+        }
+    }
+
+    #[test]
+    fn identical() {
+        let a = [1, 2, 3];
+        assert!(diff_blocks(blocks(&a), blocks(&a), 10).unwrap().is_empty());
+        assert!(diff_blocks(blocks(&[]), blocks(&[]), 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn divergences() {
+        // `b` takes a detour through 9, and skips 5.
+        let a = [1, 2, 3, 4, 5, 6];
+        let b = [1, 2, 9, 9, 3, 4, 6];
+        assert_eq!(
+            diff_blocks(blocks(&a), blocks(&b), 10).unwrap(),
+            vec![div(2, 2, &[], &[9, 9]), div(4, 6, &[5], &[])]
+        );
+
+        // Sequences which never converge again differ to their ends.
+        assert_eq!(
+            diff_blocks(blocks(&a), blocks(&[1, 7, 8]), 10).unwrap(),
+            vec![div(1, 1, &[2, 3, 4, 5, 6], &[7, 8])]
+        );
+
+        // Convergence points beyond the window aren't found.
+        assert_eq!(
+            diff_blocks(blocks(&a), blocks(&[1, 9, 9, 9, 2, 3, 4, 5, 6]), 2).unwrap(),
+            vec![div(1, 1, &[2, 3, 4, 5, 6], &[9, 9, 9, 2, 3, 4, 5, 6])]
+        );
+
+        // One sequence being a prefix of the other.
+        assert_eq!(
+            diff_blocks(blocks(&a), blocks(&a[..4]), 10).unwrap(),
+            vec![div(4, 4, &[5, 6], &[])]
+        );
+    }
+
+    #[test]
+    fn lazy() {
+        // `a` is endless, so this would never finish if it were read to the end.
+        let a = (1..).map(|a| Ok(Block::new(a, a + 4)));
+        let mut b = blocks(&[1, 9, 2, 3]);
+        b.push(Err(HWTracerError::Unknown));
+        let mut divs = iter_divergences(a, b, 2);
+        assert_eq!(divs.next().unwrap().unwrap(), div(1, 1, &[], &[9]));
+        assert!(matches!(divs.next(), Some(Err(HWTracerError::Unknown))));
+        assert!(divs.next().is_none());
+    }
+
+    #[test]
+    fn error() {
+        let mut b = blocks(&[1, 2]);
+        b.push(Err(HWTracerError::Unknown));
+        assert!(matches!(
+            diff_blocks(blocks(&[1, 2]), b, 10),
+            Err(HWTracerError::Unknown)
+        ));
+    }
+}
@@ -1,91 +1,498 @@
-use crate::{collect::TraceCollectorKind, decode::TraceDecoderKind};
-use libc::{c_int, strerror};
+#[cfg(feature = "collect")]
+use crate::collect::TraceCollectorKind;
+#[cfg(feature = "decode")]
+use crate::decode::{DecodeLimit, TraceDecoderKind};
+use crate::pt::PacketError;
+#[cfg(unix)]
+use libc::{EAGAIN, EBUSY, EINTR, ENOMEM};
 use std::error::Error;
-use std::ffi::{self, CStr};
+use std::ffi;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::num::ParseIntError;
+use std::os::raw::c_int;
+use std::path::PathBuf;
 
+/// An error reported by hwtracer.
+///
+/// Errors are grouped by what went wrong: collecting a trace, decoding one, or the way that
+/// hwtracer was configured. Callers deciding how to react to an error can also use
+/// [HWTracerError::is_transient] (is it worth retrying?) and [HWTracerError::is_bad_trace] (should
+/// the trace be skipped?). Anything else generally means that there is no point carrying on.
 #[derive(Debug)]
 pub enum HWTracerError {
+    /// Collecting a trace failed.
+    Collect(CollectError),
+    /// Reading or decoding a trace failed.
+    Decode(DecodeError),
+    /// hwtracer was configured, or built, in a way that can't do what was asked of it.
+    Config(ConfigError),
+    /// An unknown error. Used sparingly for C code which doesn't set errno.
+    Unknown,
+    /// Any other error (e.g. an I/O error).
+    Custom(Box<dyn Error>),
+}
+
+impl HWTracerError {
+    /// Returns `true` if the error may not happen again, so that the operation that failed is
+    /// worth retrying (e.g. collecting a trace whose buffer overflowed, perhaps with a bigger
+    /// buffer).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Collect(CollectError::HWBufferOverflow) => true,
+            Self::Collect(CollectError::Io(e)) => io_is_transient(e),
+            #[cfg(feature = "decode")]
+            Self::Decode(DecodeError::LimitExceeded(DecodeLimit::Deadline)) => true,
+            Self::Decode(DecodeError::LibIPT(e)) => e.kind() == LibIPTErrorKind::NoMemory,
+            Self::Custom(e) => e.downcast_ref::<io::Error>().is_some_and(io_is_transient),
+            _ => false,
+        }
+    }
+
+    /// Returns the error number (`errno`) of the failed system call behind the error, if there
+    /// was one. This distinguishes e.g. a lack of permissions (`EPERM`) from a lack of Intel PT
+    /// support (`ENODEV`) and running into the locked memory limit (`ENOMEM`).
+    pub fn errno(&self) -> Option<c_int> {
+        match self {
+            Self::Collect(CollectError::Io(e)) => e.raw_os_error(),
+            Self::Custom(e) => e.downcast_ref::<io::Error>()?.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the error is caused by the contents of the trace being read or decoded,
+    /// so that the trace should be skipped, but other traces may well be fine.
+    pub fn is_bad_trace(&self) -> bool {
+        match self {
+            Self::Decode(DecodeError::LibIPT(e)) => e.kind().is_recoverable(),
+            #[cfg(feature = "decode")]
+            Self::Decode(DecodeError::LimitExceeded(_)) => false,
+            Self::Decode(DecodeError::Cancelled) => false,
+            Self::Decode(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the offset (in bytes) into the trace at which a decoding error occurred, if known.
+    pub fn trace_offset(&self) -> Option<usize> {
+        match self {
+            Self::Decode(DecodeError::Parse { offset, .. })
+            | Self::Decode(DecodeError::Malformed { offset, .. }) => *offset,
+            Self::Decode(DecodeError::LibIPT(e)) => e.offset(),
+            _ => None,
+        }
+    }
+
+    /// Record that a decoding error occurred at `offset` bytes into the trace, unless the error
+    /// already knows where it occurred.
+    pub(crate) fn at_offset(mut self, off: usize) -> Self {
+        if let Self::Decode(DecodeError::Parse { offset, .. })
+        | Self::Decode(DecodeError::Malformed { offset, .. }) = &mut self
+        {
+            offset.get_or_insert(off);
+        }
+        self
+    }
+}
+
+/// Returns `true` if the operation that failed with `e` might succeed if retried.
+fn io_is_transient(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    return matches!(e.raw_os_error(), Some(EAGAIN | EBUSY | EINTR | ENOMEM));
+    #[cfg(not(unix))]
+    false
+}
+
+/// The ways in which collecting a trace can fail.
+#[derive(Debug)]
+pub enum CollectError {
     /// The trace buffer being used by the hardware overflowed.
     HWBufferOverflow,
     /// The hardware doesn't support a required feature.
     NoHWSupport(String),
-    /// This collector was not compiled in to hwtracer.
-    CollectorUnavailable(TraceCollectorKind),
-    /// This decoder was not compiled into hwtracer.
-    DecoderUnavailable(TraceDecoderKind),
     /// Permission denied.
     Permissions(String),
-    /// Something went wrong in C code.
-    Errno(c_int),
+    /// A system call (or C library function) failed. The error number, if any, is available from
+    /// [io::Error::raw_os_error] or [HWTracerError::errno].
+    Io(io::Error),
     /// The collector is already collecting.
     AlreadyCollecting,
     /// Trying to stop a not-currently-active collector.
     AlreadyStopped,
+}
+
+/// The ways in which reading or decoding a trace can fail.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// A trace, or a file containing one, couldn't be parsed.
+    Parse {
+        /// What couldn't be parsed, and why.
+        msg: String,
+        /// The offset (in bytes) into the trace at which the problem was found, if known.
+        offset: Option<usize>,
+    },
+    /// A saved trace has been damaged (e.g. truncated) since it was saved.
+    Corrupt(String),
+    /// The trace contains data that the decoder can't make sense of.
+    Malformed {
+        kind: MalformedTraceKind,
+        /// The offset (in bytes) into the trace at which the problem was found, if known.
+        offset: Option<usize>,
+    },
+    /// libipt reported an error.
+    LibIPT(LibIPTError),
+    /// The decoder exceeded one of its resource limits.
+    #[cfg(feature = "decode")]
+    LimitExceeded(DecodeLimit),
+    /// The decoder reached the end of the trace having left `bytes` bytes of it, and `tnt` branch
+    /// decisions from its TNT packets, unconsumed (see
+    /// [crate::decode::TraceDecoderConfig::verify_complete]).
+    Incomplete { bytes: usize, tnt: usize },
+    /// Decoding was cancelled with a [crate::decode::CancellationToken].
+    Cancelled,
+    /// An object that the trace's code is read from isn't the build of it that was loaded when the
+    /// trace was collected (e.g. it has been recompiled since), so decoding would give the wrong
+    /// control flow (see [crate::ObjectBuildId]).
+    BuildIdMismatch {
+        /// The object's path.
+        path: PathBuf,
+        /// The build ID recorded when the trace was collected.
+        expected: Vec<u8>,
+        /// The object's build ID now, or `None` if it no longer has one.
+        found: Option<Vec<u8>>,
+    },
+}
+
+impl DecodeError {
+    /// A [DecodeError::Parse] error, with an unknown offset.
+    pub(crate) fn parse(msg: String) -> Self {
+        Self::Parse { msg, offset: None }
+    }
+
+    /// A [DecodeError::Malformed] error, with an unknown offset.
+    pub(crate) fn malformed(kind: MalformedTraceKind) -> Self {
+        Self::Malformed { kind, offset: None }
+    }
+}
+
+/// The ways in which hwtracer can be configured (or built) such that it can't do what was asked.
+#[derive(Debug)]
+pub enum ConfigError {
     /// Invalid configuration.
-    BadConfig(String),
-    /// An unknown error. Used sparingly for C code which doesn't set errno.
-    Unknown,
-    /// Failed to decode trace.
-    TraceParseError(String),
-    /// Any other error.
-    Custom(Box<dyn Error>),
+    Invalid(String),
+    /// This collector was not compiled in to hwtracer.
+    #[cfg(feature = "collect")]
+    CollectorUnavailable(TraceCollectorKind),
+    /// This decoder was not compiled into hwtracer.
+    #[cfg(feature = "decode")]
+    DecoderUnavailable(TraceDecoderKind),
+    /// The requested operation isn't supported by this collector or decoder.
+    Unsupported(String),
 }
 
-impl Display for HWTracerError {
+/// The ways in which a trace can be malformed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MalformedTraceKind {
+    /// A compressed IP was encountered before any full IP was seen.
+    NoLastIP,
+    /// A packet used a reserved IP compression scheme.
+    ReservedIPBytes(u8),
+    /// A packet's IP payload didn't match its IP compression scheme.
+    IPBytesMismatch(u8),
+    /// The decoder returned a status that hwtracer didn't expect.
+    UnexpectedDecoderStatus,
+    /// A block ended part way through an instruction.
+    TruncatedBlock,
+    /// The decoder produced a block containing no instructions.
+    EmptyBlock,
+    /// The decoder reported an instruction class that hwtracer doesn't know about.
+    UnknownInstrClass,
+    /// The decoder reported an event that hwtracer doesn't know how to handle.
+    UnhandledEvent,
+    /// A sideband record collected alongside the trace couldn't be parsed.
+    BadSidebandRecord,
+    /// More branch decisions were buffered than the decoder can hold, which means that the trace
+    /// doesn't match the traced code.
+    TNTOverflow,
+}
+
+/// The kinds of error that libipt can report. See `enum pt_error_code` in libipt's `intel-pt.h`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LibIPTErrorKind {
+    /// An error code hwtracer doesn't know about.
+    Other(i32),
+    /// An internal libipt error.
+    Internal,
+    /// An invalid argument was passed to libipt.
+    Invalid,
+    /// The decoder is out of sync with the trace.
+    NoSync,
+    /// An unknown opcode was encountered in the trace.
+    BadOpcode,
+    /// An unknown packet payload was encountered in the trace.
+    BadPacket,
+    /// A packet was encountered in an unexpected context.
+    BadContext,
+    /// The end of the trace was reached unexpectedly.
+    EndOfStream,
+    /// No packet matching the query was found.
+    BadQuery,
+    /// libipt ran out of memory.
+    NoMemory,
+    /// The decoder was badly configured.
+    BadConfig,
+    /// There is no instruction pointer to decode relative to.
+    NoIP,
+    /// The instruction pointer was suppressed in the trace.
+    IPSuppressed,
+    /// No code is mapped at the instruction pointer.
+    NoMap,
+    /// An instruction couldn't be decoded.
+    BadInstruction,
+    /// No timing information is available.
+    NoTime,
+    /// No core:bus ratio is available.
+    NoCBR,
+    /// The decoder's memory image is bad.
+    BadImage,
+    /// A locking error occurred.
+    BadLock,
+    /// The requested feature isn't supported.
+    NotSupported,
+    /// A return was compressed but the return stack is empty.
+    RetStackEmpty,
+    /// A return was compressed that shouldn't have been.
+    BadRetComp,
+    /// An unexpected status update was encountered.
+    BadStatusUpdate,
+    /// An event was expected to enable tracing, but didn't.
+    NoEnable,
+    /// An event was ignored.
+    EventIgnored,
+    /// A file couldn't be opened or read.
+    BadFile,
+    /// The CPU isn't supported.
+    BadCPU,
+}
+
+impl LibIPTErrorKind {
+    /// Returns `true` if the error relates to the trace (or the code image used to decode it)
+    /// rather than to the decoder itself. Decoding can resume from the next synchronisation point
+    /// in the trace after such an error.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            Self::NoSync
+                | Self::BadOpcode
+                | Self::BadPacket
+                | Self::BadContext
+                | Self::BadQuery
+                | Self::NoIP
+                | Self::IPSuppressed
+                | Self::NoMap
+                | Self::BadInstruction
+                | Self::NoTime
+                | Self::NoCBR
+                | Self::RetStackEmpty
+                | Self::BadRetComp
+                | Self::BadStatusUpdate
+                | Self::NoEnable
+                | Self::EventIgnored
+        )
+    }
+}
+
+/// An error reported by libipt.
+#[derive(Debug)]
+pub struct LibIPTError {
+    pub(crate) kind: LibIPTErrorKind,
+    /// libipt's description of the error.
+    pub(crate) msg: String,
+    /// The offset (in bytes) into the trace at which the error occurred, if known.
+    pub(crate) offset: Option<usize>,
+}
+
+impl LibIPTError {
+    /// Returns the kind of error.
+    pub fn kind(&self) -> LibIPTErrorKind {
+        self.kind
+    }
+
+    /// Returns the offset (in bytes) into the trace at which the error occurred, if known.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+}
+
+impl Display for LibIPTError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "libipt error: {}", self.msg)?;
+        if let Some(off) = self.offset {
+            write!(f, " (at trace offset {})", off)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for CollectError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
-            HWTracerError::HWBufferOverflow => write!(f, "Hardware trace buffer overflow"),
-            HWTracerError::CollectorUnavailable(ref s) => {
-                write!(f, "Trace collector unavailble: {:?}", s)
+            CollectError::HWBufferOverflow => write!(f, "Hardware trace buffer overflow"),
+            CollectError::NoHWSupport(ref s) => write!(f, "{}", s),
+            CollectError::Permissions(ref s) => write!(f, "{}", s),
+            CollectError::Io(ref e) => write!(f, "{}", e),
+            CollectError::AlreadyCollecting => {
+                write!(f, "Can't start a collector that's already collecting")
             }
-            HWTracerError::DecoderUnavailable(ref s) => {
-                write!(f, "Trace decoder unavailble: {:?}", s)
+            CollectError::AlreadyStopped => write!(f, "Can't stop an inactice collector"),
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Parse { ref msg, .. } => write!(f, "failed to parse trace: {}", msg)?,
+            DecodeError::Corrupt(ref s) => write!(f, "corrupt trace: {}", s)?,
+            DecodeError::Malformed { kind, .. } => write!(f, "malformed trace: {:?}", kind)?,
+            // Includes the offset itself.
+            DecodeError::LibIPT(ref e) => return write!(f, "{}", e),
+            #[cfg(feature = "decode")]
+            DecodeError::LimitExceeded(l) => write!(f, "decoder limit exceeded: {:?}", l)?,
+            DecodeError::Incomplete { bytes, tnt } => write!(
+                f,
+                "decoding finished with {} bytes and {} branch decisions unconsumed",
+                bytes, tnt
+            )?,
+            DecodeError::Cancelled => write!(f, "decoding was cancelled")?,
+            DecodeError::BuildIdMismatch {
+                ref path,
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "{} has changed since the trace was collected: its build ID was {}, but is now {}",
+                path.display(),
+                hex(expected),
+                found.as_deref().map_or_else(|| "missing".into(), hex)
+            )?,
+        }
+        match *self {
+            DecodeError::Parse {
+                offset: Some(off), ..
             }
-            HWTracerError::NoHWSupport(ref s) => write!(f, "{}", s),
-            HWTracerError::Permissions(ref s) => write!(f, "{}", s),
-            HWTracerError::Errno(n) => {
-                // Ask libc for a string representation of the error code.
-                let err_str = unsafe { CStr::from_ptr(strerror(n)) };
-                write!(f, "{}", err_str.to_str().unwrap())
+            | DecodeError::Malformed {
+                offset: Some(off), ..
+            } => write!(f, " (at trace offset {})", off),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Format `bytes` as lower-case hexadecimal, as build IDs usually are.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Invalid(ref s) => write!(f, "{}", s),
+            #[cfg(feature = "collect")]
+            ConfigError::CollectorUnavailable(ref s) => {
+                write!(f, "Trace collector unavailble: {:?}", s)
             }
-            HWTracerError::AlreadyCollecting => {
-                write!(f, "Can't start a collector that's already collecting")
+            #[cfg(feature = "decode")]
+            ConfigError::DecoderUnavailable(ref s) => {
+                write!(f, "Trace decoder unavailble: {:?}", s)
             }
-            HWTracerError::AlreadyStopped => write!(f, "Can't stop an inactice collector"),
-            HWTracerError::BadConfig(ref s) => write!(f, "{}", s),
+            ConfigError::Unsupported(ref s) => write!(f, "unsupported: {}", s),
+        }
+    }
+}
+
+impl Display for HWTracerError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            HWTracerError::Collect(ref e) => write!(f, "{}", e),
+            HWTracerError::Decode(ref e) => write!(f, "{}", e),
+            HWTracerError::Config(ref e) => write!(f, "{}", e),
             HWTracerError::Custom(ref bx) => write!(f, "{}", bx),
-            HWTracerError::TraceParseError(ref s) => write!(f, "failed to parse trace: {}", s),
             HWTracerError::Unknown => write!(f, "Unknown error"),
         }
     }
 }
 
+impl Error for CollectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CollectError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for DecodeError {}
+impl Error for ConfigError {}
+
 impl Error for HWTracerError {
     fn description(&self) -> &str {
         "hwtracer error"
     }
 
-    fn cause(&self) -> Option<&dyn Error> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
-            HWTracerError::HWBufferOverflow => None,
-            HWTracerError::CollectorUnavailable(_) => None,
-            HWTracerError::DecoderUnavailable(_) => None,
-            HWTracerError::NoHWSupport(_) => None,
-            HWTracerError::Permissions(_) => None,
-            HWTracerError::AlreadyCollecting => None,
-            HWTracerError::AlreadyStopped => None,
-            HWTracerError::BadConfig(_) => None,
-            HWTracerError::Errno(_) => None,
+            // These are displayed as this error, so their sources are this error's source.
+            HWTracerError::Collect(ref e) => e.source(),
+            HWTracerError::Decode(ref e) => e.source(),
+            HWTracerError::Config(ref e) => e.source(),
             HWTracerError::Custom(ref bx) => Some(bx.as_ref()),
-            HWTracerError::TraceParseError(_) => None,
             HWTracerError::Unknown => None,
         }
     }
 }
 
+impl From<CollectError> for HWTracerError {
+    fn from(err: CollectError) -> Self {
+        HWTracerError::Collect(err)
+    }
+}
+
+impl From<DecodeError> for HWTracerError {
+    fn from(err: DecodeError) -> Self {
+        HWTracerError::Decode(err)
+    }
+}
+
+impl From<ConfigError> for HWTracerError {
+    fn from(err: ConfigError) -> Self {
+        HWTracerError::Config(err)
+    }
+}
+
+impl From<PacketError> for HWTracerError {
+    fn from(err: PacketError) -> Self {
+        let err = match err {
+            PacketError::Unparseable(msg) => DecodeError::parse(msg),
+            PacketError::NoLastIP => DecodeError::malformed(MalformedTraceKind::NoLastIP),
+            PacketError::ReservedIPBytes(b) => {
+                DecodeError::malformed(MalformedTraceKind::ReservedIPBytes(b))
+            }
+            PacketError::IPBytesMismatch(b) => {
+                DecodeError::malformed(MalformedTraceKind::IPBytesMismatch(b))
+            }
+            PacketError::TNTOverflow => DecodeError::malformed(MalformedTraceKind::TNTOverflow),
+        };
+        HWTracerError::Decode(err)
+    }
+}
+
 impl From<io::Error> for HWTracerError {
     fn from(err: io::Error) -> Self {
         HWTracerError::Custom(Box::new(err))
@@ -103,3 +510,106 @@ impl From<ParseIntError> for HWTracerError {
         HWTracerError::Custom(Box::new(err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CollectError, ConfigError, DecodeError, HWTracerError, LibIPTError, LibIPTErrorKind,
+        MalformedTraceKind,
+    };
+    #[cfg(feature = "decode")]
+    use crate::decode::DecodeLimit;
+    use std::{error::Error, io};
+
+    #[test]
+    fn classification() {
+        #[allow(unused_mut)]
+        let mut transient: Vec<HWTracerError> = vec![
+            CollectError::HWBufferOverflow.into(),
+            CollectError::Io(io::Error::from_raw_os_error(libc::EBUSY)).into(),
+            io::Error::from(io::ErrorKind::Interrupted).into(),
+        ];
+        #[cfg(feature = "decode")]
+        transient.push(DecodeError::LimitExceeded(DecodeLimit::Deadline).into());
+        for e in &transient {
+            assert!(e.is_transient(), "{:?}", e);
+            assert!(!e.is_bad_trace(), "{:?}", e);
+        }
+
+        let bad_trace: Vec<HWTracerError> = vec![
+            DecodeError::parse("bad".into()).into(),
+            DecodeError::Corrupt("truncated".into()).into(),
+            DecodeError::malformed(MalformedTraceKind::NoLastIP).into(),
+            DecodeError::Incomplete { bytes: 0, tnt: 1 }.into(),
+            DecodeError::BuildIdMismatch {
+                path: "/bin/true".into(),
+                expected: vec![0xab, 0xcd],
+                found: None,
+            }
+            .into(),
+            DecodeError::LibIPT(LibIPTError {
+                kind: LibIPTErrorKind::BadPacket,
+                msg: "bad packet".into(),
+                offset: None,
+            })
+            .into(),
+        ];
+        for e in &bad_trace {
+            assert!(!e.is_transient(), "{:?}", e);
+            assert!(e.is_bad_trace(), "{:?}", e);
+        }
+
+        #[allow(unused_mut)]
+        let mut fatal: Vec<HWTracerError> = vec![
+            CollectError::AlreadyCollecting.into(),
+            CollectError::Io(io::Error::from_raw_os_error(libc::EPERM)).into(),
+            ConfigError::Invalid("bad".into()).into(),
+            io::Error::from(io::ErrorKind::NotFound).into(),
+            DecodeError::Cancelled.into(),
+        ];
+        #[cfg(feature = "decode")]
+        fatal.push(DecodeError::LimitExceeded(DecodeLimit::Packets).into());
+        for e in &fatal {
+            assert!(!e.is_transient(), "{:?}", e);
+            assert!(!e.is_bad_trace(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn errnos() {
+        let e = HWTracerError::from(CollectError::Io(io::Error::from_raw_os_error(libc::ENODEV)));
+        assert_eq!(e.errno(), Some(libc::ENODEV));
+        // The underlying error is available from the error chain.
+        let src = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(src.raw_os_error(), Some(libc::ENODEV));
+        assert_eq!(e.to_string(), src.to_string());
+
+        let e = HWTracerError::from(io::Error::from_raw_os_error(libc::EPERM));
+        assert_eq!(e.errno(), Some(libc::EPERM));
+        assert_eq!(
+            HWTracerError::from(CollectError::AlreadyStopped).errno(),
+            None
+        );
+        assert!(HWTracerError::from(CollectError::AlreadyStopped)
+            .source()
+            .is_none());
+    }
+
+    #[test]
+    fn offsets() {
+        let e = HWTracerError::from(DecodeError::parse("bad packet".into()));
+        assert_eq!(e.trace_offset(), None);
+        assert_eq!(e.to_string(), "failed to parse trace: bad packet");
+        let e = e.at_offset(16);
+        assert_eq!(e.trace_offset(), Some(16));
+        assert_eq!(
+            e.to_string(),
+            "failed to parse trace: bad packet (at trace offset 16)"
+        );
+        // An offset that is already known isn't replaced.
+        assert_eq!(e.at_offset(32).trace_offset(), Some(16));
+        // Errors that aren't about the contents of a trace don't have offsets.
+        let e = HWTracerError::from(CollectError::AlreadyStopped).at_offset(16);
+        assert_eq!(e.trace_offset(), None);
+    }
+}
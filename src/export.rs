@@ -0,0 +1,287 @@
+//! Writing decoded traces out in formats that other tools can load, such as pandas or a SQL
+//! database.
+//!
+//! ```no_run
+//! use hwtracer::{decode::TraceDecoderBuilder, export::{write_blocks, Format}, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! write_blocks(dec.iter_blocks(&*trace), Format::Csv, &mut std::io::stdout()).unwrap();
+//! ```
+//!
+//! Every record of a given kind has the same fields, whether or not they have a value, so that
+//! the output loads as a table. Addresses are written as (decimal) integers.
+
+use crate::{
+    decode::{BranchOutcome, DecodeEvent, ExecMode},
+    errors::HWTracerError,
+    Block,
+};
+use std::io::Write;
+
+/// The format to export records in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// [JSON Lines](https://jsonlines.org/): one JSON object per record. Fields without a value
+    /// are `null`.
+    JsonLines,
+    /// Comma separated values, with a header row naming the fields. Fields without a value are
+    /// empty.
+    Csv,
+}
+
+/// The value of a field in an exported record.
+#[derive(Clone, Copy)]
+enum Value {
+    Null,
+    Bool(bool),
+    U64(u64),
+    /// A name: never anything that needs escaping.
+    Name(&'static str),
+}
+
+/// Writes records with the fields `fields` in the format `fmt`.
+struct Writer<'a> {
+    fmt: Format,
+    fields: &'static [&'static str],
+    w: &'a mut dyn Write,
+}
+
+impl<'a> Writer<'a> {
+    fn new(
+        fmt: Format,
+        fields: &'static [&'static str],
+        w: &'a mut dyn Write,
+    ) -> Result<Self, HWTracerError> {
+        if fmt == Format::Csv {
+            writeln!(w, "{}", fields.join(","))?;
+        }
+        Ok(Self { fmt, fields, w })
+    }
+
+    fn record(&mut self, vals: &[Value]) -> Result<(), HWTracerError> {
+        debug_assert_eq!(vals.len(), self.fields.len());
+        let mut line = String::new();
+        for (i, (field, val)) in self.fields.iter().zip(vals).enumerate() {
+            let val = match (self.fmt, val) {
+                (Format::JsonLines, Value::Null) => "null".to_owned(),
+                (Format::Csv, Value::Null) => String::new(),
+                (_, Value::Bool(b)) => b.to_string(),
+                (_, Value::U64(v)) => v.to_string(),
+                (Format::JsonLines, Value::Name(s)) => format!("\"{}\"", s),
+                (Format::Csv, Value::Name(s)) => (*s).to_owned(),
+            };
+            match self.fmt {
+                Format::JsonLines => {
+                    line.push(if i == 0 { '{' } else { ',' });
+                    line.push_str(&format!("\"{}\":{}", field, val));
+                }
+                Format::Csv => {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    line.push_str(&val);
+                }
+            }
+        }
+        if self.fmt == Format::JsonLines {
+            line.push('}');
+        }
+        writeln!(self.w, "{}", line)?;
+        Ok(())
+    }
+}
+
+fn opt(v: Option<u64>) -> Value {
+    v.map_or(Value::Null, Value::U64)
+}
+
+/// Write `blocks` (e.g. from [crate::decode::TraceDecoder::iter_blocks]) to `w` in the format
+/// `fmt`, with the fields `first_instr` and `last_instr`.
+///
+/// If `blocks` yields an error, the blocks before it are written and the error is returned.
+pub fn write_blocks<I>(blocks: I, fmt: Format, w: &mut dyn Write) -> Result<(), HWTracerError>
+where
+    I: IntoIterator<Item = Result<Block, HWTracerError>>,
+{
+    let mut wr = Writer::new(fmt, &["first_instr", "last_instr"], w)?;
+    for b in blocks {
+        let b = b?;
+        wr.record(&[Value::U64(b.first_instr()), Value::U64(b.last_instr())])?;
+    }
+    Ok(())
+}
+
+/// Write `branches` (e.g. from [crate::decode::TraceDecoder::iter_branches]) to `w` in the format
+/// `fmt`, with the fields:
+///
+///  - `kind`: `conditional` or `indirect`.
+///  - `taken`: whether a conditional branch was taken.
+///  - `target`: where an indirect branch went, if known.
+///
+/// If `branches` yields an error, the branches before it are written and the error is returned.
+pub fn write_branches<I>(branches: I, fmt: Format, w: &mut dyn Write) -> Result<(), HWTracerError>
+where
+    I: IntoIterator<Item = Result<BranchOutcome, HWTracerError>>,
+{
+    let mut wr = Writer::new(fmt, &["kind", "taken", "target"], w)?;
+    for b in branches {
+        let vals = match b? {
+            BranchOutcome::Conditional(taken) => {
+                [Value::Name("conditional"), Value::Bool(taken), Value::Null]
+            }
+            BranchOutcome::Indirect(target) => [Value::Name("indirect"), Value::Null, opt(target)],
+        };
+        wr.record(&vals)?;
+    }
+    Ok(())
+}
+
+/// Write `events` (e.g. from [crate::decode::TraceDecoder::iter_events]) to `w` in the format
+/// `fmt`, with the fields:
+///
+///  - `event`: the kind of event, e.g. `tracing_enabled` for [DecodeEvent::TracingEnabled].
+///  - `addr`: the address the event happened at (or, for `async_transfer`, came from), if any.
+///  - `to`: where an `async_transfer` went, if known.
+///  - `value`: the value carried by the event, if any: the number of bits for `exec_mode`, the
+///    ratio for `core_bus_ratio`, the value written for `ptwrite`, and 1 (out) or 0 (in) for
+///    `context_switch`.
+///
+/// If `events` yields an error, the events before it are written and the error is returned.
+pub fn write_events<I>(events: I, fmt: Format, w: &mut dyn Write) -> Result<(), HWTracerError>
+where
+    I: IntoIterator<Item = Result<DecodeEvent, HWTracerError>>,
+{
+    let mut wr = Writer::new(fmt, &["event", "addr", "to", "value"], w)?;
+    for ev in events {
+        let (name, addr, to, value) = match ev? {
+            DecodeEvent::TracingEnabled(addr) => ("tracing_enabled", Some(addr), None, None),
+            DecodeEvent::TracingDisabled(addr) => ("tracing_disabled", addr, None, None),
+            DecodeEvent::Overflow => ("overflow", None, None, None),
+            DecodeEvent::ExecMode(mode) => {
+                let bits = match mode {
+                    ExecMode::Bits16 => 16,
+                    ExecMode::Bits32 => 32,
+                    ExecMode::Bits64 => 64,
+                };
+                ("exec_mode", None, None, Some(bits))
+            }
+            DecodeEvent::CoreBusRatio(ratio) => {
+                ("core_bus_ratio", None, None, Some(u64::from(ratio)))
+            }
+            DecodeEvent::PTWrite(v) => ("ptwrite", None, None, Some(v)),
+            DecodeEvent::AsyncTransfer { from, to } => ("async_transfer", Some(from), to, None),
+            DecodeEvent::ContextLost => ("context_lost", None, None, None),
+            DecodeEvent::SessionBoundary => ("session_boundary", None, None, None),
+            DecodeEvent::ContextSwitch { out } => {
+                ("context_switch", None, None, Some(u64::from(out)))
+            }
+            DecodeEvent::TransactionBegin => ("transaction_begin", None, None, None),
+            DecodeEvent::TransactionCommit => ("transaction_commit", None, None, None),
+            DecodeEvent::TransactionAbort { from, to } => ("transaction_abort", from, to, None),
+        };
+        wr.record(&[Value::Name(name), opt(addr), opt(to), opt(value)])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_blocks, write_branches, write_events, Format};
+    use crate::{
+        decode::{BranchOutcome, DecodeEvent, ExecMode},
+        errors::HWTracerError,
+        Block,
+    };
+
+    fn export<F>(f: F) -> String
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<(), HWTracerError>,
+    {
+        let mut buf = Vec::new();
+        f(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn blocks() {
+        let blocks = || {
+            vec![
+                Ok(Block::new(0x1000, 0x1010)),
+                Ok(Block::new(0x2000, 0x2000)),
+            ]
+        };
+        assert_eq!(
+            export(|w| write_blocks(blocks(), Format::Csv, w)),
+            "first_instr,last_instr\n4096,4112\n8192,8192\n"
+        );
+        assert_eq!(
+            export(|w| write_blocks(blocks(), Format::JsonLines, w)),
+            "{\"first_instr\":4096,\"last_instr\":4112}\n\
+             {\"first_instr\":8192,\"last_instr\":8192}\n"
+        );
+        // Nothing but a header for no blocks.
+        assert_eq!(
+            export(|w| write_blocks(Vec::new(), Format::Csv, w)),
+            "first_instr,last_instr\n"
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let branches = || {
+            vec![
+                Ok(BranchOutcome::Conditional(true)),
+                Ok(BranchOutcome::Indirect(Some(16))),
+                Ok(BranchOutcome::Indirect(None)),
+            ]
+        };
+        assert_eq!(
+            export(|w| write_branches(branches(), Format::Csv, w)),
+            "kind,taken,target\nconditional,true,\nindirect,,16\nindirect,,\n"
+        );
+        assert_eq!(
+            export(|w| write_branches(branches(), Format::JsonLines, w)),
+            "{\"kind\":\"conditional\",\"taken\":true,\"target\":null}\n\
+             {\"kind\":\"indirect\",\"taken\":null,\"target\":16}\n\
+             {\"kind\":\"indirect\",\"taken\":null,\"target\":null}\n"
+        );
+    }
+
+    #[test]
+    fn events() {
+        let events = || {
+            vec![
+                Ok(DecodeEvent::TracingEnabled(1)),
+                Ok(DecodeEvent::ExecMode(ExecMode::Bits64)),
+                Ok(DecodeEvent::AsyncTransfer { from: 2, to: None }),
+                Ok(DecodeEvent::TracingDisabled(None)),
+            ]
+        };
+        assert_eq!(
+            export(|w| write_events(events(), Format::Csv, w)),
+            "event,addr,to,value\ntracing_enabled,1,,\nexec_mode,,,64\nasync_transfer,2,,\n\
+             tracing_disabled,,,\n"
+        );
+        assert_eq!(
+            export(|w| write_events(events(), Format::JsonLines, w))
+                .lines()
+                .nth(2),
+            Some("{\"event\":\"async_transfer\",\"addr\":2,\"to\":null,\"value\":null}")
+        );
+    }
+
+    #[test]
+    fn error() {
+        let mut buf = Vec::new();
+        let blocks = vec![Ok(Block::new(1, 2)), Err(HWTracerError::Unknown)];
+        assert!(matches!(
+            write_blocks(blocks, Format::JsonLines, &mut buf),
+            Err(HWTracerError::Unknown)
+        ));
+        // What came before the error was still written.
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\"first_instr\":1,\"last_instr\":2}\n"
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! A corpus of small traces whose decoded output is known to be good, so that decoders (and
+//! changes to them) can be checked on any machine, whether or not it has Intel PT.
+//!
+//! Each fixture is a pair of files in a directory (such as the one that ships with hwtracer, see
+//! [corpus_dir]):
+//!
+//!  - `<name>.hwtrace`: the trace, with its metadata and sideband records, as written by
+//!    [Trace::to_writer].
+//!  - `<name>.expected`: the expected output of decoding the trace. Blank lines and lines
+//!    starting with `#` are ignored. A `[blocks]` line starts the list of expected blocks, one
+//!    per line as the hexadecimal addresses of their first and last instructions. An `[events]`
+//!    line starts the list of expected events, one per line formatted with `{:?}`. Either list
+//!    may be left out, in which case that output isn't checked (e.g. the blocks of a trace whose
+//!    code isn't available).
+//!
+//! ```
+//! use hwtracer::{
+//!     decode::{TraceDecoderBuilder, TraceDecoderKind},
+//!     fixtures,
+//! };
+//! let dec = TraceDecoderBuilder::new()
+//!     .kind(TraceDecoderKind::YkPT)
+//!     .build()
+//!     .unwrap();
+//! for fixture in fixtures::load_corpus(&fixtures::corpus_dir()).unwrap() {
+//!     let mismatches = fixture.check(&*dec).unwrap();
+//!     assert!(mismatches.is_empty(), "{}: {:?}", fixture.name(), mismatches);
+//! }
+//! ```
+
+use crate::{
+    decode::TraceDecoder,
+    diff::{diff_blocks, Divergence},
+    errors::HWTracerError,
+    Block, Trace,
+};
+use std::{
+    ffi::OsStr,
+    fmt, fs,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+/// Returns the directory holding the fixtures that ship with hwtracer.
+pub fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+}
+
+/// A trace and its expected decoded output.
+#[derive(Debug)]
+pub struct Fixture {
+    name: String,
+    trace: Box<dyn Trace>,
+    blocks: Option<Vec<Block>>,
+    events: Option<Vec<String>>,
+}
+
+impl Fixture {
+    /// Returns the name of the fixture.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the trace. Its metadata is available through [Trace::meta].
+    pub fn trace(&self) -> &dyn Trace {
+        &*self.trace
+    }
+
+    /// Returns the expected blocks, if the fixture has them.
+    pub fn expected_blocks(&self) -> Option<&[Block]> {
+        self.blocks.as_deref()
+    }
+
+    /// Returns the expected events, formatted with `{:?}`, if the fixture has them.
+    pub fn expected_events(&self) -> Option<&[String]> {
+        self.events.as_deref()
+    }
+
+    /// Decode the trace with `dec`, returning the ways in which the output differs from the
+    /// expected output. An empty vector means that the output is as expected.
+    ///
+    /// An error is returned if decoding fails.
+    pub fn check(&self, dec: &dyn TraceDecoder) -> Result<Vec<Mismatch>, HWTracerError> {
+        let mut mismatches = Vec::new();
+        if let Some(blocks) = &self.blocks {
+            let expected = blocks
+                .iter()
+                .map(|b| Ok(Block::new(b.first_instr(), b.last_instr())));
+            let divs = diff_blocks(expected, dec.iter_blocks(self.trace()), 16)?;
+            mismatches.extend(divs.into_iter().map(Mismatch::Blocks));
+        }
+        if let Some(events) = &self.events {
+            let actual = dec
+                .iter_events(self.trace())
+                .map(|e| e.map(|e| format!("{:?}", e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(index) =
+                (0..events.len().max(actual.len())).find(|&i| events.get(i) != actual.get(i))
+            {
+                mismatches.push(Mismatch::Event {
+                    index,
+                    expected: events.get(index).cloned(),
+                    actual: actual.get(index).cloned(),
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+}
+
+/// A way in which a decoder's output differs from a fixture's expected output.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Mismatch {
+    /// The decoded blocks diverge from the expected blocks.
+    Blocks(Divergence),
+    /// The decoded events first differ from the expected events at `index`. `None` means that
+    /// there is no event at that index.
+    Event {
+        index: usize,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blocks(d) => write!(
+                f,
+                "blocks diverge at {} (expected {:?}, got {:?})",
+                d.a_index, d.a_only, d.b_only
+            ),
+            Self::Event {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "event {} differs (expected {:?}, got {:?})",
+                index, expected, actual
+            ),
+        }
+    }
+}
+
+/// Load the fixture called `name` from `dir`.
+pub fn load(dir: &Path, name: &str) -> Result<Fixture, HWTracerError> {
+    let trace =
+        <dyn Trace>::from_reader(&mut File::open(dir.join(name).with_extension("hwtrace"))?)?;
+    let expected = fs::read_to_string(dir.join(name).with_extension("expected"))?;
+    let (blocks, events) = parse_expected(&expected)
+        .map_err(|e| HWTracerError::Custom(format!("fixture {}: {}", name, e).into()))?;
+    Ok(Fixture {
+        name: name.to_owned(),
+        trace,
+        blocks,
+        events,
+    })
+}
+
+/// Load all of the fixtures in `dir`, sorted by name.
+pub fn load_corpus(dir: &Path) -> Result<Vec<Fixture>, HWTracerError> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("hwtrace")) {
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names.sort();
+    names.iter().map(|n| load(dir, n)).collect()
+}
+
+/// The expected outputs of a fixture, as read from its `.expected` file.
+type Expected = (Option<Vec<Block>>, Option<Vec<String>>);
+
+/// Parse the contents of a `.expected` file.
+fn parse_expected(s: &str) -> Result<Expected, String> {
+    let (mut blocks, mut events) = (None, None);
+    let mut in_blocks = None;
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line {
+            "[blocks]" => {
+                blocks.get_or_insert_with(Vec::new);
+                in_blocks = Some(true);
+            }
+            "[events]" => {
+                events.get_or_insert_with(Vec::new);
+                in_blocks = Some(false);
+            }
+            _ => match in_blocks {
+                Some(true) => {
+                    let block = parse_block(line)
+                        .ok_or_else(|| format!("line {}: bad block {:?}", i + 1, line))?;
+                    blocks.as_mut().unwrap().push(block);
+                }
+                Some(false) => events.as_mut().unwrap().push(line.to_owned()),
+                None => return Err(format!("line {}: outside of a section", i + 1)),
+            },
+        }
+    }
+    Ok((blocks, events))
+}
+
+/// Parse a block written as the hexadecimal addresses of its first and last instructions.
+fn parse_block(s: &str) -> Option<Block> {
+    let mut addrs = s
+        .split_whitespace()
+        .map(|a| u64::from_str_radix(a.trim_start_matches("0x"), 16).ok());
+    match (addrs.next(), addrs.next(), addrs.next()) {
+        (Some(Some(first)), Some(Some(last)), None) => Some(Block::new(first, last)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{corpus_dir, load_corpus, parse_expected};
+    use crate::Block;
+
+    #[test]
+    fn expected() {
+        let (blocks, events) = parse_expected(
+            "# A comment.\n[blocks]\n0x1000 0x1004\n1010 101f\n\n[events]\nOverflow\n",
+        )
+        .unwrap();
+        assert_eq!(
+            blocks.unwrap(),
+            [Block::new(0x1000, 0x1004), Block::new(0x1010, 0x101f)]
+        );
+        assert_eq!(events.unwrap(), ["Overflow"]);
+
+        // Sections left out aren't checked, but empty ones are.
+        assert_eq!(parse_expected("[events]\n").unwrap(), (None, Some(vec![])));
+        assert!(parse_expected("Overflow\n").is_err());
+        assert!(parse_expected("[blocks]\n0x1000\n").is_err());
+    }
+
+    /// Check that the YkPT decoder decodes every fixture as expected.
+    #[cfg(decoder_ykpt)]
+    #[test]
+    fn corpus_ykpt() {
+        use crate::decode::{TraceDecoderBuilder, TraceDecoderKind};
+
+        let dec = TraceDecoderBuilder::new()
+            .kind(TraceDecoderKind::YkPT)
+            .build()
+            .unwrap();
+        let corpus = load_corpus(&corpus_dir()).unwrap();
+        assert!(!corpus.is_empty());
+        for fixture in corpus {
+            // The YkPT decoder doesn't yet reconstruct blocks.
+            if fixture.expected_blocks().is_some() {
+                continue;
+            }
+            let mismatches = fixture.check(&*dec).unwrap();
+            assert!(
+                mismatches.is_empty(),
+                "{}: {}",
+                fixture.name(),
+                mismatches[0]
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Flame graphs of where a trace spent its time.
+//!
+//! ```no_run
+//! use hwtracer::{decode::TraceDecoderBuilder, flamegraph::FoldedStacks, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let stacks = FoldedStacks::from_insns(dec.iter_insns(&*trace)).unwrap();
+//! // Render with e.g. `inferno-flamegraph` or `flamegraph.pl`.
+//! stacks.to_writer(&mut std::io::stdout()).unwrap();
+//! ```
+
+use crate::{errors::HWTracerError, Insn};
+use std::{collections::BTreeMap, io::Write};
+
+/// The call stacks that a trace executed instructions in, with the number of instructions
+/// executed in each: the "folded stacks" that flame graph tools take as input. Stacks are weighted
+/// by instruction count, rather than cycles, as hwtracer doesn't collect cycle counts.
+///
+/// Call stacks are reconstructed by following the calls and returns in the trace. Each frame is
+/// named after the address of the function it is in, as found from the call into it. Since the
+/// trace starts part way through a program, the functions that were already running when it
+/// started are unknown: the outermost frame is instead named after the first instruction of the
+/// trace that executed in it.
+#[derive(Debug, Default)]
+pub struct FoldedStacks {
+    /// The number of instructions executed with each call stack (outermost frame first).
+    counts: BTreeMap<Vec<u64>, u64>,
+}
+
+impl FoldedStacks {
+    /// Fold `insns` (e.g. from [crate::decode::TraceDecoder::iter_insns]) into call stacks.
+    ///
+    /// Returns the first error that `insns` yields, if any.
+    pub fn from_insns<I>(insns: I) -> Result<Self, HWTracerError>
+    where
+        I: IntoIterator<Item = Result<Insn, HWTracerError>>,
+    {
+        let mut stacks = Self::default();
+        let mut stack = Vec::new();
+        // Set by a call or a return: the next instruction starts a new frame (on top of the
+        // current stack, or in place of it if the return left the outermost known frame).
+        let mut new_frame = true;
+        for insn in insns {
+            let insn = insn?;
+            if new_frame {
+                stack.push(insn.ip());
+                new_frame = false;
+            }
+            *stacks.counts.entry(stack.clone()).or_insert(0) += 1;
+            if insn.is_call() {
+                new_frame = true;
+            } else if insn.is_ret() {
+                stack.pop();
+                new_frame = stack.is_empty();
+            }
+        }
+        Ok(stacks)
+    }
+
+    /// Returns the call stacks (outermost frame first), in lexicographic order, with the number of
+    /// instructions executed in each.
+    pub fn stacks(&self) -> impl Iterator<Item = (&[u64], u64)> + '_ {
+        self.counts.iter().map(|(s, &n)| (s.as_slice(), n))
+    }
+
+    /// Write the call stacks to `w` in the folded format understood by `inferno` and
+    /// `flamegraph.pl`: one line per stack, with frames separated by `;`, followed by the number
+    /// of instructions executed in that stack.
+    pub fn to_writer(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        for (stack, n) in self.stacks() {
+            let frames = stack
+                .iter()
+                .map(|f| format!("{:#x}", f))
+                .collect::<Vec<_>>();
+            writeln!(w, "{} {}", frames.join(";"), n)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FoldedStacks;
+    use crate::{errors::HWTracerError, Insn};
+
+    const NOP: &[u8] = &[0x90];
+    const CALL: &[u8] = &[0xe8, 0, 0, 0, 0];
+    const RET: &[u8] = &[0xc3];
+
+    fn insns(code: &[(u64, &[u8])]) -> Vec<Result<Insn, HWTracerError>> {
+        code.iter().map(|(ip, b)| Ok(Insn::new(*ip, b))).collect()
+    }
+
+    #[test]
+    fn from_insns() {
+        let stacks = FoldedStacks::from_insns(insns(&[
+            (0x10, NOP),
+            (0x11, CALL),
+            // The function at 0x100, which calls the function at 0x200.
+            (0x100, NOP),
+            (0x101, CALL),
+            (0x200, RET),
+            (0x106, RET),
+            (0x16, NOP),
+            // Returning from the outermost frame starts a new one.
+            (0x17, RET),
+            (0x30, NOP),
+        ]))
+        .unwrap();
+        assert_eq!(
+            stacks.stacks().collect::<Vec<_>>(),
+            vec![
+                (&[0x10][..], 4),
+                (&[0x10, 0x100][..], 3),
+                (&[0x10, 0x100, 0x200][..], 1),
+                (&[0x30][..], 1),
+            ]
+        );
+        let mut buf = Vec::new();
+        stacks.to_writer(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "0x10 4\n0x10;0x100 3\n0x10;0x100;0x200 1\n0x30 1\n"
+        );
+    }
+
+    #[test]
+    fn error() {
+        let mut code = insns(&[(0x10, NOP)]);
+        code.push(Err(HWTracerError::Unknown));
+        assert!(matches!(
+            FoldedStacks::from_insns(code),
+            Err(HWTracerError::Unknown)
+        ));
+    }
+}
@@ -10,6 +10,18 @@ enum hwt_cerror_kind {
     hwt_cerror_unknown,
     hwt_cerror_errno,
     hwt_cerror_ipt,
+    hwt_cerror_malformed,
+    hwt_cerror_overflow,   // The trace buffer overflowed. `code` is unused.
+};
+
+// The `code` of a `hwt_cerror_malformed` error. Must be kept in sync with
+// `c_errors.rs`.
+enum hwt_malformed_kind {
+    hwt_malformed_unexpected_status,
+    hwt_malformed_truncated_block,
+    hwt_malformed_empty_block,
+    hwt_malformed_unknown_iclass,
+    hwt_malformed_unhandled_event,
 };
 
 struct hwt_cerror {
@@ -0,0 +1,173 @@
+//! The targets of the indirect branches, calls and returns in decoded traces.
+//!
+//! ```no_run
+//! use hwtracer::{decode::TraceDecoderBuilder, indirect::IndirectBranches, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let branches = IndirectBranches::from_insns(dec.iter_insns(&*trace)).unwrap();
+//! for (b, n) in branches.iter() {
+//!     println!("{:?} {:#x} -> {:#x} ({}x)", b.kind, b.from, b.to, n);
+//! }
+//! ```
+
+use crate::{errors::HWTracerError, Insn};
+use std::collections::BTreeMap;
+
+/// The kinds of indirect control transfer.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IndirectKind {
+    /// An indirect jump, e.g. `jmp *%rax`, as used for switch tables and tail calls.
+    Jump,
+    /// An indirect call, e.g. `call *%rax`, as used for virtual calls and function pointers.
+    Call,
+    /// A return.
+    Return,
+}
+
+/// An indirect control transfer that a trace took.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndirectBranch {
+    /// The virtual address of the branch, call or return instruction.
+    pub from: u64,
+    /// The virtual address that control was transferred to.
+    pub to: u64,
+    pub kind: IndirectKind,
+}
+
+/// The distinct indirect branches, calls and returns taken by a trace, with the number of times
+/// that each was taken.
+///
+/// These are found from the instructions of the trace: the target of an indirect transfer is the
+/// instruction executed after it. If tracing stopped straight after an indirect transfer (e.g.
+/// because it left the traced address ranges), its target wasn't traced: the instruction at which
+/// tracing resumed is reported as its target instead.
+#[derive(Debug, Default)]
+pub struct IndirectBranches {
+    counts: BTreeMap<IndirectBranch, usize>,
+}
+
+impl IndirectBranches {
+    /// Find the indirect transfers in `insns` (e.g. from
+    /// [crate::decode::TraceDecoder::iter_insns]).
+    ///
+    /// Returns the first error that `insns` yields, if any.
+    pub fn from_insns<I>(insns: I) -> Result<Self, HWTracerError>
+    where
+        I: IntoIterator<Item = Result<Insn, HWTracerError>>,
+    {
+        let mut branches = Self::default();
+        // The indirect transfer whose target is the next instruction, if any.
+        let mut pending = None;
+        for insn in insns {
+            let insn = insn?;
+            if let Some((from, kind)) = pending.take() {
+                let b = IndirectBranch {
+                    from,
+                    to: insn.ip(),
+                    kind,
+                };
+                *branches.counts.entry(b).or_insert(0) += 1;
+            }
+            pending = if insn.is_indirect_jump() {
+                Some((insn.ip(), IndirectKind::Jump))
+            } else if insn.is_indirect_call() {
+                Some((insn.ip(), IndirectKind::Call))
+            } else if insn.is_ret() {
+                Some((insn.ip(), IndirectKind::Return))
+            } else {
+                None
+            };
+        }
+        Ok(branches)
+    }
+
+    /// Returns the distinct indirect transfers, ordered by source then target address, with the
+    /// number of times that each was taken.
+    pub fn iter(&self) -> impl Iterator<Item = (IndirectBranch, usize)> + '_ {
+        self.counts.iter().map(|(&b, &n)| (b, n))
+    }
+
+    /// Returns the targets of the indirect transfer at `from`, in address order, with the number
+    /// of times that each was taken.
+    pub fn targets(&self, from: u64) -> impl Iterator<Item = (u64, usize)> + '_ {
+        let start = IndirectBranch {
+            from,
+            to: 0,
+            kind: IndirectKind::Jump,
+        };
+        self.counts
+            .range(start..)
+            .take_while(move |(b, _)| b.from == from)
+            .map(|(b, &n)| (b.to, n))
+    }
+
+    /// Returns the number of distinct indirect transfers.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Returns `true` if the trace took no indirect transfers.
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndirectBranch, IndirectBranches, IndirectKind};
+    use crate::{errors::HWTracerError, Insn};
+
+    #[test]
+    fn from_insns() {
+        let insns = [
+            // call *%rax
+            (0x10, &[0xff, 0xd0][..]),
+            (0x100, &[0x90]),
+            // ret
+            (0x101, &[0xc3]),
+            (0x12, &[0x90]),
+            // jmp *%rax
+            (0x13, &[0xff, 0xe0]),
+            (0x200, &[0x90]),
+            (0x201, &[0xeb, 0x00]),
+            (0x10, &[0xff, 0xd0]),
+            (0x100, &[0x90]),
+            (0x101, &[0xc3]),
+            (0x12, &[0x90]),
+            (0x13, &[0xff, 0xe0]),
+            (0x300, &[0x90]),
+            // A return at the end of the trace has no known target.
+            (0x301, &[0xc3]),
+        ];
+        let branches = IndirectBranches::from_insns(
+            insns
+                .iter()
+                .map(|&(ip, bytes)| Ok::<_, HWTracerError>(Insn::new(ip, bytes))),
+        )
+        .unwrap();
+        let branch = |from, to, kind| IndirectBranch { from, to, kind };
+        assert_eq!(
+            branches.iter().collect::<Vec<_>>(),
+            vec![
+                (branch(0x10, 0x100, IndirectKind::Call), 2),
+                (branch(0x13, 0x200, IndirectKind::Jump), 1),
+                (branch(0x13, 0x300, IndirectKind::Jump), 1),
+                (branch(0x101, 0x12, IndirectKind::Return), 2),
+            ]
+        );
+        assert_eq!(branches.len(), 4);
+        assert_eq!(
+            branches.targets(0x13).collect::<Vec<_>>(),
+            vec![(0x200, 1), (0x300, 1)]
+        );
+        assert_eq!(branches.targets(0x12).count(), 0);
+
+        assert!(IndirectBranches::from_insns(vec![
+            Ok(Insn::new(0x10, &[0xc3])),
+            Err(HWTracerError::Unknown)
+        ])
+        .is_err());
+    }
+}
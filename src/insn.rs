@@ -0,0 +1,119 @@
+use std::convert::TryFrom;
+
+/// The maximum length of an x86 instruction, in bytes.
+pub(crate) const MAX_INSN_LEN: usize = 15;
+
+/// Information about a single decoded instruction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Insn {
+    /// Virtual address of the start of the instruction.
+    ip: u64,
+    /// The number of bytes of `raw` that are used.
+    size: u8,
+    /// The instruction's encoding.
+    raw: [u8; MAX_INSN_LEN],
+}
+
+impl Insn {
+    /// Creates a new instruction from its virtual address and encoding.
+    pub(crate) fn new(ip: u64, bytes: &[u8]) -> Self {
+        let mut raw = [0; MAX_INSN_LEN];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        Self {
+            ip,
+            size: u8::try_from(bytes.len()).unwrap(),
+            raw,
+        }
+    }
+
+    /// Returns the virtual address of the start of the instruction.
+    pub fn ip(&self) -> u64 {
+        self.ip
+    }
+
+    /// Returns the instruction's encoding.
+    pub fn bytes(&self) -> &[u8] {
+        &self.raw[..usize::from(self.size)]
+    }
+
+    /// Returns the instruction's opcode and the byte following it (the ModR/M byte, if the
+    /// instruction has one), skipping any prefixes.
+    fn opcode(&self) -> Option<(u8, Option<u8>)> {
+        let bytes = self.bytes();
+        let i = bytes.iter().position(|b| {
+            !matches!(
+                b,
+                0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3
+            )
+        })?;
+        // A REX prefix can only come last.
+        let i = match bytes[i] {
+            0x40..=0x4f => i + 1,
+            _ => i,
+        };
+        Some((*bytes.get(i)?, bytes.get(i + 1).copied()))
+    }
+
+    /// Returns `true` if the instruction is a (near or far) call.
+    pub(crate) fn is_call(&self) -> bool {
+        matches!(self.opcode(), Some((0xe8 | 0x9a, _))) || self.is_indirect_call()
+    }
+
+    /// Returns `true` if the instruction is an indirect (near or far) call.
+    pub(crate) fn is_indirect_call(&self) -> bool {
+        // `FF /2` and `FF /3`.
+        matches!(self.ff_reg(), Some(2 | 3))
+    }
+
+    /// Returns `true` if the instruction is an indirect (near or far) jump.
+    pub(crate) fn is_indirect_jump(&self) -> bool {
+        // `FF /4` and `FF /5`.
+        matches!(self.ff_reg(), Some(4 | 5))
+    }
+
+    /// If the instruction is one of the group of instructions with opcode `FF`, returns the
+    /// opcode extension in the `reg` field of its ModR/M byte, which says which it is.
+    fn ff_reg(&self) -> Option<u8> {
+        match self.opcode() {
+            Some((0xff, Some(modrm))) => Some((modrm >> 3) & 0x7),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the instruction is a (near or far) return.
+    pub(crate) fn is_ret(&self) -> bool {
+        matches!(self.opcode(), Some((0xc2 | 0xc3 | 0xca | 0xcb, _)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Insn;
+
+    #[test]
+    fn calls_and_rets() {
+        let call = |bytes: &[u8]| Insn::new(0, bytes).is_call();
+        let ret = |bytes: &[u8]| Insn::new(0, bytes).is_ret();
+        // call rel32
+        assert!(call(&[0xe8, 0, 0, 0, 0]));
+        // call *%rax
+        assert!(call(&[0xff, 0xd0]));
+        // call *0x8(%r11)
+        assert!(call(&[0x41, 0xff, 0x53, 0x08]));
+        // jmp *%rax
+        assert!(!call(&[0xff, 0xe0]));
+        assert!(Insn::new(0, &[0xff, 0xe0]).is_indirect_jump());
+        assert!(!Insn::new(0, &[0xff, 0xe0]).is_indirect_call());
+        assert!(Insn::new(0, &[0xff, 0xd0]).is_indirect_call());
+        assert!(!Insn::new(0, &[0xe8, 0, 0, 0, 0]).is_indirect_call());
+        // jmp rel8
+        assert!(!Insn::new(0, &[0xeb, 0x00]).is_indirect_jump());
+        // ret, bnd ret, ret $8
+        assert!(ret(&[0xc3]));
+        assert!(ret(&[0xf2, 0xc3]));
+        assert!(ret(&[0xc2, 0x08, 0x00]));
+        // nop, and an instruction made of nothing but prefixes.
+        assert!(!call(&[0x90]) && !ret(&[0x90]));
+        assert!(!call(&[0x66]) && !ret(&[0x66]));
+    }
+}
@@ -0,0 +1,275 @@
+//! Optional instrumentation using the [tracing](https://docs.rs/tracing) and
+//! [metrics](https://docs.rs/metrics) crates.
+//!
+//! If hwtracer is built with the `tracing` feature, the macros here forward to their namesakes in
+//! `tracing`, so that users can see what collectors and decoders are doing by installing a
+//! subscriber. Otherwise they expand to nothing, so that the rest of hwtracer needn't be littered
+//! with `cfg`s. Note that the arguments of the macros aren't evaluated at all in the latter case.
+//!
+//! Likewise, if hwtracer is built with the `metrics` feature, the functions at the end of the
+//! module report the traces collected and the decodes done through the `metrics` facade, so that
+//! services can monitor tracing by installing a recorder. Otherwise they do nothing.
+
+// Depending on the configuration, some of the macros may be unused.
+#![allow(unused_macros)]
+// Without the `metrics` feature, the metrics functions ignore their arguments.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+#[cfg(feature = "decode")]
+use crate::{decode::TraceDecoderKind, errors::HWTracerError};
+#[cfg(all(feature = "decode", feature = "metrics"))]
+use std::time::Duration;
+
+/// A span that decoding work is done in, entered with [Span::enter].
+///
+/// Decoders are lazy iterators, so rather than entering a span around the whole decode, they keep
+/// one of these and enter it each time they are asked for more output.
+#[derive(Clone, Debug)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+impl Span {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(inner: tracing::Span) -> Self {
+        Self { inner }
+    }
+
+    /// Enter the span until the returned guard is dropped.
+    #[must_use]
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _guard: self.inner.enter(),
+            #[cfg(not(feature = "tracing"))]
+            _span: self,
+        }
+    }
+}
+
+/// The default span is disabled, and thus records nothing.
+impl Default for Span {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::Span::none(),
+        }
+    }
+}
+
+/// A guard which exits a [Span] when dropped.
+pub(crate) struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _guard: tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: &'a Span,
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_span {
+    ($($arg:tt)*) => {
+        $crate::instrument::Span::new(tracing::debug_span!($($arg)*))
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_span {
+    ($($arg:tt)*) => {
+        $crate::instrument::Span::default()
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        ()
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        ()
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        tracing::warn!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        ()
+    };
+}
+
+/// Record that a trace of `len` bytes was collected.
+#[cfg(feature = "collect")]
+pub(crate) fn trace_collected(len: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("hwtracer_traces_collected_total").increment(1);
+        metrics::histogram!("hwtracer_trace_bytes").record(len as f64);
+    }
+}
+
+/// Record that starting or stopping collection failed.
+#[cfg(feature = "collect")]
+pub(crate) fn collect_failed() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("hwtracer_collect_failures_total").increment(1);
+}
+
+/// Record that a decode which consumed `bytes` bytes of a trace finished after `elapsed`. Only
+/// compiled in with the `metrics` feature, so that decoding doesn't need a clock without it.
+#[cfg(all(feature = "decode", feature = "metrics"))]
+pub(crate) fn decode_finished(bytes: usize, elapsed: Duration) {
+    metrics::histogram!("hwtracer_decode_seconds").record(elapsed);
+    metrics::counter!("hwtracer_decoded_bytes_total").increment(bytes as u64);
+}
+
+/// Record that a decoder of kind `kind` failed with `err`.
+#[cfg(feature = "decode")]
+pub(crate) fn decode_failed(kind: TraceDecoderKind, err: &HWTracerError) {
+    #[cfg(feature = "metrics")]
+    {
+        // Separates corrupt traces from e.g. limits being exceeded.
+        let reason = if err.is_bad_trace() {
+            "bad_trace"
+        } else {
+            "other"
+        };
+        metrics::counter!(
+            "hwtracer_decode_failures_total",
+            "decoder" => kind.to_string(),
+            "reason" => reason
+        )
+        .increment(1);
+    }
+}
+
+// The tests decode with the ykpt decoder, which is always built with `decode`.
+#[cfg(all(test, feature = "metrics", decoder_ykpt))]
+mod tests {
+    use crate::{
+        decode::{TraceDecoderBuilder, TraceDecoderKind},
+        Trace,
+    };
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    /// The values recorded for one metric.
+    #[derive(Default)]
+    struct Values(Mutex<Vec<f64>>);
+
+    impl CounterFn for Values {
+        fn increment(&self, value: u64) {
+            self.0.lock().unwrap().push(value as f64);
+        }
+
+        fn absolute(&self, _value: u64) {
+            unreachable!()
+        }
+    }
+
+    impl HistogramFn for Values {
+        fn record(&self, value: f64) {
+            self.0.lock().unwrap().push(value);
+        }
+    }
+
+    /// Keeps the values recorded for each metric, keyed by its name and then its labels.
+    #[derive(Default)]
+    struct TestRecorder(Mutex<HashMap<String, Arc<Values>>>);
+
+    impl TestRecorder {
+        fn values(&self, key: &Key) -> Arc<Values> {
+            let mut name = key.name().to_owned();
+            for l in key.labels() {
+                name += &format!(",{}={}", l.key(), l.value());
+            }
+            Arc::clone(self.0.lock().unwrap().entry(name).or_default())
+        }
+
+        /// Returns the values recorded for the metric `name`.
+        fn get(&self, name: &str) -> Vec<f64> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(name)
+                .map_or_else(Vec::new, |v| v.0.lock().unwrap().clone())
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.values(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.values(key))
+        }
+    }
+
+    /// Check that decodes, the bytes they consume, and failures caused by bad traces are
+    /// reported.
+    #[test]
+    fn decode() {
+        let rec = TestRecorder::default();
+        let mut bytes = b"\x02\x82".repeat(8);
+        bytes.extend_from_slice(b"\x02\x23");
+        let good = <dyn Trace>::from_bytes(bytes.clone());
+        bytes.extend_from_slice(b"\x02\xff");
+        let bad = <dyn Trace>::from_bytes(bytes);
+        metrics::with_local_recorder(&rec, || {
+            let dec = TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::YkPT)
+                .build()
+                .unwrap();
+            assert!(dec.iter_events(&*good).all(|ev| ev.is_ok()));
+            assert!(dec.iter_events(&*bad).any(|ev| ev.is_err()));
+        });
+        assert_eq!(rec.get("hwtracer_decode_seconds").len(), 2);
+        assert_eq!(
+            rec.get("hwtracer_decoded_bytes_total").iter().sum::<f64>(),
+            36.0
+        );
+        assert_eq!(
+            rec.get("hwtracer_decode_failures_total,decoder=ykpt,reason=bad_trace"),
+            [1.0]
+        );
+    }
+}
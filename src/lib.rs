@@ -1,23 +1,135 @@
+//! Collection and decoding of Intel Processor Trace (PT) traces.
+//!
+//! Everything but the [pt] module, which parses PT packets, needs the (default) `std` feature.
+//! Collecting traces (the `collect` module) and decoding them (the `decode` module, and the
+//! analyses of decoded traces) are further split into the `collect` and `decode` features, so that
+//! e.g. a program which only decodes traces needn't build a collector.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(clippy::upper_case_acronyms)]
 #![allow(clippy::new_without_default)]
-#![feature(once_cell)]
+// Some internals are only used by the optional (C-backed) collector and decoder.
+#![cfg_attr(not(all(collector_perf, decoder_libipt)), allow(dead_code))]
+
+extern crate alloc;
 
+// Declared first, so that its macros are visible in the other modules.
+#[macro_use]
+mod instrument;
+pub mod pt;
+
+#[cfg(feature = "std")]
+mod anonymize;
+#[cfg(feature = "decode")]
 mod block;
+#[cfg(feature = "std")]
+mod build_id;
+#[cfg(feature = "std")]
+pub use build_id::ObjectBuildId;
+#[cfg(feature = "std")]
+pub mod capabilities;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "decode")]
+pub mod cfg;
+#[cfg(feature = "decode")]
 pub use block::Block;
+#[cfg(any(collector_perf, decoder_libipt))]
 mod c_errors;
+#[cfg(feature = "collect")]
 pub mod collect;
+#[cfg(feature = "std")]
+mod container;
+#[cfg(feature = "std")]
+pub use container::Codec;
+#[cfg(feature = "std")]
+use container::RawTrace;
+#[cfg(feature = "decode")]
+pub mod coverage;
+#[cfg(feature = "std")]
+mod cpu;
+#[cfg(feature = "std")]
+pub use cpu::CpuId;
+#[cfg(feature = "decode")]
 pub mod decode;
+#[cfg(feature = "decode")]
+pub mod diff;
+#[cfg(feature = "std")]
 pub mod errors;
+#[cfg(feature = "decode")]
+pub mod export;
+#[cfg(feature = "decode")]
+pub mod fixtures;
+#[cfg(feature = "decode")]
+pub mod flamegraph;
+#[cfg(feature = "decode")]
+pub mod indirect;
+#[cfg(feature = "decode")]
+mod insn;
+#[cfg(feature = "decode")]
+pub use insn::Insn;
+#[cfg(feature = "decode")]
+pub mod loops;
+#[cfg(all(feature = "std", unix))]
+mod mapped;
+#[cfg(all(feature = "std", unix))]
+use mapped::MappedTrace;
+#[cfg(feature = "std")]
+mod meta;
+#[cfg(feature = "std")]
+pub use meta::{TraceId, TraceMeta};
+#[cfg(all(feature = "std", unix))]
+mod objects;
+#[cfg(feature = "std")]
+pub mod perf_data;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(all(feature = "collect", feature = "decode"))]
+mod selftest;
+#[cfg(all(feature = "collect", feature = "decode"))]
+pub use selftest::{selftest, DecoderCheck, SelfTestReport};
+#[cfg(feature = "std")]
+mod sideband;
+#[cfg(feature = "std")]
+pub use sideband::{SidebandEvent, SidebandRecord};
+#[cfg(feature = "std")]
+mod slice;
+#[cfg(feature = "std")]
+pub use slice::PsbSample;
+#[cfg(all(feature = "symbolize", unix))]
+pub mod symbolize;
+#[cfg(feature = "bench")]
+pub mod synth;
+#[cfg(any(feature = "testing", all(test, feature = "std")))]
+pub mod testing;
 
+#[cfg(feature = "std")]
 pub use errors::HWTracerError;
-use std::fmt::Debug;
-#[cfg(test)]
-use std::fs::File;
+#[cfg(feature = "std")]
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    fs::File,
+    io::{Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+/// Returns the version of hwtracer, e.g. `"0.1.0"`. See [capabilities::capabilities] for what
+/// this build of hwtracer supports.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
 
 /// Represents a generic trace.
 ///
 /// Each trace decoder has its own concrete implementation.
-pub trait Trace: Debug + Send {
+///
+/// Traces are `Send` and `Sync`, so a trace collected on one thread can be handed to (or shared
+/// between) other threads to be decoded, e.g. by a JIT compiling in the background. A trace never
+/// changes once collection has stopped.
+#[cfg(feature = "std")]
+pub trait Trace: Debug + Send + Sync {
     fn bytes(&self) -> &[u8];
 
     /// Get the capacity of the trace in bytes.
@@ -27,6 +139,104 @@ pub trait Trace: Debug + Send {
     /// Get the size of the trace in bytes.
     fn len(&self) -> usize;
 
+    /// Returns `true` if the trace holds no bytes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the bytes of the trace, in order, in chunks of at most `size` bytes. `size`
+    /// must be non-zero.
+    ///
+    /// Unlike [Trace::bytes], this doesn't need the whole trace to be in memory at once, so
+    /// consumers which process a trace incrementally should prefer it. Traces whose bytes live
+    /// elsewhere (e.g. spilled to disk) can override it to load each chunk on demand.
+    fn chunks(
+        &self,
+        size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Cow<'_, [u8]>, HWTracerError>> + '_> {
+        Box::new(self.bytes().chunks(size).map(|c| Ok(Cow::Borrowed(c))))
+    }
+
+    /// Get the information needed to interpret the trace: where and how it was collected.
+    fn meta(&self) -> TraceMeta {
+        TraceMeta::default()
+    }
+
+    /// Get the identity of the CPU that the trace was collected on, if known.
+    fn cpu(&self) -> Option<CpuId> {
+        self.meta().cpu
+    }
+
+    /// Get the ID assigned to the trace when it was collected, if it has one.
+    fn id(&self) -> Option<TraceId> {
+        self.meta().id
+    }
+
+    /// Get the sideband records collected alongside the trace, in the order they occurred.
+    ///
+    /// Traces which don't carry sideband information return an empty vector.
+    fn sideband(&self) -> Result<Vec<SidebandRecord>, HWTracerError> {
+        Ok(Vec::new())
+    }
+
+    /// Write the trace, along with the information needed to decode it, to `w`.
+    ///
+    /// The trace can be loaded again (possibly on another machine) with [Trace::from_reader].
+    /// It is compressed if hwtracer was built with a compression codec (see [Codec::default]).
+    fn to_writer(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        self.to_writer_with(w, Codec::default())
+    }
+
+    /// Like [Trace::to_writer], but compressing the trace with `codec`.
+    fn to_writer_with(&self, w: &mut dyn Write, codec: Codec) -> Result<(), HWTracerError> {
+        container::write(self, w, codec)
+    }
+
+    /// Make a copy of the trace which can be shared (e.g. in a bug report) without revealing the
+    /// layout of the traced program.
+    ///
+    /// Every IP is replaced with an opaque identifier, consistently, so that control flow can
+    /// still be followed. `PTWRITE` payloads and other potentially sensitive packet payloads are
+    /// zeroed, and sideband records describing the program's mappings and threads are dropped, as
+    /// is the trace's provenance (see [TraceMeta::provenance]). Data before the first PSB packet,
+    /// and any packet cut short by the end of the trace, is dropped too.
+    ///
+    /// An error is returned if the trace contains packets that can't be anonymised.
+    fn anonymized(&self) -> Result<Box<dyn Trace>, HWTracerError> {
+        Ok(Box::new(anonymize::anonymize(self)?))
+    }
+
+    /// Get the offsets of the PSB packets in the trace, in ascending order.
+    ///
+    /// Decoders can only start decoding a trace at a PSB packet, so the trace is made up of
+    /// independently decodable "PSB regions", each running from one PSB packet up to the next (or
+    /// the end of the trace). Any data before the first PSB packet isn't part of a region.
+    fn psb_offsets(&self) -> Vec<usize> {
+        slice::psb_offsets(self.bytes())
+    }
+
+    /// Make a smaller, still decodable, trace holding just the PSB regions `regions` (see
+    /// [Trace::psb_offsets]), e.g. to extract the interesting part of a long trace for a bug
+    /// report.
+    ///
+    /// All sideband records up to the end of the slice are kept, as those from before it may
+    /// still be needed to decode it. Records from before the slice are moved to its start.
+    fn slice_psb_regions(&self, regions: Range<usize>) -> Result<Box<dyn Trace>, HWTracerError> {
+        Ok(Box::new(slice::slice_psb_regions(self, regions)?))
+    }
+
+    /// Make a smaller, still decodable, trace holding only a sample of the PSB regions (see
+    /// [Trace::psb_offsets]) of this one, e.g. for cheap statistical analysis of an enormous
+    /// trace.
+    ///
+    /// Wherever regions were left out, a [SidebandEvent::SessionBoundary] record marks the gap, at
+    /// which decoders report a [decode::DecodeEvent::SessionBoundary] event. As with
+    /// [Trace::slice_psb_regions], sideband records from the regions left out are moved to the
+    /// start of the next region kept.
+    fn sample_psb_regions(&self, sample: PsbSample) -> Result<Box<dyn Trace>, HWTracerError> {
+        Ok(Box::new(slice::sample_psb_regions(self, sample)?))
+    }
+
     /// Dump the trace to the specified filename.
     ///
     /// The exact format varies depending on what kind of trace it is.
@@ -34,18 +244,61 @@ pub trait Trace: Debug + Send {
     fn to_file(&self, file: &mut File);
 }
 
-#[cfg(test)]
-mod test_helpers {
-    use std::time::SystemTime;
-
-    /// A loop that does some work that we can use to build a trace.
-    #[inline(never)]
-    pub fn work_loop(iters: u64) -> u64 {
-        let mut res = 0;
-        for _ in 0..iters {
-            // Computation which stops the compiler from eliminating the loop.
-            res += SystemTime::now().elapsed().unwrap().subsec_nanos() as u64;
+#[cfg(feature = "std")]
+impl dyn Trace {
+    /// Make a trace from raw Intel PT packet data, e.g. as captured by another tool.
+    ///
+    /// The CPU that the data was collected on isn't known, so decoders assume the current CPU.
+    pub fn from_bytes(bytes: Vec<u8>) -> Box<dyn Trace> {
+        Box::new(RawTrace::new(bytes))
+    }
+
+    /// Make a trace from a file containing raw Intel PT packet data. See [Trace::from_bytes].
+    ///
+    /// The file is mapped into memory rather than read, so huge traces can be decoded without
+    /// holding them in memory: the ykpt decoder reads traces with [Trace::chunks], which releases
+    /// each part of the file once it has been read, so even a trace larger than RAM is decoded with
+    /// only a chunk or so of it in memory at once. The file must not be modified while the trace is
+    /// alive. Platforms without memory-mapped files (e.g. WebAssembly) read the file into memory
+    /// instead.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Box<dyn Trace>, HWTracerError> {
+        #[cfg(unix)]
+        return Ok(Box::new(MappedTrace::new(&File::open(path)?)?));
+        #[cfg(not(unix))]
+        {
+            let mut bytes = Vec::new();
+            File::open(path)?.read_to_end(&mut bytes)?;
+            Ok(Self::from_bytes(bytes))
         }
-        res
+    }
+
+    /// Join `traces`, which must have been collected back-to-back (e.g. several collections of the
+    /// same thread), into one logical trace.
+    ///
+    /// The traces must all have been collected in the same way (see [TraceMeta]) and each must
+    /// start with a PSB packet, as traces collected by hwtracer do. Empty traces are ignored. The
+    /// start of each trace after the first is marked with a [SidebandEvent::SessionBoundary]
+    /// record, at which decoders report a [decode::DecodeEvent::SessionBoundary] event.
+    pub fn concat(traces: &[&dyn Trace]) -> Result<Box<dyn Trace>, HWTracerError> {
+        Ok(Box::new(slice::concat(traces)?))
+    }
+
+    /// Read a trace written by [Trace::to_writer] from `r`.
+    ///
+    /// Traces written by older releases of hwtracer can be read too, but traces written by newer
+    /// releases may not be. A trace damaged since it was written is reported as
+    /// [errors::DecodeError::Corrupt].
+    pub fn from_reader(r: &mut dyn Read) -> Result<Box<dyn Trace>, HWTracerError> {
+        Ok(Box::new(container::read(r)?))
+    }
+
+    /// Read just the header of a trace written by [Trace::to_writer] from `r`, returning the
+    /// version of the container format that it was written in.
+    ///
+    /// An error is returned if [Trace::from_reader] certainly couldn't load the trace (e.g.
+    /// because it was written by a newer hwtracer, or compressed with a codec that this build
+    /// lacks), so that mismatched trace files can be refused without reading them in full.
+    pub fn probe_reader(r: &mut dyn Read) -> Result<u32, HWTracerError> {
+        container::probe(r)
     }
 }
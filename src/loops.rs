@@ -0,0 +1,456 @@
+//! Loops found in decoded traces.
+//!
+//! ```no_run
+//! use hwtracer::{cfg::Cfg, decode::TraceDecoderBuilder, loops::find_loops, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let cfg = Cfg::from_blocks(dec.iter_blocks(&*trace)).unwrap();
+//! for l in find_loops(&cfg) {
+//!     println!(
+//!         "{:#x}: {} iterations of {} blocks",
+//!         l.header().first_instr(),
+//!         l.iterations(),
+//!         l.body().len()
+//!     );
+//! }
+//! ```
+//!
+//! To find out how the loops behaved (e.g. how many iterations each ran for, and which paths
+//! through them were taken), decode the trace again and pass the blocks to [loop_stats].
+
+use crate::{cfg::Cfg, errors::HWTracerError, Block};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A loop that a trace executed: a cycle of blocks, entered through a header block.
+///
+/// Loops are the "natural loops" of the graph of blocks that the trace executed (see [Cfg]). A
+/// back edge is a transition from a block to one which dominates it (i.e. through which every path
+/// from the start of the trace to the block passes), and the loop is made up of the back edge's
+/// target (the loop's header) and the blocks which can reach the back edge without passing through
+/// the header. Since calls and returns are ordinary transitions in the graph, loops can span
+/// functions, and code reached repeatedly along one path (e.g. a function called from two places in
+/// a loop) may be reported as a loop of its own.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Loop {
+    header: Block,
+    /// Sorted by address.
+    body: Vec<Block>,
+    iterations: usize,
+    entries: usize,
+}
+
+impl Loop {
+    /// Returns the header of the loop: the block through which it is entered, and which starts
+    /// every iteration.
+    pub fn header(&self) -> &Block {
+        &self.header
+    }
+
+    /// Returns the blocks of the loop, including its header and the blocks of any loops nested in
+    /// it, in address order.
+    pub fn body(&self) -> &[Block] {
+        &self.body
+    }
+
+    /// Returns the number of iterations of the loop that the trace executed, over all of the times
+    /// that it entered the loop (i.e. the number of times that the header was executed).
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+
+    /// Returns the number of times that the trace entered the loop, from outside of it (or, if the
+    /// trace started at the header, by starting).
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Returns `true` if `other` is nested in this loop.
+    pub fn contains(&self, other: &Loop) -> bool {
+        self.header != other.header && self.body.contains(&other.header)
+    }
+}
+
+/// Find the loops in `cfg`, hottest (i.e. with the most iterations) first.
+pub fn find_loops(cfg: &Cfg) -> Vec<Loop> {
+    let entry = match cfg.entry() {
+        Some(b) => b,
+        None => return Vec::new(),
+    };
+    // Number the blocks, in address order.
+    let nodes = cfg.nodes().collect::<Vec<_>>();
+    let ids = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, (b, _))| (key(b), i))
+        .collect::<BTreeMap<_, _>>();
+    let id = |b: &Block| ids[&key(b)];
+    let block = |i: usize| Block::new(nodes[i].0.first_instr(), nodes[i].0.last_instr());
+
+    let mut succs = vec![Vec::new(); nodes.len()];
+    let mut preds = vec![Vec::new(); nodes.len()];
+    let mut edges = Vec::new();
+    for (from, to, n) in cfg.edges() {
+        let (from, to) = (id(&from), id(&to));
+        succs[from].push(to);
+        preds[to].push(from);
+        edges.push((from, to, n));
+    }
+
+    // Group the back edges by header, with the number of times that each header was reached
+    // through one.
+    let doms = Dominators::new(id(&entry), &succs, &preds);
+    let mut back_edges = BTreeMap::<usize, (Vec<usize>, usize)>::new();
+    for (from, to, n) in edges {
+        if doms.dominates(to, from) {
+            let (latches, taken) = back_edges.entry(to).or_default();
+            latches.push(from);
+            *taken += n;
+        }
+    }
+
+    let mut loops = back_edges
+        .into_iter()
+        .map(|(header, (latches, taken))| {
+            let mut body = BTreeSet::new();
+            body.insert(header);
+            let mut todo = latches;
+            while let Some(b) = todo.pop() {
+                if body.insert(b) {
+                    todo.extend(&preds[b]);
+                }
+            }
+            let iterations = nodes[header].1;
+            Loop {
+                header: block(header),
+                body: body.into_iter().map(block).collect(),
+                iterations,
+                entries: iterations - taken,
+            }
+        })
+        .collect::<Vec<_>>();
+    loops.sort_by(|a, b| {
+        b.iterations
+            .cmp(&a.iterations)
+            .then(a.header.first_instr().cmp(&b.header.first_instr()))
+    });
+    loops
+}
+
+/// How a trace executed a loop, as found by [loop_stats].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct LoopStats {
+    /// The number of times that the loop was entered and ran for each trip count.
+    trip_counts: BTreeMap<usize, usize>,
+    /// The number of iterations which took each path through the loop.
+    paths: HashMap<Vec<u64>, usize>,
+}
+
+impl LoopStats {
+    /// Returns the trip counts of the loop (the number of iterations that it ran for each time that
+    /// it was entered), in ascending order, with the number of times that each occurred.
+    pub fn trip_counts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.trip_counts.iter().map(|(&t, &n)| (t, n))
+    }
+
+    /// Returns the paths that iterations of the loop took, most frequent first, with the number of
+    /// iterations that took each.
+    ///
+    /// A path is the sequence of blocks, identified by their first instructions, that an iteration
+    /// executed from the loop's header, and so tells apart iterations whose branches went
+    /// different ways. The last iteration of each trip ends with the block which left the loop.
+    /// Iterations of loops with nested loops include every iteration of the nested loops, so are
+    /// seldom repeated exactly.
+    pub fn paths(&self) -> Vec<(&[u64], usize)> {
+        let mut paths = self
+            .paths
+            .iter()
+            .map(|(p, &n)| (p.as_slice(), n))
+            .collect::<Vec<_>>();
+        paths.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        paths
+    }
+}
+
+/// The progress of a trace through a loop.
+#[derive(Default)]
+struct LoopState {
+    /// The number of iterations so far of the current trip through the loop, or 0 if the trace is
+    /// outside the loop.
+    trip: usize,
+    /// The blocks executed so far by the current iteration.
+    path: Vec<u64>,
+}
+
+/// Find out how `blocks` (e.g. from [crate::decode::TraceDecoder::iter_blocks]) executed each of
+/// `loops`, which must have been found (with [find_loops]) in the same blocks. Returns the stats of
+/// each loop, in the same order as `loops`.
+///
+/// Returns the first error that `blocks` yields, if any.
+pub fn loop_stats<I>(loops: &[Loop], blocks: I) -> Result<Vec<LoopStats>, HWTracerError>
+where
+    I: IntoIterator<Item = Result<Block, HWTracerError>>,
+{
+    let mut stats = loops
+        .iter()
+        .map(|_| LoopStats::default())
+        .collect::<Vec<_>>();
+    let mut states = loops
+        .iter()
+        .map(|_| LoopState::default())
+        .collect::<Vec<_>>();
+    for b in blocks {
+        let b = b?;
+        for ((l, s), st) in loops.iter().zip(&mut stats).zip(&mut states) {
+            if b == l.header {
+                // Since the header dominates the body, reaching it from inside the loop always
+                // starts a new iteration.
+                if st.trip > 0 {
+                    *s.paths.entry(std::mem::take(&mut st.path)).or_insert(0) += 1;
+                }
+                st.trip += 1;
+                st.path.push(b.first_instr());
+            } else if st.trip > 0 {
+                st.path.push(b.first_instr());
+                if l.body.binary_search_by_key(&key(&b), key).is_err() {
+                    // Left the loop.
+                    st.finish(s);
+                }
+            }
+        }
+    }
+    for (s, st) in stats.iter_mut().zip(&mut states) {
+        if st.trip > 0 {
+            st.finish(s);
+        }
+    }
+    Ok(stats)
+}
+
+impl LoopState {
+    /// Record the end of the current trip through the loop in `stats`.
+    fn finish(&mut self, stats: &mut LoopStats) {
+        *stats
+            .paths
+            .entry(std::mem::take(&mut self.path))
+            .or_insert(0) += 1;
+        *stats.trip_counts.entry(self.trip).or_insert(0) += 1;
+        self.trip = 0;
+    }
+}
+
+/// A block as a `(first_instr, last_instr)` pair, which, unlike a block, can be ordered.
+fn key(b: &Block) -> (u64, u64) {
+    (b.first_instr(), b.last_instr())
+}
+
+/// The dominator tree of a graph, numbered so that dominance can be checked in constant time.
+struct Dominators {
+    /// The pre-order number of each node in the tree.
+    pre: Vec<usize>,
+    /// The post-order number of each node in the tree.
+    post: Vec<usize>,
+}
+
+impl Dominators {
+    /// Find the dominators of the graph with the edges `succs` (and, reversed, `preds`), all of
+    /// whose nodes can be reached from `entry`.
+    ///
+    /// This uses the iterative algorithm from "A Simple, Fast Dominance Algorithm" (Cooper,
+    /// Harvey and Kennedy), which is fast in practice on graphs as sparse as control-flow graphs.
+    fn new(entry: usize, succs: &[Vec<usize>], preds: &[Vec<usize>]) -> Self {
+        const UNDEF: usize = usize::MAX;
+        let n = succs.len();
+
+        let postorder = postorder(entry, succs);
+        let mut rpo_num = vec![UNDEF; n];
+        for (i, &b) in postorder.iter().rev().enumerate() {
+            rpo_num[b] = i;
+        }
+        let mut idom = vec![UNDEF; n];
+        idom[entry] = entry;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in postorder.iter().rev().skip(1) {
+                let mut new = UNDEF;
+                for &p in &preds[b] {
+                    if idom[p] == UNDEF {
+                        continue; // Not processed yet.
+                    }
+                    new = match new {
+                        UNDEF => p,
+                        _ => {
+                            // Walk up the tree from both nodes to their nearest common dominator.
+                            let (mut x, mut y) = (p, new);
+                            while x != y {
+                                while rpo_num[x] > rpo_num[y] {
+                                    x = idom[x];
+                                }
+                                while rpo_num[y] > rpo_num[x] {
+                                    y = idom[y];
+                                }
+                            }
+                            x
+                        }
+                    };
+                }
+                if idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![Vec::new(); n];
+        for (b, &d) in idom.iter().enumerate() {
+            if b != entry && d != UNDEF {
+                children[d].push(b);
+            }
+        }
+        let (mut pre, mut post) = (vec![0; n], vec![0; n]);
+        let mut clock = 0;
+        walk(entry, &children, |b, entering| {
+            if entering {
+                pre[b] = clock;
+            } else {
+                post[b] = clock;
+            }
+            clock += 1;
+        });
+        Self { pre, post }
+    }
+
+    /// Returns `true` if `a` dominates `b`.
+    fn dominates(&self, a: usize, b: usize) -> bool {
+        self.pre[a] <= self.pre[b] && self.post[b] <= self.post[a]
+    }
+}
+
+/// Returns the nodes reachable from `entry` in the graph with the edges `succs`, in post-order.
+fn postorder(entry: usize, succs: &[Vec<usize>]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(succs.len());
+    walk(entry, succs, |b, entering| {
+        if !entering {
+            order.push(b);
+        }
+    });
+    order
+}
+
+/// Walk depth-first through the graph with the edges `succs` from `entry`, calling `f` with `true`
+/// when first entering each node and with `false` when leaving it. Iterative, as traces can have
+/// very long paths.
+fn walk<F: FnMut(usize, bool)>(entry: usize, succs: &[Vec<usize>], mut f: F) {
+    let mut visited = vec![false; succs.len()];
+    // Each node being visited, with the index of the next of its successors to visit.
+    let mut stack = vec![(entry, 0)];
+    visited[entry] = true;
+    f(entry, true);
+    while let Some(&(b, i)) = stack.last() {
+        match succs[b].get(i) {
+            Some(&s) => {
+                stack.last_mut().unwrap().1 += 1;
+                if !visited[s] {
+                    visited[s] = true;
+                    f(s, true);
+                    stack.push((s, 0));
+                }
+            }
+            None => {
+                f(b, false);
+                stack.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_loops, loop_stats};
+    use crate::{cfg::Cfg, errors::HWTracerError, Block};
+
+    /// An outer loop headed by 0x10, twice running an inner loop headed by 0x20.
+    const NESTED: [u64; 14] = [
+        0x10, 0x20, 0x30, 0x20, 0x30, 0x40, 0x10, 0x20, 0x30, 0x20, 0x30, 0x20, 0x40, 0x50,
+    ];
+
+    fn blocks(addrs: &[u64]) -> impl Iterator<Item = Result<Block, HWTracerError>> + '_ {
+        addrs.iter().map(|&a| Ok(Block::new(a, a + 4)))
+    }
+
+    fn cfg(addrs: &[u64]) -> Cfg {
+        Cfg::from_blocks(blocks(addrs)).unwrap()
+    }
+
+    fn firsts(blocks: &[Block]) -> Vec<u64> {
+        blocks.iter().map(|b| b.first_instr()).collect()
+    }
+
+    #[test]
+    fn no_loops() {
+        assert!(find_loops(&Cfg::default()).is_empty());
+        assert!(find_loops(&cfg(&[0x10, 0x20, 0x30])).is_empty());
+    }
+
+    #[test]
+    fn simple() {
+        let loops = find_loops(&cfg(&[0x10, 0x20, 0x30, 0x20, 0x30, 0x20, 0x30, 0x40]));
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header().first_instr(), 0x20);
+        assert_eq!(firsts(loops[0].body()), vec![0x20, 0x30]);
+        assert_eq!(loops[0].iterations(), 3);
+        assert_eq!(loops[0].entries(), 1);
+    }
+
+    #[test]
+    fn nested() {
+        let loops = find_loops(&cfg(&NESTED));
+        assert_eq!(loops.len(), 2);
+        let (inner, outer) = (&loops[0], &loops[1]);
+        assert_eq!(inner.header().first_instr(), 0x20);
+        assert_eq!(firsts(inner.body()), vec![0x20, 0x30]);
+        assert_eq!(inner.iterations(), 5);
+        assert_eq!(inner.entries(), 2);
+        assert_eq!(outer.header().first_instr(), 0x10);
+        assert_eq!(firsts(outer.body()), vec![0x10, 0x20, 0x30, 0x40]);
+        assert_eq!(outer.iterations(), 2);
+        assert_eq!(outer.entries(), 1);
+        assert!(outer.contains(inner));
+        assert!(!inner.contains(outer));
+        assert!(!outer.contains(outer));
+    }
+
+    #[test]
+    fn stats() {
+        let loops = find_loops(&cfg(&NESTED));
+        let stats = loop_stats(&loops, blocks(&NESTED)).unwrap();
+        let (inner, outer) = (&stats[0], &stats[1]);
+        assert_eq!(
+            inner.trip_counts().collect::<Vec<_>>(),
+            vec![(2, 1), (3, 1)]
+        );
+        assert_eq!(
+            inner.paths(),
+            vec![
+                (&[0x20, 0x30][..], 3),
+                (&[0x20, 0x30, 0x40][..], 1),
+                (&[0x20, 0x40][..], 1)
+            ]
+        );
+        assert_eq!(outer.trip_counts().collect::<Vec<_>>(), vec![(2, 1)]);
+        assert_eq!(
+            outer.paths(),
+            vec![
+                (&[0x10, 0x20, 0x30, 0x20, 0x30, 0x20, 0x40, 0x50][..], 1),
+                (&[0x10, 0x20, 0x30, 0x20, 0x30, 0x40][..], 1)
+            ]
+        );
+
+        let mut bad = blocks(&NESTED).collect::<Vec<_>>();
+        bad.insert(3, Err(HWTracerError::Unknown));
+        assert!(matches!(
+            loop_stats(&loops, bad),
+            Err(HWTracerError::Unknown)
+        ));
+    }
+}
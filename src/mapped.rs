@@ -0,0 +1,215 @@
+//! Traces backed by memory-mapped files.
+
+use crate::{
+    errors::{ConfigError, HWTracerError},
+    Trace,
+};
+use libc::{
+    c_void, madvise, mmap, munmap, sysconf, _SC_PAGESIZE, MADV_DONTNEED, MADV_SEQUENTIAL,
+    MAP_FAILED, MAP_PRIVATE, PROT_READ,
+};
+#[cfg(test)]
+use std::io::Write;
+use std::{borrow::Cow, convert::TryFrom, fs::File, io, iter, os::fd::AsRawFd, ptr, slice};
+
+/// A trace whose bytes are those of a file mapped into memory.
+///
+/// Huge traces can thus be decoded without first reading them into memory: the kernel pages the
+/// trace in as the decoder reaches it, and can evict it again under memory pressure. The file must
+/// not be truncated while the trace is alive.
+///
+/// Reading the trace with [Trace::chunks] goes further, releasing the parts of the trace already
+/// read, so that a trace larger than RAM is read with only a chunk or so of it in memory.
+#[derive(Debug)]
+pub(crate) struct MappedTrace {
+    /// The start of the mapping. Null if the file is empty, as empty mappings can't be made.
+    ptr: *mut c_void,
+    /// The size of the mapping (and the file) in bytes.
+    len: usize,
+}
+
+// The mapping is read-only and private to this struct, so it can be moved to, and read from, any
+// thread.
+unsafe impl Send for MappedTrace {}
+unsafe impl Sync for MappedTrace {}
+
+impl MappedTrace {
+    /// Map the whole of `file` into memory.
+    pub(crate) fn new(file: &File) -> Result<Self, HWTracerError> {
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| {
+            HWTracerError::Config(ConfigError::Unsupported(
+                "trace file too large to map".to_owned(),
+            ))
+        })?;
+        if len == 0 {
+            return Ok(Self {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ,
+                MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        // Decoders read traces from start to end. This is only a hint, so failure doesn't matter.
+        unsafe { madvise(ptr, len, MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Trace for MappedTrace {
+    fn bytes(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.len
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Iterate over the chunks of the mapping. When a chunk is asked for, the pages wholly before
+    /// it are released with `MADV_DONTNEED`. The mapping is private and never written, so this is
+    /// safe even if earlier chunks are still in use: released pages are read from the file again
+    /// if they are touched.
+    fn chunks(
+        &self,
+        size: usize,
+    ) -> Box<dyn Iterator<Item = Result<Cow<'_, [u8]>, HWTracerError>> + '_> {
+        assert!(size > 0);
+        let page = usize::try_from(unsafe { sysconf(_SC_PAGESIZE) }).unwrap();
+        let bytes = self.bytes();
+        let (mut off, mut released) = (0, 0);
+        Box::new(iter::from_fn(move || {
+            if off >= bytes.len() {
+                return None;
+            }
+            let end = off - off % page;
+            if end > released {
+                // This is only a hint, so failure doesn't matter.
+                unsafe {
+                    madvise(
+                        self.ptr.cast::<u8>().add(released).cast(),
+                        end - released,
+                        MADV_DONTNEED,
+                    )
+                };
+                released = end;
+            }
+            let chunk = &bytes[off..bytes.len().min(off + size)];
+            off += chunk.len();
+            Some(Ok(Cow::Borrowed(chunk)))
+        }))
+    }
+
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        file.write_all(self.bytes()).unwrap();
+    }
+}
+
+impl Drop for MappedTrace {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MappedTrace;
+    use crate::Trace;
+    use std::{fs, io::Write};
+    use tempfile::NamedTempFile;
+
+    /// Returns the number of bytes of `trace`'s mapping that are resident in this process.
+    fn resident(trace: &MappedTrace) -> usize {
+        let start = format!("{:x}-", trace.ptr as usize);
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let kb = smaps
+            .lines()
+            .skip_while(|l| !l.starts_with(&start))
+            .find_map(|l| l.strip_prefix("Rss:"))
+            .unwrap()
+            .trim()
+            .trim_end_matches(" kB")
+            .parse::<usize>()
+            .unwrap();
+        kb * 1024
+    }
+
+    #[test]
+    fn from_file() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        tmp.write_all(&[1, 2, 3]).unwrap();
+        let trace = <dyn Trace>::from_file(tmp.path()).unwrap();
+        assert_eq!(trace.bytes(), &[1, 2, 3]);
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace.cpu(), None);
+
+        let empty = NamedTempFile::new().unwrap();
+        let trace = <dyn Trace>::from_file(empty.path()).unwrap();
+        assert!(trace.bytes().is_empty());
+        assert_eq!(trace.chunks(4).count(), 0);
+    }
+
+    /// Check that a big trace file is decoded (pipelined or not) with only a little of it in
+    /// memory at any time, and with events reported as they are decoded.
+    #[cfg(decoder_ykpt)]
+    #[test]
+    fn bounded_memory() {
+        use crate::decode::{TraceDecoderBuilder, TraceDecoderKind};
+        use std::ops::ControlFlow;
+
+        const LEN: usize = 32 << 20;
+        const GROUP: usize = 4096;
+        let mut tmp = NamedTempFile::new().unwrap();
+        // PSB+, then TIP.PGE with an uncompressed IP.
+        let mut head = b"\x02\x82".repeat(8);
+        head.extend_from_slice(&[0x02, 0x23, 0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0, 0]);
+        tmp.write_all(&head).unwrap();
+        // Groups of a PTW (with a 32-bit payload) and PADs.
+        let mut group = vec![0; GROUP];
+        group[..2].copy_from_slice(&[0x02, 0x12]);
+        for _ in 0..LEN / GROUP {
+            tmp.write_all(&group).unwrap();
+        }
+        let trace = MappedTrace::new(tmp.as_file()).unwrap();
+
+        for pipelined in [false, true] {
+            let dec = TraceDecoderBuilder::new()
+                .kind(TraceDecoderKind::YkPT)
+                .pipelined(pipelined)
+                .build()
+                .unwrap();
+            let (mut n, mut max) = (0, 0);
+            dec.decode_events_until(&trace, &mut |_| {
+                n += 1;
+                if n % 256 == 0 {
+                    max = max.max(resident(&trace));
+                }
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+            // TIP.PGE's event, then one per PTW.
+            assert_eq!(n, 1 + LEN / GROUP);
+            assert!(max < 1 << 20, "{} bytes resident", max);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Information about how and where traces were collected.
+
+use crate::{build_id, CpuId, ObjectBuildId};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid_count;
+use std::{
+    collections::BTreeMap,
+    fmt, process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The number of trace IDs handed out by this process.
+static TRACE_IDS: AtomicU32 = AtomicU32::new(0);
+
+/// Identifies a trace collected by hwtracer, so that it can be correlated with what the traced
+/// program was doing (e.g. in its logs) wherever the trace ends up.
+///
+/// IDs are made from the time, the process ID, and a count of the IDs handed out by the process,
+/// so are unique amongst the traces collected on a machine. They are formatted as 32 hex digits.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceId(pub u128);
+
+impl TraceId {
+    /// Returns a new ID, different from all of those handed out before.
+    pub(crate) fn next() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let seq = TRACE_IDS.fetch_add(1, Ordering::Relaxed);
+        Self(u128::from(nanos) << 64 | u128::from(process::id()) << 32 | u128::from(seq))
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// The information needed to interpret a trace, beyond the trace data itself.
+///
+/// Traces collected by hwtracer record this for the machine they were collected on. Traces from
+/// elsewhere may only know some of it (or none of it).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceMeta {
+    /// The CPU that the trace was collected on. Decoders use this to work around CPU errata.
+    pub cpu: Option<CpuId>,
+    /// The ratio of the TSC frequency to the core crystal clock frequency, as a `(numerator,
+    /// denominator)` pair (`cpuid` leaf 0x15's EBX and EAX). Needed to turn MTC packets into
+    /// timestamps.
+    pub tsc_ratio: Option<(u32, u32)>,
+    /// The Intel PT configuration that tracing was started with, in the format of the `config`
+    /// field of a `perf_event_attr` for the `intel_pt` PMU (see
+    /// `/sys/bus/event_source/devices/intel_pt/format`). Amongst other things, this says which
+    /// timing packets the trace contains and how often MTC packets were generated.
+    pub pt_config: Option<u64>,
+    /// The version of hwtracer that collected the trace.
+    pub hwtracer_version: Option<String>,
+    /// The build IDs of the objects loaded into the traced process when the trace was collected,
+    /// which decoders check against the objects that they read code from (see
+    /// [crate::decode::TraceDecoderConfig::ignore_build_ids]). Empty if unknown.
+    pub build_ids: Vec<ObjectBuildId>,
+    /// The trace's ID, if it was assigned one when it was collected.
+    pub id: Option<TraceId>,
+    /// Key/value pairs describing where the trace came from (e.g. the name of the thread traced,
+    /// or the request that it was serving), attached by the application that collected it with
+    /// [crate::collect::TraceCollector::tag].
+    pub provenance: BTreeMap<String, String>,
+}
+
+impl TraceMeta {
+    /// Describe a trace collected on the current machine by this version of hwtracer, with the
+    /// Intel PT configuration `pt_config`.
+    pub(crate) fn current(pt_config: u64) -> Self {
+        let cpu = CpuId::current();
+        Self {
+            cpu,
+            // Only Intel CPUs have the leaf we're interested in.
+            tsc_ratio: cpu.and_then(|_| tsc_ratio()),
+            pt_config: Some(pt_config),
+            hwtracer_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            build_ids: build_id::loaded(),
+            id: None,
+            provenance: BTreeMap::new(),
+        }
+    }
+}
+
+/// Read the TSC to core crystal clock ratio from `cpuid` leaf 0x15, if the CPU reports it.
+#[cfg(target_arch = "x86_64")]
+fn tsc_ratio() -> Option<(u32, u32)> {
+    if unsafe { __cpuid_count(0, 0) }.eax < 0x15 {
+        return None; // Leaf not supported.
+    }
+    let leaf = unsafe { __cpuid_count(0x15, 0) };
+    // Either value being zero means that the ratio isn't enumerated.
+    if leaf.eax == 0 || leaf.ebx == 0 {
+        return None;
+    }
+    Some((leaf.ebx, leaf.eax))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn tsc_ratio() -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TraceId, TraceMeta};
+    use crate::CpuId;
+    use std::collections::HashSet;
+
+    #[test]
+    fn current() {
+        let meta = TraceMeta::current(0x2001);
+        assert_eq!(meta.cpu, CpuId::current());
+        assert_eq!(meta.pt_config, Some(0x2001));
+        assert_eq!(
+            meta.hwtracer_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        if let Some((num, den)) = meta.tsc_ratio {
+            assert!(num != 0 && den != 0);
+        }
+    }
+
+    #[test]
+    fn trace_ids() {
+        let ids = (0..100).map(|_| TraceId::next()).collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 100);
+        assert_eq!(TraceId(0xabc).to_string(), format!("{:032x}", 0xabc));
+    }
+}
@@ -0,0 +1,39 @@
+//! The objects (the executable and shared objects) loaded into the current process.
+
+use crate::errors::HWTracerError;
+use std::{env, ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+/// The name under which the VDSO appears in the list of objects loaded into a process.
+const VDSO_NAME: &[u8] = b"linux-vdso.so.1";
+
+/// Where the code of an object loaded into the current process came from.
+pub(crate) enum ObjectFile {
+    /// The object was loaded from this file.
+    Path(PathBuf),
+    /// The object is the VDSO, whose code is only in memory.
+    Vdso,
+}
+
+/// Returns the objects loaded into the current process, each with the file that it was loaded
+/// from.
+///
+/// An error is returned if the path of the executable can't be found.
+pub(crate) fn loaded_objects() -> Result<Vec<(ObjectFile, phdrs::Object)>, HWTracerError> {
+    // FIXME: current_exe() isn't reliable. We should find another way to do this.
+    let exe = env::current_exe()?;
+    Ok(phdrs::objects()
+        .into_iter()
+        .map(|obj| {
+            let name = obj.name().to_bytes();
+            let file = if name.is_empty() {
+                // On Linux, an empty name means that it is the executable itself.
+                ObjectFile::Path(exe.clone())
+            } else if name == VDSO_NAME {
+                ObjectFile::Vdso
+            } else {
+                ObjectFile::Path(PathBuf::from(OsStr::from_bytes(name)))
+            };
+            (file, obj)
+        })
+        .collect())
+}
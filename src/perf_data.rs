@@ -0,0 +1,908 @@
+//! Reading Intel PT traces from the `perf.data` files written by `perf record -e intel_pt//`, and
+//! writing hwtracer's traces out as `perf.data` files for use with standard tools.
+//!
+//! ```no_run
+//! use hwtracer::perf_data::PerfData;
+//! let pd = PerfData::from_file("perf.data").unwrap();
+//! for t in &pd.traces {
+//!     println!("{} bytes of trace for CPU {:?}", t.trace.len(), t.cpu);
+//! }
+//! ```
+
+#[cfg(unix)]
+use crate::objects::{loaded_objects, ObjectFile};
+use crate::{
+    container::RawTrace,
+    errors::{CollectError, DecodeError, HWTracerError},
+    sideband::{parse_perf_record, perf_record, perf_record_header},
+    CpuId, SidebandEvent, SidebandRecord, Trace, TraceMeta,
+};
+#[cfg(unix)]
+use libc::{sysconf, _SC_PAGESIZE, PF_X, PT_LOAD};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    fs,
+    io::{self, Write},
+    path::Path,
+    process,
+};
+
+/// The magic bytes at the start of a (little-endian, non-pipe mode) `perf.data` file. Intel PT
+/// traces are recorded on x86_64, so files are always little-endian, whatever machine reads them.
+const PERF_MAGIC: &[u8; 8] = b"PERFILE2";
+/// The magic bytes at the start of a `perf.data` file written on a big-endian machine.
+const PERF_MAGIC_SWAPPED: &[u8; 8] = b"2ELIFREP";
+/// The size of `struct perf_file_header`.
+const PERF_FILE_HEADER_SIZE: u64 = 104;
+/// The size of the `struct perf_event_attr`s that we write.
+const PERF_ATTR_SIZE: usize = 128;
+/// The feature bit (and header section) holding the CPU identification string.
+const HEADER_CPUID: usize = 9;
+/// perf aligns the strings in header sections to this many bytes.
+const NAME_ALIGN: usize = 64;
+/// Where Linux advertises the Intel PT PMU's type.
+const PT_PMU_TYPE_PATH: &str = "/sys/bus/event_source/devices/intel_pt/type";
+
+// Record types synthesised by the perf tool. See `tools/lib/perf/include/perf/event.h`.
+const PERF_RECORD_FINISHED_ROUND: u32 = 68;
+const PERF_RECORD_AUXTRACE_INFO: u32 = 70;
+const PERF_RECORD_AUXTRACE: u32 = 71;
+const PERF_RECORD_TIME_CONV: u32 = 79;
+/// The size of a `PERF_RECORD_AUXTRACE` record, excluding the trace data which follows it.
+const AUXTRACE_RECORD_SIZE: usize = 48;
+/// The size of the smallest `PERF_RECORD_AUXTRACE_INFO` record for Intel PT that we can use: it
+/// must record at least the TSC to CTC ratio.
+const AUXTRACE_INFO_MIN_SIZE: usize = 16 + 14 * 8;
+/// `PERF_AUXTRACE_INTEL_PT` from perf's `enum auxtrace_type`.
+const PERF_AUXTRACE_INTEL_PT: u32 = 1;
+
+// `struct perf_event_attr` flag bits. See `perf_event_open(2)`.
+const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+const ATTR_MMAP: u64 = 1 << 8;
+const ATTR_COMM: u64 = 1 << 9;
+const ATTR_MMAP2: u64 = 1 << 23;
+const ATTR_COMM_EXEC: u64 = 1 << 24;
+const ATTR_CONTEXT_SWITCH: u64 = 1 << 26;
+
+// The positions of Intel PT options in `perf_event_attr.config`, as advertised by Linux in
+// `/sys/bus/event_source/devices/intel_pt/format`. perf's Intel PT decoder expects to be told
+// these.
+const PT_CYC_BIT: u64 = 1;
+const PT_MTC_BIT: u64 = 9;
+const PT_TSC_BIT: u64 = 10;
+const PT_NORETCOMP_BIT: u64 = 11;
+const PT_MTC_FREQ_BITS: u64 = 14;
+
+/// The contents of a `perf.data` file.
+#[derive(Debug)]
+pub struct PerfData {
+    /// One trace for each AUX area buffer in the file (i.e. for each CPU, or each thread if perf
+    /// was run with `--per-thread`).
+    pub traces: Vec<PerfDataTrace>,
+    /// How to convert TSC values to perf timestamps, if the file says.
+    pub time_conv: Option<TimeConv>,
+}
+
+/// The trace collected in one AUX area buffer of a `perf.data` file.
+#[derive(Debug)]
+pub struct PerfDataTrace {
+    /// The CPU that the buffer was collecting for, if it was a per-CPU buffer.
+    pub cpu: Option<u32>,
+    /// The thread that the buffer was collecting for, if it was a per-thread buffer.
+    pub tid: Option<u32>,
+    /// The trace itself.
+    ///
+    /// perf correlates sideband with trace data using timestamps. hwtracer instead assumes that
+    /// sideband records occurred after all of the trace data preceding them in the file, which is
+    /// only approximately true.
+    pub trace: Box<dyn Trace>,
+}
+
+/// The parameters for converting TSC values into perf timestamps, from a `PERF_RECORD_TIME_CONV`
+/// record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimeConv {
+    pub time_shift: u16,
+    pub time_mult: u32,
+    pub time_zero: u64,
+}
+
+impl TimeConv {
+    /// Convert the TSC value `tsc` to a perf timestamp.
+    pub fn tsc_to_perf_time(&self, tsc: u64) -> u64 {
+        let shift = u32::from(self.time_shift);
+        let mult = u64::from(self.time_mult);
+        let quot = tsc.checked_shr(shift).unwrap_or(0);
+        let rem = tsc & (1u64.checked_shl(shift).unwrap_or(0).wrapping_sub(1));
+        self.time_zero
+            .wrapping_add(quot.wrapping_mul(mult))
+            .wrapping_add(rem.wrapping_mul(mult).checked_shr(shift).unwrap_or(0))
+    }
+}
+
+fn bad(msg: &str) -> HWTracerError {
+    HWTracerError::Decode(DecodeError::parse(format!("bad perf.data file: {}", msg)))
+}
+
+fn field<const N: usize>(b: &[u8], off: usize) -> Result<[u8; N], HWTracerError> {
+    let b = b
+        .get(off..)
+        .and_then(|b| b.get(..N))
+        .ok_or_else(|| bad("truncated"))?;
+    Ok(b.try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], off: usize) -> Result<u32, HWTracerError> {
+    Ok(u32::from_le_bytes(field(b, off)?))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Result<u64, HWTracerError> {
+    Ok(u64::from_le_bytes(field(b, off)?))
+}
+
+fn usize_at(b: &[u8], off: usize) -> Result<usize, HWTracerError> {
+    usize::try_from(u64_at(b, off)?).map_err(|_| bad("offset too large"))
+}
+
+/// Get the contents of the `struct perf_file_section` (an offset and a size) at `off`.
+fn section(b: &[u8], off: usize) -> Result<&[u8], HWTracerError> {
+    let start = usize_at(b, off)?;
+    let size = usize_at(b, off + 8)?;
+    start
+        .checked_add(size)
+        .and_then(|end| b.get(start..end))
+        .ok_or_else(|| bad("section out of bounds"))
+}
+
+/// Parse the CPU identification string (e.g. `GenuineIntel,6,142,10`) from the `HEADER_CPUID`
+/// section `sec`. Returns `None` if the CPU isn't an Intel CPU.
+fn parse_cpuid(sec: &[u8]) -> Option<CpuId> {
+    let len = usize::try_from(u32_at(sec, 0).ok()?).ok()?;
+    let s = sec.get(4..4 + len)?;
+    let s = &s[..s.iter().position(|c| *c == 0).unwrap_or(s.len())];
+    let mut fields = std::str::from_utf8(s).ok()?.split(',');
+    if fields.next()? != "GenuineIntel" {
+        return None;
+    }
+    Some(CpuId {
+        family: fields.next()?.parse().ok()?,
+        model: fields.next()?.parse().ok()?,
+        stepping: fields.next()?.parse().ok()?,
+    })
+}
+
+/// An AUX area buffer under construction.
+struct AuxBuf {
+    cpu: Option<u32>,
+    tid: Option<u32>,
+    bytes: Vec<u8>,
+    sideband: Vec<SidebandRecord>,
+}
+
+impl PerfData {
+    /// Read the `perf.data` file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, HWTracerError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Parse the contents of a `perf.data` file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HWTracerError> {
+        match bytes.get(..8) {
+            Some(m) if m == PERF_MAGIC => (),
+            Some(m) if m == PERF_MAGIC_SWAPPED => {
+                return Err(bad("big-endian files aren't supported"))
+            }
+            _ => {
+                return Err(bad(
+                    "not a perf.data file (pipe mode files aren't supported)",
+                ))
+            }
+        }
+        if u64_at(bytes, 8)? < PERF_FILE_HEADER_SIZE {
+            return Err(bad("header too small"));
+        }
+
+        // The CPU identification string is in one of the feature sections, which follow the data
+        // section. There is one feature section for each bit set in the feature bitmap.
+        let data_end = usize_at(bytes, 40)?
+            .checked_add(usize_at(bytes, 48)?)
+            .ok_or_else(|| bad("section out of bounds"))?;
+        let mut cpu = None;
+        let features = (0..4)
+            .map(|i| u64_at(bytes, 72 + i * 8))
+            .collect::<Result<Vec<_>, _>>()?;
+        if features[HEADER_CPUID / 64] & (1 << (HEADER_CPUID % 64)) != 0 {
+            let idx = (0..HEADER_CPUID)
+                .filter(|b| features[b / 64] & (1 << (b % 64)) != 0)
+                .count();
+            let off = data_end
+                .checked_add(idx * 16)
+                .ok_or_else(|| bad("section out of bounds"))?;
+            cpu = parse_cpuid(section(bytes, off)?);
+        }
+
+        let mut data = section(bytes, 40)?;
+        let mut bufs: BTreeMap<u32, AuxBuf> = BTreeMap::new();
+        // Sideband records seen so far, to be given to buffers which haven't been seen yet.
+        let mut sideband = Vec::new();
+        let mut time_conv = None;
+        let mut meta = TraceMeta {
+            cpu,
+            ..Default::default()
+        };
+        // The type of the Intel PT PMU, if known.
+        let mut pmu_type = None;
+        while !data.is_empty() {
+            let (typ, _, size) = perf_record_header(data)?;
+            let rec = data.get(..size).ok_or_else(|| bad("truncated record"))?;
+            let mut next = size;
+            match typ {
+                PERF_RECORD_AUXTRACE => {
+                    if size < AUXTRACE_RECORD_SIZE {
+                        return Err(bad("AUXTRACE record too small"));
+                    }
+                    // The trace data follows the record, but isn't included in its size.
+                    let len = usize_at(rec, 8)?;
+                    let trace = size
+                        .checked_add(len)
+                        .and_then(|end| data.get(size..end))
+                        .ok_or_else(|| bad("truncated AUXTRACE data"))?;
+                    next += len;
+                    let idx = u32_at(rec, 32)?;
+                    // -1 means "any".
+                    let some = |v| if v == u32::MAX { None } else { Some(v) };
+                    let (tid, cpu) = (some(u32_at(rec, 36)?), some(u32_at(rec, 40)?));
+                    bufs.entry(idx)
+                        .or_insert_with(|| AuxBuf {
+                            cpu,
+                            tid,
+                            bytes: Vec::new(),
+                            sideband: sideband.clone(),
+                        })
+                        .bytes
+                        .extend_from_slice(trace);
+                }
+                PERF_RECORD_AUXTRACE_INFO => {
+                    // Older versions of perf record fewer values than we look at.
+                    if u32_at(rec, 8)? == PERF_AUXTRACE_INTEL_PT && size >= AUXTRACE_INFO_MIN_SIZE {
+                        // See `enum intel_pt_info_priv` in perf's `intel-pt.h`.
+                        pmu_type =
+                            Some(u32::try_from(u64_at(rec, 16)?).map_err(|_| bad("bad PMU type"))?);
+                        let n = u32::try_from(u64_at(rec, 16 + 12 * 8)?);
+                        let d = u32::try_from(u64_at(rec, 16 + 13 * 8)?);
+                        meta.tsc_ratio = match (n, d) {
+                            (Ok(n), Ok(d)) if n != 0 && d != 0 => Some((n, d)),
+                            _ => None,
+                        };
+                    }
+                }
+                PERF_RECORD_TIME_CONV => {
+                    time_conv = Some(TimeConv {
+                        time_shift: u16::try_from(u64_at(rec, 8)?)
+                            .map_err(|_| bad("bad time shift"))?,
+                        time_mult: u32::try_from(u64_at(rec, 16)?)
+                            .map_err(|_| bad("bad time multiplier"))?,
+                        time_zero: u64_at(rec, 24)?,
+                    });
+                }
+                _ => {
+                    if let Some(event) = parse_perf_record(rec)? {
+                        for buf in bufs.values_mut() {
+                            buf.sideband.push(SidebandRecord {
+                                trace_offset: buf.bytes.len(),
+                                event: event.clone(),
+                            });
+                        }
+                        // Buffers we haven't seen yet have no trace data before this record.
+                        sideband.push(SidebandRecord {
+                            trace_offset: 0,
+                            event,
+                        });
+                    }
+                }
+            }
+            data = &data[next..];
+        }
+
+        // The Intel PT configuration is that of the event with the Intel PT PMU's type.
+        let attr_size = usize_at(bytes, 16)?;
+        if let (Some(pmu_type), true) = (pmu_type, attr_size >= 16) {
+            meta.pt_config = section(bytes, 24)?
+                .chunks_exact(attr_size)
+                .find(|attr| u32_at(attr, 0).ok() == Some(pmu_type))
+                .map(|attr| u64_at(attr, 8))
+                .transpose()?;
+        }
+
+        let traces = bufs
+            .into_values()
+            .map(|b| PerfDataTrace {
+                cpu: b.cpu,
+                tid: b.tid,
+                trace: Box::new(RawTrace {
+                    bytes: b.bytes,
+                    meta: meta.clone(),
+                    sideband: b.sideband,
+                }),
+            })
+            .collect();
+        Ok(Self { traces, time_conv })
+    }
+
+    /// Wrap `trace`, which must have been collected in the current process, ready to be written
+    /// out with [PerfData::to_writer].
+    ///
+    /// Tools reading the `perf.data` file need to know what code the traced program was running,
+    /// so sideband records describing the executable mappings of the current process are
+    /// synthesised and put before any that the trace carries.
+    #[cfg(unix)]
+    pub fn from_trace(trace: &dyn Trace) -> Result<Self, HWTracerError> {
+        let pid = process::id();
+        let comm = fs::read_to_string("/proc/self/comm")?;
+        let mut events = vec![SidebandEvent::Comm {
+            pid,
+            tid: pid,
+            comm: comm.trim_end().to_owned(),
+            exec: false,
+        }];
+        let page_size = u64::try_from(unsafe { sysconf(_SC_PAGESIZE) }).unwrap();
+        for (file, obj) in loaded_objects()? {
+            let filename = match file {
+                ObjectFile::Path(p) => p,
+                // The name perf gives the VDSO.
+                ObjectFile::Vdso => PathBuf::from("[vdso]"),
+            };
+            for hdr in obj.iter_phdrs() {
+                if hdr.type_() != PT_LOAD || hdr.flags() & PF_X == 0 {
+                    continue; // Only look at loadable and executable segments.
+                }
+                // Mappings are made in whole pages.
+                let vaddr = obj.addr() + hdr.vaddr();
+                let slop = vaddr % page_size;
+                events.push(SidebandEvent::Mmap {
+                    vaddr: vaddr - slop,
+                    len: hdr.memsz() + slop,
+                    pgoff: hdr.offset() - slop,
+                    filename: filename.clone(),
+                });
+            }
+        }
+        let mut sideband = events
+            .into_iter()
+            .map(|event| SidebandRecord {
+                trace_offset: 0,
+                event,
+            })
+            .collect::<Vec<_>>();
+        sideband.extend(trace.sideband()?);
+        let trace = RawTrace {
+            bytes: trace.bytes().to_vec(),
+            meta: trace.meta(),
+            sideband,
+        };
+        Ok(Self {
+            traces: vec![PerfDataTrace {
+                cpu: None,
+                tid: None,
+                trace: Box::new(trace),
+            }],
+            time_conv: None,
+        })
+    }
+
+    /// Write the traces out as a `perf.data` file, which standard tools (e.g. `perf script
+    /// --insn-trace`) can then decode.
+    ///
+    /// The file describes the Intel PT PMU of the current machine, which must therefore support
+    /// Intel PT. Each trace's sideband records are written interleaved with its trace data, at the
+    /// points given by their trace offsets.
+    pub fn to_writer(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        let pmu_type = fs::read_to_string(PT_PMU_TYPE_PATH).map_err(|_| {
+            HWTracerError::Collect(CollectError::NoHWSupport(
+                "Intel PT isn't available".to_owned(),
+            ))
+        })?;
+        self.write(w, pmu_type.trim().parse()?)
+    }
+
+    /// Write the traces out as a `perf.data` file, describing the Intel PT PMU as having the type
+    /// `pmu_type`.
+    fn write(&self, w: &mut dyn Write, pmu_type: u32) -> Result<(), HWTracerError> {
+        let sidebands = self
+            .traces
+            .iter()
+            .map(|t| t.trace.sideband())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The data section.
+        let mut data = Vec::new();
+        let tc = self.time_conv.unwrap_or(TimeConv {
+            time_shift: 0,
+            time_mult: 0,
+            time_zero: 0,
+        });
+        let have_switch = sidebands
+            .iter()
+            .flatten()
+            .any(|r| matches!(r.event, SidebandEvent::Switch { .. }));
+        // perf can only describe one configuration, so use that of the first trace. Unknown values
+        // are written as zero.
+        let meta = self
+            .traces
+            .first()
+            .map(|t| t.trace.meta())
+            .unwrap_or_default();
+        let tsc_ratio = meta.tsc_ratio.unwrap_or((0, 0));
+        // See `enum intel_pt_info_priv` in perf's `intel-pt.h`.
+        let info: [u64; 17] = [
+            u64::from(pmu_type),
+            u64::from(tc.time_shift),
+            u64::from(tc.time_mult),
+            tc.time_zero,
+            u64::from(self.time_conv.is_some()), // cap_user_time_zero
+            PT_TSC_BIT,
+            PT_NORETCOMP_BIT,
+            // Context switches are recorded with `PERF_RECORD_SWITCH`, which perf calls 2.
+            if have_switch { 2 } else { 0 },
+            0,                                                      // snapshot_mode
+            u64::from(self.traces.iter().any(|t| t.cpu.is_some())), // per_cpu_mmaps
+            PT_MTC_BIT,
+            PT_MTC_FREQ_BITS,
+            u64::from(tsc_ratio.0), // tsc_ctc_ratio_n
+            u64::from(tsc_ratio.1), // tsc_ctc_ratio_d
+            PT_CYC_BIT,
+            0, // max_non_turbo_ratio: unknown.
+            0, // filter_str_len: no address filters.
+        ];
+        let mut body = Vec::new();
+        body.extend(PERF_AUXTRACE_INTEL_PT.to_le_bytes());
+        body.extend(0u32.to_le_bytes()); // reserved
+        info.iter().for_each(|v| body.extend(v.to_le_bytes()));
+        push_record(&mut data, PERF_RECORD_AUXTRACE_INFO, &body)?;
+        if let Some(tc) = self.time_conv {
+            let mut body = Vec::new();
+            body.extend(u64::from(tc.time_shift).to_le_bytes());
+            body.extend(u64::from(tc.time_mult).to_le_bytes());
+            body.extend(tc.time_zero.to_le_bytes());
+            push_record(&mut data, PERF_RECORD_TIME_CONV, &body)?;
+        }
+        let pid = process::id();
+        for (idx, (t, sideband)) in self.traces.iter().zip(sidebands).enumerate() {
+            let idx = u32::try_from(idx).unwrap();
+            let bytes = t.trace.bytes();
+            // Split the trace data up at the offsets of the sideband records.
+            let mut done = 0;
+            for rec in sideband {
+                let off = rec.trace_offset.min(bytes.len());
+                if off > done {
+                    push_auxtrace(&mut data, idx, t, &bytes[done..off], done)?;
+                    done = off;
+                }
+                // Records which perf can't describe are left out.
+                data.extend(perf_record(&rec.event, pid).unwrap_or_default());
+            }
+            if done < bytes.len() {
+                push_auxtrace(&mut data, idx, t, &bytes[done..], done)?;
+            }
+        }
+        push_record(&mut data, PERF_RECORD_FINISHED_ROUND, &[])?;
+
+        // The attributes section, which describes the single Intel PT event.
+        let mut attr = vec![0; PERF_ATTR_SIZE];
+        attr[0..4].copy_from_slice(&pmu_type.to_le_bytes());
+        attr[4..8].copy_from_slice(&u32::try_from(PERF_ATTR_SIZE).unwrap().to_le_bytes());
+        attr[8..16].copy_from_slice(&meta.pt_config.unwrap_or(0).to_le_bytes());
+        attr[16..24].copy_from_slice(&1u64.to_le_bytes()); // sample_period
+        let flags = ATTR_EXCLUDE_KERNEL
+            | ATTR_EXCLUDE_HV
+            | ATTR_MMAP
+            | ATTR_COMM
+            | ATTR_MMAP2
+            | ATTR_COMM_EXEC
+            | ATTR_CONTEXT_SWITCH;
+        attr[40..48].copy_from_slice(&flags.to_le_bytes());
+        // A `struct perf_file_attr` is the attributes followed by a (here empty) section of IDs.
+        attr.extend([0; 16]);
+
+        // The feature sections.
+        let mut features = [0u64; 4];
+        let mut feature_secs = Vec::new();
+        if let Some(cpu) = meta.cpu {
+            features[HEADER_CPUID / 64] |= 1 << (HEADER_CPUID % 64);
+            let s = format!("GenuineIntel,{},{},{}", cpu.family, cpu.model, cpu.stepping);
+            let mut s = s.into_bytes();
+            s.resize((s.len() / NAME_ALIGN + 1) * NAME_ALIGN, 0);
+            feature_secs.extend(u32::try_from(s.len()).unwrap().to_le_bytes());
+            feature_secs.extend(s);
+        }
+
+        let attrs_off = PERF_FILE_HEADER_SIZE;
+        let data_off = attrs_off + u64::try_from(attr.len()).unwrap();
+        let data_end = data_off + u64::try_from(data.len()).unwrap();
+        w.write_all(PERF_MAGIC)?;
+        w.write_all(&PERF_FILE_HEADER_SIZE.to_le_bytes())?;
+        w.write_all(&u64::try_from(attr.len()).unwrap().to_le_bytes())?; // attr_size
+        w.write_all(&attrs_off.to_le_bytes())?;
+        w.write_all(&u64::try_from(attr.len()).unwrap().to_le_bytes())?;
+        w.write_all(&data_off.to_le_bytes())?;
+        w.write_all(&u64::try_from(data.len()).unwrap().to_le_bytes())?;
+        w.write_all(&[0; 16])?; // event_types: unused.
+        for f in &features {
+            w.write_all(&f.to_le_bytes())?;
+        }
+        w.write_all(&attr)?;
+        w.write_all(&data)?;
+        if !feature_secs.is_empty() {
+            // The table of feature sections, followed by their contents.
+            w.write_all(&(data_end + 16).to_le_bytes())?;
+            w.write_all(&u64::try_from(feature_secs.len()).unwrap().to_le_bytes())?;
+            w.write_all(&feature_secs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append a perf record with the given type and body to `buf`. An error is returned if the body
+/// is too big for a perf record.
+fn push_record(buf: &mut Vec<u8>, typ: u32, body: &[u8]) -> Result<(), HWTracerError> {
+    let size = u16::try_from(8 + body.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "perf record too big"))?;
+    buf.extend(typ.to_le_bytes());
+    buf.extend(0u16.to_le_bytes()); // misc
+    buf.extend(size.to_le_bytes());
+    buf.extend(body);
+    Ok(())
+}
+
+/// Append to `buf` an AUXTRACE record holding `bytes`, which were found at `offset` in the AUX
+/// area buffer `idx` holding `t`.
+fn push_auxtrace(
+    buf: &mut Vec<u8>,
+    idx: u32,
+    t: &PerfDataTrace,
+    bytes: &[u8],
+    offset: usize,
+) -> Result<(), HWTracerError> {
+    let mut body = Vec::new();
+    body.extend(u64::try_from(bytes.len()).unwrap().to_le_bytes());
+    body.extend(u64::try_from(offset).unwrap().to_le_bytes());
+    body.extend(0u64.to_le_bytes()); // reference
+    body.extend(idx.to_le_bytes());
+    // -1 means "any".
+    body.extend(t.tid.unwrap_or(u32::MAX).to_le_bytes());
+    body.extend(t.cpu.unwrap_or(u32::MAX).to_le_bytes());
+    body.extend(0u32.to_le_bytes()); // reserved
+    push_record(buf, PERF_RECORD_AUXTRACE, &body)?;
+    buf.extend(bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        PerfData, PerfDataTrace, TimeConv, AUXTRACE_RECORD_SIZE, HEADER_CPUID,
+        PERF_FILE_HEADER_SIZE, PERF_MAGIC, PERF_RECORD_AUXTRACE, PERF_RECORD_TIME_CONV,
+    };
+    use crate::{
+        container::RawTrace,
+        errors::{DecodeError, HWTracerError},
+        sideband::{PERF_RECORD_COMM, PERF_RECORD_MISC_COMM_EXEC, PERF_RECORD_MMAP2},
+        testing::work_loop,
+        CpuId, SidebandEvent, SidebandRecord, TraceMeta,
+    };
+    use std::{convert::TryFrom, path::PathBuf, process};
+
+    /// Append a perf record with the given type, misc flags and body to `buf`.
+    fn push_record(buf: &mut Vec<u8>, typ: u32, misc: u16, body: &[u8]) {
+        buf.extend(typ.to_le_bytes());
+        buf.extend(misc.to_le_bytes());
+        buf.extend(u16::try_from(8 + body.len()).unwrap().to_le_bytes());
+        buf.extend(body);
+    }
+
+    /// Append an AUXTRACE record for buffer `idx`, followed by `trace`, to `buf`.
+    fn push_auxtrace(buf: &mut Vec<u8>, idx: u32, cpu: u32, trace: &[u8]) {
+        let mut body = Vec::new();
+        body.extend(u64::try_from(trace.len()).unwrap().to_le_bytes()); // size
+        body.extend(0u64.to_le_bytes()); // offset
+        body.extend(0u64.to_le_bytes()); // reference
+        body.extend(idx.to_le_bytes());
+        body.extend(u32::MAX.to_le_bytes()); // tid
+        body.extend(cpu.to_le_bytes());
+        body.extend(0u32.to_le_bytes()); // reserved
+        assert_eq!(8 + body.len(), AUXTRACE_RECORD_SIZE);
+        push_record(buf, PERF_RECORD_AUXTRACE, 0, &body);
+        buf.extend(trace);
+    }
+
+    /// Make a `perf.data` file with the data section `data` and the CPU identification string
+    /// `cpuid`.
+    fn mk_perf_data(data: &[u8], cpuid: &str) -> Vec<u8> {
+        let hdr_size = usize::try_from(PERF_FILE_HEADER_SIZE).unwrap();
+        let data_off = u64::try_from(hdr_size).unwrap();
+        let data_size = u64::try_from(data.len()).unwrap();
+        let mut buf = Vec::new();
+        buf.extend(PERF_MAGIC);
+        buf.extend(PERF_FILE_HEADER_SIZE.to_le_bytes());
+        buf.extend(0u64.to_le_bytes()); // attr_size
+        buf.extend([0; 16]); // attrs
+        buf.extend(data_off.to_le_bytes());
+        buf.extend(data_size.to_le_bytes());
+        buf.extend([0; 16]); // event_types
+
+        // Set the HEADER_CPUID feature bit and one before it, to check that sections are counted.
+        buf.extend(((1u64 << HEADER_CPUID) | (1 << 3)).to_le_bytes());
+        buf.extend([0; 24]);
+        assert_eq!(buf.len(), hdr_size);
+        buf.extend(data);
+
+        let mut cpuid = cpuid.as_bytes().to_vec();
+        cpuid.push(0);
+        let sec_off = u64::try_from(buf.len() + 2 * 16).unwrap();
+        buf.extend(0u64.to_le_bytes()); // The feature before HEADER_CPUID: empty.
+        buf.extend(0u64.to_le_bytes());
+        buf.extend(sec_off.to_le_bytes());
+        buf.extend(u64::try_from(4 + cpuid.len()).unwrap().to_le_bytes());
+        buf.extend(u32::try_from(cpuid.len()).unwrap().to_le_bytes());
+        buf.extend(cpuid);
+        buf
+    }
+
+    #[test]
+    fn parse_perf_data() {
+        let mut data = Vec::new();
+        let mut time_conv = Vec::new();
+        time_conv.extend(10u64.to_le_bytes());
+        time_conv.extend(3u64.to_le_bytes());
+        time_conv.extend(1000u64.to_le_bytes());
+        push_record(&mut data, PERF_RECORD_TIME_CONV, 0, &time_conv);
+
+        let mut mmap = Vec::new();
+        mmap.extend(1u32.to_le_bytes()); // pid
+        mmap.extend(2u32.to_le_bytes()); // tid
+        mmap.extend(0x1000u64.to_le_bytes()); // addr
+        mmap.extend(0x2000u64.to_le_bytes()); // len
+        mmap.extend(0x3000u64.to_le_bytes()); // pgoff
+        mmap.extend([0; 24]); // maj, min, ino, ino_generation
+        mmap.extend([0; 8]); // prot, flags
+        mmap.extend(b"/lib/libfoo.so\0\0");
+        push_record(&mut data, PERF_RECORD_MMAP2, 0, &mmap);
+
+        push_auxtrace(&mut data, 0, 0, &[1, 2, 3, 4]);
+
+        let mut comm = Vec::new();
+        comm.extend(1u32.to_le_bytes()); // pid
+        comm.extend(2u32.to_le_bytes()); // tid
+        comm.extend(b"prog\0\0\0\0");
+        push_record(
+            &mut data,
+            PERF_RECORD_COMM,
+            PERF_RECORD_MISC_COMM_EXEC,
+            &comm,
+        );
+
+        push_auxtrace(&mut data, 0, 0, &[5, 6]);
+        push_auxtrace(&mut data, 1, 1, &[7]);
+        // Unknown records are skipped.
+        push_record(&mut data, 0xffff, 0, &[0; 8]);
+
+        let pd = PerfData::from_bytes(&mk_perf_data(&data, "GenuineIntel,6,142,10")).unwrap();
+        assert_eq!(
+            pd.time_conv,
+            Some(TimeConv {
+                time_shift: 10,
+                time_mult: 3,
+                time_zero: 1000
+            })
+        );
+        assert_eq!(pd.traces.len(), 2);
+        let cpu = Some(CpuId {
+            family: 6,
+            model: 142,
+            stepping: 10,
+        });
+        let mmap = SidebandRecord {
+            trace_offset: 0,
+            event: SidebandEvent::Mmap {
+                vaddr: 0x1000,
+                len: 0x2000,
+                pgoff: 0x3000,
+                filename: PathBuf::from("/lib/libfoo.so"),
+            },
+        };
+        let comm = |trace_offset| SidebandRecord {
+            trace_offset,
+            event: SidebandEvent::Comm {
+                pid: 1,
+                tid: 2,
+                comm: "prog".to_owned(),
+                exec: true,
+            },
+        };
+
+        let t0 = &pd.traces[0];
+        assert_eq!((t0.cpu, t0.tid), (Some(0), None));
+        assert_eq!(t0.trace.bytes(), &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(t0.trace.cpu(), cpu);
+        assert_eq!(t0.trace.sideband().unwrap(), vec![mmap.clone(), comm(4)]);
+
+        let t1 = &pd.traces[1];
+        assert_eq!((t1.cpu, t1.tid), (Some(1), None));
+        assert_eq!(t1.trace.bytes(), &[7]);
+        assert_eq!(t1.trace.sideband().unwrap(), vec![mmap, comm(0)]);
+    }
+
+    #[test]
+    fn non_intel_cpu() {
+        let mut data = Vec::new();
+        push_auxtrace(&mut data, 0, 0, &[1]);
+        let pd = PerfData::from_bytes(&mk_perf_data(&data, "AuthenticAMD,25,33,0")).unwrap();
+        assert_eq!(pd.traces[0].trace.cpu(), None);
+    }
+
+    #[test]
+    fn bad_perf_data() {
+        assert!(matches!(
+            PerfData::from_bytes(b"not a perf.data file"),
+            Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+        ));
+
+        let mut data = Vec::new();
+        push_auxtrace(&mut data, 0, 0, &[1, 2, 3, 4]);
+        data.truncate(data.len() - 1);
+        assert!(PerfData::from_bytes(&mk_perf_data(&data, "GenuineIntel,6,142,10")).is_err());
+
+        // A data section ending at the very end of the address space.
+        let mut bytes = mk_perf_data(&[], "GenuineIntel,6,142,10");
+        bytes[40..48].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        bytes[48..56].copy_from_slice(&8u64.to_le_bytes());
+        assert!(matches!(
+            PerfData::from_bytes(&bytes),
+            Err(HWTracerError::Decode(DecodeError::Parse { .. }))
+        ));
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let meta = TraceMeta {
+            cpu: Some(CpuId {
+                family: 6,
+                model: 142,
+                stepping: 10,
+            }),
+            tsc_ratio: Some((188, 2)),
+            pt_config: Some(0x2001),
+            // perf.data files have nowhere to put these.
+            hwtracer_version: None,
+            build_ids: Vec::new(),
+            id: None,
+            provenance: Default::default(),
+        };
+        let sideband = vec![
+            SidebandRecord {
+                trace_offset: 0,
+                event: SidebandEvent::Comm {
+                    pid: process::id(),
+                    tid: process::id(),
+                    comm: "prog".to_owned(),
+                    exec: false,
+                },
+            },
+            SidebandRecord {
+                trace_offset: 2,
+                event: SidebandEvent::Mmap {
+                    vaddr: 0x1000,
+                    len: 0x2000,
+                    pgoff: 0x3000,
+                    filename: PathBuf::from("/lib/libfoo.so"),
+                },
+            },
+            SidebandRecord {
+                trace_offset: 5,
+                event: SidebandEvent::Switch { out: true },
+            },
+        ];
+        let time_conv = Some(TimeConv {
+            time_shift: 10,
+            time_mult: 3,
+            time_zero: 1000,
+        });
+        let pd = PerfData {
+            traces: vec![PerfDataTrace {
+                cpu: Some(3),
+                tid: None,
+                trace: Box::new(RawTrace {
+                    bytes: vec![1, 2, 3, 4, 5, 6, 7],
+                    meta: meta.clone(),
+                    sideband: sideband.clone(),
+                }),
+            }],
+            time_conv,
+        };
+        let mut buf = Vec::new();
+        pd.write(&mut buf, 8).unwrap();
+
+        let loaded = PerfData::from_bytes(&buf).unwrap();
+        assert_eq!(loaded.time_conv, time_conv);
+        assert_eq!(loaded.traces.len(), 1);
+        let t = &loaded.traces[0];
+        assert_eq!((t.cpu, t.tid), (Some(3), None));
+        assert_eq!(t.trace.bytes(), &[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(t.trace.meta(), meta);
+        assert_eq!(t.trace.sideband().unwrap(), sideband);
+    }
+
+    /// Check that sideband records too big for a perf record are left out, rather than panicking.
+    #[test]
+    fn write_huge_sideband() {
+        let comm = SidebandRecord {
+            trace_offset: 0,
+            event: SidebandEvent::Comm {
+                pid: 1,
+                tid: 1,
+                comm: "prog".to_owned(),
+                exec: false,
+            },
+        };
+        let huge = SidebandRecord {
+            trace_offset: 1,
+            event: SidebandEvent::Mmap {
+                vaddr: 0x1000,
+                len: 0x2000,
+                pgoff: 0,
+                filename: PathBuf::from("/".repeat(usize::from(u16::MAX))),
+            },
+        };
+        let pd = PerfData {
+            traces: vec![PerfDataTrace {
+                cpu: None,
+                tid: None,
+                trace: Box::new(RawTrace {
+                    bytes: vec![1, 2],
+                    meta: TraceMeta::default(),
+                    sideband: vec![comm.clone(), huge],
+                }),
+            }],
+            time_conv: None,
+        };
+        let mut buf = Vec::new();
+        pd.write(&mut buf, 8).unwrap();
+        let loaded = PerfData::from_bytes(&buf).unwrap();
+        assert_eq!(loaded.traces[0].trace.sideband().unwrap(), vec![comm]);
+    }
+
+    /// Check that a trace collected in this process is described along with the code it ran.
+    #[test]
+    fn from_trace() {
+        let trace = RawTrace::new(vec![1, 2, 3]);
+        let pd = PerfData::from_trace(&trace).unwrap();
+        assert_eq!(pd.traces.len(), 1);
+        let sideband = pd.traces[0].trace.sideband().unwrap();
+        assert!(matches!(
+            sideband[0].event,
+            SidebandEvent::Comm { pid, .. } if pid == process::id()
+        ));
+        let ip = u64::try_from(work_loop as fn(u64) -> u64 as usize).unwrap();
+        assert!(sideband.iter().any(|r| matches!(
+            r.event,
+            SidebandEvent::Mmap { vaddr, len, .. } if vaddr <= ip && ip < vaddr + len
+        )));
+    }
+
+    #[test]
+    fn tsc_to_perf_time() {
+        let tc = TimeConv {
+            time_shift: 10,
+            time_mult: 1 << 9,
+            time_zero: 1000,
+        };
+        // A multiplier of 2^9 with a shift of 10 halves the TSC.
+        assert_eq!(tc.tsc_to_perf_time(0), 1000);
+        assert_eq!(tc.tsc_to_perf_time(4096), 1000 + 2048);
+        assert_eq!(tc.tsc_to_perf_time(4097), 1000 + 2048);
+    }
+}
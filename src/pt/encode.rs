@@ -0,0 +1,786 @@
+//! Encoding of Intel PT packets, for making synthetic traces.
+//!
+//! This is the inverse of [super::PacketParser]: it makes it possible to write precise traces for
+//! tests and benchmarks without PT hardware, and to check that parsed packets round-trip.
+//!
+//! ```
+//! use hwtracer::pt::{
+//!     encode::{Encoder, IPCompression},
+//!     ExecMode, Packet, PacketParser,
+//! };
+//!
+//! let mut enc = Encoder::new();
+//! enc.psb()
+//!     .psbend()
+//!     .mode_exec(ExecMode::Bits64)
+//!     .tip_pge(0x5555_5555_1234, IPCompression::Full)
+//!     .tnt(&[true, false, true])
+//!     .tip(0x5555_5555_5678, IPCompression::Update16);
+//! let pkts = PacketParser::new(enc.bytes())
+//!     .collect::<Result<Vec<_>, _>>()
+//!     .unwrap();
+//! assert!(matches!(pkts[..], [Packet::PSB(_), Packet::PSBEND(_), .., Packet::TIP(..)]));
+//! assert_eq!(pkts[5].target_ip(), Ok(Some(0x5555_5555_5678)));
+//! ```
+
+use super::{ExecMode, Packet};
+use alloc::vec::Vec;
+
+/// The most branch decisions that a short TNT packet can hold.
+pub const SHORT_TNT_MAX: usize = 6;
+
+/// The most branch decisions that a long TNT packet can hold.
+pub const LONG_TNT_MAX: usize = 47;
+
+/// How the IP of a TIP, TIP.PGE, TIP.PGD or FUP packet is compressed (i.e. the packet's `IPBytes`
+/// field).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IPCompression {
+    /// No IP: the IP is "out of context".
+    Suppressed,
+    /// The low 16 bits of the IP, with the rest taken from the last IP.
+    Update16,
+    /// The low 32 bits of the IP, with the rest taken from the last IP.
+    Update32,
+    /// The low 48 bits of the IP, sign extended from bit 47.
+    Sext48,
+    /// The low 48 bits of the IP, with the rest taken from the last IP.
+    Update48,
+    /// The whole IP.
+    Full,
+}
+
+impl IPCompression {
+    /// Returns the smallest compression which can encode `ip`, given the `last` IP (as returned by
+    /// [Encoder::last_ip]).
+    pub fn smallest(ip: u64, last: Option<u64>) -> Self {
+        [Self::Update16, Self::Update32, Self::Sext48, Self::Update48]
+            .iter()
+            .copied()
+            .find(|c| c.can_encode(ip, last))
+            .unwrap_or(Self::Full)
+    }
+
+    /// Returns `true` if `ip`, compressed this way, decompresses to `ip` again given the `last`
+    /// IP. A suppressed IP never does.
+    pub fn can_encode(self, ip: u64, last: Option<u64>) -> bool {
+        let same_high = |bits: u32| matches!(last, Some(last) if ip >> bits == last >> bits);
+        match self {
+            Self::Suppressed => false,
+            Self::Update16 => same_high(16),
+            Self::Update32 => same_high(32),
+            Self::Sext48 => ((ip << 16) as i64 >> 16) as u64 == ip,
+            Self::Update48 => same_high(48),
+            Self::Full => true,
+        }
+    }
+
+    /// Returns the value of the `IPBytes` field and the number of bytes of the IP that follow.
+    fn ip_bytes(self) -> (u8, usize) {
+        match self {
+            Self::Suppressed => (0b000, 0),
+            Self::Update16 => (0b001, 2),
+            Self::Update32 => (0b010, 4),
+            Self::Sext48 => (0b011, 6),
+            Self::Update48 => (0b100, 6),
+            Self::Full => (0b110, 8),
+        }
+    }
+}
+
+/// Encodes packets into a trace.
+///
+/// Packets are encoded exactly as asked: in particular, IPs are compressed as asked even if the
+/// result can't be decompressed (e.g. a compressed IP with no last IP). This makes it possible to
+/// encode malformed traces for testing error handling.
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+    last_ip: Option<u64>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the bytes encoded so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the bytes encoded.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Returns the number of bytes encoded so far.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns `true` if nothing has been encoded.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the last IP, from which the IPs of later packets are decompressed. There is none
+    /// at the start of a trace or after a PSB.
+    pub fn last_ip(&self) -> Option<u64> {
+        self.last_ip
+    }
+
+    /// Encode a parsed packet, as it was parsed (e.g. with the same IP compression).
+    pub fn packet(&mut self, pkt: &Packet) -> &mut Self {
+        pkt.write(&mut self.bytes);
+        match pkt {
+            Packet::PSB(_) => self.last_ip = None,
+            _ if !pkt.ip_suppressed() => {
+                if let Ok(Some(ip)) = pkt.target_ip() {
+                    self.last_ip = Some(ip);
+                }
+            }
+            _ => (),
+        }
+        self
+    }
+
+    /// Encode a PSB packet, which resets the last IP.
+    pub fn psb(&mut self) -> &mut Self {
+        for _ in 0..8 {
+            self.bytes.extend_from_slice(&[0x02, 0x82]);
+        }
+        self.last_ip = None;
+        self
+    }
+
+    /// Encode a PSBEND packet.
+    pub fn psbend(&mut self) -> &mut Self {
+        self.bytes.extend_from_slice(&[0x02, 0x23]);
+        self
+    }
+
+    /// Encode a CBR packet with the core:bus ratio `ratio`.
+    pub fn cbr(&mut self, ratio: u8) -> &mut Self {
+        self.bytes.extend_from_slice(&[0x02, 0x03, ratio, 0x00]);
+        self
+    }
+
+    /// Encode a PAD packet.
+    pub fn pad(&mut self) -> &mut Self {
+        self.bytes.push(0x00);
+        self
+    }
+
+    /// Encode an OVF packet.
+    pub fn ovf(&mut self) -> &mut Self {
+        self.bytes.extend_from_slice(&[0x02, 0xf3]);
+        self
+    }
+
+    /// Encode a `MODE.Exec` packet for the execution mode `mode`.
+    pub fn mode_exec(&mut self, mode: ExecMode) -> &mut Self {
+        // Bit 0 is `CS.L` and bit 1 is `CS.D`.
+        let bits = match mode {
+            ExecMode::Bits16 => 0b00,
+            ExecMode::Bits32 => 0b10,
+            ExecMode::Bits64 => 0b01,
+        };
+        self.bytes.extend_from_slice(&[0x99, bits]);
+        self
+    }
+
+    /// Encode a `MODE.TSX` packet, with the given `InTX` and `TXAbort` bits.
+    pub fn mode_tsx(&mut self, intx: bool, abort: bool) -> &mut Self {
+        let bits = u8::from(intx) | u8::from(abort) << 1;
+        self.bytes.extend_from_slice(&[0x99, 0b001 << 5 | bits]);
+        self
+    }
+
+    /// Encode the branch decisions `taken` (oldest first, `true` for a taken branch) in as few TNT
+    /// packets as possible: a short TNT packet if they fit, otherwise as many long TNT packets as
+    /// needed.
+    pub fn tnt(&mut self, taken: &[bool]) -> &mut Self {
+        if taken.len() <= SHORT_TNT_MAX {
+            self.short_tnt(taken)
+        } else {
+            for chunk in taken.chunks(LONG_TNT_MAX) {
+                self.long_tnt(chunk);
+            }
+            self
+        }
+    }
+
+    /// Encode the branch decisions `taken` in a short TNT packet.
+    ///
+    /// # Panics
+    ///
+    /// If there are no decisions, or more than [SHORT_TNT_MAX].
+    pub fn short_tnt(&mut self, taken: &[bool]) -> &mut Self {
+        assert!((1..=SHORT_TNT_MAX).contains(&taken.len()));
+        self.bytes.push((tnt_payload(taken) as u8) << 1);
+        self
+    }
+
+    /// Encode the branch decisions `taken` in a long TNT packet.
+    ///
+    /// # Panics
+    ///
+    /// If there are no decisions, or more than [LONG_TNT_MAX].
+    pub fn long_tnt(&mut self, taken: &[bool]) -> &mut Self {
+        assert!((1..=LONG_TNT_MAX).contains(&taken.len()));
+        self.bytes.extend_from_slice(&[0x02, 0xa3]);
+        self.bytes
+            .extend_from_slice(&tnt_payload(taken).to_le_bytes()[..6]);
+        self
+    }
+
+    /// Encode a TIP packet with the IP `ip`, compressed as `comp`.
+    pub fn tip(&mut self, ip: u64, comp: IPCompression) -> &mut Self {
+        self.ip_packet(0x0d, ip, comp)
+    }
+
+    /// Encode a TIP.PGE packet with the IP `ip`, compressed as `comp`.
+    pub fn tip_pge(&mut self, ip: u64, comp: IPCompression) -> &mut Self {
+        self.ip_packet(0x11, ip, comp)
+    }
+
+    /// Encode a TIP.PGD packet with the IP `ip`, compressed as `comp`.
+    pub fn tip_pgd(&mut self, ip: u64, comp: IPCompression) -> &mut Self {
+        self.ip_packet(0x01, ip, comp)
+    }
+
+    /// Encode a FUP packet with the IP `ip`, compressed as `comp`.
+    pub fn fup(&mut self, ip: u64, comp: IPCompression) -> &mut Self {
+        self.ip_packet(0x1d, ip, comp)
+    }
+
+    fn ip_packet(&mut self, opcode: u8, ip: u64, comp: IPCompression) -> &mut Self {
+        let (ip_bytes, n) = comp.ip_bytes();
+        self.bytes.push(ip_bytes << 5 | opcode);
+        self.bytes.extend_from_slice(&ip.to_le_bytes()[..n]);
+        if comp != IPCompression::Suppressed {
+            self.last_ip = Some(ip);
+        }
+        self
+    }
+
+    /// Encode a CYC packet with the cycle count `cycles`, using as few extended bytes as possible.
+    pub fn cyc(&mut self, cycles: u64) -> &mut Self {
+        let ext = cycles >> 5;
+        self.bytes
+            .push((cycles as u8 & 0x1f) << 3 | u8::from(ext != 0) << 2 | 0b11);
+        if ext != 0 {
+            let mut ext = ext;
+            loop {
+                // Each extended byte holds 7 bits, with the low bit set if another follows.
+                let more = ext >> 7 != 0;
+                self.bytes.push((ext as u8) << 1 | u8::from(more));
+                ext >>= 7;
+                if !more {
+                    break;
+                }
+            }
+        }
+        self
+    }
+
+    /// Encode a PTW packet with the payload `payload`, in 4 bytes if it fits and 8 otherwise. If
+    /// `ip` is `true`, the packet says that a FUP packet follows (which must be encoded
+    /// separately).
+    pub fn ptw(&mut self, payload: u64, ip: bool) -> &mut Self {
+        let wide = payload >> 32 != 0;
+        self.bytes
+            .extend_from_slice(&[0x02, u8::from(ip) << 7 | u8::from(wide) << 5 | 0x12]);
+        if wide {
+            self.bytes.extend_from_slice(&payload.to_le_bytes());
+        } else {
+            self.bytes
+                .extend_from_slice(&(payload as u32).to_le_bytes());
+        }
+        self
+    }
+
+    /// Encode a PIP packet reporting that CR3 was set to `cr3` (whose low 5 bits and bits above 51
+    /// aren't encoded), in a VMX guest if `non_root` is `true`.
+    pub fn pip(&mut self, cr3: u64, non_root: bool) -> &mut Self {
+        let payload = (cr3 >> 5) << 1 | u64::from(non_root);
+        self.bytes.extend_from_slice(&[0x02, 0x43]);
+        self.bytes.extend_from_slice(&payload.to_le_bytes()[..6]);
+        self
+    }
+}
+
+/// A packet, as encoded by one of the methods of an [Encoder] (see [Encoder::spec]).
+///
+/// With the `arbitrary` feature, arbitrary packets can be made for property-based tests. Their IPs
+/// may not be decompressible: see [PacketSeq] for well-formed sequences of packets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PacketSpec {
+    PSB,
+    PSBEND,
+    CBR(u8),
+    PAD,
+    OVF,
+    ModeExec(ExecMode),
+    ModeTSX {
+        intx: bool,
+        abort: bool,
+    },
+    /// Branch decisions, encoded as by [Encoder::tnt]. There must be at least one.
+    TNT(Vec<bool>),
+    TIP(u64, IPCompression),
+    TIPPGE(u64, IPCompression),
+    TIPPGD(u64, IPCompression),
+    FUP(u64, IPCompression),
+    CYC(u64),
+    PTW {
+        payload: u64,
+        ip: bool,
+    },
+    PIP {
+        cr3: u64,
+        non_root: bool,
+    },
+}
+
+impl PacketSpec {
+    /// If this is an IP packet, returns its IP and compression.
+    pub fn ip(&self) -> Option<(u64, IPCompression)> {
+        match *self {
+            Self::TIP(ip, comp)
+            | Self::TIPPGE(ip, comp)
+            | Self::TIPPGD(ip, comp)
+            | Self::FUP(ip, comp) => Some((ip, comp)),
+            _ => None,
+        }
+    }
+}
+
+impl Encoder {
+    /// Encode the packet described by `spec`.
+    pub fn spec(&mut self, spec: &PacketSpec) -> &mut Self {
+        match *spec {
+            PacketSpec::PSB => self.psb(),
+            PacketSpec::PSBEND => self.psbend(),
+            PacketSpec::CBR(ratio) => self.cbr(ratio),
+            PacketSpec::PAD => self.pad(),
+            PacketSpec::OVF => self.ovf(),
+            PacketSpec::ModeExec(mode) => self.mode_exec(mode),
+            PacketSpec::ModeTSX { intx, abort } => self.mode_tsx(intx, abort),
+            PacketSpec::TNT(ref taken) => self.tnt(taken),
+            PacketSpec::TIP(ip, comp) => self.tip(ip, comp),
+            PacketSpec::TIPPGE(ip, comp) => self.tip_pge(ip, comp),
+            PacketSpec::TIPPGD(ip, comp) => self.tip_pgd(ip, comp),
+            PacketSpec::FUP(ip, comp) => self.fup(ip, comp),
+            PacketSpec::CYC(cycles) => self.cyc(cycles),
+            PacketSpec::PTW { payload, ip } => self.ptw(payload, ip),
+            PacketSpec::PIP { cr3, non_root } => self.pip(cr3, non_root),
+        }
+    }
+}
+
+/// A well-formed sequence of packets: it starts with a PSB+ sequence, every PSB packet starts a
+/// PSB+ sequence (holding at most a CBR packet), and every IP is compressed in a way that can be
+/// decompressed (see [IPCompression::can_encode]).
+///
+/// With the `arbitrary` feature, arbitrary sequences can be made for property-based tests (e.g.
+/// that parsing an encoded sequence gives back the IPs returned by [PacketSeq::ips]).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PacketSeq(pub Vec<PacketSpec>);
+
+impl PacketSeq {
+    /// Returns the encoded packets.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        for spec in &self.0 {
+            enc.spec(spec);
+        }
+        enc.into_bytes()
+    }
+
+    /// Returns the IPs of the IP packets, in order, as they should be decompressed: `None` for a
+    /// suppressed IP.
+    pub fn ips(&self) -> impl Iterator<Item = Option<u64>> + '_ {
+        self.0.iter().filter_map(|s| {
+            s.ip()
+                .map(|(ip, comp)| (comp != IPCompression::Suppressed).then_some(ip))
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls {
+    use super::{ExecMode, IPCompression, PacketSeq, PacketSpec, LONG_TNT_MAX};
+    use crate::pt::{Packet, PacketParser};
+    use alloc::vec::Vec;
+    use arbitrary::{Arbitrary, Error, Result, Unstructured};
+
+    impl<'a> Arbitrary<'a> for ExecMode {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            u.choose(&[Self::Bits16, Self::Bits32, Self::Bits64])
+                .copied()
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for IPCompression {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            u.choose(&[
+                Self::Suppressed,
+                Self::Update16,
+                Self::Update32,
+                Self::Sext48,
+                Self::Update48,
+                Self::Full,
+            ])
+            .copied()
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for PacketSpec {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=14)? {
+                0 => Self::PSB,
+                1 => Self::PSBEND,
+                2 => Self::CBR(u.arbitrary()?),
+                3 => Self::PAD,
+                4 => Self::OVF,
+                5 => Self::ModeExec(u.arbitrary()?),
+                6 => Self::ModeTSX {
+                    intx: u.arbitrary()?,
+                    abort: u.arbitrary()?,
+                },
+                7 => {
+                    // Up to three long TNT packets' worth.
+                    let n = u.int_in_range(1..=3 * LONG_TNT_MAX)?;
+                    Self::TNT((0..n).map(|_| u.arbitrary()).collect::<Result<_>>()?)
+                }
+                8 => Self::TIP(u.arbitrary()?, u.arbitrary()?),
+                9 => Self::TIPPGE(u.arbitrary()?, u.arbitrary()?),
+                10 => Self::TIPPGD(u.arbitrary()?, u.arbitrary()?),
+                11 => Self::FUP(u.arbitrary()?, u.arbitrary()?),
+                12 => Self::CYC(u.arbitrary()?),
+                13 => Self::PIP {
+                    // Only bits 51:5 of CR3 are encoded.
+                    cr3: u64::arbitrary(u)? & 0x000f_ffff_ffff_ffe0,
+                    non_root: u.arbitrary()?,
+                },
+                _ => Self::PTW {
+                    payload: u.arbitrary()?,
+                    ip: u.arbitrary()?,
+                },
+            })
+        }
+    }
+
+    /// Append a PSB+ sequence to `specs`.
+    fn psb_plus(u: &mut Unstructured<'_>, specs: &mut Vec<PacketSpec>) -> Result<()> {
+        specs.push(PacketSpec::PSB);
+        if u.arbitrary()? {
+            specs.push(PacketSpec::CBR(u.arbitrary()?));
+        }
+        specs.push(PacketSpec::PSBEND);
+        Ok(())
+    }
+
+    impl<'a> Arbitrary<'a> for PacketSeq {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let mut specs = Vec::new();
+            psb_plus(u, &mut specs)?;
+            let mut last = None;
+            for _ in 0..u.arbitrary_len::<PacketSpec>()? {
+                let mut spec = PacketSpec::arbitrary(u)?;
+                match &mut spec {
+                    PacketSpec::PSB => {
+                        // A PSB resets the last IP.
+                        psb_plus(u, &mut specs)?;
+                        last = None;
+                        continue;
+                    }
+                    // Only valid in PSB+.
+                    PacketSpec::PSBEND => continue,
+                    PacketSpec::TIP(ip, comp)
+                    | PacketSpec::TIPPGE(ip, comp)
+                    | PacketSpec::TIPPGD(ip, comp)
+                    | PacketSpec::FUP(ip, comp)
+                        if *comp != IPCompression::Suppressed =>
+                    {
+                        if !comp.can_encode(*ip, last) {
+                            *comp = IPCompression::Full;
+                        }
+                        last = Some(*ip);
+                    }
+                    _ => (),
+                }
+                specs.push(spec);
+            }
+            Ok(Self(specs))
+        }
+    }
+
+    /// An arbitrary packet is one of the packets of an arbitrary [PacketSeq], so its IP (if it
+    /// has one) can be decompressed.
+    impl<'a> Arbitrary<'a> for Packet {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let bytes = PacketSeq::arbitrary(u)?.encode();
+            let mut pkts = PacketParser::new(&bytes)
+                .collect::<core::result::Result<Vec<_>, _>>()
+                .map_err(|_| Error::IncorrectFormat)?;
+            let i = u.choose_index(pkts.len())?;
+            Ok(pkts.swap_remove(i))
+        }
+    }
+}
+
+/// Returns the payload of a TNT packet holding the branch decisions `taken`: the decisions, oldest
+/// in the most significant bit, below a stop bit.
+fn tnt_payload(taken: &[bool]) -> u64 {
+    taken.iter().fold(1, |bits, &t| bits << 1 | u64::from(t))
+}
+
+/// Append the bytes of `pkts` to `out`, each encoded as it was parsed.
+pub fn encode<'p>(pkts: impl IntoIterator<Item = &'p Packet>, out: &mut Vec<u8>) {
+    for pkt in pkts {
+        pkt.write(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Encoder, IPCompression, PacketSeq, PacketSpec};
+    use crate::pt::{ExecMode, Packet, PacketKind, PacketParser};
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    fn parse(bytes: &[u8]) -> Vec<Packet> {
+        PacketParser::new(bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    /// Check that every kind of packet is parsed as encoded, and that re-encoding the parsed
+    /// packets gives the same bytes.
+    #[test]
+    fn round_trip() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .cbr(0x20)
+            .psbend()
+            .mode_exec(ExecMode::Bits64)
+            .tip_pge(0x5555_5555_1234, IPCompression::Full)
+            .tnt(&[true, false, true])
+            .tnt(&[false; 20])
+            .tip(0x5555_5555_5678, IPCompression::Update16)
+            .tip(0x5555_0000_0001, IPCompression::Update32)
+            .tip(0x7fff_0000_0002, IPCompression::Sext48)
+            .fup(0x7fff_0000_0003, IPCompression::Update48)
+            .tip(0, IPCompression::Suppressed)
+            .cyc(0x11)
+            .cyc(0x1234_5678)
+            .pad()
+            .mode_tsx(true, false)
+            .ptw(0x1234, false)
+            .ptw(0x1_0000_0000, true)
+            .fup(0x7fff_0000_0004, IPCompression::Update16)
+            .ovf()
+            .pip(0x1_2345_6000, true)
+            .tip_pgd(0, IPCompression::Suppressed);
+        let pkts = parse(enc.bytes());
+        let dump = pkts.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            dump,
+            [
+                "psb",
+                "cbr        20",
+                "psbend",
+                "mode.exec  cs.l",
+                "tip.pge    6: 0000555555551234",
+                "tnt.8      !.!",
+                "tnt.64     ....................",
+                "tip        1: ????????????5678",
+                "tip        2: ????????00000001",
+                "tip        3: 00007fff00000002",
+                "fup        4: ????7fff00000003",
+                "tip        0: ????????????????",
+                "cyc        11",
+                "cyc        12345678",
+                "pad",
+                "mode.tsx   intx",
+                "ptw        0: 1234",
+                "ptw        1: 100000000, ip",
+                "fup        1: ????????????0004",
+                "ovf",
+                "pip        123456000, nr",
+                "tip.pgd    0: ????????????????",
+            ]
+        );
+        assert_eq!(pkts[8].target_ip(), Ok(Some(0x5555_0000_0001)));
+        assert_eq!(pkts[18].target_ip(), Ok(Some(0x7fff_0000_0004)));
+
+        let mut bytes = Vec::new();
+        encode(&pkts, &mut bytes);
+        assert_eq!(bytes, enc.bytes());
+        let mut again = Encoder::new();
+        for pkt in &pkts {
+            again.packet(pkt);
+        }
+        assert_eq!(again.bytes(), enc.bytes());
+        assert_eq!(again.last_ip(), enc.last_ip());
+    }
+
+    /// Check that each packet of every kind, including CYC packets with redundant extended bytes,
+    /// is written as exactly the bytes that it was parsed from.
+    #[test]
+    fn packet_bytes() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .cbr(0x7f)
+            .psbend()
+            .mode_exec(ExecMode::Bits32)
+            .tip_pge(0xffff_8000_0000_1234, IPCompression::Full)
+            .tnt(&[true, true, false, false, true, false])
+            .tnt(&[true; 47])
+            .tip(0x5678, IPCompression::Update16)
+            .tip(0xffff_ffff_9abc_def0, IPCompression::Update32)
+            .tip(0xffff_8000_0000_0002, IPCompression::Sext48)
+            .fup(0x7fff_0000_0003, IPCompression::Update48)
+            .fup(0, IPCompression::Suppressed)
+            .cyc(0)
+            .cyc(0x1f)
+            .cyc(u64::MAX >> 6)
+            .pad()
+            .mode_tsx(false, true)
+            .ptw(u64::from(u32::MAX), false)
+            .ptw(u64::MAX, true)
+            .ovf()
+            .pip(0x000f_ffff_ffff_ffe0, false)
+            .tip_pgd(0x1234, IPCompression::Update16);
+        let mut bytes = enc.bytes().to_vec();
+        // A CYC packet whose last extended byte adds no bits.
+        bytes.extend_from_slice(&[0x07, 0x03, 0x00]);
+        let mut parser = PacketParser::new(&bytes);
+        let mut start = 0;
+        let mut n = 0;
+        while let Some(pkt) = parser.next() {
+            let pkt = pkt.unwrap();
+            let end = parser.offset();
+            let mut out = Vec::new();
+            pkt.write(&mut out);
+            if n == 22 {
+                assert_eq!(out, [0x07, 0x02]);
+            } else {
+                assert_eq!(out, &bytes[start..end], "packet {}: {}", n, pkt);
+            }
+            start = end;
+            n += 1;
+        }
+        assert_eq!((n, start), (23, bytes.len()));
+    }
+
+    #[test]
+    fn tnt_splitting() {
+        let mut enc = Encoder::new();
+        enc.psb().psbend().tnt(&[true; 100]);
+        let pkts = parse(enc.bytes());
+        let n = pkts[2..]
+            .iter()
+            .map(|p| match p {
+                Packet::LongTNT(p) => 63 - p.branches().leading_zeros(),
+                _ => panic!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(n, [47, 47, 6]);
+    }
+
+    #[test]
+    fn smallest_compression() {
+        let last = Some(0x5555_5555_0000);
+        let smallest = |ip| IPCompression::smallest(ip, last);
+        assert_eq!(smallest(0x5555_5555_ffff), IPCompression::Update16);
+        assert_eq!(smallest(0x5555_0000_0000), IPCompression::Update32);
+        assert_eq!(smallest(0x1000_0000_0000), IPCompression::Sext48);
+        assert_eq!(smallest(0xffff_8000_0000_0000), IPCompression::Sext48);
+        assert_eq!(smallest(0x0000_8000_0000_0000), IPCompression::Update48);
+        assert_eq!(
+            IPCompression::smallest(0x0000_8000_0000_0000, None),
+            IPCompression::Full
+        );
+
+        // A PSB resets the last IP, so a compressed IP after one isn't the smallest.
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip(0x1234, IPCompression::Full)
+            .psb()
+            .psbend();
+        assert_eq!(enc.last_ip(), None);
+        assert_eq!(
+            IPCompression::smallest(0x1234, enc.last_ip()),
+            IPCompression::Sext48
+        );
+    }
+
+    /// Returns the IPs of the IP packets in `pkts`.
+    fn ips(pkts: &[Packet]) -> Vec<Option<u64>> {
+        pkts.iter()
+            .filter(|p| {
+                matches!(
+                    p.kind(),
+                    PacketKind::TIP | PacketKind::TIPPGE | PacketKind::TIPPGD | PacketKind::FUP
+                )
+            })
+            .map(|p| p.target_ip().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn packet_seq() {
+        let seq = PacketSeq(vec![
+            PacketSpec::PSB,
+            PacketSpec::PSBEND,
+            PacketSpec::TIPPGE(0x5555_5555_1234, IPCompression::Full),
+            PacketSpec::TNT(vec![true, false]),
+            PacketSpec::TIP(0, IPCompression::Suppressed),
+            PacketSpec::FUP(0x5555_5555_5678, IPCompression::Update16),
+        ]);
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x5555_5555_1234, IPCompression::Full)
+            .tnt(&[true, false])
+            .tip(0, IPCompression::Suppressed)
+            .fup(0x5555_5555_5678, IPCompression::Update16);
+        assert_eq!(seq.encode(), enc.bytes());
+        let expected = [Some(0x5555_5555_1234), None, Some(0x5555_5555_5678)];
+        assert!(seq.ips().eq(expected));
+        assert_eq!(ips(&parse(enc.bytes())), expected);
+    }
+
+    /// Check that arbitrary packet sequences parse, giving back the IPs in them, and round-trip.
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_seqs() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut data = vec![0; 4096];
+        for _ in 0..256 {
+            for b in &mut data {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *b = state as u8;
+            }
+            let mut u = Unstructured::new(&data);
+            let seq = PacketSeq::arbitrary(&mut u).unwrap();
+            let bytes = seq.encode();
+            let pkts = parse(&bytes);
+            assert_eq!(ips(&pkts), seq.ips().collect::<Vec<_>>());
+            let mut again = Vec::new();
+            encode(&pkts, &mut again);
+            assert_eq!(again, bytes);
+
+            let pkt = Packet::arbitrary(&mut u).unwrap();
+            pkt.target_ip().unwrap();
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! The Intel PT errata of particular CPU models, which decoders may need to work around.
+
+/// The Intel PT errata that affect a CPU, named after Intel's specification updates.
+///
+/// This mirrors libipt's `struct pt_errata`, and [Errata::for_model] mirrors `pt_cpu_errata()`, so
+/// that both decoders agree on which CPUs are affected. Most of the errata concern packets which
+/// only matter when walking instructions, so only some of them change how the Yk PT decoder parses
+/// a trace: each field says if it does.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Errata {
+    /// BDM70: PSB+ packets may contain unexpected FUP and MODE.Exec packets before a TIP.PGE.
+    pub bdm70: bool,
+    /// BDM64: an incorrect TIP may be recorded following a transactional abort.
+    pub bdm64: bool,
+    /// SKD007: an OVF packet may be issued after the first byte of a multi-byte CYC packet,
+    /// instead of its remaining bytes. Worked around by [super::PacketParser].
+    pub skd007: bool,
+    /// SKD022: a VM entry that disables tracing may generate a FUP before the TIP.PGD.
+    pub skd022: bool,
+    /// SKD010: the FUP that should follow an OVF packet may be dropped.
+    pub skd010: bool,
+    /// SKL014: a TIP.PGD caused by a direct branch may not have a target IP.
+    pub skl014: bool,
+    /// APL12: an OVF packet may be followed by a FUP while tracing is disabled.
+    pub apl12: bool,
+    /// APL11: an OVF packet may be followed by a TIP.PGD.
+    pub apl11: bool,
+    /// SKL168: CYC packets may be dropped when immediately preceding a PSB.
+    pub skl168: bool,
+}
+
+impl Errata {
+    /// Returns the errata of the Intel CPU with the given `family` and `model` (as reported by
+    /// `cpuid`, see [crate::CpuId]). Unknown CPUs have no errata.
+    pub fn for_model(family: u16, model: u8) -> Self {
+        let mut errata = Self::default();
+        if family != 0x6 {
+            return errata;
+        }
+        match model {
+            0x3d | 0x47 | 0x4f | 0x56 => {
+                errata.bdm70 = true;
+                errata.bdm64 = true;
+            }
+            0x4e | 0x5e | 0x8e | 0x9e | 0xa5 | 0xa6 => {
+                errata.bdm70 = true;
+                errata.skd007 = true;
+                errata.skd022 = true;
+                errata.skd010 = true;
+                errata.skl014 = true;
+                errata.skl168 = true;
+            }
+            0x55 | 0x66 | 0x7d | 0x7e | 0x6a | 0x6c | 0xa7 | 0x8c | 0x8d | 0x8f | 0x97 | 0x9a
+            | 0xb7 | 0xba | 0xbe | 0xbf => {
+                errata.bdm70 = true;
+                errata.skl014 = true;
+                errata.skd022 = true;
+            }
+            0x5c | 0x5f => {
+                errata.apl12 = true;
+                errata.apl11 = true;
+            }
+            0x7a | 0x86 | 0x96 | 0x9c => errata.apl11 = true,
+            _ => (),
+        }
+        errata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Errata;
+
+    #[test]
+    fn for_model() {
+        // Skylake.
+        let skl = Errata::for_model(0x6, 0x5e);
+        assert!(skl.skd007 && skl.bdm70 && !skl.apl11);
+        // Goldmont.
+        let glm = Errata::for_model(0x6, 0x5c);
+        assert!(glm.apl11 && glm.apl12 && !glm.skd007);
+        assert_eq!(Errata::for_model(0x6, 0x01), Errata::default());
+        assert_eq!(Errata::for_model(0xf, 0x5e), Errata::default());
+    }
+}
@@ -0,0 +1,1218 @@
+//! Parsing of Intel PT packets, and decompression of the IPs that they carry.
+//!
+//! This is the core of the Yk PT decoder. It only needs `core` and `alloc`, so unlike the rest of
+//! hwtracer, it is available in `no_std` builds (i.e. without the default `std` feature), where it
+//! can be used by e.g. bare-metal tools or kernels that collect their own traces.
+//!
+//! ```
+//! use hwtracer::pt::{Packet, PacketParser};
+//!
+//! // A PSB+ sequence (PSB and PSBEND packets), then a PAD packet.
+//! let mut bytes = b"\x02\x82".repeat(8);
+//! bytes.extend_from_slice(b"\x02\x23\x00");
+//! let pkts = PacketParser::new(&bytes)
+//!     .collect::<Result<Vec<_>, _>>()
+//!     .unwrap();
+//! assert!(matches!(pkts[..], [Packet::PSB(_), Packet::PSBEND(_), Packet::PAD(_)]));
+//! ```
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+use core::{fmt, iter::Iterator};
+use deku::{bitvec::BitSlice, DekuRead};
+
+pub mod encode;
+mod errata;
+mod packets;
+mod stats;
+pub use errata::Errata;
+pub use packets::*;
+pub use stats::{KindStats, PacketStats};
+
+/// The ways in which parsing packets can fail.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PacketError {
+    /// The bytes at the parser's position aren't a packet that may appear there.
+    Unparseable(String),
+    /// A compressed IP was encountered before any full IP was seen.
+    NoLastIP,
+    /// A packet used a reserved IP compression scheme.
+    ReservedIPBytes(u8),
+    /// A packet's IP payload didn't match its IP compression scheme.
+    IPBytesMismatch(u8),
+    /// More branch decisions were buffered than a [TNTBuffer] can hold.
+    TNTOverflow,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unparseable(msg) => write!(f, "{}", msg),
+            Self::NoLastIP => write!(f, "compressed IP with no last IP"),
+            Self::ReservedIPBytes(b) => write!(f, "reserved IP compression {:03b}", b),
+            Self::IPBytesMismatch(b) => {
+                write!(f, "IP payload doesn't match IP compression {:03b}", b)
+            }
+            Self::TNTOverflow => write!(f, "too many branch decisions buffered"),
+        }
+    }
+}
+
+/// The state of a [PacketParser], which decides which kinds of packet it accepts next.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PacketParserState {
+    /// Initial state, waiting for a PSB packet. Any bytes before it are skipped.
+    Init,
+    /// The "normal" decoding state.
+    Normal,
+    /// We are decoding a PSB+ sequence.
+    PSBPlus,
+}
+
+impl PacketParserState {
+    /// Returns `true` if packets of kind `kind` are valid in the state.
+    ///
+    /// The kind of the next packet is found from its opcode (see [packet_kind]) rather than by
+    /// trying to parse each valid kind in turn, so there is no order of kinds to tune to the
+    /// workload: every packet costs one opcode lookup and one parse, however common it is.
+    fn accepts(&self, kind: PacketKind) -> bool {
+        match self {
+            Self::Init => kind == PacketKind::PSB,
+            Self::Normal => kind != PacketKind::PSBEND,
+            Self::PSBPlus => matches!(kind, PacketKind::CBR | PacketKind::PIP | PacketKind::PSBEND),
+        }
+    }
+
+    /// Check if the parser needs to transition to a new state as a result of parsing a certain
+    /// kind of packet.
+    fn transition(&mut self, pkt_kind: PacketKind) {
+        let new = match (*self, pkt_kind) {
+            (Self::Init, PacketKind::PSB) => Self::PSBPlus,
+            (Self::Normal, PacketKind::PSB) => Self::PSBPlus,
+            (Self::PSBPlus, PacketKind::PSBEND) => Self::Normal,
+            _ => return, // No state transition.
+        };
+        *self = new;
+    }
+}
+
+/// The length, in bytes, of the longest packet in a well-formed trace (a PSB packet). See
+/// [PacketParser::partial].
+pub const MAX_PACKET_LEN: usize = 16;
+
+/// The bytes of a PSB packet.
+const PSB_BYTES: [u8; MAX_PACKET_LEN] = [
+    0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+];
+
+/// The state of a [PacketParser] between packets, saved by [PacketParser::checkpoint] so that
+/// parsing can carry on from it with [PacketParser::resume].
+#[derive(Clone, Copy, Debug)]
+pub struct ParserCheckpoint {
+    state: PacketParserState,
+    prev_tip: u64,
+    skip_pads: bool,
+    errata: Errata,
+    skipped: usize,
+}
+
+/// Parses a stream of Intel PT packets, starting with a PSB packet.
+///
+/// Parsing can't continue after an error.
+pub struct PacketParser<'t> {
+    /// The raw bytes of the PT trace we are iterating over.
+    bytes: &'t [u8],
+    /// The length of the trace, in bytes.
+    len: usize,
+    /// The parser operates as a state machine. This field keeps track of which state we are in.
+    state: PacketParserState,
+    /// The most recent Target IP (TIP) value that we've seen. This is needed because updated TIP
+    /// values are sometimes compressed using bits from the previous TIP value.
+    prev_tip: u64,
+    /// If true, runs of PAD packets are skipped rather than returned.
+    skip_pads: bool,
+    /// If true, `bytes` is only part of the trace, and parsing stops short of its end.
+    partial: bool,
+    /// The errata of the CPU that the trace was collected on, which parsing works around.
+    errata: Errata,
+    /// The number of bytes skipped to find the first PSB packet.
+    skipped: usize,
+}
+
+/// Attempt to read the packet of type `$packet` using deku. On success wrap the packet up into the
+/// corresponding discriminant of `Packet`.
+macro_rules! read_to_packet {
+    ($packet: ty, $bits: expr, $discr: expr) => {
+        <$packet>::read($bits, ()).and_then(|(r, p)| Ok((r, $discr(p))))
+    };
+}
+
+/// Same as `read_to_packet!`, but with extra logic for dealing with packets which encode a TIP.
+macro_rules! read_to_packet_tip {
+    ($packet: ty, $bits: expr, $discr: expr, $prev_tip: expr) => {
+        <$packet>::read($bits, ()).and_then(|(r, p)| {
+            let ret = if p.needs_prev_tip() {
+                Ok((r, $discr(p, Some($prev_tip))))
+            } else {
+                Ok((r, $discr(p, None)))
+            };
+            ret
+        })
+    };
+}
+
+impl<'t> PacketParser<'t> {
+    /// Make a parser for the packets in `bytes`. Anything before the first PSB packet is skipped
+    /// (see [PacketParser::skipped]): traces captured from a ring buffer, or a snapshot of one,
+    /// often start part way through a packet. If there's no PSB packet, parsing fails.
+    pub fn new(bytes: &'t [u8]) -> Self {
+        Self {
+            bytes,
+            len: bytes.len(),
+            state: PacketParserState::Init,
+            prev_tip: 0,
+            skip_pads: false,
+            errata: Errata::default(),
+            partial: false,
+            skipped: 0,
+        }
+    }
+
+    /// Like [PacketParser::new], but start parsing `start` bytes into `bytes`, which must be the
+    /// start of a packet (e.g. a PSB). Offsets are still relative to the start of `bytes`. If
+    /// `start` is beyond the end of `bytes`, there is nothing to parse.
+    pub fn new_at(bytes: &'t [u8], start: usize) -> Self {
+        Self {
+            bytes: bytes.get(start..).unwrap_or(&[]),
+            len: bytes.len(),
+            state: PacketParserState::Init,
+            prev_tip: 0,
+            skip_pads: false,
+            errata: Errata::default(),
+            partial: false,
+            skipped: 0,
+        }
+    }
+
+    /// Make a parser which carries on from `checkpoint` (see [PacketParser::checkpoint]), parsing
+    /// `bytes`, which hold the trace from `offset` bytes in onwards. Offsets are relative to the
+    /// start of the trace.
+    ///
+    /// This allows a trace to be parsed a piece at a time (e.g. as it is read from a file),
+    /// without the whole of it being in memory at once: see [PacketParser::partial].
+    pub fn resume(bytes: &'t [u8], offset: usize, checkpoint: ParserCheckpoint) -> Self {
+        Self {
+            bytes,
+            len: offset + bytes.len(),
+            state: checkpoint.state,
+            prev_tip: checkpoint.prev_tip,
+            skip_pads: checkpoint.skip_pads,
+            partial: false,
+            errata: checkpoint.errata,
+            skipped: checkpoint.skipped,
+        }
+    }
+
+    /// Save the parser's state, so that parsing can carry on from the next packet with
+    /// [PacketParser::resume].
+    pub fn checkpoint(&self) -> ParserCheckpoint {
+        ParserCheckpoint {
+            state: self.state,
+            prev_tip: self.prev_tip,
+            skip_pads: self.skip_pads,
+            errata: self.errata,
+            skipped: self.skipped,
+        }
+    }
+
+    /// Returns the number of bytes that were skipped to find the first PSB packet. Until one is
+    /// found, this is the number of bytes skipped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// If `partial` is true, the parser's bytes are only part of the trace, with more to follow.
+    /// Rather than parse a packet which may be cut short by the end of the bytes, the parser then
+    /// stops when fewer than [MAX_PACKET_LEN] bytes remain, leaving them to be parsed (once more
+    /// of the trace is at hand) by a parser made with [PacketParser::resume].
+    pub fn partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// If `skip` is true, skip over PAD packets rather than returning them.
+    ///
+    /// PADs carry no information, but traces often contain long runs of them (e.g. where the
+    /// hardware flushed a partly filled buffer). Skipping them checks a word of bytes at a time,
+    /// which is much faster than parsing each PAD.
+    pub fn skip_pads(mut self, skip: bool) -> Self {
+        self.skip_pads = skip;
+        self
+    }
+
+    /// Work around `errata`, those of the CPU that the trace was collected on (see
+    /// [Errata::for_model]). By default, no workarounds are applied.
+    ///
+    /// Only SKD007 affects parsing: a CYC packet cut short by an OVF packet is parsed as a one
+    /// byte CYC, followed by the OVF, rather than as a CYC which swallowed the OVF's first byte.
+    pub fn errata(mut self, errata: Errata) -> Self {
+        self.errata = errata;
+        self
+    }
+
+    /// Parse up to `n` packets into `buf`, replacing its contents, and return the number parsed.
+    /// Fewer than `n` are parsed only at the end of the trace, so a return value of zero means
+    /// that there are no more packets.
+    ///
+    /// This saves consumers which process packets in batches from going through the iterator for
+    /// each packet, and reuses `buf`'s allocation from one batch to the next.
+    ///
+    /// If a packet can't be parsed, the error is returned and `buf` holds the packets before it.
+    pub fn parse_into(&mut self, buf: &mut Vec<Packet>, n: usize) -> Result<usize, PacketError> {
+        buf.clear();
+        while buf.len() < n {
+            match self.next() {
+                Some(pkt) => buf.push(pkt?),
+                None => break,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Move the branch decisions of the short TNT packets at the parser's position into `tnt`,
+    /// stopping at the first other packet (PADs excepted, if they are being skipped), or when `tnt`
+    /// is full. Returns the number of packets consumed.
+    ///
+    /// Hot loops produce long runs of short TNT packets, which this consumes in a tight loop,
+    /// rather than parsing them one by one. Nothing is consumed outside of the normal state (e.g.
+    /// within PSB+).
+    pub fn tnt_run(&mut self, tnt: &mut TNTBuffer) -> usize {
+        if !matches!(self.state, PacketParserState::Normal) {
+            return 0;
+        }
+        let mut n = 0;
+        while let Some(&b) = self.bytes.first() {
+            if is_short_tnt(b) {
+                if tnt.push(u64::from(b >> 1)).is_err() {
+                    break;
+                }
+                n += 1;
+            } else if !(b == 0 && self.skip_pads) {
+                break;
+            }
+            self.bytes = &self.bytes[1..];
+        }
+        n
+    }
+
+    /// Returns the offset (in bytes) into the trace of the next packet to be parsed.
+    pub fn offset(&self) -> usize {
+        self.len - self.bytes.len()
+    }
+
+    /// Returns the parser's state, in which the next packet will be parsed.
+    pub fn state(&self) -> PacketParserState {
+        self.state
+    }
+
+    /// Parse the rest of the packets, adding them to `stats` (see [PacketStats]). PAD packets are
+    /// counted even if the parser skips them (see [PacketParser::skip_pads]). Statistics can be
+    /// gathered a piece at a time by passing the same `stats` to each parser made with
+    /// [PacketParser::resume].
+    ///
+    /// If a packet can't be parsed, the error is returned and `stats` counts the packets before
+    /// it, which is often what's wanted when investigating a bad trace.
+    pub fn stats(&mut self, stats: &mut PacketStats) -> Result<(), PacketError> {
+        let skip_pads = self.skip_pads;
+        self.skip_pads = false;
+        let res = self.count_into(stats);
+        self.skip_pads = skip_pads;
+        res
+    }
+
+    /// Parse the rest of the packets, adding them to `stats`.
+    fn count_into(&mut self, stats: &mut PacketStats) -> Result<(), PacketError> {
+        let mut start = self.offset();
+        let mut skipped = self.skipped;
+        while let Some(pkt) = self.next() {
+            // Until the first PSB packet is found, the bytes before it are skipped.
+            start += self.skipped - skipped;
+            stats.skipped += self.skipped - skipped;
+            skipped = self.skipped;
+            let pkt = pkt?;
+            let end = self.offset();
+            stats.add(&pkt, end - start);
+            start = end;
+        }
+        stats.skipped += self.skipped - skipped;
+        Ok(())
+    }
+
+    /// Attempt to parse a packet of the specified `PacketKind`.
+    fn parse_kind(&mut self, kind: PacketKind) -> Option<Packet> {
+        let bits = BitSlice::from_slice(self.bytes).ok()?;
+        let parse_res = match kind {
+            PacketKind::PSB => {
+                read_to_packet!(PSBPacket, bits, Packet::PSB)
+            }
+            PacketKind::CBR => read_to_packet!(CBRPacket, bits, Packet::CBR),
+            PacketKind::PSBEND => read_to_packet!(PSBENDPacket, bits, Packet::PSBEND),
+            PacketKind::PAD => read_to_packet!(PADPacket, bits, Packet::PAD),
+            PacketKind::MODE => read_to_packet!(MODEPacket, bits, Packet::MODE),
+            PacketKind::TIPPGE => {
+                read_to_packet_tip!(TIPPGEPacket, bits, Packet::TIPPGE, self.prev_tip)
+            }
+            PacketKind::TIPPGD => {
+                read_to_packet_tip!(TIPPGDPacket, bits, Packet::TIPPGD, self.prev_tip)
+            }
+            PacketKind::ShortTNT => read_to_packet!(ShortTNTPacket, bits, Packet::ShortTNT),
+            PacketKind::LongTNT => read_to_packet!(LongTNTPacket, bits, Packet::LongTNT),
+            PacketKind::TIP => read_to_packet_tip!(TIPPacket, bits, Packet::TIP, self.prev_tip),
+            PacketKind::FUP => read_to_packet_tip!(FUPPacket, bits, Packet::FUP, self.prev_tip),
+            PacketKind::CYC if self.errata.skd007 && is_skd007(self.bytes) => {
+                let header = self.bytes[0];
+                self.bytes = &self.bytes[1..];
+                return Some(Packet::CYC(CYCPacket::from_header(header)));
+            }
+            PacketKind::CYC => read_to_packet!(CYCPacket, bits, Packet::CYC),
+            PacketKind::OVF => read_to_packet!(OVFPacket, bits, Packet::OVF),
+            PacketKind::PTW => read_to_packet!(PTWPacket, bits, Packet::PTW),
+            PacketKind::PIP => read_to_packet!(PIPPacket, bits, Packet::PIP),
+        };
+        if let Ok((remain, pkt)) = parse_res {
+            self.bytes = remain.as_raw_slice();
+            Some(pkt)
+        } else {
+            None
+        }
+    }
+
+    /// Attempt to parse a packet for the current parser state.
+    ///
+    /// The kind of packet is worked out from its opcode, so that only one attempt is made to parse
+    /// it: failed attempts are slow, and make deku allocate an error.
+    fn parse_state(&mut self) -> Result<Packet, PacketError> {
+        if let Some(kind) = packet_kind(self.bytes) {
+            if self.state.accepts(kind) {
+                if let Some(pkt) = self.parse_kind(kind) {
+                    if kind == PacketKind::PSBEND {
+                        self.state = PacketParserState::Normal;
+                    }
+                    return Ok(pkt);
+                }
+            }
+        }
+        Err(PacketError::Unparseable(format!(
+            "In state {:?}, failed to parse packet: {}",
+            self.state,
+            self.byte_stream_str(8, ", ")
+        )))
+    }
+
+    /// Returns a string showing a binary formatted peek at the next `nbytes` bytes of
+    /// `self.bytes`. Bytes in the output are separated by `sep`.
+    ///
+    /// This is used to format error messages, but is also useful when debugging.
+    fn byte_stream_str(&self, nbytes: usize, sep: &str) -> String {
+        use core::cmp::min;
+        let nbytes = min(nbytes, self.bytes.len());
+        let mut vals = Vec::new();
+        for i in 0..nbytes {
+            vals.push(format!("{:08b}", self.bytes[i]));
+        }
+
+        if self.bytes.len() > nbytes {
+            vals.push("...".to_owned());
+        }
+
+        format!("{}", vals.join(sep))
+    }
+
+    /// Skip to the first PSB packet, counting the bytes skipped. Returns `false` if there's no PSB
+    /// packet in the bytes of a partial parser, in which case all but the bytes which might be the
+    /// start of one (cut short by the end of the bytes) are skipped, and parsing must resume with
+    /// more of the trace. If there's no PSB packet in a whole trace, nothing is skipped, so that
+    /// parsing fails where the trace starts.
+    fn sync(&mut self) -> bool {
+        match self
+            .bytes
+            .windows(PSB_BYTES.len())
+            .position(|w| w == PSB_BYTES)
+        {
+            Some(off) => {
+                self.skipped += off;
+                self.bytes = &self.bytes[off..];
+                true
+            }
+            None if self.partial => {
+                let off = self.bytes.len().saturating_sub(PSB_BYTES.len() - 1);
+                self.skipped += off;
+                self.bytes = &self.bytes[off..];
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Attempt to parse a packet.
+    fn parse_packet(&mut self) -> Result<Packet, PacketError> {
+        // Attempt to parse a packet.
+        let pkt = self.parse_state()?;
+
+        // If the packet contains an updated TIP, then cache it. Note that out of context packets
+        // don't affect the cached value.
+        if let Some(tip) = pkt.target_ip()? {
+            self.prev_tip = tip;
+        }
+
+        // See if the packet we just parsed triggers a state transition.
+        self.state.transition(pkt.kind());
+
+        Ok(pkt)
+    }
+}
+
+/// Work out the kind of the packet at the start of `bytes` from its opcode, without checking the
+/// rest of the packet. Returns `None` if the opcode isn't that of a kind of packet that can be
+/// parsed.
+fn packet_kind(bytes: &[u8]) -> Option<PacketKind> {
+    let kind = match *bytes.first()? {
+        0x00 => PacketKind::PAD,
+        0x02 => match *bytes.get(1)? {
+            0x82 => PacketKind::PSB,
+            0x23 => PacketKind::PSBEND,
+            0x03 => PacketKind::CBR,
+            0xa3 => PacketKind::LongTNT,
+            0xf3 => PacketKind::OVF,
+            0x43 => PacketKind::PIP,
+            b if b & 0x1f == 0x12 => PacketKind::PTW,
+            _ => return None,
+        },
+        0x99 => PacketKind::MODE,
+        // The IP packets have a 5-bit opcode below a 3-bit IP compression field.
+        b if b & 0x1f == 0x0d => PacketKind::TIP,
+        b if b & 0x1f == 0x11 => PacketKind::TIPPGE,
+        b if b & 0x1f == 0x01 => PacketKind::TIPPGD,
+        b if b & 0x1f == 0x1d => PacketKind::FUP,
+        b if b & 0x03 == 0x03 => PacketKind::CYC,
+        b if b & 0x01 == 0x00 => PacketKind::ShortTNT,
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Returns `true` if `bytes` start with a CYC packet cut short by an OVF packet (erratum SKD007):
+/// a CYC whose `Exp` bit says that extended bytes follow, but whose "extended" byte is the start of
+/// an OVF.
+fn is_skd007(bytes: &[u8]) -> bool {
+    matches!(bytes, [cyc, 0x02, 0xf3, ..] if cyc & 0x4 != 0)
+}
+
+/// Returns `true` if `b` is a short TNT packet (as opposed to a PAD, or the first byte of a longer
+/// packet). Short TNT packets are the only packets with an even opcode other than 0 or 2.
+fn is_short_tnt(b: u8) -> bool {
+    b & 0x1 == 0 && b > 0x2
+}
+
+impl<'t> Iterator for PacketParser<'t> {
+    type Item = Result<Packet, PacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if matches!(self.state, PacketParserState::Init) && !self.sync() {
+            return None;
+        }
+        let normal = matches!(self.state, PacketParserState::Normal);
+        if self.skip_pads && normal {
+            self.bytes = &self.bytes[pad_run(self.bytes)..];
+        }
+        // Short TNTs are by far the most common packets in traces of hot loops. They are only one
+        // byte long, and change neither the state nor the last IP, so are dealt with first, without
+        // going through the state machine or deku.
+        if let (true, Some(&b)) = (normal, self.bytes.first()) {
+            if is_short_tnt(b) {
+                self.bytes = &self.bytes[1..];
+                return Some(Ok(Packet::ShortTNT(ShortTNTPacket::from_header(b))));
+            }
+        }
+        if self.partial && self.bytes.len() < MAX_PACKET_LEN {
+            None
+        } else if !self.bytes.is_empty() {
+            Some(self.parse_packet())
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the number of PAD (i.e. zero) bytes at the start of `bytes`.
+fn pad_run(bytes: &[u8]) -> usize {
+    // Check a word at a time until one has a non-zero byte, then find it.
+    let words = bytes
+        .chunks_exact(8)
+        .take_while(|w| u64::from_ne_bytes([w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7]]) == 0)
+        .count();
+    let rest = &bytes[words * 8..];
+    words * 8 + rest.iter().position(|&b| b != 0).unwrap_or(rest.len())
+}
+
+/// A queue of the branch decisions from TNT packets which are waiting to be consumed by a decoder,
+/// oldest first.
+///
+/// The decisions are stored as bits in a fixed-capacity buffer, rather than in a heap-allocated
+/// vector per packet, so buffering them costs the same however TNT-heavy a trace is. A decoder
+/// consumes a packet's decisions before the next TNT packet can be meaningful, so the capacity
+/// (more than two long TNT packets' worth) is only exceeded if the trace doesn't match the code
+/// being walked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TNTBuffer {
+    /// The buffered decisions, the newest in the least significant bit. A set bit is a taken
+    /// branch.
+    bits: u128,
+    /// The number of buffered decisions.
+    len: u32,
+}
+
+impl TNTBuffer {
+    /// The maximum number of decisions that can be buffered.
+    pub const CAPACITY: u32 = u128::BITS;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of buffered decisions.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns `true` if no decisions are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append the decisions encoded in a TNT packet's payload, as returned by
+    /// [ShortTNTPacket::branches] or [LongTNTPacket::branches].
+    ///
+    /// If they don't fit, an error is returned and the buffer is unchanged.
+    pub fn push(&mut self, branches: u64) -> Result<(), PacketError> {
+        // The decisions are below the stop bit.
+        let n = (u64::BITS - branches.leading_zeros()).saturating_sub(1);
+        if self.len + n > Self::CAPACITY {
+            return Err(PacketError::TNTOverflow);
+        }
+        if n > 0 {
+            self.bits = self.bits << n | u128::from(branches & ((1 << n) - 1));
+            self.len += n;
+        }
+        Ok(())
+    }
+
+    /// Remove and return the oldest decision (`true` for a taken branch), if there is one.
+    pub fn pop(&mut self) -> Option<bool> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.bits >> self.len & 1 == 1)
+    }
+
+    /// Discard all of the buffered decisions (e.g. after an overflow, when they are stale).
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode::{Encoder, IPCompression},
+        packets::*,
+        Errata, KindStats, PacketError, PacketParser, PacketStats, TNTBuffer,
+    };
+    #[cfg(feature = "collect")]
+    use crate::testing::{collector, trace_closure, work_loop};
+
+    /// Parse the packets of a small trace, checking the basic structure of the decoded trace.
+    #[cfg(feature = "collect")]
+    #[test]
+    fn parse_small_trace() {
+        let Some(tc) = collector() else {
+            return;
+        };
+        let trace = trace_closure(&tc, || work_loop(3)).unwrap();
+
+        #[derive(Clone, Copy, Debug)]
+        enum TestState {
+            /// Start here.
+            Init,
+            /// Saw the start of the PSB+ sequence.
+            SawPSBPlusStart,
+            /// Saw the end of the PSB+ sequence.
+            SawPSBPlusEnd,
+            /// Saw the packet generation enable packet.
+            SawPacketGenEnable,
+            /// Saw a TNT packet.
+            SawTNT,
+            /// Saw the packet generation disable packet.
+            SawPacketGenDisable,
+        }
+
+        let mut ts = TestState::Init;
+        for pkt in PacketParser::new(trace.bytes()) {
+            dbg!(&ts, &pkt);
+            ts = match (ts, pkt.unwrap().kind()) {
+                (TestState::Init, PacketKind::PSB) => TestState::SawPSBPlusStart,
+                (TestState::SawPSBPlusStart, PacketKind::PSBEND) => TestState::SawPSBPlusEnd,
+                (TestState::SawPSBPlusEnd, PacketKind::TIPPGE) => TestState::SawPacketGenEnable,
+                (TestState::SawPacketGenEnable, PacketKind::ShortTNT)
+                | (TestState::SawPacketGenEnable, PacketKind::LongTNT) => TestState::SawTNT,
+                (TestState::SawTNT, PacketKind::TIPPGD) => TestState::SawPacketGenDisable,
+                (ts, _) => ts,
+            };
+        }
+        assert!(matches!(ts, TestState::SawPacketGenDisable));
+    }
+
+    /// Check that an out of context TIP doesn't disturb the last IP used for decompression.
+    #[test]
+    fn out_of_context_keeps_last_ip() {
+        #[rustfmt::skip]
+        let bytes = [
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // TIP, out of context.
+            0x0d,
+            // TIP with a 16-bit compressed IP.
+            0x2d, 0x00, 0x20,
+        ];
+        let pkts = PacketParser::new(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pkts.len(), 5);
+        assert!(pkts[3].ip_suppressed());
+        assert_eq!(pkts[3].target_ip().unwrap(), None);
+        assert!(!pkts[4].ip_suppressed());
+        assert_eq!(pkts[4].target_ip().unwrap(), Some(0x555512342000));
+    }
+
+    /// Check that a TIP.PGD with a suppressed IP doesn't update the last IP.
+    #[test]
+    fn tippgd_suppressed_ip() {
+        #[rustfmt::skip]
+        let bytes = [
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+            // TIP.PGE with an uncompressed IP.
+            0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+            // TIP.PGD with no IP.
+            0x01,
+            // TIP.PGE with a 16-bit compressed IP.
+            0x31, 0x00, 0x30,
+        ];
+        let pkts = PacketParser::new(&bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pkts.len(), 5);
+        assert_eq!(pkts[3].kind(), PacketKind::TIPPGD);
+        assert!(pkts[3].ip_suppressed());
+        assert_eq!(pkts[3].target_ip().unwrap(), None);
+        assert_eq!(pkts[4].target_ip().unwrap(), Some(0x555512343000));
+    }
+
+    /// Test target IP decompression when the `IPBytes = 0b000`.
+    #[test]
+    fn ipbytes_decompress_000() {
+        let ipbytes0 = IPBytes::new(0b000);
+        assert_eq!(
+            TargetIP::from_bits(0, 0)
+                .decompress(ipbytes0, Some(0xdeafcafedeadcafe))
+                .unwrap(),
+            None
+        );
+    }
+
+    /// Test target IP decompression when the `IPBytes = 0b001`.
+    #[test]
+    fn ipbytes_decompress_001() {
+        let ipb = IPBytes::new(0b001);
+        assert_eq!(
+            TargetIP::from_bits(16, 0x000000000000cccc)
+                .decompress(ipb, Some(0xa1a2a3a4a5a69999))
+                .unwrap(),
+            Some(0xa1a2a3a4a5a6cccc)
+        );
+    }
+
+    /// Test target IP decompression when the `IPBytes = 0b010`.
+    #[test]
+    fn ipbytes_decompress_010() {
+        let ipb = IPBytes::new(0b010);
+        assert_eq!(
+            TargetIP::from_bits(32, 0x00000000bbbbbbbb)
+                .decompress(ipb, Some(0xcccccccc99999999))
+                .unwrap(),
+            Some(0xccccccccbbbbbbbb)
+        );
+    }
+
+    /// Test target IP decompression when the `IPBytes = 0b011`.
+    #[test]
+    fn ipbytes_decompress_011() {
+        let ipb = IPBytes::new(0b011);
+
+        // Bit 47 zero-extend.
+        assert_eq!(
+            TargetIP::from_bits(48, 0).decompress(ipb, None).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            TargetIP::from_bits(48, 0x0000010203040506)
+                .decompress(ipb, None)
+                .unwrap(),
+            Some(0x0000010203040506)
+        );
+
+        // Bit 47 one-extend.
+        assert_eq!(
+            TargetIP::from_bits(48, 1 << 47)
+                .decompress(ipb, None)
+                .unwrap(),
+            Some(0xffff800000000000)
+        );
+        assert_eq!(
+            TargetIP::from_bits(48, 0x0000887766554433)
+                .decompress(ipb, None)
+                .unwrap(),
+            Some(0xffff887766554433)
+        );
+    }
+
+    /// Test target IP decompression when the `IPBytes = 0b100`.
+    #[test]
+    fn ipbytes_decompress_100() {
+        let ipb = IPBytes::new(0b100);
+        assert_eq!(
+            TargetIP::from_bits(48, 0x0000bbbbbbbbbbbb)
+                .decompress(ipb, Some(0xcccc999999999999))
+                .unwrap(),
+            Some(0xccccbbbbbbbbbbbb)
+        );
+    }
+
+    /// Check that reserved `IPBytes` values are reported as errors rather than panicking.
+    #[test]
+    fn ipbytes_decompress_reserved() {
+        for val in [0b101, 0b111] {
+            assert!(matches!(
+                TargetIP::from_bits(64, 0).decompress(IPBytes::new(val), Some(0)),
+                Err(PacketError::ReservedIPBytes(v)) if v == val
+            ));
+        }
+    }
+
+    /// Check that an IP payload whose width doesn't match `IPBytes` is reported as an error.
+    #[test]
+    fn ipbytes_decompress_mismatch() {
+        assert!(matches!(
+            TargetIP::from_bits(16, 0).decompress(IPBytes::new(0b110), Some(0)),
+            Err(PacketError::IPBytesMismatch(0b110))
+        ));
+    }
+
+    /// Check that decompressing relative to a missing last IP is an error.
+    #[test]
+    fn ipbytes_decompress_no_last_ip() {
+        assert!(matches!(
+            TargetIP::from_bits(16, 0).decompress(IPBytes::new(0b001), None),
+            Err(PacketError::NoLastIP)
+        ));
+    }
+
+    /// Counts the heap allocations made by each thread.
+    #[cfg(feature = "std")]
+    mod alloc_counter {
+        use std::{
+            alloc::{GlobalAlloc, Layout, System},
+            cell::Cell,
+        };
+
+        thread_local! {
+            static ALLOCS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        struct Counter;
+
+        unsafe impl GlobalAlloc for Counter {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = ALLOCS.try_with(|n| n.set(n.get() + 1));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        #[global_allocator]
+        static COUNTER: Counter = Counter;
+
+        /// Returns the number of heap allocations made by this thread so far.
+        pub(super) fn allocs() -> usize {
+            ALLOCS.with(|n| n.get())
+        }
+    }
+
+    /// A packet stream with one of each kind of packet that turns up in the bulk of a trace.
+    #[rustfmt::skip]
+    const MIXED: [u8; 46] = [
+        // PSB+
+        0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+        0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+        0x02, 0x23,
+        // TIP.PGE with an uncompressed IP.
+        0xd1, 0x78, 0x56, 0x34, 0x12, 0x55, 0x55, 0x00, 0x00,
+        // Short TNT, PAD, long TNT.
+        0x06, 0x00, 0x02, 0xa3, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // A CYC with two extended bytes.
+        0x0f, 0x03, 0x04,
+        // TIP and FUP with compressed IPs.
+        0x2d, 0x00, 0x20,
+        0x3d, 0x00, 0x10,
+    ];
+
+    /// Check that the extended bytes of a CYC packet are parsed.
+    #[test]
+    fn cyc_extended() {
+        let pkts = PacketParser::new(&MIXED)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pkts.len(), 9);
+        assert_eq!(pkts[6].kind(), PacketKind::CYC);
+        assert_eq!(pkts[6].to_string(), "cyc        2021");
+        assert_eq!(pkts[7].target_ip().unwrap(), Some(0x555512342000));
+    }
+
+    /// Check that a trace parsed a piece at a time, resuming from checkpoints, gives the same
+    /// packets (with the same offsets and IPs) as one parsed in one go.
+    #[test]
+    fn resume() {
+        let bytes: &[u8] = &MIXED;
+        let whole = PacketParser::new(bytes)
+            .map(|p| {
+                let p = p.unwrap();
+                (p.to_string(), p.target_ip().unwrap())
+            })
+            .collect::<Vec<_>>();
+        for piece in 1..bytes.len() {
+            let mut parser = PacketParser::new(&[]);
+            let (mut start, mut end) = (0, 0);
+            let mut pkts = Vec::new();
+            while end < bytes.len() {
+                end = (end + piece).min(bytes.len());
+                let checkpoint = parser.checkpoint();
+                parser = PacketParser::resume(&bytes[start..end], start, checkpoint)
+                    .partial(end < bytes.len());
+                for pkt in &mut parser {
+                    let pkt = pkt.unwrap();
+                    pkts.push((pkt.to_string(), pkt.target_ip().unwrap()));
+                }
+                start = parser.offset();
+            }
+            assert_eq!(pkts, whole, "in pieces of {} bytes", piece);
+        }
+    }
+
+    #[test]
+    fn tnt_run() {
+        #[rustfmt::skip]
+        let mut bytes = vec![
+            // PSB+
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x23,
+        ];
+        // Short TNTs of two decisions each (not taken then taken), with a PAD between two of them.
+        bytes.extend_from_slice(&[0x0a; 70]);
+        bytes.insert(20, 0x00);
+        // A long TNT.
+        bytes.extend_from_slice(&[0x02, 0xa3, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let mut tnt = TNTBuffer::new();
+
+        // Nothing is consumed in PSB+.
+        let mut parser = PacketParser::new(&bytes);
+        assert!(matches!(parser.next(), Some(Ok(Packet::PSB(_)))));
+        assert_eq!(parser.tnt_run(&mut tnt), 0);
+        assert!(matches!(parser.next(), Some(Ok(Packet::PSBEND(_)))));
+
+        // PADs stop a run, unless they are being skipped.
+        let mut no_skip = PacketParser::resume(&bytes[18..], 18, parser.checkpoint());
+        assert_eq!(no_skip.tnt_run(&mut tnt), 2);
+        tnt.clear();
+
+        // A run stops when the buffer is full, and can then carry on.
+        let mut parser = parser.skip_pads(true);
+        assert_eq!(parser.tnt_run(&mut tnt), 64);
+        assert_eq!(tnt.len(), 128);
+        while let Some(taken) = tnt.pop() {
+            assert_eq!(taken, tnt.len() % 2 == 0);
+        }
+        assert_eq!(parser.tnt_run(&mut tnt), 6);
+        assert!(matches!(parser.next(), Some(Ok(Packet::LongTNT(_)))));
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn parse_into() {
+        let whole = PacketParser::new(&MIXED)
+            .map(|p| p.unwrap().to_string())
+            .collect::<Vec<_>>();
+        let mut parser = PacketParser::new(&MIXED);
+        let mut buf = Vec::new();
+        let mut pkts = Vec::new();
+        for expected in [4, 4, 1, 0, 0] {
+            assert_eq!(parser.parse_into(&mut buf, 4).unwrap(), expected);
+            assert_eq!(buf.len(), expected);
+            pkts.extend(buf.iter().map(|p| p.to_string()));
+        }
+        assert_eq!(pkts, whole);
+
+        // The packets before an error are kept.
+        let mut bytes = MIXED.to_vec();
+        bytes.extend_from_slice(&[0x02, 0xff]);
+        let mut parser = PacketParser::new(&bytes);
+        assert_eq!(parser.parse_into(&mut buf, 8).unwrap(), 8);
+        assert!(matches!(
+            parser.parse_into(&mut buf, 8),
+            Err(PacketError::Unparseable(_))
+        ));
+        assert_eq!(buf.len(), 1);
+    }
+
+    /// Check that the parser skips to the first PSB packet, whether or not it has the whole trace.
+    #[test]
+    fn sync() {
+        // The end of a packet, and what looks like the start of a PSB.
+        let mut bytes = vec![0x56, 0x34, 0x12, 0x02, 0x82, 0x02];
+        bytes.extend_from_slice(&b"\x02\x82".repeat(8));
+        bytes.extend_from_slice(b"\x02\x23\x00");
+        let mut parser = PacketParser::new(&bytes);
+        assert!(matches!(parser.next(), Some(Ok(Packet::PSB(_)))));
+        assert_eq!(parser.skipped(), 6);
+        assert_eq!(parser.by_ref().count(), 2);
+
+        // A partial parser skips what it can, then resumes where it left off.
+        let mut parser = PacketParser::new(&bytes[..12]).partial(true);
+        assert!(parser.next().is_none());
+        let off = parser.offset();
+        assert_eq!((off, parser.skipped()), (0, 0));
+        let mut parser = PacketParser::resume(&bytes[off..], off, parser.checkpoint());
+        assert!(matches!(parser.next(), Some(Ok(Packet::PSB(_)))));
+        assert_eq!(parser.skipped(), 6);
+
+        let mut parser = PacketParser::new(&bytes[..20]).partial(true);
+        assert!(parser.next().is_none());
+        let off = parser.offset();
+        assert_eq!((off, parser.skipped()), (5, 5));
+        let mut parser = PacketParser::resume(&bytes[off..], off, parser.checkpoint());
+        assert!(matches!(parser.next(), Some(Ok(Packet::PSB(_)))));
+        assert_eq!((parser.offset(), parser.skipped()), (22, 6));
+        assert_eq!(parser.map(Result::unwrap).count(), 2);
+    }
+
+    /// Check that a CYC packet cut short by an OVF packet is only split from it when working around
+    /// SKD007.
+    #[test]
+    fn skd007() {
+        let mut bytes = b"\x02\x82".repeat(8);
+        // PSBEND, then a CYC whose extended bytes were replaced by an OVF.
+        bytes.extend_from_slice(b"\x02\x23\x0f\x02\xf3");
+        let kinds = |errata| {
+            PacketParser::new(&bytes)
+                .errata(errata)
+                .map(|p| p.unwrap().kind())
+                .collect::<Vec<_>>()
+        };
+        let skl = Errata::for_model(0x6, 0x5e);
+        assert_eq!(kinds(skl)[2..], [PacketKind::CYC, PacketKind::OVF]);
+        assert_eq!(
+            kinds(Errata::default())[2..],
+            [PacketKind::CYC, PacketKind::CYC]
+        );
+    }
+
+    /// Check that packets are only accepted where they may appear.
+    #[test]
+    fn out_of_place() {
+        let psb = b"\x02\x82".repeat(8);
+        for rest in [&b"\x00"[..], b"\x02\x23\x02\x23"] {
+            let mut bytes = psb.clone();
+            bytes.extend_from_slice(rest);
+            assert!(matches!(
+                PacketParser::new(&bytes).find(Result::is_err),
+                Some(Err(PacketError::Unparseable(_)))
+            ));
+        }
+        // Nothing before the first PSB is accepted.
+        assert!(matches!(
+            PacketParser::new(&[0x00]).next(),
+            Some(Err(PacketError::Unparseable(_)))
+        ));
+    }
+
+    /// Check that a PIP packet is parsed both inside a PSB+ sequence (where it reports the current
+    /// CR3) and outside it (where it reports a change of CR3).
+    #[test]
+    fn pip() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .pip(0x1000, false)
+            .psbend()
+            .pip(0x7_ffff_ffe0, false);
+        let pkts = PacketParser::new(enc.bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let cr3s = pkts
+            .iter()
+            .filter_map(|p| match p {
+                Packet::PIP(p) => Some(p.cr3()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cr3s, [0x1000, 0x7_ffff_ffe0]);
+    }
+
+    /// Check that packets are counted by kind, with the bytes skipped before the first PSB and PADs
+    /// (even if they are being skipped) accounted for.
+    #[test]
+    fn stats() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .cbr(0x20)
+            .psbend()
+            .tip_pge(0x1234, IPCompression::Full)
+            .tnt(&[true, false, true])
+            .pad()
+            .pad()
+            .tnt(&[true; 10])
+            .tip(0x5678, IPCompression::Update16)
+            .psb()
+            .psbend();
+        let mut bytes = vec![0xff; 3];
+        bytes.extend_from_slice(enc.bytes());
+        let mut stats = PacketStats::new();
+        let mut parser = PacketParser::new(&bytes).skip_pads(true);
+        parser.stats(&mut stats).unwrap();
+        let kb = |count, bytes| KindStats { count, bytes };
+        assert_eq!(stats.kind(PacketKind::PSB), kb(2, 32));
+        assert_eq!(stats.kind(PacketKind::PSBEND), kb(2, 4));
+        assert_eq!(stats.kind(PacketKind::CBR), kb(1, 4));
+        assert_eq!(stats.kind(PacketKind::TIPPGE), kb(1, 9));
+        assert_eq!(stats.kind(PacketKind::TIP), kb(1, 3));
+        assert_eq!(stats.kind(PacketKind::PAD), kb(2, 2));
+        assert_eq!(stats.kind(PacketKind::ShortTNT), kb(1, 1));
+        assert_eq!(stats.kind(PacketKind::LongTNT), kb(1, 8));
+        assert_eq!(stats.kind(PacketKind::FUP), kb(0, 0));
+        assert_eq!((stats.psbs(), stats.tnt_bits, stats.skipped), (2, 13, 3));
+        assert_eq!(stats.packets(), 11);
+        assert_eq!(stats.bytes(), bytes.len() - 3);
+        assert_eq!(stats.iter().map(|(_, s)| s.count).sum::<usize>(), 11);
+
+        // The packets before a bad one are counted.
+        bytes.extend_from_slice(&[0x02, 0xff]);
+        let mut bad = PacketStats::new();
+        assert!(PacketParser::new(&bytes).stats(&mut bad).is_err());
+        assert_eq!(bad, stats);
+    }
+
+    #[test]
+    fn pad_run() {
+        for len in 0..20 {
+            let mut bytes = vec![0; len];
+            assert_eq!(super::pad_run(&bytes), len);
+            bytes.push(0x06);
+            bytes.push(0x00);
+            assert_eq!(super::pad_run(&bytes), len);
+        }
+    }
+
+    /// Check that runs of PADs are skipped if asked, and only outside of PSB+.
+    #[test]
+    fn skip_pads() {
+        let mut bytes = MIXED.to_vec();
+        bytes.extend([0; 100]);
+        // Short TNT.
+        bytes.push(0x06);
+        bytes.extend([0; 3]);
+        let kinds = |skip| {
+            PacketParser::new(&bytes)
+                .skip_pads(skip)
+                .map(|p| p.unwrap().kind())
+                .collect::<Vec<_>>()
+        };
+        let all = kinds(false);
+        assert_eq!(all.len(), 9 + 100 + 1 + 3);
+        assert_eq!(
+            kinds(true),
+            all.into_iter()
+                .filter(|&k| k != PacketKind::PAD)
+                .collect::<Vec<_>>()
+        );
+
+        // A PAD inside PSB+ is still an error.
+        let mut bytes = b"\x02\x82".repeat(8);
+        bytes.push(0x00);
+        assert!(PacketParser::new(&bytes)
+            .skip_pads(true)
+            .any(|p| p.is_err()));
+    }
+
+    #[test]
+    fn tnt_buffer() {
+        let mut tnt = TNTBuffer::new();
+        // Not taken, then taken, below the stop bit.
+        tnt.push(0b101).unwrap();
+        // No decisions.
+        tnt.push(0b1).unwrap();
+        tnt.push(0).unwrap();
+        assert_eq!(tnt.len(), 2);
+        assert_eq!(tnt.pop(), Some(false));
+        assert_eq!(tnt.pop(), Some(true));
+        assert_eq!(tnt.pop(), None);
+
+        // Long TNTs, of 47 decisions each, alternating between taken and not taken.
+        let long = 1 << 47 | 0x2aaa_aaaa_aaaa;
+        tnt.push(long).unwrap();
+        tnt.push(long).unwrap();
+        assert_eq!(tnt.len(), 94);
+        assert_eq!(tnt.push(long), Err(PacketError::TNTOverflow));
+        assert_eq!(tnt.len(), 94);
+        for i in 0..94 {
+            assert_eq!(tnt.pop(), Some(i % 47 % 2 == 1));
+        }
+        assert!(tnt.is_empty());
+
+        tnt.push(long).unwrap();
+        tnt.clear();
+        assert_eq!(tnt.pop(), None);
+    }
+
+    /// Check that iterating over packets doesn't allocate.
+    #[cfg(feature = "std")]
+    #[test]
+    fn no_allocations() {
+        let before = alloc_counter::allocs();
+        let mut n = 0;
+        for pkt in PacketParser::new(&MIXED) {
+            pkt.unwrap();
+            n += 1;
+        }
+        assert_eq!(alloc_counter::allocs(), before);
+        assert_eq!(n, 9);
+    }
+}
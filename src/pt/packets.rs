@@ -0,0 +1,774 @@
+//! Intel PT packets and their constituents.
+
+use super::PacketError;
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+use core::fmt;
+use deku::{
+    bitvec::{BitSlice, BitVec, Msb0},
+    prelude::*,
+};
+
+/// The `IPBytes` field common to all IP packets.
+///
+/// This tells us what kind of compression was used for a `TargetIP`.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct IPBytes {
+    val: u8,
+}
+
+impl IPBytes {
+    /// Extract the field from the top 3 bits of an IP packet's first byte.
+    fn from_header(header: u8) -> Self {
+        Self { val: header >> 5 }
+    }
+
+    #[cfg(test)]
+    pub(super) fn new(val: u8) -> Self {
+        debug_assert!(val >> 3 == 0);
+        Self { val }
+    }
+
+    /// Returns `true` if we need the previous TIP value to make sense of the new one.
+    pub(super) fn needs_prev_tip(&self) -> bool {
+        match self.val {
+            0b001 | 0b010 | 0b100 => true,
+            _ => false,
+        }
+    }
+}
+
+/// The `TargetIP` fields in packets which update the TIP.
+///
+/// This is a variable-width field depending upon the value if `IPBytes` in the containing packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(id = "ip_bytes_val", ctx = "ip_bytes_val: u8")]
+pub(super) enum TargetIP {
+    #[deku(id = "0b000")]
+    OutOfContext,
+    #[deku(id = "0b001")]
+    Ip16(u16),
+    #[deku(id = "0b010")]
+    Ip32(u32),
+    #[deku(id_pat = "0b011 | 0b100")]
+    Ip48(#[deku(bytes = "6")] u64),
+    #[deku(id = "0b110")]
+    Ip64(u64),
+}
+
+impl TargetIP {
+    #[cfg(test)]
+    pub(super) fn from_bits(bits: u8, val: u64) -> Self {
+        use core::convert::TryFrom;
+        match bits {
+            0 => Self::OutOfContext,
+            16 => Self::Ip16(u16::try_from(val).unwrap()),
+            32 => Self::Ip32(u32::try_from(val).unwrap()),
+            48 => Self::Ip48(val),
+            64 => Self::Ip64(val),
+            _ => panic!(),
+        }
+    }
+
+    /// Format the IP payload as `ptdump` does: the compression scheme, then the IP bits carried by
+    /// the packet, with `?` standing in for bits that must be taken from the last IP.
+    fn ptdump(&self, ip_bytes: IPBytes) -> String {
+        let ip = match self {
+            Self::OutOfContext => "????????????????".to_owned(),
+            Self::Ip16(v) => format!("????????????{:04x}", v),
+            Self::Ip32(v) => format!("????????{:08x}", v),
+            Self::Ip48(v) if ip_bytes.val == 0b011 => {
+                // Sign extended from bit 47.
+                format!("{:016x}", ((v << 16) as i64 >> 16) as u64)
+            }
+            Self::Ip48(v) => format!("????{:012x}", v),
+            Self::Ip64(v) => format!("{:016x}", v),
+        };
+        format!("{:x}: {}", ip_bytes.val, ip)
+    }
+
+    /// Decompress a `TargetIP` and `IPBytes` pair into an instruction pointer address.
+    ///
+    /// Returns `None` if the target IP was "out of context". This happens when the IP isn't
+    /// reported by the hardware (e.g. because control was transferred outside of the traced
+    /// context). An out of context IP carries no information about the new IP and must not be
+    /// used to update the "last IP" used to decompress later IPs.
+    ///
+    /// Returns an error if the compressed IP needs a previous IP and there isn't one, or if
+    /// `ip_bytes` is reserved or doesn't match the width of the payload.
+    pub(super) fn decompress(
+        &self,
+        ip_bytes: IPBytes,
+        prev_tip: Option<u64>,
+    ) -> Result<Option<u64>, PacketError> {
+        let prev_tip = || prev_tip.ok_or(PacketError::NoLastIP);
+        let res = match (ip_bytes.val, self) {
+            (0b000, Self::OutOfContext) => return Ok(None),
+            (0b001, Self::Ip16(v)) => {
+                // The result is bytes 63..=16 from `prev_tip` and bytes 15..=0 from `ip`.
+                prev_tip()? & 0xffffffffffff0000 | u64::from(*v)
+            }
+            (0b010, Self::Ip32(v)) => {
+                // The result is bytes 63..=32 from `prev_tip` and bytes 31..=0 from `ip`.
+                prev_tip()? & 0xffffffff00000000 | u64::from(*v)
+            }
+            (0b011, Self::Ip48(v)) => {
+                // The result is bits 0..=47 from the IP, with the remaining high-order bits
+                // extended with the value of bit 47.
+                debug_assert!(v >> 48 == 0);
+                // Extract the value of bit 47.
+                let b47 = (v & (1 << 47)) >> 47;
+                // Copy the value of bit 47 across all 64 bits.
+                let all = u64::wrapping_sub(!b47 & 0x1, 1);
+                // Restore bits 47..=0 to arrive at the result.
+                all & 0xffff000000000000 | v
+            }
+            (0b100, Self::Ip48(v)) => {
+                // The result is bits 63..=48 from `prev_tip` and bits 47..=0 from `ip`.
+                debug_assert!(v >> 48 == 0);
+                prev_tip()? & 0xffff000000000000 | *v
+            }
+            (0b110, Self::Ip64(v)) => {
+                // Uncompressed IP.
+                *v
+            }
+            (0b101, _) | (0b111, _) => {
+                // Reserved by Intel.
+                return Err(PacketError::ReservedIPBytes(ip_bytes.val));
+            }
+            _ => {
+                // The payload doesn't have the width implied by `ip_bytes`.
+                return Err(PacketError::IPBytesMismatch(ip_bytes.val));
+            }
+        };
+        Ok(Some(res))
+    }
+}
+
+/// Packet Stream Boundary (PSB) packet.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(magic = b"\x02\x82\x02\x82\x02\x82\x02\x82\x02\x82\x02\x82\x02\x82\x02\x82")]
+pub struct PSBPacket {}
+
+/// Core Bus Ratio (CBR) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\x03")]
+pub struct CBRPacket {
+    /// The new core:bus ratio.
+    pub ratio: u8,
+    /// Reserved. Kept so that the packet is re-encoded as it was parsed.
+    reserved: u8,
+}
+
+/// End of PSB+ sequence (PSBEND) packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(magic = b"\x02\x23")]
+pub struct PSBENDPacket {}
+
+/// Padding (PAD) packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(magic = b"\x00")]
+pub struct PADPacket {}
+
+/// An x86 execution mode, as reported by a `MODE.Exec` packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExecMode {
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+/// The transactional state reported by a `MODE.TSX` packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TsxState {
+    /// Not in a transaction. Reported when a transaction commits, and in PSB+.
+    Outside,
+    /// In a transaction. Reported when a transaction begins, and in PSB+.
+    Inside,
+    /// The transaction aborted.
+    Aborted,
+}
+
+/// Mode (MODE.*) packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(magic = b"\x99")]
+pub struct MODEPacket {
+    /// The leaf ID, which identifies which kind of `MODE.*` packet this is, in the top 3 bits,
+    /// then the mode bits, the meaning of which depend upon the leaf ID.
+    payload: u8,
+}
+
+impl MODEPacket {
+    fn leaf_id(&self) -> u8 {
+        self.payload >> 5
+    }
+
+    fn mode(&self) -> u8 {
+        self.payload & 0x1f
+    }
+
+    /// If this is a `MODE.Exec` packet, return the execution mode that it indicates.
+    pub fn exec_mode(&self) -> Option<ExecMode> {
+        if self.leaf_id() != 0b000 {
+            return None;
+        }
+        // Bit 0 is `CS.L` and bit 1 is `CS.D`.
+        match (self.mode() & 0b1 != 0, self.mode() & 0b10 != 0) {
+            (true, _) => Some(ExecMode::Bits64),
+            (false, true) => Some(ExecMode::Bits32),
+            (false, false) => Some(ExecMode::Bits16),
+        }
+    }
+
+    /// Returns the packet's leaf ID, which identifies the kind of mode that it reports.
+    pub fn leaf(&self) -> u8 {
+        self.leaf_id()
+    }
+
+    /// Returns `true` if this is a `MODE.TSX` packet.
+    pub fn is_tsx(&self) -> bool {
+        self.leaf_id() == 0b001
+    }
+
+    /// If this is a `MODE.TSX` packet, return the transactional state that it indicates.
+    pub fn tsx_state(&self) -> Option<TsxState> {
+        if !self.is_tsx() {
+            return None;
+        }
+        // Bit 0 is `InTX` and bit 1 is `TXAbort`.
+        match (self.mode() & 0b1 != 0, self.mode() & 0b10 != 0) {
+            (_, true) => Some(TsxState::Aborted),
+            (true, false) => Some(TsxState::Inside),
+            (false, false) => Some(TsxState::Outside),
+        }
+    }
+
+    /// Returns the `ptdump` name and payload of the packet.
+    fn ptdump(&self) -> (&'static str, String) {
+        let (name, bits): (_, &[_]) = match self.leaf_id() {
+            0b000 => ("mode.exec", &["cs.l", "cs.d"]),
+            0b001 => ("mode.tsx", &["intx", "abrt"]),
+            _ => return ("mode", format!("{:x}: {:x}", self.leaf_id(), self.mode())),
+        };
+        let set = bits
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.mode() & (1 << i) != 0)
+            .map(|(_, b)| *b)
+            .collect::<Vec<_>>();
+        (name, set.join(", "))
+    }
+}
+
+/// Overflow (OVF) packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(magic = b"\x02\xf3")]
+pub struct OVFPacket {}
+
+/// The payload of a `PTWPacket`.
+///
+/// This is a variable-width field depending upon the `PayloadBytes` field of the packet.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(id = "payload_bytes", ctx = "payload_bytes: u8")]
+pub(super) enum PTWPayload {
+    #[deku(id = "0b00")]
+    Bits32(u32),
+    #[deku(id = "0b01")]
+    Bits64(u64),
+}
+
+/// PTWRITE (PTW) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+#[deku(magic = b"\x02")]
+pub struct PTWPacket {
+    /// The IP bit, which when set means that a FUP packet containing the address of the
+    /// `PTWRITE` instruction follows, then the 2-bit `PayloadBytes` field, then the opcode.
+    #[deku(assert = "*header & 0x1f == 0b10010")]
+    header: u8,
+    #[deku(ctx = "(*header >> 5) & 0b11")]
+    payload: PTWPayload,
+}
+
+/// Paging Information Packet (PIP), reporting a write to the CR3 register: i.e. a switch of address
+/// space, which usually means that a different process is now running.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(magic = b"\x02\x43")]
+pub struct PIPPacket {
+    /// The NR ("non-root", i.e. in a VMX guest) bit, then bits 51:5 of the new CR3 value.
+    #[deku(bytes = "6")]
+    payload: u64,
+}
+
+impl PIPPacket {
+    /// Returns the new value of the CR3 register, which identifies the address space.
+    pub fn cr3(&self) -> u64 {
+        (self.payload >> 1) << 5
+    }
+
+    /// Returns `true` if the CR3 write happened in a VMX non-root operation (i.e. in a guest).
+    pub fn non_root(&self) -> bool {
+        self.payload & 0x1 != 0
+    }
+}
+
+impl PTWPacket {
+    /// Returns the value written by the `PTWRITE` instruction.
+    pub fn payload(&self) -> u64 {
+        match self.payload {
+            PTWPayload::Bits32(v) => u64::from(v),
+            PTWPayload::Bits64(v) => v,
+        }
+    }
+
+    /// Returns `true` if a FUP packet carrying the IP of the `PTWRITE` instruction follows.
+    pub fn has_ip(&self) -> bool {
+        self.header & 0x80 != 0
+    }
+
+    /// Returns the value of the packet's `PayloadBytes` field.
+    fn payload_bytes(&self) -> u8 {
+        match self.payload {
+            PTWPayload::Bits32(_) => 0b00,
+            PTWPayload::Bits64(_) => 0b01,
+        }
+    }
+}
+
+/// Packet Generation Enable (TIP.PGE) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct TIPPGEPacket {
+    /// The `IPBytes` field in the top 3 bits, then the opcode.
+    #[deku(assert = "*header & 0x1f == 0x11")]
+    header: u8,
+    #[deku(ctx = "*header >> 5")]
+    target_ip: TargetIP,
+}
+
+impl TIPPGEPacket {
+    fn ip_bytes(&self) -> IPBytes {
+        IPBytes::from_header(self.header)
+    }
+
+    fn target_ip(&self, prev_tip: Option<u64>) -> Result<Option<u64>, PacketError> {
+        self.target_ip.decompress(self.ip_bytes(), prev_tip)
+    }
+
+    pub(super) fn needs_prev_tip(&self) -> bool {
+        self.ip_bytes().needs_prev_tip()
+    }
+}
+
+/// Short Taken/Not-Taken (TNT) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct ShortTNTPacket {
+    /// Bits encoding the branch decisions **and** a stop bit, above a clear low bit.
+    ///
+    /// The deku assertion here is subtle: we know that the top 7 bits must contain a stop bit
+    /// terminating the field, but if the stop bit appears in place of the first branch, then this
+    /// is not a short TNT packet at all; it's a long TNT packet (or, with no stop bit, a PAD).
+    #[deku(assert = "*header & 0x1 == 0 && *header >> 1 > 0x1")]
+    header: u8,
+}
+
+impl ShortTNTPacket {
+    /// Make the packet whose only byte is `header`, which must be that of a short TNT packet.
+    pub(super) fn from_header(header: u8) -> Self {
+        Self { header }
+    }
+
+    /// Returns the packet's branch decisions, oldest first, in the bits below the highest set bit
+    /// (the stop bit). A set bit is a taken branch.
+    pub fn branches(&self) -> u64 {
+        u64::from(self.header >> 1)
+    }
+}
+
+/// Long Taken/Not-Taken (TNT) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\xa3")]
+pub struct LongTNTPacket {
+    /// Bits encoding the branch decisions **and** a stop bit.
+    #[deku(bytes = "6")]
+    branches: u64,
+}
+
+impl LongTNTPacket {
+    /// Returns the packet's branch decisions, encoded as by [ShortTNTPacket::branches].
+    pub fn branches(&self) -> u64 {
+        self.branches
+    }
+}
+
+/// Format the branch decisions encoded in the payload of a TNT packet as `ptdump` does: `!` for a
+/// taken branch and `.` for a not-taken one, oldest first.
+///
+/// The decisions occupy the bits below the highest set bit (the stop bit), the oldest being the
+/// most significant.
+fn tnt_ptdump(branches: u64) -> String {
+    let n = (64 - branches.leading_zeros()).saturating_sub(1);
+    (0..n)
+        .rev()
+        .map(|i| if branches & (1 << i) != 0 { '!' } else { '.' })
+        .collect()
+}
+
+/// Target IP (TIP) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct TIPPacket {
+    /// The `IPBytes` field in the top 3 bits, then the opcode.
+    #[deku(assert = "*header & 0x1f == 0x0d")]
+    header: u8,
+    #[deku(ctx = "*header >> 5")]
+    target_ip: TargetIP,
+}
+
+impl TIPPacket {
+    fn ip_bytes(&self) -> IPBytes {
+        IPBytes::from_header(self.header)
+    }
+
+    fn target_ip(&self, prev_tip: Option<u64>) -> Result<Option<u64>, PacketError> {
+        self.target_ip.decompress(self.ip_bytes(), prev_tip)
+    }
+
+    pub(super) fn needs_prev_tip(&self) -> bool {
+        self.ip_bytes().needs_prev_tip()
+    }
+}
+
+/// Packet Generation Disable (TIP.PGD) packet.
+///
+/// The target IP of this packet is frequently suppressed (`IPBytes == 0b000`), meaning that
+/// tracing stopped and the destination is unknown. In that case the packet is an end of traced
+/// region marker only, and must not be treated as an IP update.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct TIPPGDPacket {
+    /// The `IPBytes` field in the top 3 bits, then the opcode.
+    #[deku(assert = "*header & 0x1f == 0x1")]
+    header: u8,
+    #[deku(ctx = "*header >> 5")]
+    target_ip: TargetIP,
+}
+
+impl TIPPGDPacket {
+    fn ip_bytes(&self) -> IPBytes {
+        IPBytes::from_header(self.header)
+    }
+
+    fn target_ip(&self, prev_tip: Option<u64>) -> Result<Option<u64>, PacketError> {
+        self.target_ip.decompress(self.ip_bytes(), prev_tip)
+    }
+
+    pub(super) fn needs_prev_tip(&self) -> bool {
+        self.ip_bytes().needs_prev_tip()
+    }
+}
+
+/// Flow Update (FUP) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct FUPPacket {
+    /// The `IPBytes` field in the top 3 bits, then the opcode.
+    #[deku(assert = "*header & 0x1f == 0b11101")]
+    header: u8,
+    #[deku(ctx = "*header >> 5")]
+    target_ip: TargetIP,
+}
+
+impl FUPPacket {
+    fn ip_bytes(&self) -> IPBytes {
+        IPBytes::from_header(self.header)
+    }
+
+    fn target_ip(&self, prev_tip: Option<u64>) -> Result<Option<u64>, PacketError> {
+        self.target_ip.decompress(self.ip_bytes(), prev_tip)
+    }
+
+    pub(super) fn needs_prev_tip(&self) -> bool {
+        self.ip_bytes().needs_prev_tip()
+    }
+}
+
+/// Cycle count (CYC) packet.
+#[deku_derive(DekuRead, DekuWrite)]
+#[derive(Debug)]
+pub struct CYCPacket {
+    /// The low 5 bits of the cycle counter, then the `Exp` bit, which is set if extended bytes
+    /// follow, then the opcode.
+    #[deku(assert = "*header & 0x3 == 0b11")]
+    header: u8,
+    /// A CYC packet is variable length and has 0 or more "extended" bytes, each holding 7 more
+    /// (higher) bits of the cycle counter. This holds those bits, which are read without
+    /// allocating.
+    #[deku(
+        reader = "CYCPacket::read_extended(deku::rest, *header & 0x4 != 0)",
+        writer = "CYCPacket::write_extended(deku::output, *header & 0x4 != 0, *extended)"
+    )]
+    extended: u64,
+}
+
+impl CYCPacket {
+    /// Make the one byte packet whose first byte is `header`, which must be that of a CYC packet,
+    /// ignoring its `Exp` bit (see [super::Errata::skd007]).
+    pub(super) fn from_header(header: u8) -> Self {
+        Self {
+            header: header & !0x4,
+            extended: 0,
+        }
+    }
+
+    /// Returns the cycle counter value carried by the packet.
+    fn cycles(&self) -> u64 {
+        u64::from(self.header >> 3) | self.extended << 5
+    }
+
+    /// Read the extended bytes of a CYC packet, if `exp` says that there are any, returning the
+    /// bits of the cycle counter that they hold. The last extended byte has its low bit clear.
+    /// Bits beyond the 64th are discarded.
+    fn read_extended(
+        mut rest: &BitSlice<Msb0, u8>,
+        exp: bool,
+    ) -> Result<(&BitSlice<Msb0, u8>, u64), DekuError> {
+        let mut bits = 0;
+        if exp {
+            let mut shift = 0u32;
+            loop {
+                let (r, e) = u8::read(rest, ())?;
+                rest = r;
+                bits |= u64::from(e >> 1).checked_shl(shift).unwrap_or(0);
+                if e & 0x01 != 0x01 {
+                    break;
+                }
+                // However many extended bytes there are, the shift mustn't overflow.
+                shift = shift.saturating_add(7);
+            }
+        }
+        Ok((rest, bits))
+    }
+
+    /// Write the extended bytes holding the bits `ext` of the cycle counter, if `exp` says that
+    /// there are any, in as few bytes as possible.
+    fn write_extended(
+        output: &mut BitVec<Msb0, u8>,
+        exp: bool,
+        mut ext: u64,
+    ) -> Result<(), DekuError> {
+        if exp {
+            // Each extended byte holds 7 bits, with the low bit set if another follows.
+            loop {
+                let more = ext >> 7 != 0;
+                ((ext as u8) << 1 | u8::from(more)).write(output, ())?;
+                ext >>= 7;
+                if !more {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of packet that can be parsed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketKind {
+    PSB,
+    CBR,
+    PSBEND,
+    PAD,
+    MODE,
+    TIPPGE,
+    TIPPGD,
+    ShortTNT,
+    LongTNT,
+    TIP,
+    FUP,
+    CYC,
+    OVF,
+    PTW,
+    PIP,
+}
+
+impl PacketKind {
+    /// Every kind of packet, in the order that they are declared.
+    pub const ALL: [PacketKind; 15] = [
+        Self::PSB,
+        Self::CBR,
+        Self::PSBEND,
+        Self::PAD,
+        Self::MODE,
+        Self::TIPPGE,
+        Self::TIPPGD,
+        Self::ShortTNT,
+        Self::LongTNT,
+        Self::TIP,
+        Self::FUP,
+        Self::CYC,
+        Self::OVF,
+        Self::PTW,
+        Self::PIP,
+    ];
+}
+
+/// The top-level representation of an Intel Processor Trace packet.
+///
+/// Variants with an `Option<u64>` may cache the previous TIP value (at the time the packet was
+/// created). This may be needed to get the updated TIP value from the packet.
+#[derive(Debug)]
+pub enum Packet {
+    PSB(PSBPacket),
+    CBR(CBRPacket),
+    PSBEND(PSBENDPacket),
+    PAD(PADPacket),
+    MODE(MODEPacket),
+    TIPPGE(TIPPGEPacket, Option<u64>),
+    TIPPGD(TIPPGDPacket, Option<u64>),
+    ShortTNT(ShortTNTPacket),
+    LongTNT(LongTNTPacket),
+    TIP(TIPPacket, Option<u64>),
+    FUP(FUPPacket, Option<u64>),
+    CYC(CYCPacket),
+    OVF(OVFPacket),
+    PTW(PTWPacket),
+    PIP(PIPPacket),
+}
+
+impl Packet {
+    /// Returns `true` if the packet is an IP packet whose IP was suppressed (i.e. it was "out of
+    /// context").
+    pub fn ip_suppressed(&self) -> bool {
+        match self {
+            Self::TIPPGE(p, _) => p.ip_bytes().val == 0,
+            Self::TIPPGD(p, _) => p.ip_bytes().val == 0,
+            Self::TIP(p, _) => p.ip_bytes().val == 0,
+            Self::FUP(p, _) => p.ip_bytes().val == 0,
+            _ => false,
+        }
+    }
+
+    /// If the packet contains a TIP update, return the IP value.
+    ///
+    /// An error is returned if the IP can't be decompressed.
+    pub fn target_ip(&self) -> Result<Option<u64>, PacketError> {
+        match self {
+            Self::TIPPGE(p, prev_tip) => p.target_ip(*prev_tip),
+            Self::TIPPGD(p, prev_tip) => p.target_ip(*prev_tip),
+            Self::TIP(p, prev_tip) => p.target_ip(*prev_tip),
+            Self::FUP(p, prev_tip) => p.target_ip(*prev_tip),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the number of branch decisions in a TNT packet, or zero for any other packet.
+    pub fn num_branches(&self) -> usize {
+        let branches = match self {
+            Self::ShortTNT(p) => p.branches(),
+            Self::LongTNT(p) => p.branches(),
+            _ => return 0,
+        };
+        // The decisions are below the stop bit.
+        (u64::BITS - branches.leading_zeros()).saturating_sub(1) as usize
+    }
+
+    /// Returns the packet's name and payload, as `ptdump` would show them.
+    fn ptdump(&self) -> (&'static str, String) {
+        match self {
+            Self::PSB(_) => ("psb", String::new()),
+            Self::CBR(p) => ("cbr", format!("{:x}", p.ratio)),
+            Self::PSBEND(_) => ("psbend", String::new()),
+            Self::PAD(_) => ("pad", String::new()),
+            Self::MODE(p) => p.ptdump(),
+            Self::TIPPGE(p, _) => ("tip.pge", p.target_ip.ptdump(p.ip_bytes())),
+            Self::TIPPGD(p, _) => ("tip.pgd", p.target_ip.ptdump(p.ip_bytes())),
+            Self::ShortTNT(p) => ("tnt.8", tnt_ptdump(p.branches())),
+            Self::LongTNT(p) => ("tnt.64", tnt_ptdump(p.branches)),
+            Self::TIP(p, _) => ("tip", p.target_ip.ptdump(p.ip_bytes())),
+            Self::FUP(p, _) => ("fup", p.target_ip.ptdump(p.ip_bytes())),
+            Self::CYC(p) => ("cyc", format!("{:x}", p.cycles())),
+            Self::OVF(_) => ("ovf", String::new()),
+            Self::PTW(p) => (
+                "ptw",
+                format!(
+                    "{:x}: {:x}{}",
+                    p.payload_bytes(),
+                    p.payload(),
+                    if p.has_ip() { ", ip" } else { "" }
+                ),
+            ),
+            Self::PIP(p) => (
+                "pip",
+                format!("{:x}{}", p.cr3(), if p.non_root() { ", nr" } else { "" }),
+            ),
+        }
+    }
+
+    /// Append the packet's bytes to `out`. The packet is encoded as it was parsed (e.g. with the
+    /// same IP compression), so the bytes are those parsed, unless they were a CYC packet with
+    /// redundant extended bytes.
+    ///
+    /// Packets are written by deku from the same field declarations that they are read with, so
+    /// that reading and writing can't disagree on the width of a field.
+    pub(super) fn write(&self, out: &mut Vec<u8>) {
+        let mut bits = BitVec::new();
+        let res = match self {
+            Self::PSB(p) => p.write(&mut bits, ()),
+            Self::CBR(p) => p.write(&mut bits, ()),
+            Self::PSBEND(p) => p.write(&mut bits, ()),
+            Self::PAD(p) => p.write(&mut bits, ()),
+            Self::MODE(p) => p.write(&mut bits, ()),
+            Self::TIPPGE(p, _) => p.write(&mut bits, ()),
+            Self::TIPPGD(p, _) => p.write(&mut bits, ()),
+            Self::ShortTNT(p) => p.write(&mut bits, ()),
+            Self::LongTNT(p) => p.write(&mut bits, ()),
+            Self::TIP(p, _) => p.write(&mut bits, ()),
+            Self::FUP(p, _) => p.write(&mut bits, ()),
+            Self::CYC(p) => p.write(&mut bits, ()),
+            Self::OVF(p) => p.write(&mut bits, ()),
+            Self::PTW(p) => p.write(&mut bits, ()),
+            Self::PIP(p) => p.write(&mut bits, ()),
+        };
+        // Packets can only be made by parsing them, so their fields are always valid.
+        res.expect("parsed packet can't be written");
+        out.extend_from_slice(bits.as_raw_slice());
+    }
+
+    /// Returns the kind of the packet.
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            Self::PSB(_) => PacketKind::PSB,
+            Self::CBR(_) => PacketKind::CBR,
+            Self::PSBEND(_) => PacketKind::PSBEND,
+            Self::PAD(_) => PacketKind::PAD,
+            Self::MODE(_) => PacketKind::MODE,
+            Self::TIPPGE(..) => PacketKind::TIPPGE,
+            Self::TIPPGD(..) => PacketKind::TIPPGD,
+            Self::ShortTNT(_) => PacketKind::ShortTNT,
+            Self::LongTNT(_) => PacketKind::LongTNT,
+            Self::TIP(..) => PacketKind::TIP,
+            Self::FUP(..) => PacketKind::FUP,
+            Self::CYC(_) => PacketKind::CYC,
+            Self::OVF(_) => PacketKind::OVF,
+            Self::PTW(_) => PacketKind::PTW,
+            Self::PIP(_) => PacketKind::PIP,
+        }
+    }
+}
+
+impl fmt::Display for Packet {
+    /// Formats the packet as libipt's `ptdump` tool does, so that packet dumps made with
+    /// hwtracer can be compared with `ptdump`'s.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, payload) = self.ptdump();
+        if payload.is_empty() {
+            write!(f, "{}", name)
+        } else {
+            write!(f, "{:<11}{}", name, payload)
+        }
+    }
+}
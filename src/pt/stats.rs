@@ -0,0 +1,94 @@
+//! Summary statistics over the packets of a trace, for investigating its size or the cost of
+//! decoding it.
+
+use super::{Packet, PacketKind};
+use core::fmt;
+
+/// The number of packets of one kind, and the bytes that they take up.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KindStats {
+    /// The number of packets.
+    pub count: usize,
+    /// The total length of the packets, in bytes.
+    pub bytes: usize,
+}
+
+/// Counts of the packets in a trace, by kind. Made by [super::PacketParser::stats], or by
+/// [PacketStats::add]ing packets one at a time.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PacketStats {
+    /// Indexed by `PacketKind as usize`.
+    kinds: [KindStats; PacketKind::ALL.len()],
+    /// The number of bytes skipped to find the first PSB packet.
+    pub skipped: usize,
+    /// The number of branch decisions in TNT packets.
+    pub tnt_bits: usize,
+}
+
+impl PacketStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `pkt`, which took up `len` bytes of the trace.
+    pub fn add(&mut self, pkt: &Packet, len: usize) {
+        let kind = &mut self.kinds[pkt.kind() as usize];
+        kind.count += 1;
+        kind.bytes += len;
+        self.tnt_bits += pkt.num_branches();
+    }
+
+    /// Returns the counts of packets of kind `kind`.
+    pub fn kind(&self, kind: PacketKind) -> KindStats {
+        self.kinds[kind as usize]
+    }
+
+    /// Iterate over the counts of each kind of packet, including those that weren't seen, in the
+    /// order of [PacketKind::ALL].
+    pub fn iter(&self) -> impl Iterator<Item = (PacketKind, KindStats)> + '_ {
+        PacketKind::ALL.iter().map(move |&k| (k, self.kind(k)))
+    }
+
+    /// Returns the total number of packets.
+    pub fn packets(&self) -> usize {
+        self.kinds.iter().map(|k| k.count).sum()
+    }
+
+    /// Returns the total length of the packets, in bytes. Bytes skipped to find the first PSB
+    /// packet aren't included.
+    pub fn bytes(&self) -> usize {
+        self.kinds.iter().map(|k| k.bytes).sum()
+    }
+
+    /// Returns the number of PSB packets, i.e. of points at which decoding can (re)start.
+    pub fn psbs(&self) -> usize {
+        self.kind(PacketKind::PSB).count
+    }
+}
+
+impl fmt::Display for PacketStats {
+    /// Formats the statistics as a table with a row for each kind of packet seen, giving its count,
+    /// bytes, and share of the trace's bytes, followed by the totals.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.bytes();
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>14} {:>7}",
+            "kind", "count", "bytes", "%bytes"
+        )?;
+        for (kind, stats) in self.iter().filter(|(_, s)| s.count != 0) {
+            writeln!(
+                f,
+                "{:<10} {:>12} {:>14} {:>6.2}%",
+                // `PacketKind`'s `Debug` doesn't support padding.
+                alloc::format!("{:?}", kind),
+                stats.count,
+                stats.bytes,
+                stats.bytes as f64 * 100.0 / total as f64
+            )?;
+        }
+        writeln!(f, "{:<10} {:>12} {:>14}", "total", self.packets(), total)?;
+        writeln!(f, "skipped bytes: {}", self.skipped)?;
+        write!(f, "TNT bits: {}", self.tnt_bits)
+    }
+}
@@ -0,0 +1,170 @@
+//! Python bindings, so that traces can be analysed (e.g. in notebooks) without writing Rust.
+//!
+//! The bindings are only built with the `python` feature. Build and install the `hwtracer`
+//! Python module into the current virtualenv with [maturin](https://www.maturin.rs/):
+//!
+//! ```text
+//! maturin develop --release
+//! ```
+//!
+//! and then:
+//!
+//! ```python
+//! import hwtracer
+//! trace = hwtracer.Trace.load("trace.hwt")
+//! for off, pkt in trace.packets():
+//!     print(f"{off:016x}  {pkt}")
+//! for first, last in trace.blocks():
+//!     print(hex(first), hex(last))
+//! ```
+//!
+//! Errors are raised as `hwtracer.HWTracerError` exceptions.
+
+use crate::{
+    decode::{self, TraceDecoder, TraceDecoderBuilder, TraceDecoderKind},
+    errors, Block, Trace,
+};
+use pyo3::{create_exception, exceptions::PyException, prelude::*, types::PyBytes};
+use std::{fs::File, path::PathBuf};
+
+create_exception!(hwtracer, HWTracerError, PyException);
+
+fn py_err(e: errors::HWTracerError) -> PyErr {
+    HWTracerError::new_err(e.to_string())
+}
+
+/// A trace, as loaded from a file or from raw Intel PT packet data.
+#[pyclass(name = "Trace")]
+struct PyTrace(Box<dyn Trace>);
+
+#[pymethods]
+impl PyTrace {
+    /// Make a trace from raw Intel PT packet data. See [crate::Trace::from_bytes].
+    #[new]
+    fn new(data: &[u8]) -> Self {
+        Self(<dyn Trace>::from_bytes(data.to_vec()))
+    }
+
+    /// Load a trace from a file of raw Intel PT packet data. See [crate::Trace::from_file].
+    #[staticmethod]
+    fn from_file(path: PathBuf) -> PyResult<Self> {
+        Ok(Self(<dyn Trace>::from_file(path).map_err(py_err)?))
+    }
+
+    /// Load a trace saved by [PyTrace::save] (or [crate::Trace::to_writer]).
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let mut f = File::open(path)?;
+        Ok(Self(<dyn Trace>::from_reader(&mut f).map_err(py_err)?))
+    }
+
+    /// Save the trace, along with the information needed to decode it, to the file `path`.
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        let mut f = File::create(path)?;
+        self.0.to_writer(&mut f).map_err(py_err)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the trace's raw Intel PT packet data.
+    fn bytes<'p>(&self, py: Python<'p>) -> &'p PyBytes {
+        PyBytes::new(py, self.0.bytes())
+    }
+
+    /// Returns the offsets of the PSB packets in the trace. See [crate::Trace::psb_offsets].
+    fn psb_offsets(&self) -> Vec<usize> {
+        self.0.psb_offsets()
+    }
+
+    /// Returns the packets in the trace, as `(offset, text)` tuples, where `text` is formatted as
+    /// by libipt's `ptdump`. See [crate::decode::dump_packets].
+    fn packets(&self) -> PyResult<Vec<(usize, String)>> {
+        decode::iter_packets(&*self.0)
+            .collect::<Result<_, _>>()
+            .map_err(py_err)
+    }
+
+    /// Returns an iterator over the trace's blocks, as `(first_instr, last_instr)` tuples, decoded
+    /// with the decoder named `decoder` (e.g. `"ykpt"`) or, by default, the platform's default
+    /// decoder.
+    #[pyo3(signature = (decoder = None))]
+    fn blocks(slf: &PyCell<Self>, decoder: Option<&str>) -> PyResult<PyBlockIter> {
+        let mut bldr = TraceDecoderBuilder::new();
+        if let Some(d) = decoder {
+            bldr = bldr.kind(d.parse::<TraceDecoderKind>().map_err(py_err)?);
+        }
+        let decoder = bldr.build().map_err(py_err)?;
+        // The iterator borrows the trace and the decoder, which `PyBlockIter` keeps alive (and
+        // doesn't move out of their boxes) for as long as the iterator.
+        let trace: &'static dyn Trace = unsafe { &*(&*slf.borrow().0 as *const dyn Trace) };
+        let dec: &'static dyn TraceDecoder = unsafe { &*(&*decoder as *const dyn TraceDecoder) };
+        Ok(PyBlockIter {
+            blocks: dec.iter_blocks(trace),
+            _decoder: decoder,
+            _trace: slf.into(),
+        })
+    }
+}
+
+/// An iterator over the blocks of a trace.
+#[pyclass(name = "BlockIter", unsendable)]
+struct PyBlockIter {
+    /// The blocks being iterated over. This borrows from `_decoder` and `_trace`, so it must be
+    /// declared, and thus dropped, first.
+    blocks: Box<dyn Iterator<Item = Result<Block, errors::HWTracerError>>>,
+    _decoder: Box<dyn TraceDecoder>,
+    _trace: Py<PyTrace>,
+}
+
+#[pymethods]
+impl PyBlockIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<Option<(u64, u64)>> {
+        match self.blocks.next() {
+            Some(Ok(b)) => Ok(Some((b.first_instr(), b.last_instr()))),
+            Some(Err(e)) => Err(py_err(e)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[pymodule]
+fn hwtracer(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add("HWTracerError", py.get_type::<HWTracerError>())?;
+    m.add_class::<PyTrace>()?;
+    m.add_class::<PyBlockIter>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hwtracer, HWTracerError};
+    use pyo3::{prelude::*, wrap_pymodule};
+
+    #[test]
+    fn trace() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let m = wrap_pymodule!(hwtracer)(py);
+            let m = m.as_ref(py);
+            let t = m
+                .getattr("Trace")
+                .unwrap()
+                .call1((&[1u8, 2, 3][..],))
+                .unwrap();
+            assert_eq!(t.len().unwrap(), 3);
+            let bytes: Vec<u8> = t.call_method0("bytes").unwrap().extract().unwrap();
+            assert_eq!(bytes, [1, 2, 3]);
+            // There are no PSB packets, so no packets are parsed.
+            let pkts: Vec<(usize, String)> = t.call_method0("packets").unwrap().extract().unwrap();
+            assert!(pkts.is_empty());
+            let err = t.call_method1("blocks", ("nope",)).unwrap_err();
+            assert!(err.is_instance_of::<HWTracerError>(py));
+        });
+    }
+}
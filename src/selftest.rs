@@ -0,0 +1,257 @@
+//! Checking, end to end, that tracing works where hwtracer is deployed.
+
+use crate::{
+    collect::{TraceCollectorBuilder, TraceCollectorKind},
+    decode::{DecodeEvent, TraceDecoder, TraceDecoderBuilder, TraceDecoderKind},
+    Trace,
+};
+use std::{collections::HashMap, fmt, hint::black_box};
+
+/// The number of times that the workload goes around its loop.
+const ITERS: u64 = 100;
+
+/// The workload traced by [selftest]: a loop which the compiler can't optimise away.
+#[inline(never)]
+fn workload(iters: u64) -> u64 {
+    let mut res = 0u64;
+    for i in 0..iters {
+        res = black_box(res.wrapping_mul(31).wrapping_add(i));
+    }
+    res
+}
+
+/// The outcome of decoding the self-test's trace with one kind of decoder.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecoderCheck {
+    /// The kind of decoder.
+    pub kind: TraceDecoderKind,
+    /// The number of blocks decoded (or, for decoders which don't reconstruct blocks, the number
+    /// of events), or why decoding failed or didn't give what was expected.
+    pub result: Result<usize, String>,
+}
+
+/// What [selftest] found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SelfTestReport {
+    /// The kind of collector used, or `None` if none is available.
+    pub collector: Option<TraceCollectorKind>,
+    /// The length of the trace collected (in bytes), or why it couldn't be collected.
+    pub collection: Result<usize, String>,
+    /// The outcome for each available kind of decoder (see [TraceDecoderKind::available]), in
+    /// order of preference. Empty if no trace was collected.
+    pub decoders: Vec<DecoderCheck>,
+}
+
+impl SelfTestReport {
+    /// Returns `true` if a trace was collected, and every available decoder decoded it as
+    /// expected.
+    pub fn passed(&self) -> bool {
+        self.collection.is_ok()
+            && !self.decoders.is_empty()
+            && self.decoders.iter().all(|d| d.result.is_ok())
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    /// Formats the report with a line for collection and for each decoder, then the verdict.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let via = self
+            .collector
+            .map_or_else(String::new, |k| format!(" ({})", k));
+        match &self.collection {
+            Ok(len) => writeln!(f, "collect{}: ok, {} bytes", via, len)?,
+            Err(e) => writeln!(f, "collect{}: failed: {}", via, e)?,
+        }
+        for d in &self.decoders {
+            match &d.result {
+                Ok(n) => {
+                    let what = if reconstructs_blocks(d.kind) {
+                        "blocks"
+                    } else {
+                        "events"
+                    };
+                    writeln!(f, "decode ({}): ok, {} {}", d.kind, n, what)?
+                }
+                Err(e) => writeln!(f, "decode ({}): failed: {}", d.kind, e)?,
+            }
+        }
+        write!(f, "{}", if self.passed() { "passed" } else { "failed" })
+    }
+}
+
+/// Check, end to end, that tracing works here: collect a small trace of a built-in workload, then
+/// decode it with each available kind of decoder, checking that the workload's code appears as it
+/// should. Failures are reported rather than returned as errors, so that the report says how far
+/// the self-test got.
+///
+/// That a CPU supports Intel PT doesn't mean that it can be used: containers, virtual machines and
+/// locked-down kernels often stop `perf` from collecting traces, or collect traces that can't be
+/// decoded. Applications can run the self-test at startup to find out, rather than failing (or
+/// silently tracing nothing) later.
+///
+/// ```no_run
+/// let report = hwtracer::selftest();
+/// if !report.passed() {
+///     eprintln!("tracing is unavailable:\n{}", report);
+/// }
+/// ```
+pub fn selftest() -> SelfTestReport {
+    let Some(&kind) = TraceCollectorKind::available().first() else {
+        return SelfTestReport {
+            collector: None,
+            collection: Err("no trace collector is available".to_owned()),
+            decoders: Vec::new(),
+        };
+    };
+    let trace = TraceCollectorBuilder::new()
+        .kind(kind)
+        .build()
+        .and_then(|tc| {
+            tc.start_thread_collector()?;
+            black_box(workload(black_box(ITERS)));
+            tc.stop_thread_collector()
+        });
+    let trace = match trace {
+        Ok(trace) => trace,
+        Err(e) => {
+            return SelfTestReport {
+                collector: Some(kind),
+                collection: Err(e.to_string()),
+                decoders: Vec::new(),
+            }
+        }
+    };
+    let decoders = TraceDecoderKind::available()
+        .into_iter()
+        .map(|kind| DecoderCheck {
+            kind,
+            result: check_decoder(kind, &*trace),
+        })
+        .collect();
+    SelfTestReport {
+        collector: Some(kind),
+        collection: Ok(trace.len()),
+        decoders,
+    }
+}
+
+/// Returns `true` if decoders of kind `kind` reconstruct the blocks executed. The ykpt decoder
+/// doesn't yet, so can only be checked through the events that it reports.
+fn reconstructs_blocks(kind: TraceDecoderKind) -> bool {
+    kind != TraceDecoderKind::YkPT
+}
+
+/// Decode `trace` with a decoder of kind `kind`, and check that it saw what it should of the
+/// workload. Returns the number of blocks (or events) decoded.
+fn check_decoder(kind: TraceDecoderKind, trace: &dyn Trace) -> Result<usize, String> {
+    let dec = TraceDecoderBuilder::new()
+        .kind(kind)
+        .build()
+        .map_err(|e| e.to_string())?;
+    if reconstructs_blocks(kind) {
+        check_blocks(&*dec, trace)
+    } else {
+        check_events(&*dec, trace)
+    }
+}
+
+/// Check that `dec` decodes `trace` into blocks that go into the workload and around its loop.
+/// Returns the number of blocks decoded.
+fn check_blocks(dec: &dyn TraceDecoder, trace: &dyn Trace) -> Result<usize, String> {
+    let entry = workload as fn(u64) -> u64 as usize as u64;
+    let mut blocks = 0;
+    let mut entered = false;
+    let mut counts = HashMap::new();
+    for block in dec.iter_blocks(trace) {
+        let block = block.map_err(|e| e.to_string())?;
+        blocks += 1;
+        entered |= block.first_instr() == entry;
+        *counts.entry(block.first_instr()).or_insert(0u64) += 1;
+    }
+    if blocks == 0 {
+        return Err("no blocks were decoded".to_owned());
+    }
+    if !entered {
+        return Err(format!("no block starts at the workload ({:#x})", entry));
+    }
+    // Allow for the compiler having unrolled the loop.
+    if counts.values().all(|&n| n < ITERS / 10) {
+        return Err("no block was executed as often as the workload's loop".to_owned());
+    }
+    Ok(blocks)
+}
+
+/// Check that `dec` decodes `trace` into events without error, and that tracing was enabled and
+/// ended up disabled again when collection stopped. Returns the number of events decoded.
+fn check_events(dec: &dyn TraceDecoder, trace: &dyn Trace) -> Result<usize, String> {
+    let mut events = 0;
+    let mut enabled = None;
+    for ev in dec.iter_events(trace) {
+        match ev.map_err(|e| e.to_string())? {
+            DecodeEvent::TracingEnabled(_) => enabled = Some(true),
+            DecodeEvent::TracingDisabled(_) => enabled = enabled.map(|_| false),
+            _ => (),
+        }
+        events += 1;
+    }
+    match enabled {
+        None => Err("tracing was never enabled".to_owned()),
+        Some(true) => Err("tracing wasn't disabled at the end of the trace".to_owned()),
+        Some(false) => Ok(events),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_decoder, selftest};
+    use crate::{
+        collect::TraceCollectorKind,
+        decode::TraceDecoderKind,
+        pt::encode::{Encoder, IPCompression},
+        Trace,
+    };
+
+    #[test]
+    fn report() {
+        let report = selftest();
+        if TraceCollectorKind::available().is_empty() {
+            assert!(!report.passed());
+            assert!(report.decoders.is_empty());
+            assert!(report.to_string().ends_with("failed"));
+            return;
+        }
+        assert!(report.passed(), "{}", report);
+        assert_eq!(
+            report.decoders.iter().map(|d| d.kind).collect::<Vec<_>>(),
+            TraceDecoderKind::available()
+        );
+    }
+
+    /// Check that the ykpt decoder is judged by its events, as it doesn't reconstruct blocks.
+    #[test]
+    fn ykpt_events() {
+        let mut enc = Encoder::new();
+        enc.psb()
+            .psbend()
+            .tip_pge(0x5555_1000, IPCompression::Full)
+            .tnt(&[true, false])
+            .tip(0x5555_2000, IPCompression::Update16);
+        let enabled = enc.bytes().to_vec();
+        enc.tip_pgd(0x5555_3000, IPCompression::Update16);
+        let trace = <dyn Trace>::from_bytes(enc.into_bytes());
+        assert!(check_decoder(TraceDecoderKind::YkPT, &*trace).unwrap() > 0);
+
+        // Tracing must have been stopped at the end.
+        let trace = <dyn Trace>::from_bytes(enabled);
+        assert!(check_decoder(TraceDecoderKind::YkPT, &*trace)
+            .unwrap_err()
+            .contains("disabled"));
+        // And started in the first place.
+        let mut enc = Encoder::new();
+        enc.psb().psbend();
+        let trace = <dyn Trace>::from_bytes(enc.into_bytes());
+        assert!(check_decoder(TraceDecoderKind::YkPT, &*trace)
+            .unwrap_err()
+            .contains("never enabled"));
+    }
+}
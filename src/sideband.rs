@@ -0,0 +1,220 @@
+use crate::errors::{DecodeError, HWTracerError, MalformedTraceKind};
+use std::{
+    borrow::Cow,
+    convert::{TryFrom, TryInto},
+    path::{Path, PathBuf},
+};
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+// Perf record types and flags used in sideband records. See `perf_event_open(2)`.
+pub(crate) const PERF_RECORD_MMAP: u32 = 1;
+pub(crate) const PERF_RECORD_COMM: u32 = 3;
+pub(crate) const PERF_RECORD_MMAP2: u32 = 10;
+pub(crate) const PERF_RECORD_SWITCH: u32 = 14;
+pub(crate) const PERF_RECORD_SWITCH_CPU_WIDE: u32 = 15;
+pub(crate) const PERF_RECORD_MISC_MMAP_DATA: u16 = 1 << 13;
+pub(crate) const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+pub(crate) const PERF_RECORD_MISC_SWITCH_OUT: u16 = 1 << 13;
+// Linux's `mmap(2)` protection and flags values, as carried by perf records. These are spelled
+// out, rather than taken from libc, so that perf records can be read and written on any platform.
+const PROT_READ: u32 = 0x1;
+const PROT_EXEC: u32 = 0x4;
+const MAP_PRIVATE: u32 = 0x2;
+
+/// A record of a change to the traced program's environment, collected alongside a trace.
+///
+/// Decoders use sideband records to keep their view of the traced program's memory up to date
+/// (e.g. when a shared object is loaded part way through a trace).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SidebandRecord {
+    /// An offset (in bytes) into the trace. All trace data before this offset was generated
+    /// before the event occurred.
+    pub trace_offset: usize,
+    /// What happened.
+    pub event: SidebandEvent,
+}
+
+/// The events that may be described by a [SidebandRecord].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SidebandEvent {
+    /// `len` bytes of `filename`, starting at file offset `pgoff`, were mapped executable at
+    /// virtual address `vaddr`.
+    Mmap {
+        vaddr: u64,
+        len: u64,
+        pgoff: u64,
+        filename: PathBuf,
+    },
+    /// The name of the thread `tid` (of process `pid`) changed to `comm`. If `exec` is true, this
+    /// was caused by `execve(2)`, which replaces the traced program's address space.
+    Comm {
+        pid: u32,
+        tid: u32,
+        comm: String,
+        exec: bool,
+    },
+    /// The traced thread was scheduled on (`out == false`) or off (`out == true`) a CPU.
+    Switch { out: bool },
+    /// The trace data before and after this point come from separate collection sessions (see
+    /// [crate::Trace::concat]), or trace data between them was left out (see
+    /// [crate::Trace::sample_psb_regions]). Control flow doesn't continue across the boundary.
+    SessionBoundary,
+}
+
+fn bad() -> HWTracerError {
+    HWTracerError::Decode(DecodeError::malformed(
+        MalformedTraceKind::BadSidebandRecord,
+    ))
+}
+
+// Perf records are little-endian, as written by the x86_64 machines that collect Intel PT traces,
+// whatever machine they are read on.
+
+pub(crate) fn u16_at(b: &[u8], off: usize) -> Result<u16, HWTracerError> {
+    let b = b.get(off..off + 2).ok_or_else(bad)?;
+    Ok(u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+pub(crate) fn u32_at(b: &[u8], off: usize) -> Result<u32, HWTracerError> {
+    let b = b.get(off..off + 4).ok_or_else(bad)?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+pub(crate) fn u64_at(b: &[u8], off: usize) -> Result<u64, HWTracerError> {
+    let b = b.get(off..off + 8).ok_or_else(bad)?;
+    Ok(u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Read a NUL-terminated (and possibly NUL-padded) string starting at `off`.
+fn str_at(b: &[u8], off: usize) -> Result<&[u8], HWTracerError> {
+    let b = b.get(off..).ok_or_else(bad)?;
+    let end = b.iter().position(|c| *c == 0).ok_or_else(bad)?;
+    Ok(&b[..end])
+}
+
+/// Make a path from a filename as recorded in a perf record (or a saved trace): raw bytes.
+pub(crate) fn path_from_bytes(b: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    return PathBuf::from(OsStr::from_bytes(b));
+    // Elsewhere (e.g. WebAssembly) paths are strings. The filename only names a file on the
+    // machine that the trace was collected on anyway.
+    #[cfg(not(unix))]
+    return PathBuf::from(String::from_utf8_lossy(b).into_owned());
+}
+
+/// The inverse of [path_from_bytes].
+pub(crate) fn path_bytes(p: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    return Cow::Borrowed(p.as_os_str().as_bytes());
+    #[cfg(not(unix))]
+    return match p.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    };
+}
+
+/// Read the `struct perf_event_header` at the start of `bytes`, returning the record's type, misc
+/// flags and size (including the header itself).
+pub(crate) fn perf_record_header(bytes: &[u8]) -> Result<(u32, u16, usize), HWTracerError> {
+    let size = usize::from(u16_at(bytes, 6)?);
+    if size < 8 {
+        // The size includes the header itself.
+        return Err(bad());
+    }
+    Ok((u32_at(bytes, 0)?, u16_at(bytes, 4)?, size))
+}
+
+/// Parse the perf record `rec` (including its header) into a sideband event.
+///
+/// Returns `None` for records which hwtracer doesn't care about.
+pub(crate) fn parse_perf_record(rec: &[u8]) -> Result<Option<SidebandEvent>, HWTracerError> {
+    let (typ, misc, size) = perf_record_header(rec)?;
+    let rec = rec.get(..size).ok_or_else(bad)?;
+    let event = match typ {
+        // Data mappings don't affect the code that the decoder sees.
+        PERF_RECORD_MMAP | PERF_RECORD_MMAP2 if misc & PERF_RECORD_MISC_MMAP_DATA != 0 => None,
+        PERF_RECORD_MMAP => Some(SidebandEvent::Mmap {
+            vaddr: u64_at(rec, 16)?,
+            len: u64_at(rec, 24)?,
+            pgoff: u64_at(rec, 32)?,
+            filename: path_from_bytes(str_at(rec, 40)?),
+        }),
+        PERF_RECORD_MMAP2 => Some(SidebandEvent::Mmap {
+            vaddr: u64_at(rec, 16)?,
+            len: u64_at(rec, 24)?,
+            pgoff: u64_at(rec, 32)?,
+            filename: path_from_bytes(str_at(rec, 72)?),
+        }),
+        PERF_RECORD_COMM => Some(SidebandEvent::Comm {
+            pid: u32_at(rec, 8)?,
+            tid: u32_at(rec, 12)?,
+            comm: String::from_utf8_lossy(str_at(rec, 16)?).into_owned(),
+            exec: misc & PERF_RECORD_MISC_COMM_EXEC != 0,
+        }),
+        PERF_RECORD_SWITCH | PERF_RECORD_SWITCH_CPU_WIDE => Some(SidebandEvent::Switch {
+            out: misc & PERF_RECORD_MISC_SWITCH_OUT != 0,
+        }),
+        _ => None,
+    };
+    Ok(event)
+}
+
+/// Encode `event` as a perf record, attributing it to the process `pid`. This is the inverse of
+/// [parse_perf_record]. Returns `None` for events that perf has no record for, or which are too
+/// big to fit in one (a record's size is a `u16`, which e.g. a very long path can overflow).
+pub(crate) fn perf_record(event: &SidebandEvent, pid: u32) -> Option<Vec<u8>> {
+    /// Append `s` to `body` as a NUL-terminated string, padded to a multiple of 8 bytes as perf
+    /// does.
+    fn push_str(body: &mut Vec<u8>, s: &[u8]) {
+        body.extend(s);
+        body.extend(vec![0; 8 - s.len() % 8]);
+    }
+
+    let mut body = Vec::new();
+    let (typ, misc) = match event {
+        SidebandEvent::Mmap {
+            vaddr,
+            len,
+            pgoff,
+            filename,
+        } => {
+            body.extend(pid.to_le_bytes());
+            body.extend(pid.to_le_bytes()); // tid
+            body.extend(vaddr.to_le_bytes());
+            body.extend(len.to_le_bytes());
+            body.extend(pgoff.to_le_bytes());
+            body.extend([0; 24]); // maj, min, ino, ino_generation: unknown.
+            body.extend((PROT_READ | PROT_EXEC).to_le_bytes());
+            body.extend(MAP_PRIVATE.to_le_bytes());
+            push_str(&mut body, &path_bytes(filename));
+            (PERF_RECORD_MMAP2, 0)
+        }
+        SidebandEvent::Comm {
+            pid,
+            tid,
+            comm,
+            exec,
+        } => {
+            body.extend(pid.to_le_bytes());
+            body.extend(tid.to_le_bytes());
+            push_str(&mut body, comm.as_bytes());
+            let misc = if *exec { PERF_RECORD_MISC_COMM_EXEC } else { 0 };
+            (PERF_RECORD_COMM, misc)
+        }
+        SidebandEvent::Switch { out } => {
+            let misc = if *out { PERF_RECORD_MISC_SWITCH_OUT } else { 0 };
+            (PERF_RECORD_SWITCH, misc)
+        }
+        SidebandEvent::SessionBoundary => return None,
+    };
+    let size = u16::try_from(8 + body.len()).ok()?;
+    let mut rec = Vec::with_capacity(8 + body.len());
+    rec.extend(typ.to_le_bytes());
+    rec.extend(misc.to_le_bytes());
+    rec.extend(size.to_le_bytes());
+    rec.extend(body);
+    Some(rec)
+}
@@ -0,0 +1,442 @@
+//! Cutting traces down to smaller, still decodable, traces, and joining traces together.
+
+use crate::{
+    container::RawTrace,
+    errors::{ConfigError, HWTracerError},
+    SidebandEvent, SidebandRecord, Trace,
+};
+use memchr::memmem::Finder;
+use std::{ops::Range, sync::OnceLock};
+
+/// A PSB packet: the pattern which decoders look for to synchronise with a trace.
+const PSB: [u8; 16] = [
+    0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+];
+
+/// Returns the searcher for PSB packets, building it the first time it is needed. Traces can be
+/// gigabytes long, so this uses `memchr`'s vectorised substring search, which skips quickly over
+/// bytes that can't start a PSB packet and only then compares the whole pattern.
+fn psb_finder() -> &'static Finder<'static> {
+    static FINDER: OnceLock<Finder<'static>> = OnceLock::new();
+    FINDER.get_or_init(|| Finder::new(&PSB))
+}
+
+/// Find the offsets of the PSB packets in `bytes`. See [Trace::psb_offsets].
+///
+/// Matches don't overlap: a run of PSB bytes longer than a PSB packet is one PSB packet followed
+/// by junk.
+pub(crate) fn psb_offsets(bytes: &[u8]) -> Vec<usize> {
+    psb_finder().find_iter(bytes).collect()
+}
+
+/// Find the offset of the first PSB packet in `bytes`, if there is one.
+pub(crate) fn first_psb_offset(bytes: &[u8]) -> Option<usize> {
+    psb_finder().find(bytes)
+}
+
+/// Make a trace holding the PSB regions `regions` of `trace`. See [Trace::slice_psb_regions].
+pub(crate) fn slice_psb_regions<T: Trace + ?Sized>(
+    trace: &T,
+    regions: Range<usize>,
+) -> Result<RawTrace, HWTracerError> {
+    let bytes = trace.bytes();
+    let psbs = psb_offsets(bytes);
+    if regions.start >= regions.end || regions.end > psbs.len() {
+        return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+            "PSB regions {:?} out of range: the trace has {} PSB regions",
+            regions,
+            psbs.len()
+        ))));
+    }
+    let start = psbs[regions.start];
+    let end = psbs.get(regions.end).copied().unwrap_or(bytes.len());
+    // Records from before the slice still describe the traced program (e.g. which code is mapped
+    // where), so they are kept, but moved to the start of the slice.
+    let sideband = trace
+        .sideband()?
+        .into_iter()
+        .take_while(|r| r.trace_offset <= end)
+        .map(|r| SidebandRecord {
+            trace_offset: r.trace_offset.saturating_sub(start),
+            event: r.event,
+        })
+        .collect();
+    Ok(RawTrace {
+        bytes: bytes[start..end].to_vec(),
+        meta: trace.meta(),
+        sideband,
+    })
+}
+
+/// Which PSB regions of a trace to keep when sampling it. See [Trace::sample_psb_regions].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PsbSample {
+    /// Keep every `n`th region, starting with the first.
+    EveryNth(usize),
+    /// Keep each region with probability `probability` (between 0 and 1). Which regions are kept
+    /// depends only on `seed` and the number of regions, so the same seed gives the same sample.
+    Random { probability: f64, seed: u64 },
+}
+
+impl PsbSample {
+    /// Returns `true` if the `i`th region is in the sample.
+    fn keeps(&self, i: usize) -> bool {
+        match *self {
+            Self::EveryNth(n) => i % n == 0,
+            Self::Random { probability, seed } => {
+                // SplitMix64, which gives well spread values even for consecutive inputs.
+                let mut z = seed.wrapping_add((i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                // The top 53 bits make a uniformly distributed float in [0, 1).
+                ((z >> 11) as f64 / (1u64 << 53) as f64) < probability
+            }
+        }
+    }
+}
+
+/// Make a trace holding a sample of the PSB regions of `trace`. See [Trace::sample_psb_regions].
+pub(crate) fn sample_psb_regions<T: Trace + ?Sized>(
+    trace: &T,
+    sample: PsbSample,
+) -> Result<RawTrace, HWTracerError> {
+    match sample {
+        PsbSample::EveryNth(0) => {
+            return Err(HWTracerError::Config(ConfigError::Invalid(
+                "can't sample every 0th PSB region".to_owned(),
+            )))
+        }
+        PsbSample::Random { probability, .. } if !(0.0..=1.0).contains(&probability) => {
+            return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+                "PSB region sampling probability {} isn't between 0 and 1",
+                probability
+            ))))
+        }
+        _ => (),
+    }
+    let bytes = trace.bytes();
+    let psbs = psb_offsets(bytes);
+    let mut out = Vec::new();
+    let mut sideband = Vec::new();
+    let mut records = trace.sideband()?.into_iter().peekable();
+    // The index of the last region kept, if any.
+    let mut last = None;
+    for (i, &start) in psbs.iter().enumerate() {
+        if !sample.keeps(i) {
+            continue;
+        }
+        let end = psbs.get(i + 1).copied().unwrap_or(bytes.len());
+        let base = out.len();
+        if matches!(last, Some(l) if l + 1 != i) {
+            sideband.push(SidebandRecord {
+                trace_offset: base,
+                event: SidebandEvent::SessionBoundary,
+            });
+        }
+        // As with slicing, records from before the region are moved to its start.
+        while let Some(r) = records.next_if(|r| r.trace_offset <= end) {
+            sideband.push(SidebandRecord {
+                trace_offset: base + r.trace_offset.saturating_sub(start),
+                event: r.event,
+            });
+        }
+        out.extend_from_slice(&bytes[start..end]);
+        last = Some(i);
+    }
+    Ok(RawTrace {
+        bytes: out,
+        meta: trace.meta(),
+        sideband,
+    })
+}
+
+/// Join `traces` into one trace. See [Trace::concat].
+pub(crate) fn concat(traces: &[&dyn Trace]) -> Result<RawTrace, HWTracerError> {
+    let traces = traces
+        .iter()
+        .filter(|t| !t.bytes().is_empty())
+        .collect::<Vec<_>>();
+    let mut meta = traces.first().map(|t| t.meta()).unwrap_or_default();
+    let mut bytes = Vec::with_capacity(traces.iter().map(|t| t.len()).sum());
+    let mut sideband = Vec::new();
+    for (i, t) in traces.iter().enumerate() {
+        // hwtracer's version doesn't affect how a trace is decoded.
+        let tmeta = t.meta();
+        if (tmeta.cpu, tmeta.tsc_ratio, tmeta.pt_config)
+            != (meta.cpu, meta.tsc_ratio, meta.pt_config)
+        {
+            return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+                "can't concatenate traces: trace {} was collected with a different configuration",
+                i
+            ))));
+        }
+        // The joined trace only keeps the identity that all of its parts share.
+        if tmeta.id != meta.id {
+            meta.id = None;
+        }
+        meta.provenance
+            .retain(|k, v| tmeta.provenance.get(k) == Some(v));
+        // Each session may have loaded objects that the others didn't.
+        for id in tmeta.build_ids {
+            if !meta.build_ids.contains(&id) {
+                meta.build_ids.push(id);
+            }
+        }
+        // Decoders must be able to synchronise with each session as it starts.
+        if !t.bytes().starts_with(&PSB) {
+            return Err(HWTracerError::Config(ConfigError::Invalid(format!(
+                "can't concatenate traces: trace {} doesn't start with a PSB packet",
+                i
+            ))));
+        }
+        let base = bytes.len();
+        if base > 0 {
+            sideband.push(SidebandRecord {
+                trace_offset: base,
+                event: SidebandEvent::SessionBoundary,
+            });
+        }
+        sideband.extend(t.sideband()?.into_iter().map(|r| SidebandRecord {
+            trace_offset: base + r.trace_offset,
+            event: r.event,
+        }));
+        bytes.extend_from_slice(t.bytes());
+    }
+    Ok(RawTrace {
+        bytes,
+        meta,
+        sideband,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_psb_offset, psb_offsets, PsbSample, PSB};
+    use crate::{
+        container::RawTrace,
+        errors::{ConfigError, HWTracerError},
+        SidebandEvent, SidebandRecord, Trace, TraceId, TraceMeta,
+    };
+
+    /// A trace with three PSB regions, preceded by some junk.
+    fn sample() -> RawTrace {
+        let mut bytes = vec![0xff, 0x00];
+        for payload in [[1, 1], [2, 2], [3, 3]] {
+            bytes.extend(PSB);
+            bytes.extend(payload);
+        }
+        let switch = |trace_offset| SidebandRecord {
+            trace_offset,
+            event: SidebandEvent::Switch { out: false },
+        };
+        let mut trace = RawTrace::new(bytes);
+        trace.sideband = vec![switch(0), switch(19), switch(21), switch(37), switch(55)];
+        trace
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(psb_offsets(sample().bytes()), vec![2, 20, 38]);
+        assert!(psb_offsets(&PSB[1..]).is_empty());
+        // A run of PSB bytes longer than a PSB packet is one PSB packet followed by junk.
+        let mut bytes = PSB.to_vec();
+        bytes.extend([0x02, 0x82]);
+        assert_eq!(psb_offsets(&bytes), vec![0]);
+    }
+
+    /// Check the scanner against a naive one, on a large input full of near misses, with PSB
+    /// packets at every alignment.
+    #[test]
+    fn offsets_large() {
+        let naive = |bytes: &[u8]| {
+            let mut offs = Vec::new();
+            let mut i = 0;
+            while i + PSB.len() <= bytes.len() {
+                if bytes[i..i + PSB.len()] == PSB {
+                    offs.push(i);
+                    i += PSB.len();
+                } else {
+                    i += 1;
+                }
+            }
+            offs
+        };
+        let mut bytes = Vec::new();
+        for i in 0..1000 {
+            // All but the last byte of a PSB packet, then junk of varying length.
+            bytes.extend(&PSB[..PSB.len() - 1]);
+            bytes.extend((0..i % 37).map(|j| j as u8));
+            if i % 3 == 0 {
+                bytes.extend(PSB);
+            }
+        }
+        let offs = psb_offsets(&bytes);
+        assert_eq!(offs.len(), 334);
+        assert_eq!(offs, naive(&bytes));
+        assert_eq!(first_psb_offset(&bytes), Some(offs[0]));
+        assert_eq!(first_psb_offset(&bytes[..offs[0] + PSB.len() - 1]), None);
+    }
+
+    #[test]
+    fn slice() {
+        let trace = sample();
+        let sliced = trace.slice_psb_regions(1..2).unwrap();
+        let mut expect = PSB.to_vec();
+        expect.extend([2, 2]);
+        assert_eq!(sliced.bytes(), expect);
+        assert_eq!(sliced.meta(), trace.meta);
+        let offs = sliced
+            .sideband()
+            .unwrap()
+            .iter()
+            .map(|r| r.trace_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offs, vec![0, 0, 1, 17]);
+
+        // The last region runs to the end of the trace.
+        let sliced = trace.slice_psb_regions(1..3).unwrap();
+        assert_eq!(sliced.bytes(), &trace.bytes[20..]);
+        assert_eq!(sliced.sideband().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn concat() {
+        let trace = sample();
+        let a = trace.slice_psb_regions(0..1).unwrap();
+        let b = trace.slice_psb_regions(1..3).unwrap();
+        let empty = RawTrace::new(Vec::new());
+        let joined = <dyn Trace>::concat(&[&*a, &empty, &*b]).unwrap();
+        assert_eq!(joined.bytes(), &trace.bytes[2..]);
+        let sb = joined.sideband().unwrap();
+        let boundaries = sb
+            .iter()
+            .filter(|r| r.event == SidebandEvent::SessionBoundary)
+            .map(|r| r.trace_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(boundaries, vec![18]);
+        // Each session keeps its own sideband.
+        assert_eq!(
+            sb.len(),
+            a.sideband().unwrap().len() + b.sideband().unwrap().len() + 1
+        );
+
+        // Traces have to start at a PSB packet.
+        assert!(matches!(
+            <dyn Trace>::concat(&[&*a, &trace]),
+            Err(HWTracerError::Config(ConfigError::Invalid(_)))
+        ));
+
+        // Traces have to be collected in the same way.
+        let mut c = RawTrace::new(a.bytes().to_vec());
+        c.meta = TraceMeta {
+            pt_config: Some(1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            <dyn Trace>::concat(&[&*a, &c]),
+            Err(HWTracerError::Config(ConfigError::Invalid(_)))
+        ));
+
+        // Only the ID and provenance that the traces share are kept.
+        let mut c = RawTrace::new(a.bytes().to_vec());
+        c.meta.id = Some(TraceId(1));
+        c.meta.provenance = [("thread", "main"), ("request", "1")]
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let mut d = RawTrace::new(b.bytes().to_vec());
+        d.meta = c.meta.clone();
+        let joined = <dyn Trace>::concat(&[&c, &d]).unwrap();
+        assert_eq!(joined.meta(), c.meta);
+        d.meta.id = Some(TraceId(2));
+        d.meta
+            .provenance
+            .insert("request".to_owned(), "2".to_owned());
+        let joined = <dyn Trace>::concat(&[&c, &d]).unwrap().meta();
+        assert_eq!(joined.id, None);
+        assert_eq!(joined.provenance.keys().collect::<Vec<_>>(), ["thread"]);
+    }
+
+    #[test]
+    fn sampling() {
+        let mut trace = sample();
+        // Add a fourth region, so that samples can have gaps.
+        trace.bytes.extend(PSB);
+        trace.bytes.extend([4, 4]);
+        let sampled = trace.sample_psb_regions(PsbSample::EveryNth(2)).unwrap();
+        let mut expect = PSB.to_vec();
+        expect.extend([1, 1]);
+        expect.extend(PSB);
+        expect.extend([3, 3]);
+        assert_eq!(sampled.bytes(), expect);
+        assert_eq!(sampled.meta(), trace.meta);
+        let sb = sampled
+            .sideband()
+            .unwrap()
+            .iter()
+            .map(|r| (r.trace_offset, r.event == SidebandEvent::SessionBoundary))
+            .collect::<Vec<_>>();
+        // The records in the skipped region come after the gap's marker.
+        assert_eq!(
+            sb,
+            vec![
+                (0, false),
+                (17, false),
+                (18, true),
+                (18, false),
+                (18, false),
+                (35, false)
+            ]
+        );
+
+        // Consecutive regions have no gap between them.
+        let all = trace.sample_psb_regions(PsbSample::EveryNth(1)).unwrap();
+        assert_eq!(all.bytes(), &trace.bytes[2..]);
+        assert!(all
+            .sideband()
+            .unwrap()
+            .iter()
+            .all(|r| r.event != SidebandEvent::SessionBoundary));
+
+        // Random samples are reproducible.
+        let random = |probability, seed| {
+            trace
+                .sample_psb_regions(PsbSample::Random { probability, seed })
+                .unwrap()
+                .bytes()
+                .to_vec()
+        };
+        assert_eq!(random(0.5, 1), random(0.5, 1));
+        assert_eq!(random(1.0, 1), &trace.bytes[2..]);
+        assert!(random(0.0, 1).is_empty());
+
+        for bad in [
+            PsbSample::EveryNth(0),
+            PsbSample::Random {
+                probability: 1.5,
+                seed: 0,
+            },
+            PsbSample::Random {
+                probability: f64::NAN,
+                seed: 0,
+            },
+        ] {
+            assert!(matches!(
+                trace.sample_psb_regions(bad),
+                Err(HWTracerError::Config(ConfigError::Invalid(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_regions() {
+        let trace = sample();
+        for regions in [0..0, 1..1, 0..4, 3..4] {
+            assert!(matches!(
+                trace.slice_psb_regions(regions),
+                Err(HWTracerError::Config(ConfigError::Invalid(_)))
+            ));
+        }
+    }
+}
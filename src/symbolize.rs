@@ -0,0 +1,379 @@
+//! Annotating decoded blocks and events with the names of the functions they are in.
+//!
+//! Symbols are looked up in the ELF symbol tables of the objects (the executable and shared
+//! objects) loaded into a process, and source locations and inlined functions in their DWARF
+//! debugging information, if any.
+//!
+//! ```no_run
+//! use hwtracer::{decode::TraceDecoderBuilder, symbolize::Symbolizer, Trace};
+//! # let trace = <dyn Trace>::from_bytes(Vec::new());
+//! let dec = TraceDecoderBuilder::new().build().unwrap();
+//! let syms = Symbolizer::new().unwrap();
+//! for blk in syms.annotate_blocks(dec.iter_blocks(&*trace)) {
+//!     let blk = blk.unwrap();
+//!     match blk.symbol {
+//!         Some(sym) => println!("{:#x} {}", blk.block.first_instr(), sym),
+//!         None => println!("{:#x} ???", blk.block.first_instr()),
+//!     }
+//! }
+//! ```
+
+use crate::{
+    decode::DecodeEvent,
+    errors::HWTracerError,
+    objects::{loaded_objects, ObjectFile},
+    Block,
+};
+use addr2line::Loader;
+use libc::{PF_X, PT_LOAD};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+/// The function that an address is in, and where in the source code it came from.
+///
+/// Symbols are displayed as e.g. `inner (a.rs:1) inlined into outer (b.rs:2)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// The (demangled) name of the function.
+    pub name: String,
+    /// The object (executable or shared object) that the function is in.
+    pub object: PathBuf,
+    /// The source file of the function that the address came from, if there is debugging
+    /// information for it. If the address is in inlined code, this is where the outermost inlined
+    /// function was inlined.
+    pub file: Option<String>,
+    /// The line of [Symbol::file] that the address came from, if known.
+    pub line: Option<u32>,
+    /// The functions inlined at the address, innermost first, each with the source location in
+    /// it that the address came from (or, for all but the innermost, where the next function was
+    /// inlined). Empty if the address isn't in inlined code, or there is no debugging
+    /// information for it.
+    ///
+    /// In optimised code, this is often more informative than [Symbol::name]: e.g. an address in
+    /// an inlined iterator method is reported as being in the function which called it.
+    pub inlined: Vec<InlineFrame>,
+}
+
+/// A function inlined at an address, as reported by [Symbol::inlined].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InlineFrame {
+    /// The (demangled) name of the inlined function, if known.
+    pub function: Option<String>,
+    /// The source file that the address came from, if known.
+    pub file: Option<String>,
+    /// The line of [InlineFrame::file] that the address came from, if known.
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for InlineFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("???"))?;
+        fmt_location(f, self.file.as_deref(), self.line)
+    }
+}
+
+/// Format a source location as " (file:line)", or nothing if the file is unknown.
+fn fmt_location(f: &mut fmt::Formatter<'_>, file: Option<&str>, line: Option<u32>) -> fmt::Result {
+    match (file, line) {
+        (Some(file), Some(line)) => write!(f, " ({}:{})", file, line),
+        (Some(file), None) => write!(f, " ({})", file),
+        _ => Ok(()),
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for frame in &self.inlined {
+            write!(f, "{} inlined into ", frame)?;
+        }
+        write!(f, "{}", self.name)?;
+        fmt_location(f, self.file.as_deref(), self.line)
+    }
+}
+
+/// A block annotated with the function that it starts in, as yielded by
+/// [Symbolizer::annotate_blocks].
+#[derive(Debug, Eq, PartialEq)]
+pub struct SymbolizedBlock {
+    pub block: Block,
+    /// The function containing [Block::first_instr], if it could be found.
+    pub symbol: Option<Symbol>,
+}
+
+/// An event annotated with the functions that control was transferred from and to, as yielded by
+/// [Symbolizer::annotate_events].
+#[derive(Debug, Eq, PartialEq)]
+pub struct SymbolizedEvent {
+    pub event: DecodeEvent,
+    /// The function that control was transferred from, for events that report it (i.e.
+    /// [DecodeEvent::AsyncTransfer]).
+    pub from: Option<Symbol>,
+    /// The function that control was transferred to, for events that report it (e.g.
+    /// [DecodeEvent::TracingEnabled]).
+    pub to: Option<Symbol>,
+}
+
+/// An object whose symbols can be looked up.
+struct Object {
+    path: PathBuf,
+    /// The difference between the addresses the object is loaded at and those in the object file.
+    bias: u64,
+    /// The object's symbols and debugging information, loaded on first use. `None` if the object
+    /// couldn't be loaded (e.g. because it isn't an ELF file).
+    loader: OnceCell<Option<Loader>>,
+}
+
+/// Finds the functions that addresses in a process are in.
+///
+/// Objects are only read the first time an address in them is looked up, and the symbol for each
+/// address is cached, so creating a symbolizer is cheap and looking up the same addresses
+/// repeatedly (as in a trace of a loop) is fast. Since it caches lookups, a symbolizer can't be
+/// shared between threads.
+pub struct Symbolizer {
+    /// The executable segments of the objects, sorted by start address, with the index in
+    /// `objects` of the object that each is part of.
+    segments: Vec<(Range<u64>, usize)>,
+    objects: Vec<Object>,
+    cache: RefCell<HashMap<u64, Option<Symbol>>>,
+}
+
+impl Symbolizer {
+    /// Create a symbolizer for the objects currently loaded into this process, e.g. to symbolize
+    /// a trace collected by it. Objects loaded later (e.g. with `dlopen`) aren't included.
+    pub fn new() -> Result<Self, HWTracerError> {
+        let mut syms = Self::empty();
+        for (file, obj) in loaded_objects()? {
+            let path = match file {
+                ObjectFile::Path(p) => p,
+                ObjectFile::Vdso => continue, // The VDSO has no file to read symbols from.
+            };
+            let bias = obj.addr();
+            for hdr in obj.iter_phdrs() {
+                if hdr.type_() != PT_LOAD || hdr.flags() & PF_X == 0 {
+                    continue; // Only look at loadable and executable segments.
+                }
+                let start = bias + hdr.vaddr();
+                syms.add_object(&path, start..start + hdr.filesz(), bias);
+            }
+        }
+        Ok(syms)
+    }
+
+    /// Create a symbolizer with no objects. Add them with [Symbolizer::add_object].
+    pub fn empty() -> Self {
+        Self {
+            segments: Vec::new(),
+            objects: Vec::new(),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Look up the symbols of addresses `vaddrs` in the object file at `path`, which was loaded
+    /// `bias` bytes above the addresses in the file. This is for symbolizing traces of other
+    /// processes, or of code loaded after the symbolizer was created.
+    ///
+    /// `vaddrs` must not overlap the addresses of any object already added.
+    pub fn add_object(&mut self, path: &Path, vaddrs: Range<u64>, bias: u64) {
+        let idx = match self
+            .objects
+            .iter()
+            .position(|o| o.path == path && o.bias == bias)
+        {
+            Some(idx) => idx,
+            None => {
+                self.objects.push(Object {
+                    path: path.to_owned(),
+                    bias,
+                    loader: OnceCell::new(),
+                });
+                self.objects.len() - 1
+            }
+        };
+        let pos = self
+            .segments
+            .partition_point(|(r, _)| r.start < vaddrs.start);
+        self.segments.insert(pos, (vaddrs, idx));
+        self.cache.get_mut().clear();
+    }
+
+    /// Find the function that the virtual address `vaddr` is in. Returns `None` if `vaddr` isn't
+    /// in any known object, or the object has no symbol for it.
+    pub fn symbolize(&self, vaddr: u64) -> Option<Symbol> {
+        if let Some(sym) = self.cache.borrow().get(&vaddr) {
+            return sym.clone();
+        }
+        let sym = self.lookup(vaddr);
+        self.cache.borrow_mut().insert(vaddr, sym.clone());
+        sym
+    }
+
+    fn lookup(&self, vaddr: u64) -> Option<Symbol> {
+        let pos = self
+            .segments
+            .partition_point(|(r, _)| r.start <= vaddr)
+            .checked_sub(1)?;
+        let (range, idx) = &self.segments[pos];
+        if !range.contains(&vaddr) {
+            return None;
+        }
+        let obj = &self.objects[*idx];
+        let loader = obj
+            .loader
+            .get_or_init(|| match Loader::new(&obj.path) {
+                Ok(l) => Some(l),
+                Err(_e) => {
+                    warn!("can't read symbols from {}: {}", obj.path.display(), _e);
+                    None
+                }
+            })
+            .as_ref()?;
+        let probe = vaddr.checked_sub(obj.bias)?;
+        // The frames at `probe`, innermost first: any inlined functions, then the function that
+        // they were inlined into.
+        let mut inlined = Vec::new();
+        if let Ok(mut frames) = loader.find_frames(probe) {
+            while let Ok(Some(frame)) = frames.next() {
+                inlined.push(InlineFrame {
+                    function: frame
+                        .function
+                        .and_then(|f| f.demangle().ok().map(|n| n.into_owned())),
+                    file: frame
+                        .location
+                        .as_ref()
+                        .and_then(|l| l.file)
+                        .map(str::to_owned),
+                    line: frame.location.and_then(|l| l.line),
+                });
+            }
+        }
+        let outer = inlined.pop();
+        // Prefer the symbol table's name, which is present even without debugging information.
+        let name = match loader.find_symbol(probe) {
+            Some(n) => addr2line::demangle_auto(n.into(), None).into_owned(),
+            None => outer.as_ref()?.function.clone()?,
+        };
+        let (file, line) = outer.map_or((None, None), |f| (f.file, f.line));
+        Some(Symbol {
+            name,
+            object: obj.path.clone(),
+            file,
+            line,
+            inlined,
+        })
+    }
+
+    /// Annotate each of `blocks` (e.g. from [crate::decode::TraceDecoder::iter_blocks]) with the
+    /// function that it starts in.
+    pub fn annotate_blocks<'s, I>(
+        &'s self,
+        blocks: I,
+    ) -> impl Iterator<Item = Result<SymbolizedBlock, HWTracerError>> + 's
+    where
+        I: IntoIterator<Item = Result<Block, HWTracerError>>,
+        I::IntoIter: 's,
+    {
+        blocks.into_iter().map(move |b| {
+            let block = b?;
+            let symbol = self.symbolize(block.first_instr());
+            Ok(SymbolizedBlock { block, symbol })
+        })
+    }
+
+    /// Annotate each of `events` (e.g. from [crate::decode::TraceDecoder::iter_events]) with the
+    /// functions that it transferred control from and to.
+    pub fn annotate_events<'s, I>(
+        &'s self,
+        events: I,
+    ) -> impl Iterator<Item = Result<SymbolizedEvent, HWTracerError>> + 's
+    where
+        I: IntoIterator<Item = Result<DecodeEvent, HWTracerError>>,
+        I::IntoIter: 's,
+    {
+        events.into_iter().map(move |e| {
+            let event = e?;
+            let (from, to) = match event {
+                DecodeEvent::TracingEnabled(to) | DecodeEvent::TracingDisabled(Some(to)) => {
+                    (None, Some(to))
+                }
+                DecodeEvent::AsyncTransfer { from, to } => (Some(from), to),
+                _ => (None, None),
+            };
+            Ok(SymbolizedEvent {
+                from: from.and_then(|a| self.symbolize(a)),
+                to: to.and_then(|a| self.symbolize(a)),
+                event,
+            })
+        })
+    }
+}
+
+impl fmt::Debug for Symbolizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Symbolizer")
+            .field(
+                "objects",
+                &self.objects.iter().map(|o| &o.path).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbolizer;
+    use crate::testing::work_loop;
+
+    #[test]
+    fn this_process() {
+        let syms = Symbolizer::new().unwrap();
+        let addr = work_loop as fn(u64) -> u64 as usize as u64;
+        let sym = syms.symbolize(addr).unwrap();
+        assert!(sym.name.contains("work_loop"), "{}", sym);
+        assert_eq!(sym.object, std::env::current_exe().unwrap());
+        // Cached lookups give the same answer.
+        assert_eq!(syms.symbolize(addr), Some(sym));
+        assert_eq!(syms.symbolize(0), None);
+    }
+
+    /// Check that addresses below an object's bias are not found, rather than underflowing.
+    #[test]
+    fn below_bias() {
+        let mut syms = Symbolizer::empty();
+        let exe = std::env::current_exe().unwrap();
+        syms.add_object(&exe, 0x1000..0x2000, 0x1800);
+        assert_eq!(syms.symbolize(0x1400), None);
+    }
+
+    #[inline(always)]
+    fn inlined_work(x: u64) -> u64 {
+        work_loop(x) * 3
+    }
+
+    #[inline(never)]
+    fn calls_inlined(x: u64) -> u64 {
+        inlined_work(x) + 1
+    }
+
+    #[test]
+    fn inlined() {
+        let syms = Symbolizer::new().unwrap();
+        let start = calls_inlined as fn(u64) -> u64 as usize as u64;
+        // Some address in `calls_inlined` must be in the inlined `inlined_work`.
+        let sym = (start..start + 256)
+            .filter_map(|a| syms.symbolize(a))
+            .find(|s| !s.inlined.is_empty())
+            .unwrap();
+        assert!(sym.name.contains("calls_inlined"), "{}", sym);
+        let inner = sym.inlined[0].function.as_deref().unwrap();
+        assert!(inner.contains("inlined_work"), "{}", sym);
+        assert!(sym.to_string().contains(" inlined into "));
+        // Both functions are in this file.
+        assert!(sym.file.as_deref().unwrap().ends_with("symbolize.rs"));
+        assert_eq!(sym.file, sym.inlined[0].file);
+    }
+}
@@ -0,0 +1,154 @@
+//! Generation of synthetic traces, for benchmarking.
+//!
+//! The generated traces are streams of well-formed packets, laid out like those of a real
+//! program: PSB+ sequences at regular intervals, between which there are mostly TNT packets,
+//! with some compressed TIPs, CYCs and PADs. The addresses in them don't refer to real code, so
+//! they can be parsed and walked by decoders which don't need the traced code (e.g. ykpt), but
+//! not decoded into blocks by decoders which do (e.g. libipt).
+//!
+//! ```
+//! use hwtracer::synth::SynthTraceBuilder;
+//! let trace = SynthTraceBuilder::new(1 << 20).seed(42).build();
+//! assert!(trace.len() >= 1 << 20);
+//! ```
+
+use crate::{
+    pt::{
+        encode::{Encoder, IPCompression},
+        ExecMode,
+    },
+    Trace,
+};
+
+/// The address in whose 64KiB region all of the IPs in a generated trace lie, so that every TIP
+/// can be compressed to 16 bits.
+const BASE_IP: u64 = 0x5555_5555_0000;
+
+/// Builds a synthetic trace.
+#[derive(Clone, Debug)]
+pub struct SynthTraceBuilder {
+    len: usize,
+    seed: u64,
+    psb_period: usize,
+}
+
+impl SynthTraceBuilder {
+    /// Create a builder for a trace of at least `len` bytes.
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            seed: 1,
+            psb_period: 4096,
+        }
+    }
+
+    /// Set the seed from which the packets are chosen. Traces built with the same settings are
+    /// identical.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Set the number of bytes between PSB packets (4KiB by default, as with real traces).
+    pub fn psb_period(mut self, period: usize) -> Self {
+        self.psb_period = period;
+        self
+    }
+
+    /// Generate the trace.
+    pub fn build(self) -> Box<dyn Trace> {
+        let mut rng = Rng(self.seed.max(1));
+        let mut enc = Encoder::new();
+        enc.psb()
+            .cbr(0x20)
+            .psbend()
+            .mode_exec(ExecMode::Bits64)
+            .tip_pge(BASE_IP, IPCompression::Full);
+        let mut next_psb = self.psb_period;
+        while enc.len() < self.len {
+            if enc.len() >= next_psb {
+                // PSB resets the last IP, so the next TIP has an uncompressed IP.
+                enc.psb()
+                    .cbr(0x20)
+                    .psbend()
+                    .tip(BASE_IP | rng.next() & 0xffff, IPCompression::Full);
+                next_psb = enc.len() + self.psb_period;
+                continue;
+            }
+            let r = rng.next();
+            match r % 100 {
+                // Short TNT, with 1 to 6 branches.
+                0..=59 => {
+                    enc.short_tnt(&branches(1 + (r >> 8) % 6, r >> 16));
+                }
+                // Long TNT, with 1 to 47 branches.
+                60..=64 => {
+                    enc.long_tnt(&branches(1 + (r >> 8) % 47, r >> 16));
+                }
+                // TIP with a 16-bit compressed IP.
+                65..=84 => {
+                    enc.tip(BASE_IP | (r >> 8) & 0xffff, IPCompression::Update16);
+                }
+                // CYC, without extended bytes.
+                85..=94 => {
+                    enc.cyc((r >> 8) & 0x1f);
+                }
+                // PAD.
+                _ => {
+                    enc.pad();
+                }
+            }
+        }
+        enc.tip_pgd(0, IPCompression::Suppressed);
+        <dyn Trace>::from_bytes(enc.into_bytes())
+    }
+}
+
+/// Returns `n` branch decisions, taken from the low `n` bits of `bits`, the oldest being the most
+/// significant.
+fn branches(n: u64, bits: u64) -> Vec<bool> {
+    (0..n).rev().map(|i| bits >> i & 1 != 0).collect()
+}
+
+/// A xorshift64* pseudo-random number generator: fast, deterministic, and plenty random enough
+/// for choosing packets.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SynthTraceBuilder;
+    use crate::pt::{Packet, PacketParser};
+
+    #[test]
+    fn well_formed() {
+        let trace = SynthTraceBuilder::new(1 << 16).seed(3).build();
+        assert!(trace.len() >= 1 << 16);
+        let psbs = trace.psb_offsets();
+        assert_eq!(psbs.len(), 16);
+        assert!(psbs
+            .windows(2)
+            .all(|w| (4096..4096 + 64).contains(&(w[1] - w[0]))));
+        let pkts = PacketParser::new(trace.bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(matches!(pkts[0], Packet::PSB(_)));
+        assert!(pkts.iter().any(|p| matches!(p, Packet::LongTNT(_))));
+        assert!(pkts.iter().any(|p| matches!(p, Packet::TIP(..))));
+        assert!(matches!(pkts.last(), Some(Packet::TIPPGD(..))));
+
+        // The same settings give the same trace.
+        let again = SynthTraceBuilder::new(1 << 16).seed(3).build();
+        assert_eq!(trace.bytes(), again.bytes());
+        let other = SynthTraceBuilder::new(1 << 16).seed(4).build();
+        assert_ne!(trace.bytes(), other.bytes());
+    }
+}
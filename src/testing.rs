@@ -0,0 +1,103 @@
+//! Helpers for testing code which uses hwtracer (enabled with the `testing` feature).
+//!
+//! Tracing needs hardware support (and e.g. permission to use `perf`) that CI machines often lack,
+//! so tests which collect traces should get their collector from [collector], and skip themselves
+//! if it returns `None`:
+//!
+//! ```no_run
+//! use hwtracer::testing::{collector, trace_closure, work_loop};
+//!
+//! let Some(tc) = collector() else {
+//!     return;
+//! };
+//! let trace = trace_closure(&tc, || work_loop(100)).unwrap();
+//! assert_ne!(trace.len(), 0);
+//! ```
+//!
+//! Setting the [REQUIRE_HW_ENV_VAR] environment variable makes such tests fail instead, so that a
+//! machine which is expected to support tracing can't silently skip them.
+
+#[cfg(feature = "decode")]
+use crate::decode::{TraceDecoder, TraceDecoderBuilder, TraceDecoderKind};
+#[cfg(feature = "collect")]
+use crate::{
+    collect::{TraceCollector, TraceCollectorBuilder, TraceCollectorKind},
+    errors::HWTracerError,
+    Trace,
+};
+#[cfg(feature = "collect")]
+use std::hint::black_box;
+use std::{env, time::SystemTime};
+
+/// The environment variable which, if set (to anything but the empty string), makes [collector]
+/// and [decoder] panic rather than return `None`.
+pub const REQUIRE_HW_ENV_VAR: &str = "HWTRACER_REQUIRE_HW";
+
+/// A loop that does some work that we can use to build a trace. The result is meaningless, but
+/// stops the compiler from eliminating the loop.
+#[inline(never)]
+pub fn work_loop(iters: u64) -> u64 {
+    let mut res = 0;
+    for _ in 0..iters {
+        // Computation which stops the compiler from eliminating the loop.
+        res += SystemTime::now().elapsed().unwrap().subsec_nanos() as u64;
+    }
+    res
+}
+
+/// Trace a closure that returns a u64 with `tc`, returning the trace.
+///
+/// The closure's result is kept alive so that the work it does isn't optimised away. An error is
+/// returned if the collector can't be started or stopped.
+#[cfg(feature = "collect")]
+pub fn trace_closure<F>(tc: &TraceCollector, f: F) -> Result<Box<dyn Trace>, HWTracerError>
+where
+    F: FnOnce() -> u64,
+{
+    tc.start_thread_collector()?;
+    black_box(f());
+    tc.stop_thread_collector()
+}
+
+/// Returns a trace collector for the current machine, or `None` (after printing why to stderr)
+/// if no kind of collector is available, in which case the calling test should skip itself.
+///
+/// # Panics
+///
+/// If no collector is available and [REQUIRE_HW_ENV_VAR] is set, or if a collector is available
+/// but can't be built.
+#[cfg(feature = "collect")]
+pub fn collector() -> Option<TraceCollector> {
+    if TraceCollectorKind::available().is_empty() {
+        skip("no trace collector is available");
+        return None;
+    }
+    Some(TraceCollectorBuilder::new().build().unwrap())
+}
+
+/// Returns a decoder of the given kind, or `None` (after printing why to stderr) if that kind
+/// isn't available, in which case the calling test should skip itself.
+///
+/// # Panics
+///
+/// If the decoder isn't available and [REQUIRE_HW_ENV_VAR] is set, or if it is available but
+/// can't be built.
+#[cfg(feature = "decode")]
+pub fn decoder(kind: TraceDecoderKind) -> Option<Box<dyn TraceDecoder>> {
+    if !kind.is_available() {
+        skip(&format!("the {} decoder isn't available", kind));
+        return None;
+    }
+    Some(TraceDecoderBuilder::new().kind(kind).build().unwrap())
+}
+
+/// Report that a test is being skipped because `reason`, or panic if [REQUIRE_HW_ENV_VAR] is set.
+fn skip(reason: &str) {
+    if env::var_os(REQUIRE_HW_ENV_VAR)
+        .filter(|v| !v.is_empty())
+        .is_some()
+    {
+        panic!("{} (and {} is set)", reason, REQUIRE_HW_ENV_VAR);
+    }
+    eprintln!("skipping test: {}", reason);
+}
@@ -36,7 +36,6 @@
 // SOFTWARE.
 
 #include <stdbool.h>
-#include <intel-pt.h>
 #include "hwtracer_private.h"
 
 /*